@@ -88,7 +88,10 @@ impl From<&SignalFfiError> for SignalErrorCode {
             }
 
             SignalFfiError::Signal(SignalProtocolError::InvalidPreKeyId)
-            | SignalFfiError::Signal(SignalProtocolError::InvalidSignedPreKeyId) => {
+            | SignalFfiError::Signal(SignalProtocolError::PreKeyAlreadyConsumed(_))
+            | SignalFfiError::Signal(SignalProtocolError::InvalidSignedPreKeyId)
+            | SignalFfiError::Signal(SignalProtocolError::PreKeyNotFound(_))
+            | SignalFfiError::Signal(SignalProtocolError::SignedPreKeyNotFound(_)) => {
                 SignalErrorCode::InvalidKeyIdentifier
             }
 
@@ -103,13 +106,15 @@ impl From<&SignalFfiError> for SignalErrorCode {
             SignalFfiError::Signal(SignalProtocolError::NoKeyTypeIdentifier)
             | SignalFfiError::Signal(SignalProtocolError::BadKeyType(_))
             | SignalFfiError::Signal(SignalProtocolError::BadKeyLength(_, _))
+            | SignalFfiError::Signal(SignalProtocolError::BadKeyData(_))
             | SignalFfiError::DeviceTransfer(DeviceTransferError::KeyDecodingFailed)
             | SignalFfiError::HsmEnclave(HsmEnclaveError::InvalidPublicKeyError)
             | SignalFfiError::SignalCrypto(SignalCryptoError::InvalidKeySize) => {
                 SignalErrorCode::InvalidKey
             }
 
-            SignalFfiError::Signal(SignalProtocolError::SessionNotFound(_)) => {
+            SignalFfiError::Signal(SignalProtocolError::SessionNotFound(_))
+            | SignalFfiError::Signal(SignalProtocolError::SessionCorruptRecoverable(_)) => {
                 SignalErrorCode::SessionNotFound
             }
 
@@ -131,11 +136,14 @@ impl From<&SignalFfiError> for SignalErrorCode {
 
             SignalFfiError::Signal(SignalProtocolError::CiphertextMessageTooShort(_))
             | SignalFfiError::Signal(SignalProtocolError::InvalidCiphertext)
+            | SignalFfiError::Signal(SignalProtocolError::InvalidCiphertextLength { .. })
             | SignalFfiError::SignalCrypto(SignalCryptoError::InvalidTag) => {
                 SignalErrorCode::InvalidCiphertext
             }
 
             SignalFfiError::Signal(SignalProtocolError::UnrecognizedMessageVersion(_))
+            | SignalFfiError::Signal(SignalProtocolError::UnsupportedSessionVersion(_))
+            | SignalFfiError::Signal(SignalProtocolError::VersionDowngrade { .. })
             | SignalFfiError::Signal(SignalProtocolError::UnknownSealedSenderVersion(_)) => {
                 SignalErrorCode::UnrecognizedMessageVersion
             }
@@ -145,6 +153,13 @@ impl From<&SignalFfiError> for SignalErrorCode {
             }
 
             SignalFfiError::Signal(SignalProtocolError::InvalidMessage(_))
+            | SignalFfiError::Signal(SignalProtocolError::InvalidPreKeyMessage(_))
+            | SignalFfiError::Signal(SignalProtocolError::UnexpectedPreKeyMessage(_))
+            | SignalFfiError::Signal(SignalProtocolError::MessageGapTooLarge { .. })
+            | SignalFfiError::Signal(SignalProtocolError::AmbiguousSession(_))
+            | SignalFfiError::Signal(SignalProtocolError::DecryptFailedSessionArchived(_))
+            | SignalFfiError::Signal(SignalProtocolError::MessageTooLong(_))
+            | SignalFfiError::Signal(SignalProtocolError::ReflectedIdentity(_))
             | SignalFfiError::Signal(SignalProtocolError::InvalidProtobufEncoding)
             | SignalFfiError::Signal(SignalProtocolError::InvalidSealedSenderMessage(_))
             | SignalFfiError::HsmEnclave(HsmEnclaveError::HSMCommunicationError(_)) => {
@@ -156,6 +171,7 @@ impl From<&SignalFfiError> for SignalErrorCode {
             }
 
             SignalFfiError::Signal(SignalProtocolError::UntrustedIdentity(_))
+            | SignalFfiError::Signal(SignalProtocolError::PinnedIdentityMismatch(_))
             | SignalFfiError::HsmEnclave(HsmEnclaveError::TrustedCodeError) => {
                 SignalErrorCode::UntrustedIdentity
             }
@@ -163,6 +179,9 @@ impl From<&SignalFfiError> for SignalErrorCode {
             SignalFfiError::Signal(SignalProtocolError::InvalidState(_, _))
             | SignalFfiError::Signal(SignalProtocolError::NoSenderKeyState)
             | SignalFfiError::Signal(SignalProtocolError::InvalidSessionStructure)
+            | SignalFfiError::Signal(SignalProtocolError::MessageKeysWrapped)
+            | SignalFfiError::Signal(SignalProtocolError::SessionConcurrentlyModified(_))
+            | SignalFfiError::Signal(SignalProtocolError::WouldMutate)
             | SignalFfiError::HsmEnclave(HsmEnclaveError::InvalidBridgeStateError) => {
                 SignalErrorCode::InvalidState
             }
@@ -172,7 +191,8 @@ impl From<&SignalFfiError> for SignalErrorCode {
             | SignalFfiError::SignalCrypto(_)
             | SignalFfiError::ZkGroup(ZkGroupError::BadArgs) => SignalErrorCode::InvalidArgument,
 
-            SignalFfiError::Signal(SignalProtocolError::ApplicationCallbackError(_, _)) => {
+            SignalFfiError::Signal(SignalProtocolError::ApplicationCallbackError(_, _))
+            | SignalFfiError::Signal(SignalProtocolError::StoreTransient(_)) => {
                 SignalErrorCode::CallbackError
             }
 
@@ -72,7 +72,8 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
             ))
         }
 
-        SignalJniError::Signal(SignalProtocolError::UntrustedIdentity(ref addr)) => {
+        SignalJniError::Signal(SignalProtocolError::UntrustedIdentity(ref addr))
+        | SignalJniError::Signal(SignalProtocolError::PinnedIdentityMismatch(ref addr)) => {
             let result = env.throw_new(
                 jni_class_name!(org.whispersystems.libsignal.UntrustedIdentityException),
                 addr.name(),
@@ -190,7 +191,10 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
         SignalJniError::Signal(SignalProtocolError::InvalidState(_, _))
         | SignalJniError::Signal(SignalProtocolError::NoSenderKeyState)
         | SignalJniError::SignalCrypto(SignalCryptoError::InvalidState)
-        | SignalJniError::Signal(SignalProtocolError::InvalidSessionStructure) => {
+        | SignalJniError::Signal(SignalProtocolError::InvalidSessionStructure)
+        | SignalJniError::Signal(SignalProtocolError::MessageKeysWrapped)
+        | SignalJniError::Signal(SignalProtocolError::SessionConcurrentlyModified(_))
+        | SignalJniError::Signal(SignalProtocolError::WouldMutate) => {
             jni_class_name!(java.lang.IllegalStateException)
         }
 
@@ -205,6 +209,7 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
         SignalJniError::IntegerOverflow(_)
         | SignalJniError::Jni(_)
         | SignalJniError::Signal(SignalProtocolError::ApplicationCallbackError(_, _))
+        | SignalJniError::Signal(SignalProtocolError::StoreTransient(_))
         | SignalJniError::Signal(SignalProtocolError::FfiBindingError(_))
         | SignalJniError::Signal(SignalProtocolError::InternalError(_))
         | SignalJniError::DeviceTransfer(DeviceTransferError::InternalError(_))
@@ -222,7 +227,10 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
         }
 
         SignalJniError::Signal(SignalProtocolError::InvalidPreKeyId)
-        | SignalJniError::Signal(SignalProtocolError::InvalidSignedPreKeyId) => {
+        | SignalJniError::Signal(SignalProtocolError::PreKeyAlreadyConsumed(_))
+        | SignalJniError::Signal(SignalProtocolError::InvalidSignedPreKeyId)
+        | SignalJniError::Signal(SignalProtocolError::PreKeyNotFound(_))
+        | SignalJniError::Signal(SignalProtocolError::SignedPreKeyNotFound(_)) => {
             jni_class_name!(org.whispersystems.libsignal.InvalidKeyIdException)
         }
 
@@ -230,17 +238,27 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
         | SignalJniError::Signal(SignalProtocolError::SignatureValidationFailed)
         | SignalJniError::Signal(SignalProtocolError::BadKeyType(_))
         | SignalJniError::Signal(SignalProtocolError::BadKeyLength(_, _))
+        | SignalJniError::Signal(SignalProtocolError::BadKeyData(_))
         | SignalJniError::SignalCrypto(SignalCryptoError::InvalidKeySize) => {
             jni_class_name!(org.whispersystems.libsignal.InvalidKeyException)
         }
 
-        SignalJniError::Signal(SignalProtocolError::SessionNotFound(_)) => {
+        SignalJniError::Signal(SignalProtocolError::SessionNotFound(_))
+        | SignalJniError::Signal(SignalProtocolError::SessionCorruptRecoverable(_)) => {
             jni_class_name!(org.whispersystems.libsignal.NoSessionException)
         }
 
         SignalJniError::Signal(SignalProtocolError::InvalidMessage(_))
+        | SignalJniError::Signal(SignalProtocolError::InvalidPreKeyMessage(_))
+        | SignalJniError::Signal(SignalProtocolError::UnexpectedPreKeyMessage(_))
+        | SignalJniError::Signal(SignalProtocolError::MessageGapTooLarge { .. })
+        | SignalJniError::Signal(SignalProtocolError::AmbiguousSession(_))
+        | SignalJniError::Signal(SignalProtocolError::DecryptFailedSessionArchived(_))
+        | SignalJniError::Signal(SignalProtocolError::MessageTooLong(_))
+        | SignalJniError::Signal(SignalProtocolError::ReflectedIdentity(_))
         | SignalJniError::Signal(SignalProtocolError::CiphertextMessageTooShort(_))
         | SignalJniError::Signal(SignalProtocolError::InvalidCiphertext)
+        | SignalJniError::Signal(SignalProtocolError::InvalidCiphertextLength { .. })
         | SignalJniError::Signal(SignalProtocolError::InvalidProtobufEncoding)
         | SignalJniError::Signal(SignalProtocolError::ProtobufDecodingError(_))
         | SignalJniError::Signal(SignalProtocolError::InvalidSealedSenderMessage(_))
@@ -250,6 +268,8 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
 
         SignalJniError::Signal(SignalProtocolError::UnrecognizedCiphertextVersion(_))
         | SignalJniError::Signal(SignalProtocolError::UnrecognizedMessageVersion(_))
+        | SignalJniError::Signal(SignalProtocolError::UnsupportedSessionVersion(_))
+        | SignalJniError::Signal(SignalProtocolError::VersionDowngrade { .. })
         | SignalJniError::Signal(SignalProtocolError::UnknownSealedSenderVersion(_)) => {
             jni_class_name!(org.whispersystems.libsignal.InvalidVersionException)
         }
@@ -270,6 +290,7 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
 
         SignalJniError::Signal(SignalProtocolError::SealedSenderSelfSend)
         | SignalJniError::Signal(SignalProtocolError::UntrustedIdentity(_))
+        | SignalJniError::Signal(SignalProtocolError::PinnedIdentityMismatch(_))
         | SignalJniError::Signal(SignalProtocolError::FingerprintVersionMismatch(_, _))
         | SignalJniError::Signal(SignalProtocolError::InvalidRegistrationId(..))
         | SignalJniError::UnexpectedPanic(_)
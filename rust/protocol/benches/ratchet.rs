@@ -98,6 +98,31 @@ pub fn ratchet_forward(mut c: &mut Criterion) {
     ratchet_forward_result(&mut c).expect("success");
 }
 
-criterion_group!(ratchet, ratchet_forward);
+pub fn chain_key_catch_up(c: &mut Criterion) {
+    let mut group = c.benchmark_group("chain key catch-up");
+    let chain_key = ChainKey::new(&[0x42u8; 32], 0).expect("valid key");
+
+    for gap in [100, 2000].iter() {
+        let gap = *gap;
+
+        group.bench_function(format!("step-by-step {}", gap), |b| {
+            b.iter(|| {
+                let mut chain_key = chain_key.clone();
+                let mut keys = Vec::with_capacity(gap as usize);
+                while chain_key.index() < gap {
+                    keys.push(chain_key.message_keys().expect("ok"));
+                    chain_key = chain_key.next_chain_key().expect("ok");
+                }
+                keys
+            })
+        });
+
+        group.bench_function(format!("range {}", gap), |b| {
+            b.iter(|| chain_key.derive_message_keys_range(0, gap).expect("ok"))
+        });
+    }
+}
+
+criterion_group!(ratchet, ratchet_forward, chain_key_catch_up);
 
 criterion_main!(ratchet);
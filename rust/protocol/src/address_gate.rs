@@ -0,0 +1,165 @@
+//
+// Copyright 2021 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use crate::ProtocolAddress;
+
+use futures_util::lock::Mutex as AsyncMutex;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+/// Runs operations on sessions one at a time per [`ProtocolAddress`].
+///
+/// Cipher operations load a session, update it, and store it again. Two of them running
+/// concurrently for the same address can each store their own update, and whichever stores last
+/// wins. Wrapping the calls in [`run`](Self::run) with a shared gate waits for any earlier
+/// operation on the same address to finish first, while operations on different addresses still
+/// run concurrently:
+///
+/// ```ignore
+/// let ptext = gate
+///     .run(&address, message_decrypt(&ciphertext, &address, /* stores... */))
+///     .await?;
+/// ```
+///
+/// This is only a lock: it does not detect modifications made without going through the gate,
+/// which [`SessionStore::store_session_if_version`](crate::SessionStore::store_session_if_version)
+/// does.
+///
+/// Requires the `address-gate` feature.
+#[derive(Default)]
+pub struct PerAddressGate {
+    locks: Mutex<HashMap<ProtocolAddress, Arc<AsyncMutex<()>>>>,
+}
+
+impl PerAddressGate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `operation` once no other operation for `address` is running through this gate.
+    ///
+    /// Operations for the same address run in the order they started waiting. Dropping the
+    /// returned future gives up its turn.
+    pub async fn run<F: Future>(&self, address: &ProtocolAddress, operation: F) -> F::Output {
+        let entry = self.entry(address);
+        let _guard = entry.lock.lock().await;
+        operation.await
+    }
+
+    /// The number of operations for `address` that are running or waiting to run.
+    pub fn in_flight(&self, address: &ProtocolAddress) -> usize {
+        self.locks
+            .lock()
+            .expect("not poisoned")
+            .get(address)
+            .map_or(0, |lock| Arc::strong_count(lock) - 1)
+    }
+
+    /// The number of addresses with an operation running or waiting to run.
+    pub fn active_addresses(&self) -> usize {
+        self.locks.lock().expect("not poisoned").len()
+    }
+
+    fn entry<'a>(&'a self, address: &'a ProtocolAddress) -> GateEntry<'a> {
+        let lock = self
+            .locks
+            .lock()
+            .expect("not poisoned")
+            .entry(address.clone())
+            .or_insert_with(|| Arc::new(AsyncMutex::new(())))
+            .clone();
+        GateEntry {
+            gate: self,
+            address,
+            lock,
+        }
+    }
+}
+
+/// A reference to the lock for an address, which removes the lock from the gate when the last
+/// operation for the address is done.
+struct GateEntry<'a> {
+    gate: &'a PerAddressGate,
+    address: &'a ProtocolAddress,
+    lock: Arc<AsyncMutex<()>>,
+}
+
+impl Drop for GateEntry<'_> {
+    fn drop(&mut self) {
+        let mut locks = self.gate.locks.lock().expect("not poisoned");
+        // References are only taken while holding `locks`, so this cannot race with a new one.
+        if Arc::strong_count(&self.lock) == 2 {
+            locks.remove(self.address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_util::future::poll_fn;
+    use futures_util::task::noop_waker_ref;
+    use futures_util::FutureExt;
+    use std::cell::{Cell, RefCell};
+    use std::task::{Context, Poll};
+
+    #[test]
+    fn test_operations_for_one_address_are_serialized() {
+        let alice = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob = ProtocolAddress::new("+14151111112".to_owned(), 1);
+        let gate = PerAddressGate::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let release = Cell::new(false);
+        let order = RefCell::new(Vec::new());
+        let mut first = Box::pin(gate.run(
+            &alice,
+            poll_fn(|_| {
+                if release.get() {
+                    order.borrow_mut().push(1);
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }),
+        ));
+        let mut second = Box::pin(gate.run(&alice, async { order.borrow_mut().push(2) }));
+
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(gate.in_flight(&alice), 2);
+
+        // Other addresses are not blocked.
+        assert_eq!(gate.run(&bob, async { 5 }).now_or_never(), Some(5));
+        assert_eq!(gate.in_flight(&bob), 0);
+
+        release.set(true);
+        assert!(first.as_mut().poll(&mut cx).is_ready());
+        assert!(second.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(*order.borrow(), [1, 2]);
+        drop((first, second));
+        assert_eq!(gate.active_addresses(), 0);
+    }
+
+    #[test]
+    fn test_cancelled_operations_release_the_address() {
+        let alice = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let gate = PerAddressGate::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let mut pending = Box::pin(gate.run(&alice, poll_fn(|_| Poll::<()>::Pending)));
+        assert!(pending.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(gate.active_addresses(), 1);
+        drop(pending);
+        assert_eq!(gate.active_addresses(), 0);
+
+        assert_eq!(
+            gate.run(&alice, async { "done" }).now_or_never(),
+            Some("done")
+        );
+    }
+}
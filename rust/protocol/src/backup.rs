@@ -0,0 +1,302 @@
+//
+// Copyright 2021 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Backup and restore of a complete set of protocol stores.
+//!
+//! A snapshot holds the local identity key pair and registration ID, every known remote identity,
+//! every one-time and signed pre-key, and every session. Sender keys are not included; group
+//! sessions are re-established by redistributing sender keys after a restore.
+//!
+//! Snapshots are taken through the store traits, so the stores must support enumerating their
+//! contents: [`SessionStore::all_addresses`], [`IdentityKeyStore::all_identities`],
+//! [`PreKeyStore::pre_key_ids`], and [`SignedPreKeyStore::signed_pre_key_ids`].
+//!
+//! The format is a version byte followed by a protobuf message. Snapshots contain private keys and
+//! must be stored as securely as the stores themselves.
+
+use crate::proto::storage::{protocol_snapshot_structure, ProtocolSnapshotStructure};
+use crate::state::{PreKeyId, SignedPreKeyId};
+use crate::{
+    Context, IdentityKey, IdentityKeyPair, IdentityKeyStore, InMemSignalProtocolStore,
+    PreKeyRecord, PreKeyStore, ProtocolAddress, Result, SessionRecord, SessionStore,
+    SignalProtocolError, SignedPreKeyRecord, SignedPreKeyStore,
+};
+
+use prost::Message;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+const SNAPSHOT_VERSION: u8 = 1;
+
+/// Serializes the contents of every store except the sender key store.
+///
+/// Entries are written in a fixed order, so the same store contents always produce the same
+/// snapshot. One-time pre-keys that are currently
+/// [claimed](crate::PreKeyStore::claim_pre_key) are included as ordinary pre-keys.
+pub async fn snapshot(
+    session_store: &dyn SessionStore,
+    identity_store: &dyn IdentityKeyStore,
+    pre_key_store: &dyn PreKeyStore,
+    signed_pre_key_store: &dyn SignedPreKeyStore,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    let identity_key_pair = identity_store.get_identity_key_pair(ctx).await?;
+    let registration_id = identity_store.get_local_registration_id(ctx).await?;
+
+    let mut identities = identity_store.all_identities(ctx).await?;
+    identities.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+
+    let mut pre_key_ids = pre_key_store.pre_key_ids(ctx).await?;
+    pre_key_ids.sort_unstable();
+    let mut pre_keys = Vec::with_capacity(pre_key_ids.len());
+    for id in pre_key_ids {
+        pre_keys.push(pre_key_store.get_pre_key(id, ctx).await?.serialize()?);
+    }
+
+    let mut signed_pre_key_ids = signed_pre_key_store.signed_pre_key_ids(ctx).await?;
+    signed_pre_key_ids.sort_unstable();
+    let mut signed_pre_keys = Vec::with_capacity(signed_pre_key_ids.len());
+    for id in signed_pre_key_ids {
+        signed_pre_keys.push(
+            signed_pre_key_store
+                .get_signed_pre_key(id, ctx)
+                .await?
+                .serialize()?,
+        );
+    }
+
+    let mut addresses = session_store.all_addresses(ctx).await?;
+    addresses.sort();
+    let mut sessions = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        // A session removed since all_addresses was called is simply left out.
+        if let Some(record) = session_store.load_session(&address, ctx).await? {
+            sessions.push(protocol_snapshot_structure::Session {
+                name: address.name().to_owned(),
+                device_id: address.device_id(),
+                record: record.serialize()?,
+            });
+        }
+    }
+
+    let structure = ProtocolSnapshotStructure {
+        identity_key_pair: identity_key_pair.serialize().into_vec(),
+        registration_id,
+        identities: identities
+            .into_iter()
+            .map(|(address, key, _)| protocol_snapshot_structure::Identity {
+                name: address.name().to_owned(),
+                device_id: address.device_id(),
+                identity_key: key.serialize().into_vec(),
+            })
+            .collect(),
+        pre_keys,
+        signed_pre_keys,
+        sessions,
+    };
+
+    let mut result = Vec::with_capacity(1 + structure.encoded_len());
+    result.push(SNAPSHOT_VERSION);
+    structure.encode(&mut result)?;
+    Ok(result)
+}
+
+/// Writes the contents saved by [`snapshot`] into existing stores.
+///
+/// The identity store must already hold the snapshot's identity key pair and registration ID,
+/// since the store traits cannot change them; use [`restore_in_memory`] to start from scratch.
+/// Entries already in the stores are kept unless the snapshot has one for the same address or ID,
+/// in which case they are overwritten.
+///
+/// Nothing is written unless the whole snapshot is valid. Besides each record being well-formed,
+/// this requires that:
+///
+/// - the identity key pair's public and private keys match,
+/// - no address, pre-key ID, or signed pre-key ID appears twice, and
+/// - each current session was set up with the local identity key and with the identity key
+///   stored for its address.
+///
+/// Any violation fails with [`SignalProtocolError::InvalidArgument`], as does restoring into an
+/// identity store with a different key pair or registration ID.
+pub async fn restore(
+    bytes: &[u8],
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    ctx: Context,
+) -> Result<()> {
+    let contents = SnapshotContents::parse(bytes)?;
+
+    let identity_key_pair = identity_store.get_identity_key_pair(ctx).await?;
+    let registration_id = identity_store.get_local_registration_id(ctx).await?;
+    if identity_key_pair.serialize() != contents.identity_key_pair.serialize()
+        || registration_id != contents.registration_id
+    {
+        return Err(SignalProtocolError::InvalidArgument(
+            "protocol snapshot belongs to a different local identity".to_owned(),
+        ));
+    }
+
+    contents
+        .write(
+            session_store,
+            identity_store,
+            pre_key_store,
+            signed_pre_key_store,
+            ctx,
+        )
+        .await
+}
+
+/// Rebuilds the stores saved by [`snapshot`] as a new [`InMemSignalProtocolStore`], with an
+/// empty sender key store.
+///
+/// The snapshot is validated as described for [`restore`].
+pub async fn restore_in_memory(bytes: &[u8]) -> Result<InMemSignalProtocolStore> {
+    let contents = SnapshotContents::parse(bytes)?;
+    let mut store =
+        InMemSignalProtocolStore::new(contents.identity_key_pair, contents.registration_id)?;
+    contents
+        .write(
+            &mut store.session_store,
+            &mut store.identity_store,
+            &mut store.pre_key_store,
+            &mut store.signed_pre_key_store,
+            None,
+        )
+        .await?;
+    Ok(store)
+}
+
+/// A snapshot that has been decoded and checked for consistency.
+struct SnapshotContents {
+    identity_key_pair: IdentityKeyPair,
+    registration_id: u32,
+    identities: HashMap<ProtocolAddress, IdentityKey>,
+    pre_keys: HashMap<PreKeyId, PreKeyRecord>,
+    signed_pre_keys: HashMap<SignedPreKeyId, SignedPreKeyRecord>,
+    sessions: HashMap<ProtocolAddress, SessionRecord>,
+}
+
+impl SnapshotContents {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let (&version, body) = bytes.split_first().ok_or_else(|| {
+            SignalProtocolError::InvalidArgument("protocol snapshot is empty".to_owned())
+        })?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "unsupported protocol snapshot version {}",
+                version
+            )));
+        }
+        let structure = ProtocolSnapshotStructure::decode(body)?;
+
+        let identity_key_pair = IdentityKeyPair::try_from(&structure.identity_key_pair[..])?;
+        if identity_key_pair.private_key().public_key()? != *identity_key_pair.public_key() {
+            return Err(SignalProtocolError::InvalidArgument(
+                "protocol snapshot identity key pair does not match".to_owned(),
+            ));
+        }
+
+        let mut identities = HashMap::with_capacity(structure.identities.len());
+        for identity in structure.identities {
+            let address = ProtocolAddress::new(identity.name, identity.device_id);
+            let key = IdentityKey::decode(&identity.identity_key)?;
+            match identities.entry(address) {
+                Entry::Occupied(entry) => return Err(duplicate("identity", entry.key())),
+                Entry::Vacant(entry) => entry.insert(key),
+            };
+        }
+
+        let mut pre_keys = HashMap::with_capacity(structure.pre_keys.len());
+        for bytes in structure.pre_keys {
+            let record = PreKeyRecord::deserialize(&bytes)?;
+            let id = record.id()?;
+            if pre_keys.insert(id, record).is_some() {
+                return Err(duplicate("pre-key", id));
+            }
+        }
+
+        let mut signed_pre_keys = HashMap::with_capacity(structure.signed_pre_keys.len());
+        for bytes in structure.signed_pre_keys {
+            let record = SignedPreKeyRecord::deserialize(&bytes)?;
+            let id = record.id()?;
+            if signed_pre_keys.insert(id, record).is_some() {
+                return Err(duplicate("signed pre-key", id));
+            }
+        }
+
+        let local_identity_key = identity_key_pair.identity_key().serialize();
+        let mut sessions = HashMap::with_capacity(structure.sessions.len());
+        for session in structure.sessions {
+            let address = ProtocolAddress::new(session.name, session.device_id);
+            let record = SessionRecord::deserialize(&session.record)?;
+            if record.has_current_session_state() {
+                if record.local_identity_key_bytes()? != *local_identity_key {
+                    return Err(SignalProtocolError::InvalidArgument(format!(
+                        "session for {} in protocol snapshot belongs to a different local identity",
+                        address
+                    )));
+                }
+                let stored_identity = identities
+                    .get(&address)
+                    .map(|key| key.serialize().into_vec());
+                if record.remote_identity_key_bytes()? != stored_identity {
+                    return Err(SignalProtocolError::InvalidArgument(format!(
+                        "session for {} in protocol snapshot does not match its stored identity",
+                        address
+                    )));
+                }
+            }
+            match sessions.entry(address) {
+                Entry::Occupied(entry) => return Err(duplicate("session", entry.key())),
+                Entry::Vacant(entry) => entry.insert(record),
+            };
+        }
+
+        Ok(Self {
+            identity_key_pair,
+            registration_id: structure.registration_id,
+            identities,
+            pre_keys,
+            signed_pre_keys,
+            sessions,
+        })
+    }
+
+    async fn write(
+        self,
+        session_store: &mut dyn SessionStore,
+        identity_store: &mut dyn IdentityKeyStore,
+        pre_key_store: &mut dyn PreKeyStore,
+        signed_pre_key_store: &mut dyn SignedPreKeyStore,
+        ctx: Context,
+    ) -> Result<()> {
+        for (address, key) in &self.identities {
+            identity_store.save_identity(address, key, ctx).await?;
+        }
+        for (id, record) in &self.pre_keys {
+            pre_key_store.save_pre_key(*id, record, ctx).await?;
+        }
+        for (id, record) in &self.signed_pre_keys {
+            signed_pre_key_store
+                .save_signed_pre_key(*id, record, ctx)
+                .await?;
+        }
+        for (address, record) in &self.sessions {
+            session_store.store_session(address, record, ctx).await?;
+        }
+        Ok(())
+    }
+}
+
+fn duplicate(kind: &str, key: impl std::fmt::Display) -> SignalProtocolError {
+    SignalProtocolError::InvalidArgument(format!(
+        "protocol snapshot contains more than one {} for {}",
+        kind, key
+    ))
+}
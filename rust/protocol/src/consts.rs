@@ -6,5 +6,10 @@
 pub const MAX_FORWARD_JUMPS: usize = 25_000;
 pub const MAX_MESSAGE_KEYS: usize = 2000;
 pub const MAX_RECEIVER_CHAINS: usize = 5;
+pub const MAX_IMPORTED_RECEIVER_CHAINS: usize = 20;
 pub const ARCHIVED_STATES_MAX_LENGTH: usize = 40;
 pub const MAX_SENDER_KEY_STATES: usize = 5;
+pub const MAX_SESSION_STORE_ATTEMPTS: usize = 3;
+#[cfg(feature = "compression")]
+pub const MAX_DECOMPRESSED_MESSAGE_LEN: usize = 1 << 20;
+pub const MAX_STORE_RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
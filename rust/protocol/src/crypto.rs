@@ -3,9 +3,22 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use crate::{error::Result, SignalProtocolError};
+//! Symmetric primitives used by the protocol.
+//!
+//! The public functions are re-exported from the crate root. The AES-256-CBC functions let
+//! applications reuse the same vetted implementation for their own data.
+//! [`decrypt_with_message_keys`] and [`verify_message_mac`] expose the two steps of decrypting a
+//! [`SignalMessage`] with keys that were saved outside of a session, such as backed-up message
+//! history.
+//!
+//! With the experimental `weak-ciphers` feature, AES-128-CBC equivalents are also available. They
+//! exist only for benchmarking on constrained hardware and must not be used in production.
+
+use crate::{error::Result, IdentityKey, MessageKeys, SignalMessage, SignalProtocolError};
 
 use aes::cipher::{NewCipher, StreamCipher};
+#[cfg(feature = "weak-ciphers")]
+use aes::Aes128;
 use aes::{Aes256, Aes256Ctr};
 use block_modes::block_padding::Pkcs7;
 use block_modes::{BlockMode, Cbc};
@@ -13,7 +26,7 @@ use hmac::{Hmac, Mac, NewMac};
 use sha2::Sha256;
 use subtle::ConstantTimeEq;
 
-pub fn aes_256_ctr_encrypt(ptext: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+pub(crate) fn aes_256_ctr_encrypt(ptext: &[u8], key: &[u8]) -> Result<Vec<u8>> {
     if key.len() != 32 {
         return Err(SignalProtocolError::InvalidCipherCryptographicParameters(
             32, 0,
@@ -28,11 +41,20 @@ pub fn aes_256_ctr_encrypt(ptext: &[u8], key: &[u8]) -> Result<Vec<u8>> {
     Ok(ctext)
 }
 
-pub fn aes_256_ctr_decrypt(ctext: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+pub(crate) fn aes_256_ctr_decrypt(ctext: &[u8], key: &[u8]) -> Result<Vec<u8>> {
     aes_256_ctr_encrypt(ctext, key)
 }
 
+/// Encrypts `ptext` with AES-256 in CBC mode, applying PKCS#7 padding.
+///
+/// The output is always a non-empty multiple of 16 bytes; an empty `ptext` encrypts to a single
+/// block of padding.
+///
+/// Returns [`SignalProtocolError::InvalidCipherCryptographicParameters`] if `key` is not 32 bytes
+/// or `iv` is not 16 bytes.
 pub fn aes_256_cbc_encrypt(ptext: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    check_cbc_parameters(key, 32, iv)?;
+
     match Cbc::<Aes256, Pkcs7>::new_from_slices(key, iv) {
         Ok(mode) => Ok(mode.encrypt_vec(ptext)),
         Err(block_modes::InvalidKeyIvLength) => Err(
@@ -41,7 +63,33 @@ pub fn aes_256_cbc_encrypt(ptext: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8
     }
 }
 
+/// Decrypts `ctext` with AES-256 in CBC mode, removing PKCS#7 padding.
+///
+/// Returns [`SignalProtocolError::InvalidCipherCryptographicParameters`] if `key` is not 32 bytes
+/// or `iv` is not 16 bytes, and [`SignalProtocolError::InvalidCiphertext`] if `ctext` is not a
+/// non-empty multiple of 16 bytes or its padding is invalid.
+///
+/// CBC mode is not authenticated; callers must verify the integrity of `ctext` separately.
 pub fn aes_256_cbc_decrypt(ctext: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    let mut ptext = Vec::new();
+    aes_256_cbc_decrypt_into(ctext, key, iv, &mut ptext)?;
+    Ok(ptext)
+}
+
+/// Like [`aes_256_cbc_decrypt`], but writes the plaintext into `out` instead of allocating a new
+/// buffer.
+///
+/// `out` is cleared first, so its existing capacity is reused. If decryption fails, `out` is left
+/// empty.
+pub fn aes_256_cbc_decrypt_into(
+    ctext: &[u8],
+    key: &[u8],
+    iv: &[u8],
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    out.clear();
+    check_cbc_parameters(key, 32, iv)?;
+
     if ctext.is_empty() || ctext.len() % 16 != 0 {
         return Err(SignalProtocolError::InvalidCiphertext);
     }
@@ -56,18 +104,156 @@ pub fn aes_256_cbc_decrypt(ctext: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8
         }
     };
 
+    out.extend_from_slice(ctext);
+    match mode.decrypt(out) {
+        Ok(ptext) => {
+            let ptext_len = ptext.len();
+            out.truncate(ptext_len);
+            Ok(())
+        }
+        Err(_) => {
+            out.clear();
+            Err(SignalProtocolError::InvalidCiphertext)
+        }
+    }
+}
+
+/// Decrypts the body of a [`SignalMessage`] with the keys it was encrypted with.
+///
+/// This is the decryption step of [`message_decrypt`](crate::message_decrypt) on its own, for
+/// messages from version 3 sessions whose keys were saved elsewhere. It does not authenticate the
+/// message, so call [`verify_message_mac`] first. Fails with
+/// [`SignalProtocolError::InvalidCiphertext`] if the body is malformed or its padding is invalid.
+///
+/// The result is the plaintext as encrypted; if the message
+/// [is compressed](SignalMessage::is_compressed), it still has to be inflated.
+pub fn decrypt_with_message_keys(
+    ciphertext_body: &[u8],
+    message_keys: &MessageKeys,
+) -> Result<Vec<u8>> {
+    aes_256_cbc_decrypt(
+        ciphertext_body,
+        message_keys.cipher_key(),
+        message_keys.iv(),
+    )
+}
+
+/// Checks that `message` was sent by `sender_identity_key` to `receiver_identity_key` and
+/// authenticated with `message_keys`.
+///
+/// Fails with [`SignalProtocolError::InvalidCiphertext`] if the MAC does not match, as
+/// [`message_decrypt`](crate::message_decrypt) does. Messages encrypted with associated data can
+/// only be checked with [`SignalMessage::verify_mac_with_associated_data`].
+pub fn verify_message_mac(
+    message: &SignalMessage,
+    sender_identity_key: &IdentityKey,
+    receiver_identity_key: &IdentityKey,
+    message_keys: &MessageKeys,
+) -> Result<()> {
+    if message.verify_mac(
+        sender_identity_key,
+        receiver_identity_key,
+        message_keys.mac_key(),
+    )? {
+        Ok(())
+    } else {
+        Err(SignalProtocolError::InvalidCiphertext)
+    }
+}
+
+/// **EXPERIMENTAL AND WEAKER THAN THE DEFAULT.** Encrypts `ptext` with AES-128 in CBC mode,
+/// applying PKCS#7 padding.
+///
+/// Identical to [`aes_256_cbc_encrypt`] except that `key` must be 16 bytes. Only available with the
+/// `weak-ciphers` feature, which exists for benchmarking on constrained hardware.
+#[cfg(feature = "weak-ciphers")]
+pub fn aes_128_cbc_encrypt(ptext: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    check_cbc_parameters(key, 16, iv)?;
+
+    match Cbc::<Aes128, Pkcs7>::new_from_slices(key, iv) {
+        Ok(mode) => Ok(mode.encrypt_vec(ptext)),
+        Err(block_modes::InvalidKeyIvLength) => Err(
+            SignalProtocolError::InvalidCipherCryptographicParameters(key.len(), iv.len()),
+        ),
+    }
+}
+
+/// **EXPERIMENTAL AND WEAKER THAN THE DEFAULT.** Decrypts `ctext` with AES-128 in CBC mode,
+/// removing PKCS#7 padding.
+///
+/// Identical to [`aes_256_cbc_decrypt`] except that `key` must be 16 bytes. Only available with the
+/// `weak-ciphers` feature, which exists for benchmarking on constrained hardware.
+#[cfg(feature = "weak-ciphers")]
+pub fn aes_128_cbc_decrypt(ctext: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    check_cbc_parameters(key, 16, iv)?;
+
+    if ctext.is_empty() || ctext.len() % 16 != 0 {
+        return Err(SignalProtocolError::InvalidCiphertext);
+    }
+
+    let mode = match Cbc::<Aes128, Pkcs7>::new_from_slices(key, iv) {
+        Ok(mode) => mode,
+        Err(block_modes::InvalidKeyIvLength) => {
+            return Err(SignalProtocolError::InvalidCipherCryptographicParameters(
+                key.len(),
+                iv.len(),
+            ))
+        }
+    };
+
     mode.decrypt_vec(ctext)
         .map_err(|_| SignalProtocolError::InvalidCiphertext)
 }
 
-pub fn hmac_sha256(key: &[u8], input: &[u8]) -> Result<[u8; 32]> {
+/// Encrypts with AES-CBC, choosing the key size from the length of `key`.
+///
+/// 16-byte keys are only accepted with the `weak-ciphers` feature.
+pub(crate) fn aes_cbc_encrypt(ptext: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    match key.len() {
+        #[cfg(feature = "weak-ciphers")]
+        16 => aes_128_cbc_encrypt(ptext, key, iv),
+        _ => aes_256_cbc_encrypt(ptext, key, iv),
+    }
+}
+
+/// Decrypts with AES-CBC into `out`, choosing the key size from the length of `key`.
+///
+/// 16-byte keys are only accepted with the `weak-ciphers` feature.
+pub(crate) fn aes_cbc_decrypt_into(
+    ctext: &[u8],
+    key: &[u8],
+    iv: &[u8],
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    match key.len() {
+        #[cfg(feature = "weak-ciphers")]
+        16 => {
+            out.clear();
+            out.extend_from_slice(&aes_128_cbc_decrypt(ctext, key, iv)?);
+            Ok(())
+        }
+        _ => aes_256_cbc_decrypt_into(ctext, key, iv, out),
+    }
+}
+
+fn check_cbc_parameters(key: &[u8], key_len: usize, iv: &[u8]) -> Result<()> {
+    if key.len() != key_len || iv.len() != 16 {
+        return Err(SignalProtocolError::InvalidCipherCryptographicParameters(
+            key.len(),
+            iv.len(),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], input: &[u8]) -> Result<[u8; 32]> {
     let mut hmac =
         Hmac::<Sha256>::new_from_slice(key).expect("HMAC-SHA256 should accept any size key");
     hmac.update(input);
     Ok(hmac.finalize().into_bytes().into())
 }
 
-pub fn aes256_ctr_hmacsha256_encrypt(
+pub(crate) fn aes256_ctr_hmacsha256_encrypt(
     msg: &[u8],
     cipher_key: &[u8],
     mac_key: &[u8],
@@ -80,7 +266,7 @@ pub fn aes256_ctr_hmacsha256_encrypt(
     Ok(result)
 }
 
-pub fn aes256_ctr_hmacsha256_decrypt(
+pub(crate) fn aes256_ctr_hmacsha256_decrypt(
     ctext: &[u8],
     cipher_key: &[u8],
     mac_key: &[u8],
@@ -99,7 +285,7 @@ pub fn aes256_ctr_hmacsha256_decrypt(
 
 #[cfg(test)]
 mod test {
-    use super::Result;
+    use super::{Result, SignalProtocolError};
 
     #[test]
     fn aes_cbc_test() -> Result<()> {
@@ -127,6 +313,63 @@ mod test {
         let recovered = super::aes_256_cbc_decrypt(&ctext, &key, &bad_iv)?;
         assert_eq!(hex::encode(recovered), "b0736294a124482a4159");
 
+        // wrong key or IV sizes are rejected up front:
+        assert!(matches!(
+            super::aes_256_cbc_encrypt(b"", &key[..16], &iv),
+            Err(SignalProtocolError::InvalidCipherCryptographicParameters(
+                16, 16
+            ))
+        ));
+        assert!(matches!(
+            super::aes_256_cbc_decrypt(&ctext, &key, &iv[..8]),
+            Err(SignalProtocolError::InvalidCipherCryptographicParameters(
+                32, 8
+            ))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn aes_cbc_decrypt_into_reuses_buffer() -> Result<()> {
+        let key = [0x42; 32];
+        let iv = [0x24; 16];
+
+        let long_ctext = super::aes_256_cbc_encrypt(&[1; 100], &key, &iv)?;
+        let short_ctext = super::aes_256_cbc_encrypt(b"short", &key, &iv)?;
+
+        let mut out = vec![];
+        super::aes_256_cbc_decrypt_into(&long_ctext, &key, &iv, &mut out)?;
+        assert_eq!(out, [1; 100]);
+        let capacity = out.capacity();
+
+        super::aes_256_cbc_decrypt_into(&short_ctext, &key, &iv, &mut out)?;
+        assert_eq!(out, b"short");
+        assert_eq!(out.capacity(), capacity);
+
+        // On failure the buffer is left empty rather than holding partial plaintext.
+        assert!(super::aes_256_cbc_decrypt_into(&short_ctext[..8], &key, &iv, &mut out).is_err());
+        assert!(out.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn aes_cbc_empty_plaintext() -> Result<()> {
+        let key = [0x42; 32];
+        let iv = [0x24; 16];
+
+        // A full block of PKCS#7 padding.
+        let ctext = super::aes_256_cbc_encrypt(b"", &key, &iv)?;
+        assert_eq!(ctext.len(), 16);
+        assert_eq!(
+            super::aes_256_cbc_encrypt(&[16; 16], &key, &iv)?[..16],
+            ctext[..]
+        );
+
+        assert!(super::aes_256_cbc_decrypt(&ctext, &key, &iv)?.is_empty());
+        assert!(super::aes_256_cbc_decrypt(b"", &key, &iv).is_err());
+
         Ok(())
     }
 
@@ -144,4 +387,31 @@ mod test {
 
         Ok(())
     }
+
+    #[cfg(feature = "weak-ciphers")]
+    #[test]
+    fn aes_128_cbc_test() -> Result<()> {
+        // NIST SP 800-38A F.2.1, first block (without padding) followed by a padding block.
+        let key = hex::decode("2b7e151628aed2a6abf7158809cf4f3c").expect("valid hex");
+        let iv = hex::decode("000102030405060708090a0b0c0d0e0f").expect("valid hex");
+        let ptext = hex::decode("6bc1bee22e409f96e93d7e117393172a").expect("valid hex");
+
+        let ctext = super::aes_128_cbc_encrypt(&ptext, &key, &iv)?;
+        assert_eq!(
+            hex::encode(&ctext[..16]),
+            "7649abac8119b246cee98e9b12e9197d"
+        );
+        assert_eq!(super::aes_128_cbc_decrypt(&ctext, &key, &iv)?, ptext);
+        let mut out = vec![];
+        super::aes_cbc_decrypt_into(&ctext, &key, &iv, &mut out)?;
+        assert_eq!(out, ptext);
+
+        assert!(matches!(
+            super::aes_128_cbc_encrypt(&ptext, &[0; 32], &iv),
+            Err(SignalProtocolError::InvalidCipherCryptographicParameters(
+                32, 16
+            ))
+        ));
+        Ok(())
+    }
 }
@@ -3,6 +3,11 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+//! Curve25519 keys and operations.
+//!
+//! The key types and [`calculate_agreement`] are re-exported from the crate root. The latter
+//! exposes the raw Diffie-Hellman agreement used by the ratchet, e.g. for checking test vectors.
+
 pub(crate) mod curve25519;
 
 use crate::{Result, SignalProtocolError};
@@ -122,6 +127,23 @@ impl PublicKey {
         }
     }
 
+    /// Checks that this key is a usable point on its curve.
+    ///
+    /// Deserializing a key only checks its type and length, since X25519 accepts any input;
+    /// this additionally rejects points on the twist of Curve25519 and points of small order,
+    /// which no honest peer produces. Such a key is not usable as its declared type, so this fails
+    /// with [`SignalProtocolError::BadKeyType`] carrying that type.
+    pub fn validate_point(&self) -> Result<()> {
+        match self.key {
+            PublicKeyData::DjbPublicKey(ref v) => {
+                if !curve25519::is_valid_public_key(v) {
+                    return Err(SignalProtocolError::BadKeyType(KeyType::Djb.value()));
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn key_data(&self) -> &[u8] {
         match self.key {
             PublicKeyData::DjbPublicKey(ref k) => k.as_ref(),
@@ -342,6 +364,24 @@ impl TryFrom<PrivateKey> for KeyPair {
     }
 }
 
+/// Computes the raw Diffie-Hellman shared secret between `private_key` and `public_key`.
+///
+/// This is the same agreement the ratchet performs internally, exposed so that derived keys can be
+/// checked independently. Unlike [`PrivateKey::calculate_agreement`], `public_key` is first
+/// checked with [`PublicKey::validate_point`], so invalid points fail with
+/// [`SignalProtocolError::BadKeyType`] instead of producing a predictable secret.
+pub fn calculate_agreement(
+    private_key: &PrivateKey,
+    public_key: &PublicKey,
+) -> Result<[u8; curve25519::AGREEMENT_LENGTH]> {
+    public_key.validate_point()?;
+    match (private_key.key, public_key.key) {
+        (PrivateKeyData::DjbPrivateKey(priv_key), PublicKeyData::DjbPublicKey(pub_key)) => {
+            Ok(curve25519::PrivateKey::from(priv_key).calculate_agreement(&pub_key))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use rand::rngs::OsRng;
@@ -399,4 +439,28 @@ mod tests {
         assert_eq!(&serialized_public[..], &extra_space_decode?.serialize()[..]);
         Ok(())
     }
+
+    #[test]
+    fn test_calculate_agreement() -> Result<()> {
+        let mut csprng = OsRng;
+        let alice = KeyPair::generate(&mut csprng);
+        let bob = KeyPair::generate(&mut csprng);
+
+        let shared = calculate_agreement(&alice.private_key, &bob.public_key)?;
+        assert_eq!(
+            shared,
+            calculate_agreement(&bob.private_key, &alice.public_key)?
+        );
+        assert_eq!(
+            &shared[..],
+            &*alice.private_key.calculate_agreement(&bob.public_key)?
+        );
+
+        let small_order = PublicKey::from_djb_public_key_bytes(&[0u8; 32])?;
+        assert!(matches!(
+            calculate_agreement(&alice.private_key, &small_order),
+            Err(SignalProtocolError::BadKeyType(0x05))
+        ));
+        Ok(())
+    }
 }
@@ -12,7 +12,7 @@ use sha2::{Digest, Sha512};
 use subtle::ConstantTimeEq;
 use x25519_dalek::{PublicKey, StaticSecret};
 
-const AGREEMENT_LENGTH: usize = 32;
+pub const AGREEMENT_LENGTH: usize = 32;
 pub const PRIVATE_KEY_LENGTH: usize = 32;
 pub const PUBLIC_KEY_LENGTH: usize = 32;
 pub const SIGNATURE_LENGTH: usize = 64;
@@ -139,6 +139,14 @@ impl PrivateKey {
     }
 }
 
+/// Whether `public_key` encodes a point on Curve25519 itself (rather than on its twist) that is
+/// not of small order.
+pub(crate) fn is_valid_public_key(public_key: &[u8; PUBLIC_KEY_LENGTH]) -> bool {
+    MontgomeryPoint(*public_key)
+        .to_edwards(0)
+        .map_or(false, |point| !point.is_small_order())
+}
+
 impl From<[u8; PRIVATE_KEY_LENGTH]> for PrivateKey {
     fn from(private_key: [u8; 32]) -> Self {
         let secret = StaticSecret::from(private_key);
@@ -37,6 +37,13 @@ pub enum SignalProtocolError {
     UnrecognizedCiphertextVersion(u8),
     /// unrecognized message version <{0}>
     UnrecognizedMessageVersion(u32),
+    /// session version {0} is not supported for sending
+    UnsupportedSessionVersion(u32),
+    /// message version {message_version} is below the session's version {highest_version_seen}
+    VersionDowngrade {
+        message_version: u32,
+        highest_version_seen: u32,
+    },
 
     /// fingerprint identifiers do not match
     FingerprintIdentifierMismatch,
@@ -51,17 +58,27 @@ pub enum SignalProtocolError {
     BadKeyType(u8),
     /// bad key length <{1}> for key with type <{0}>
     BadKeyLength(KeyType, usize),
+    /// bad key data: {0}
+    BadKeyData(&'static str),
 
     /// invalid signature detected
     SignatureValidationFailed,
 
     /// untrusted identity for address {0}
     UntrustedIdentity(crate::ProtocolAddress),
+    /// identity for address {0} does not match the pinned identity
+    PinnedIdentityMismatch(crate::ProtocolAddress),
 
     /// invalid prekey identifier
     InvalidPreKeyId,
+    /// prekey {0} has already been consumed
+    PreKeyAlreadyConsumed(u32),
     /// invalid signed prekey identifier
     InvalidSignedPreKeyId,
+    /// prekey {0} referenced by a PreKeySignalMessage was not found
+    PreKeyNotFound(u32),
+    /// signed prekey {0} referenced by a PreKeySignalMessage was not found
+    SignedPreKeyNotFound(u32),
 
     /// invalid root key length <{0}>
     InvalidRootKeyLength(usize),
@@ -74,6 +91,8 @@ pub enum SignalProtocolError {
     InvalidCipherCryptographicParameters(usize, usize),
     /// invalid ciphertext message
     InvalidCiphertext,
+    /// invalid ciphertext length {len}; must be a non-zero multiple of the AES block size
+    InvalidCiphertextLength { len: usize },
 
     /// no sender key state
     NoSenderKeyState,
@@ -82,13 +101,37 @@ pub enum SignalProtocolError {
     SessionNotFound(String),
     /// invalid session structure
     InvalidSessionStructure,
+    /// stored message keys are wrapped, but the session record has no key wrapper
+    MessageKeysWrapped,
     /// session for {0} has invalid registration ID {1:X}
     InvalidRegistrationId(crate::ProtocolAddress, u32),
+    /// decrypting the message would modify the session
+    WouldMutate,
+    /// session for {0} was modified concurrently
+    SessionConcurrentlyModified(crate::ProtocolAddress),
+    /// session for {0} was corrupt and has been archived; a new session must be established
+    SessionCorruptRecoverable(crate::ProtocolAddress),
+    /// message from {0} could not be decrypted; the current session has been archived
+    DecryptFailedSessionArchived(crate::ProtocolAddress),
+    /// unexpected pre-key message from {0}: a session with the same identity already exists
+    UnexpectedPreKeyMessage(crate::ProtocolAddress),
+    /// message would skip {gap} messages, more than the allowed maximum
+    MessageGapTooLarge { gap: u32 },
+    /// message from {0} could be decrypted with more than one session state
+    AmbiguousSession(crate::ProtocolAddress),
+    /// compressed message body expands to more than {0} bytes
+    MessageTooLong(usize),
+    /// message from {0} is authenticated with our own identity key
+    ReflectedIdentity(crate::ProtocolAddress),
+    /// transient storage error: {0}
+    StoreTransient(String),
 
     /// message with old counter {0} / {1}
     DuplicatedMessage(u32, u32),
     /// invalid message {0}
     InvalidMessage(&'static str),
+    /// invalid pre-key message: {0}
+    InvalidPreKeyMessage(&'static str),
     /// internal error {0}
     InternalError(&'static str),
     /// error while invoking an ffi callback: {0}
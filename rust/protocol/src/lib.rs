@@ -23,6 +23,9 @@
 // #![warn(missing_docs)]
 
 mod address;
+#[cfg(feature = "address-gate")]
+mod address_gate;
+pub mod backup;
 mod consts;
 mod crypto;
 mod curve;
@@ -45,7 +48,11 @@ use error::Result;
 
 pub use {
     address::{DeviceId, ProtocolAddress},
-    curve::{KeyPair, PrivateKey, PublicKey},
+    crypto::{
+        aes_256_cbc_decrypt, aes_256_cbc_decrypt_into, aes_256_cbc_encrypt,
+        decrypt_with_message_keys, verify_message_mac,
+    },
+    curve::{calculate_agreement, KeyPair, PrivateKey, PublicKey},
     error::SignalProtocolError,
     fingerprint::{DisplayableFingerprint, Fingerprint, ScannableFingerprint},
     group_cipher::{
@@ -54,13 +61,16 @@ pub use {
     },
     identity_key::{IdentityKey, IdentityKeyPair},
     protocol::{
-        extract_decryption_error_message_from_serialized_content, CiphertextMessage,
-        CiphertextMessageType, DecryptionErrorMessage, PlaintextContent, PreKeySignalMessage,
-        SenderKeyDistributionMessage, SenderKeyMessage, SignalMessage,
+        expected_ciphertext_len, extract_decryption_error_message_from_serialized_content,
+        message_unique_id, referenced_pre_keys, CiphertextMessage, CiphertextMessageType,
+        DecryptionErrorMessage, KeepAliveMessage, PlaintextContent, PreKeySignalMessage,
+        SenderKeyDistributionMessage, SenderKeyMessage, SignalMessage, SUPPORTED_SESSION_VERSIONS,
     },
     ratchet::{
-        initialize_alice_session_record, initialize_bob_session_record,
-        AliceSignalProtocolParameters, BobSignalProtocolParameters,
+        initialize_alice_session_record, initialize_alice_session_record_with_config,
+        initialize_bob_session_record, initialize_bob_session_record_with_config,
+        AliceSignalProtocolParameters, BobSignalProtocolParameters, ChainKey, MessageKeys,
+        ProtocolConfig,
     },
     sealed_sender::{
         sealed_sender_decrypt, sealed_sender_decrypt_to_usmc, sealed_sender_encrypt,
@@ -69,14 +79,45 @@ pub use {
         SenderCertificate, ServerCertificate, UnidentifiedSenderMessageContent,
     },
     sender_keys::SenderKeyRecord,
-    session::{process_prekey, process_prekey_bundle},
+    session::{
+        process_prekey, process_prekey_bundle, process_prekey_bundle_bytes,
+        process_prekey_bundle_with_config, process_prekey_with_config, required_keys,
+        rotate_signed_pre_key, VerifiedBundleCache,
+    },
     session_cipher::{
-        message_decrypt, message_decrypt_prekey, message_decrypt_signal, message_encrypt,
+        accept_identity_change, audit_sessions, check_trust, create_decryption_failure_report,
+        decrypt_keepalive, decrypt_message_with_keys, encrypt_empty, encrypt_keepalive,
+        is_session_for_bundle, local_identity_key, message_decrypt, message_decrypt_batch,
+        message_decrypt_deferred, message_decrypt_into, message_decrypt_prekey,
+        message_decrypt_readonly, message_decrypt_signal, message_decrypt_with_config,
+        message_decrypt_with_version, message_encrypt, message_encrypt_deferred,
+        message_encrypt_with_aad, message_encrypt_with_config, message_encrypt_with_metadata,
+        peek_decrypt, pending_prekey_info, purge_message_keys, remote_identity_key, reseal,
+        same_identity, session_readiness, touch_session, verify_session_identity,
+        version_consensus, CandidateSessionReport, Compression, DecryptionConfig,
+        DecryptionFailureReport, EncryptionConfig, FirstMessageCallback, KeepAlive,
+        PendingPreKeyInfo, PreKeyConsumedCallback, ReceiverChainReport, SessionHealth,
+        SessionReadiness, SessionResetCallback, SpeculativeDecrypt, StoreRetry, StoreRetrySleep,
+        UntrustedIdentityCallback,
+    },
+    state::{
+        ClassifyResult, KeyWrapper, PreKeyBundle, PreKeyId, PreKeyRecord,
+        ProtobufSessionSerializer, ReadOnlySessionRecord, SessionMetrics, SessionRecord,
+        SessionSerializer, SignedPreKeyId, SignedPreKeyRecord,
     },
-    state::{PreKeyBundle, PreKeyRecord, SessionRecord, SignedPreKeyRecord},
     storage::{
-        Context, Direction, IdentityKeyStore, InMemIdentityKeyStore, InMemPreKeyStore,
-        InMemSenderKeyStore, InMemSessionStore, InMemSignalProtocolStore, InMemSignedPreKeyStore,
-        PreKeyStore, ProtocolStore, SenderKeyStore, SessionStore, SignedPreKeyStore,
+        Context, Direction, IdentityChangeCallback, IdentityKeyStore, InMemIdentityKeyStore,
+        InMemPreKeyStore, InMemSenderKeyStore, InMemSessionStore, InMemSignalProtocolStore,
+        InMemSignedPreKeyStore, PreKeyStore, ProtocolStore, SenderKeyStore, SessionStore,
+        SignedPreKeyStore, StoreInstrumentation, TofuIdentityStore, TrustStatus,
     },
 };
+
+#[cfg(feature = "weak-ciphers")]
+pub use {
+    crypto::{aes_128_cbc_decrypt, aes_128_cbc_encrypt},
+    protocol::CIPHERTEXT_MESSAGE_AES_128_VERSION,
+};
+
+#[cfg(feature = "address-gate")]
+pub use address_gate::PerAddressGate;
@@ -4,20 +4,78 @@
 //
 
 use crate::proto;
+use crate::state::{PreKeyId, SignedPreKeyId};
 use crate::{IdentityKey, PrivateKey, PublicKey, Result, SignalProtocolError};
 
 use std::convert::TryFrom;
+use std::ops::RangeInclusive;
 
 use hmac::{Hmac, Mac, NewMac};
 use prost::Message;
 use rand::{CryptoRng, Rng};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 use subtle::ConstantTimeEq;
 use uuid::Uuid;
 
 pub const CIPHERTEXT_MESSAGE_CURRENT_VERSION: u8 = 3;
+
+/// **EXPERIMENTAL.** The session and message version used by sessions that encrypt message bodies
+/// with AES-128-CBC instead of AES-256-CBC.
+///
+/// Messages of this version are authenticated with the full 32-byte HMAC-SHA256 rather than the
+/// 8-byte truncation used by version 3.
+///
+/// Only available with the `weak-ciphers` feature; see [`ProtocolConfig`](crate::ProtocolConfig).
+#[cfg(feature = "weak-ciphers")]
+pub const CIPHERTEXT_MESSAGE_AES_128_VERSION: u8 = 4;
+
+/// The highest message version this build will parse.
+#[cfg(not(feature = "weak-ciphers"))]
+const MAX_SUPPORTED_MESSAGE_VERSION: u8 = CIPHERTEXT_MESSAGE_CURRENT_VERSION;
+#[cfg(feature = "weak-ciphers")]
+const MAX_SUPPORTED_MESSAGE_VERSION: u8 = CIPHERTEXT_MESSAGE_AES_128_VERSION;
+
+/// The length of a serialized Curve25519 [`PublicKey`], including its type byte.
+const PUBLIC_KEY_SERIALIZED_LENGTH: usize = 33;
+
+/// The session versions this build can send messages for.
+///
+/// Encrypting with a session outside this range fails with
+/// [`SignalProtocolError::UnsupportedSessionVersion`].
+pub const SUPPORTED_SESSION_VERSIONS: RangeInclusive<u32> =
+    CIPHERTEXT_MESSAGE_CURRENT_VERSION as u32..=MAX_SUPPORTED_MESSAGE_VERSION as u32;
 pub const SENDERKEY_MESSAGE_CURRENT_VERSION: u8 = 3;
 
+/// How a [`SignalMessage`] or [`KeepAliveMessage`] is authenticated, which is determined by its
+/// message version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MessageMac {
+    /// HMAC-SHA256 truncated to 8 bytes, used by version 3.
+    TruncatedHmacSha256,
+    /// The full 32-byte HMAC-SHA256, used by every later version.
+    HmacSha256,
+}
+
+impl MessageMac {
+    fn for_version(message_version: u8) -> Self {
+        if message_version > CIPHERTEXT_MESSAGE_CURRENT_VERSION {
+            Self::HmacSha256
+        } else {
+            Self::TruncatedHmacSha256
+        }
+    }
+
+    fn len(self) -> usize {
+        match self {
+            Self::TruncatedHmacSha256 => 8,
+            Self::HmacSha256 => 32,
+        }
+    }
+
+    /// The shortest MAC of any version, for rejecting messages before their version is known.
+    const MIN_LEN: usize = 8;
+}
+
 pub enum CiphertextMessage {
     SignalMessage(SignalMessage),
     PreKeySignalMessage(PreKeySignalMessage),
@@ -62,12 +120,11 @@ pub struct SignalMessage {
     #[allow(dead_code)]
     previous_counter: u32,
     ciphertext: Box<[u8]>,
+    compressed: bool,
     serialized: Box<[u8]>,
 }
 
 impl SignalMessage {
-    const MAC_LENGTH: usize = 8;
-
     pub fn new(
         message_version: u8,
         mac_key: &[u8],
@@ -77,24 +134,88 @@ impl SignalMessage {
         ciphertext: &[u8],
         sender_identity_key: &IdentityKey,
         receiver_identity_key: &IdentityKey,
+    ) -> Result<Self> {
+        Self::new_with_associated_data(
+            message_version,
+            mac_key,
+            sender_ratchet_key,
+            counter,
+            previous_counter,
+            ciphertext,
+            sender_identity_key,
+            receiver_identity_key,
+            &[],
+        )
+    }
+
+    /// Like [`new`](Self::new), but also authenticates `associated_data`, which is not included in
+    /// the message.
+    ///
+    /// The message will only pass [`verify_mac_with_associated_data`] with the same associated
+    /// data. Empty associated data is the same as none.
+    ///
+    /// [`verify_mac_with_associated_data`]: Self::verify_mac_with_associated_data
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_associated_data(
+        message_version: u8,
+        mac_key: &[u8],
+        sender_ratchet_key: PublicKey,
+        counter: u32,
+        previous_counter: u32,
+        ciphertext: &[u8],
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+        associated_data: &[u8],
+    ) -> Result<Self> {
+        Self::new_with_flags(
+            message_version,
+            mac_key,
+            sender_ratchet_key,
+            counter,
+            previous_counter,
+            ciphertext,
+            sender_identity_key,
+            receiver_identity_key,
+            associated_data,
+            false,
+        )
+    }
+
+    /// Like [`new_with_associated_data`](Self::new_with_associated_data), but can also mark the
+    /// body as compressed. The flag is covered by the MAC.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new_with_flags(
+        message_version: u8,
+        mac_key: &[u8],
+        sender_ratchet_key: PublicKey,
+        counter: u32,
+        previous_counter: u32,
+        ciphertext: &[u8],
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+        associated_data: &[u8],
+        compressed: bool,
     ) -> Result<Self> {
         let message = proto::wire::SignalMessage {
             ratchet_key: Some(sender_ratchet_key.serialize().into_vec()),
             counter: Some(counter),
             previous_counter: Some(previous_counter),
             ciphertext: Some(Vec::<u8>::from(ciphertext)),
+            compressed: if compressed { Some(true) } else { None },
         };
-        let mut serialized = vec![0u8; 1 + message.encoded_len() + Self::MAC_LENGTH];
+        let mac_length = MessageMac::for_version(message_version).len();
+        let mut serialized = vec![0u8; 1 + message.encoded_len() + mac_length];
         serialized[0] = ((message_version & 0xF) << 4) | CIPHERTEXT_MESSAGE_CURRENT_VERSION;
         message.encode(&mut &mut serialized[1..message.encoded_len() + 1])?;
-        let msg_len_for_mac = serialized.len() - Self::MAC_LENGTH;
+        let msg_len_for_mac = serialized.len() - mac_length;
         let mac = Self::compute_mac(
             sender_identity_key,
             receiver_identity_key,
             mac_key,
             &serialized[..msg_len_for_mac],
+            associated_data,
         )?;
-        serialized[msg_len_for_mac..].copy_from_slice(&mac);
+        serialized[msg_len_for_mac..].copy_from_slice(&mac[..mac_length]);
         let serialized = serialized.into_boxed_slice();
         Ok(Self {
             message_version,
@@ -102,10 +223,28 @@ impl SignalMessage {
             counter,
             previous_counter,
             ciphertext: ciphertext.into(),
+            compressed,
             serialized,
         })
     }
 
+    /// The serialized length of a message of version `message_version` with a
+    /// `ciphertext_len`-byte body, assuming the counters take as many bytes as they can.
+    pub(crate) fn max_serialized_len(message_version: u8, ciphertext_len: usize) -> usize {
+        let message = proto::wire::SignalMessage {
+            ratchet_key: Some(vec![0; PUBLIC_KEY_SERIALIZED_LENGTH]),
+            counter: Some(u32::MAX),
+            previous_counter: Some(u32::MAX),
+            ciphertext: None,
+            compressed: None,
+        };
+        1 + message.encoded_len()
+            + prost::encoding::key_len(4)
+            + prost::encoding::encoded_len_varint(ciphertext_len as u64)
+            + ciphertext_len
+            + MessageMac::for_version(message_version).len()
+    }
+
     #[inline]
     pub fn message_version(&self) -> u8 {
         self.message_version
@@ -116,11 +255,36 @@ impl SignalMessage {
         &self.sender_ratchet_key
     }
 
+    /// Checks the parts of this message that can be checked without a session: that its version
+    /// is supported and that its sender ratchet key is a valid point (see
+    /// [`PublicKey::validate_point`]).
+    ///
+    /// Passing this check does not mean the message will decrypt; it only allows obviously
+    /// malformed messages to be dropped early.
+    pub fn validate_structure(&self) -> Result<()> {
+        if self.message_version < CIPHERTEXT_MESSAGE_CURRENT_VERSION {
+            return Err(SignalProtocolError::LegacyCiphertextVersion(
+                self.message_version,
+            ));
+        }
+        if self.message_version > MAX_SUPPORTED_MESSAGE_VERSION {
+            return Err(SignalProtocolError::UnrecognizedCiphertextVersion(
+                self.message_version,
+            ));
+        }
+        self.sender_ratchet_key.validate_point()
+    }
+
     #[inline]
     pub fn counter(&self) -> u32 {
         self.counter
     }
 
+    /// This message's [`message_unique_id`].
+    pub fn unique_id(&self) -> [u8; 16] {
+        message_unique_id(&self.sender_ratchet_key, self.counter)
+    }
+
     #[inline]
     pub fn serialized(&self) -> &[u8] {
         &*self.serialized
@@ -131,19 +295,45 @@ impl SignalMessage {
         &*self.ciphertext
     }
 
+    /// Whether the plaintext was compressed with DEFLATE before it was encrypted.
+    #[inline]
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
     pub fn verify_mac(
         &self,
         sender_identity_key: &IdentityKey,
         receiver_identity_key: &IdentityKey,
         mac_key: &[u8],
     ) -> Result<bool> {
-        let our_mac = &Self::compute_mac(
+        self.verify_mac_with_associated_data(
+            sender_identity_key,
+            receiver_identity_key,
+            mac_key,
+            &[],
+        )
+    }
+
+    /// Like [`verify_mac`](Self::verify_mac), for messages created with
+    /// [`new_with_associated_data`](Self::new_with_associated_data).
+    pub fn verify_mac_with_associated_data(
+        &self,
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+        mac_key: &[u8],
+        associated_data: &[u8],
+    ) -> Result<bool> {
+        let mac_length = MessageMac::for_version(self.message_version).len();
+        let full_mac = Self::compute_mac(
             sender_identity_key,
             receiver_identity_key,
             mac_key,
-            &self.serialized[..self.serialized.len() - Self::MAC_LENGTH],
+            &self.serialized[..self.serialized.len() - mac_length],
+            associated_data,
         )?;
-        let their_mac = &self.serialized[self.serialized.len() - Self::MAC_LENGTH..];
+        let our_mac = &full_mac[..mac_length];
+        let their_mac = &self.serialized[self.serialized.len() - mac_length..];
         let result: bool = our_mac.ct_eq(their_mac).into();
         if !result {
             // A warning instead of an error because we try multiple sessions.
@@ -156,12 +346,15 @@ impl SignalMessage {
         Ok(result)
     }
 
+    /// Computes the full HMAC-SHA256 over `message`; callers truncate it as the message version
+    /// requires (see [`MessageMac`]).
     fn compute_mac(
         sender_identity_key: &IdentityKey,
         receiver_identity_key: &IdentityKey,
         mac_key: &[u8],
         message: &[u8],
-    ) -> Result<[u8; Self::MAC_LENGTH]> {
+        associated_data: &[u8],
+    ) -> Result<[u8; 32]> {
         if mac_key.len() != 32 {
             return Err(SignalProtocolError::InvalidMacKeyLength(mac_key.len()));
         }
@@ -171,9 +364,13 @@ impl SignalMessage {
         mac.update(sender_identity_key.public_key().serialize().as_ref());
         mac.update(receiver_identity_key.public_key().serialize().as_ref());
         mac.update(message);
-        let mut result = [0u8; Self::MAC_LENGTH];
-        result.copy_from_slice(&mac.finalize().into_bytes()[..Self::MAC_LENGTH]);
-        Ok(result)
+        // Omitted entirely when empty, so that messages without associated data are unchanged.
+        // The trailing length keeps the boundary between message and associated data unambiguous.
+        if !associated_data.is_empty() {
+            mac.update(associated_data);
+            mac.update(&(associated_data.len() as u64).to_be_bytes());
+        }
+        Ok(mac.finalize().into_bytes().into())
     }
 }
 
@@ -187,7 +384,7 @@ impl TryFrom<&[u8]> for SignalMessage {
     type Error = SignalProtocolError;
 
     fn try_from(value: &[u8]) -> Result<Self> {
-        if value.len() < SignalMessage::MAC_LENGTH + 1 {
+        if value.len() < MessageMac::MIN_LEN + 1 {
             return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
         }
         let message_version = value[0] >> 4;
@@ -196,14 +393,18 @@ impl TryFrom<&[u8]> for SignalMessage {
                 message_version,
             ));
         }
-        if message_version > CIPHERTEXT_MESSAGE_CURRENT_VERSION {
+        if message_version > MAX_SUPPORTED_MESSAGE_VERSION {
             return Err(SignalProtocolError::UnrecognizedCiphertextVersion(
                 message_version,
             ));
         }
+        let mac_length = MessageMac::for_version(message_version).len();
+        if value.len() < mac_length + 1 {
+            return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
+        }
 
         let proto_structure =
-            proto::wire::SignalMessage::decode(&value[1..value.len() - SignalMessage::MAC_LENGTH])?;
+            proto::wire::SignalMessage::decode(&value[1..value.len() - mac_length])?;
 
         let sender_ratchet_key = proto_structure
             .ratchet_key
@@ -217,6 +418,7 @@ impl TryFrom<&[u8]> for SignalMessage {
             .ciphertext
             .ok_or(SignalProtocolError::InvalidProtobufEncoding)?
             .into_boxed_slice();
+        let compressed = proto_structure.compressed.unwrap_or(false);
 
         Ok(SignalMessage {
             message_version,
@@ -224,6 +426,149 @@ impl TryFrom<&[u8]> for SignalMessage {
             counter,
             previous_counter,
             ciphertext,
+            compressed,
+            serialized: Box::from(value),
+        })
+    }
+}
+
+/// An authenticated frame with no content, sent to keep an idle session alive.
+///
+/// Unlike an ordinary message (see [`encrypt_empty`](crate::encrypt_empty)), a keepalive does not
+/// use up a message key: it is authenticated with a key derived from the sender's current chain
+/// key, and neither side advances a chain when sending or receiving it. It is not a
+/// [`CiphertextMessage`], so it must be marked as a keepalive wherever the application transports
+/// its messages.
+///
+/// See [`encrypt_keepalive`](crate::encrypt_keepalive) and
+/// [`decrypt_keepalive`](crate::decrypt_keepalive).
+#[derive(Debug, Clone)]
+pub struct KeepAliveMessage {
+    message_version: u8,
+    sender_ratchet_key: PublicKey,
+    counter: u32,
+    serialized: Box<[u8]>,
+}
+
+impl KeepAliveMessage {
+    pub fn new(
+        message_version: u8,
+        mac_key: &[u8],
+        sender_ratchet_key: PublicKey,
+        counter: u32,
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+    ) -> Result<Self> {
+        let message = proto::wire::KeepAliveMessage {
+            ratchet_key: Some(sender_ratchet_key.serialize().into_vec()),
+            counter: Some(counter),
+        };
+        let mac_length = MessageMac::for_version(message_version).len();
+        let mut serialized = vec![0u8; 1 + message.encoded_len() + mac_length];
+        serialized[0] = ((message_version & 0xF) << 4) | CIPHERTEXT_MESSAGE_CURRENT_VERSION;
+        message.encode(&mut &mut serialized[1..message.encoded_len() + 1])?;
+        let msg_len_for_mac = serialized.len() - mac_length;
+        let mac = SignalMessage::compute_mac(
+            sender_identity_key,
+            receiver_identity_key,
+            mac_key,
+            &serialized[..msg_len_for_mac],
+            &[],
+        )?;
+        serialized[msg_len_for_mac..].copy_from_slice(&mac[..mac_length]);
+        Ok(Self {
+            message_version,
+            sender_ratchet_key,
+            counter,
+            serialized: serialized.into_boxed_slice(),
+        })
+    }
+
+    #[inline]
+    pub fn message_version(&self) -> u8 {
+        self.message_version
+    }
+
+    #[inline]
+    pub fn sender_ratchet_key(&self) -> &PublicKey {
+        &self.sender_ratchet_key
+    }
+
+    /// The index of the sender's chain key when the keepalive was sent, i.e. the counter of the
+    /// next message the sender will send with this ratchet key.
+    #[inline]
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    #[inline]
+    pub fn serialized(&self) -> &[u8] {
+        &*self.serialized
+    }
+
+    pub fn verify_mac(
+        &self,
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+        mac_key: &[u8],
+    ) -> Result<bool> {
+        let mac_length = MessageMac::for_version(self.message_version).len();
+        let our_mac = SignalMessage::compute_mac(
+            sender_identity_key,
+            receiver_identity_key,
+            mac_key,
+            &self.serialized[..self.serialized.len() - mac_length],
+            &[],
+        )?;
+        let their_mac = &self.serialized[self.serialized.len() - mac_length..];
+        Ok(our_mac[..mac_length].ct_eq(their_mac).into())
+    }
+}
+
+impl AsRef<[u8]> for KeepAliveMessage {
+    fn as_ref(&self) -> &[u8] {
+        &*self.serialized
+    }
+}
+
+impl TryFrom<&[u8]> for KeepAliveMessage {
+    type Error = SignalProtocolError;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        if value.len() < MessageMac::MIN_LEN + 1 {
+            return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
+        }
+        let message_version = value[0] >> 4;
+        if message_version < CIPHERTEXT_MESSAGE_CURRENT_VERSION {
+            return Err(SignalProtocolError::LegacyCiphertextVersion(
+                message_version,
+            ));
+        }
+        if message_version > MAX_SUPPORTED_MESSAGE_VERSION {
+            return Err(SignalProtocolError::UnrecognizedCiphertextVersion(
+                message_version,
+            ));
+        }
+
+        let mac_length = MessageMac::for_version(message_version).len();
+        if value.len() < mac_length + 1 {
+            return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
+        }
+
+        let proto_structure =
+            proto::wire::KeepAliveMessage::decode(&value[1..value.len() - mac_length])?;
+        let sender_ratchet_key = proto_structure
+            .ratchet_key
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let sender_ratchet_key = PublicKey::deserialize(&sender_ratchet_key)?;
+        let counter = proto_structure
+            .counter
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+
+        Ok(KeepAliveMessage {
+            message_version,
+            sender_ratchet_key,
+            counter,
             serialized: Box::from(value),
         })
     }
@@ -274,6 +619,23 @@ impl PreKeySignalMessage {
         })
     }
 
+    /// The serialized length of a message wrapping a `message_len`-byte [`SignalMessage`],
+    /// assuming there is a one-time pre-key and the IDs take as many bytes as they can.
+    pub(crate) fn max_serialized_len(message_len: usize) -> usize {
+        let proto_message = proto::wire::PreKeySignalMessage {
+            registration_id: Some(u32::MAX),
+            pre_key_id: Some(u32::MAX),
+            signed_pre_key_id: Some(u32::MAX),
+            base_key: Some(vec![0; PUBLIC_KEY_SERIALIZED_LENGTH]),
+            identity_key: Some(vec![0; PUBLIC_KEY_SERIALIZED_LENGTH]),
+            message: None,
+        };
+        1 + proto_message.encoded_len()
+            + prost::encoding::key_len(4)
+            + prost::encoding::encoded_len_varint(message_len as u64)
+            + message_len
+    }
+
     #[inline]
     pub fn message_version(&self) -> u8 {
         self.message_version
@@ -284,13 +646,15 @@ impl PreKeySignalMessage {
         self.registration_id
     }
 
+    /// The one-time pre-key this message was encrypted to, if any.
     #[inline]
-    pub fn pre_key_id(&self) -> Option<u32> {
+    pub fn pre_key_id(&self) -> Option<PreKeyId> {
         self.pre_key_id
     }
 
+    /// The signed pre-key this message was encrypted to.
     #[inline]
-    pub fn signed_pre_key_id(&self) -> u32 {
+    pub fn signed_pre_key_id(&self) -> SignedPreKeyId {
         self.signed_pre_key_id
     }
 
@@ -335,28 +699,43 @@ impl TryFrom<&[u8]> for PreKeySignalMessage {
                 message_version,
             ));
         }
-        if message_version > CIPHERTEXT_MESSAGE_CURRENT_VERSION {
+        if message_version > MAX_SUPPORTED_MESSAGE_VERSION {
             return Err(SignalProtocolError::UnrecognizedCiphertextVersion(
                 message_version,
             ));
         }
 
-        let proto_structure = proto::wire::PreKeySignalMessage::decode(&value[1..])?;
-
-        let base_key = proto_structure
-            .base_key
-            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
-        let identity_key = proto_structure
-            .identity_key
-            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let proto_structure = proto::wire::PreKeySignalMessage::decode(&value[1..])
+            .map_err(|_| SignalProtocolError::InvalidPreKeyMessage("invalid protobuf encoding"))?;
+
+        let base_key =
+            proto_structure
+                .base_key
+                .ok_or(SignalProtocolError::InvalidPreKeyMessage(
+                    "missing base key",
+                ))?;
+        let identity_key =
+            proto_structure
+                .identity_key
+                .ok_or(SignalProtocolError::InvalidPreKeyMessage(
+                    "missing identity key",
+                ))?;
         let message = proto_structure
             .message
-            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
-        let signed_pre_key_id = proto_structure
-            .signed_pre_key_id
-            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
-
-        let base_key = PublicKey::deserialize(base_key.as_ref())?;
+            .ok_or(SignalProtocolError::InvalidPreKeyMessage("missing message"))?;
+        let signed_pre_key_id =
+            proto_structure
+                .signed_pre_key_id
+                .ok_or(SignalProtocolError::InvalidPreKeyMessage(
+                    "missing signed pre-key ID",
+                ))?;
+
+        let base_key = PublicKey::deserialize(base_key.as_ref())
+            .map_err(|_| SignalProtocolError::InvalidPreKeyMessage("invalid base key"))?;
+        let identity_key = IdentityKey::try_from(identity_key.as_ref())
+            .map_err(|_| SignalProtocolError::InvalidPreKeyMessage("invalid identity key"))?;
+        let message = SignalMessage::try_from(message.as_ref())
+            .map_err(|_| SignalProtocolError::InvalidPreKeyMessage("invalid embedded message"))?;
 
         Ok(PreKeySignalMessage {
             message_version,
@@ -364,8 +743,8 @@ impl TryFrom<&[u8]> for PreKeySignalMessage {
             pre_key_id: proto_structure.pre_key_id,
             signed_pre_key_id,
             base_key,
-            identity_key: IdentityKey::try_from(identity_key.as_ref())?,
-            message: SignalMessage::try_from(message.as_ref())?,
+            identity_key,
+            message,
             serialized: Box::from(value),
         })
     }
@@ -807,6 +1186,68 @@ impl TryFrom<&[u8]> for DecryptionErrorMessage {
     }
 }
 
+/// An identifier for the message sent on the chain of `sender_ratchet_key` at position `counter`.
+///
+/// Retransmissions of a message have the same ID, and no two messages in a session do, so the ID
+/// can be used to deduplicate deliveries. Both inputs are sent in the clear, so the ID reveals
+/// nothing about the plaintext, but anyone who sees the message can compute it.
+pub fn message_unique_id(sender_ratchet_key: &PublicKey, counter: u32) -> [u8; 16] {
+    let mut hash = Sha256::new();
+    hash.update(b"Signal_MessageUniqueId");
+    hash.update(sender_ratchet_key.serialize());
+    hash.update(counter.to_be_bytes());
+    let mut id = [0; 16];
+    id.copy_from_slice(&hash.finalize()[..16]);
+    id
+}
+
+/// The one-time pre-keys that decrypting `messages` would consume, in ascending order and without
+/// duplicates.
+///
+/// Messages that do not use a one-time pre-key are skipped. Nothing is decrypted or verified, so
+/// a forged message can make any ID appear here; the result is only suitable for keeping pre-keys
+/// around longer, never for deciding that one may be removed early.
+pub fn referenced_pre_keys(messages: &[PreKeySignalMessage]) -> Vec<PreKeyId> {
+    let mut ids: Vec<PreKeyId> = messages.iter().filter_map(|m| m.pre_key_id()).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+/// An upper bound on the length of the serialized message [`message_encrypt`] produces for a
+/// `plaintext_len`-byte plaintext in a session of version `session_version`.
+///
+/// This accounts for the block cipher padding, the MAC, and the framing of a [`SignalMessage`],
+/// wrapped in a [`PreKeySignalMessage`] if `is_prekey` is set (i.e. while the session is not yet
+/// acknowledged by the peer). Counters and pre-key IDs are encoded with a variable length, so
+/// actual messages can be up to a few bytes shorter. Messages encrypted with
+/// [compression](crate::EncryptionConfig::compression) are not covered.
+///
+/// Fails with [`SignalProtocolError::UnsupportedSessionVersion`] for versions outside
+/// [`SUPPORTED_SESSION_VERSIONS`].
+///
+/// [`message_encrypt`]: crate::message_encrypt
+pub fn expected_ciphertext_len(
+    plaintext_len: usize,
+    session_version: u32,
+    is_prekey: bool,
+) -> Result<usize> {
+    if !SUPPORTED_SESSION_VERSIONS.contains(&session_version) {
+        return Err(SignalProtocolError::UnsupportedSessionVersion(
+            session_version,
+        ));
+    }
+    // Every supported version pads with PKCS#7 to a multiple of the 16-byte AES block size, which
+    // always adds at least one byte.
+    let ciphertext_len = (plaintext_len / 16 + 1) * 16;
+    let message_len = SignalMessage::max_serialized_len(session_version as u8, ciphertext_len);
+    Ok(if is_prekey {
+        PreKeySignalMessage::max_serialized_len(message_len)
+    } else {
+        message_len
+    })
+}
+
 /// For testing
 pub fn extract_decryption_error_message_from_serialized_content(
     bytes: &[u8],
@@ -881,6 +1322,125 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_signal_message_validate_structure() -> Result<()> {
+        let mut csprng = OsRng;
+        let message = create_signal_message(&mut csprng)?;
+        message.validate_structure()?;
+        SignalMessage::try_from(message.as_ref())?.validate_structure()?;
+
+        let identity_key: IdentityKey = KeyPair::generate(&mut csprng).public_key.into();
+        let mut low_order_point = [0u8; 32];
+        for u in [0, 1] {
+            low_order_point[0] = u;
+            let message = SignalMessage::new(
+                3,
+                &[0; 32],
+                PublicKey::from_djb_public_key_bytes(&low_order_point)?,
+                42,
+                41,
+                &[0; 16],
+                &identity_key,
+                &identity_key,
+            )?;
+            assert!(matches!(
+                SignalMessage::try_from(message.as_ref())?.validate_structure(),
+                Err(SignalProtocolError::BadKeyType(_))
+            ));
+        }
+
+        let message = SignalMessage::new(
+            2,
+            &[0; 32],
+            KeyPair::generate(&mut csprng).public_key,
+            42,
+            41,
+            &[0; 16],
+            &identity_key,
+            &identity_key,
+        )?;
+        assert!(matches!(
+            message.validate_structure(),
+            Err(SignalProtocolError::LegacyCiphertextVersion(2))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_signal_message_mac_depends_on_version() -> Result<()> {
+        let mut csprng = OsRng;
+        let ratchet_key = KeyPair::generate(&mut csprng).public_key;
+        let sender: IdentityKey = KeyPair::generate(&mut csprng).public_key.into();
+        let receiver: IdentityKey = KeyPair::generate(&mut csprng).public_key.into();
+
+        let create = |version| {
+            SignalMessage::new(
+                version,
+                &[1; 32],
+                ratchet_key,
+                42,
+                41,
+                &[0; 16],
+                &sender,
+                &receiver,
+            )
+        };
+        let legacy = create(3)?;
+        let newer = create(4)?;
+        assert_eq!(newer.serialized().len(), legacy.serialized().len() + 32 - 8);
+
+        for message in [&legacy, &newer] {
+            assert!(message.verify_mac(&sender, &receiver, &[1; 32])?);
+            assert!(!message.verify_mac(&sender, &receiver, &[2; 32])?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_signal_message_associated_data() -> Result<()> {
+        let mut csprng = OsRng;
+        let mac_key = [0x42; 32];
+        let sender_ratchet_key = KeyPair::generate(&mut csprng).public_key;
+        let sender_identity_key = IdentityKey::new(KeyPair::generate(&mut csprng).public_key);
+        let receiver_identity_key = IdentityKey::new(KeyPair::generate(&mut csprng).public_key);
+
+        let create = |associated_data: &[u8]| {
+            SignalMessage::new_with_associated_data(
+                3,
+                &mac_key,
+                sender_ratchet_key,
+                42,
+                41,
+                b"ciphertext",
+                &sender_identity_key,
+                &receiver_identity_key,
+                associated_data,
+            )
+        };
+        let verify = |message: &SignalMessage, associated_data: &[u8]| {
+            message.verify_mac_with_associated_data(
+                &sender_identity_key,
+                &receiver_identity_key,
+                &mac_key,
+                associated_data,
+            )
+        };
+
+        let message = create(b"conversation 1")?;
+        assert!(verify(&message, b"conversation 1")?);
+        assert!(!verify(&message, b"conversation 2")?);
+        assert!(!verify(&message, b"conversation")?);
+        assert!(!verify(&message, b"")?);
+
+        // Empty associated data produces the same message as none at all.
+        let message = create(b"")?;
+        assert!(message.verify_mac(&sender_identity_key, &receiver_identity_key, &mac_key)?);
+        assert!(!verify(&message, b"conversation 1")?);
+
+        Ok(())
+    }
+
     #[test]
     fn test_pre_key_signal_message_serialize_deserialize() -> Result<()> {
         let mut csprng = OsRng;
@@ -934,6 +1494,50 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_referenced_pre_keys() -> Result<()> {
+        let mut csprng = OsRng;
+        let identity_key_pair = KeyPair::generate(&mut csprng);
+        let base_key_pair = KeyPair::generate(&mut csprng);
+        let messages = [Some(7), None, Some(3), Some(7)]
+            .iter()
+            .map(|&pre_key_id| {
+                PreKeySignalMessage::new(
+                    3,
+                    365,
+                    pre_key_id,
+                    97,
+                    base_key_pair.public_key,
+                    identity_key_pair.public_key.into(),
+                    create_signal_message(&mut csprng)?,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        assert_eq!(referenced_pre_keys(&messages), [3, 7]);
+        assert!(referenced_pre_keys(&messages[1..2]).is_empty());
+        assert!(referenced_pre_keys(&[]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pre_key_signal_message_invalid_body() {
+        let mut bytes = vec![(CIPHERTEXT_MESSAGE_CURRENT_VERSION << 4) | 3];
+        bytes.extend_from_slice(&[0xff; 8]);
+        assert!(matches!(
+            PreKeySignalMessage::try_from(bytes.as_slice()),
+            Err(SignalProtocolError::InvalidPreKeyMessage(_))
+        ));
+
+        // An empty body decodes, but lacks every required field.
+        assert!(matches!(
+            PreKeySignalMessage::try_from(&bytes[..1]),
+            Err(SignalProtocolError::InvalidPreKeyMessage(
+                "missing base key"
+            ))
+        ));
+    }
+
     #[test]
     fn test_sender_key_message_serialize_deserialize() -> Result<()> {
         let mut csprng = OsRng;
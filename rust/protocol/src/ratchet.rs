@@ -11,13 +11,61 @@ pub use self::params::{AliceSignalProtocolParameters, BobSignalProtocolParameter
 use crate::proto::storage::SessionStructure;
 use crate::protocol::CIPHERTEXT_MESSAGE_CURRENT_VERSION;
 use crate::state::SessionState;
+use crate::utils::current_time_millis;
 use crate::{KeyPair, Result, SessionRecord};
 use rand::{CryptoRng, Rng};
 
-fn derive_keys(secret_input: &[u8]) -> Result<(RootKey, ChainKey)> {
+/// The HKDF info string used to derive the initial root key in the Signal protocol.
+const SIGNAL_PROTOCOL_INFO: &[u8] = b"WhisperText";
+
+/// Parameters of the protocol that all participants in a network must agree on.
+///
+/// The default configuration is the one used by Signal.
+#[derive(Clone, Debug)]
+pub struct ProtocolConfig {
+    /// The HKDF info string used when deriving the initial root key of a new session.
+    ///
+    /// Using a distinct identifier isolates a network from Signal's: sessions can still be set up
+    /// with peers using a different identifier, but none of their messages will decrypt.
+    pub protocol_info: Vec<u8>,
+
+    /// **EXPERIMENTAL AND WEAKER THAN THE DEFAULT.** If set, new sessions are created with
+    /// [`CIPHERTEXT_MESSAGE_AES_128_VERSION`](crate::CIPHERTEXT_MESSAGE_AES_128_VERSION) and
+    /// encrypt message bodies with AES-128-CBC instead of AES-256-CBC. Like any version after 3,
+    /// their messages carry a full-length MAC.
+    ///
+    /// Both peers must enable this; messages from such a session are rejected by builds without
+    /// the `weak-ciphers` feature and by sessions of the other version. This exists only for
+    /// benchmarking on constrained hardware and must not be used in production.
+    #[cfg(feature = "weak-ciphers")]
+    pub aes_128: bool,
+}
+
+impl ProtocolConfig {
+    /// The version given to sessions created with this configuration.
+    fn session_version(&self) -> u32 {
+        #[cfg(feature = "weak-ciphers")]
+        if self.aes_128 {
+            return crate::protocol::CIPHERTEXT_MESSAGE_AES_128_VERSION as u32;
+        }
+        CIPHERTEXT_MESSAGE_CURRENT_VERSION as u32
+    }
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            protocol_info: SIGNAL_PROTOCOL_INFO.to_vec(),
+            #[cfg(feature = "weak-ciphers")]
+            aes_128: false,
+        }
+    }
+}
+
+fn derive_keys(secret_input: &[u8], config: &ProtocolConfig) -> Result<(RootKey, ChainKey)> {
     let mut secrets = [0; 64];
     hkdf::Hkdf::<sha2::Sha256>::new(None, secret_input)
-        .expand(b"WhisperText", &mut secrets)
+        .expand(&config.protocol_info, &mut secrets)
         .expect("valid length");
 
     let root_key = RootKey::new(&secrets[0..32])?;
@@ -28,6 +76,7 @@ fn derive_keys(secret_input: &[u8]) -> Result<(RootKey, ChainKey)> {
 
 pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
     parameters: &AliceSignalProtocolParameters,
+    config: &ProtocolConfig,
     mut csprng: &mut R,
 ) -> Result<SessionState> {
     let local_identity = parameters.our_identity_key_pair().identity_key();
@@ -60,7 +109,7 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
             .extend_from_slice(&our_base_private_key.calculate_agreement(their_one_time_prekey)?);
     }
 
-    let (root_key, chain_key) = derive_keys(&secrets)?;
+    let (root_key, chain_key) = derive_keys(&secrets, config)?;
 
     let (sending_chain_root_key, sending_chain_chain_key) = root_key.create_chain(
         parameters.their_ratchet_key(),
@@ -68,7 +117,7 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
     )?;
 
     let session = SessionStructure {
-        session_version: CIPHERTEXT_MESSAGE_CURRENT_VERSION as u32,
+        session_version: config.session_version(),
         local_identity_public: local_identity.public_key().serialize().to_vec(),
         remote_identity_public: parameters.their_identity_key().serialize().to_vec(),
         root_key: sending_chain_root_key.key().to_vec(),
@@ -80,6 +129,10 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
         local_registration_id: 0,
         needs_refresh: false,
         alice_base_key: vec![],
+        last_used_at: 0,
+        capabilities: 0,
+        created_at: current_time_millis(),
+        highest_version_seen: config.session_version(),
     };
 
     let mut session = SessionState::new(session);
@@ -92,6 +145,7 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
 
 pub(crate) fn initialize_bob_session(
     parameters: &BobSignalProtocolParameters,
+    config: &ProtocolConfig,
 ) -> Result<SessionState> {
     let local_identity = parameters.our_identity_key_pair().identity_key();
 
@@ -128,10 +182,10 @@ pub(crate) fn initialize_bob_session(
         );
     }
 
-    let (root_key, chain_key) = derive_keys(&secrets)?;
+    let (root_key, chain_key) = derive_keys(&secrets, config)?;
 
     let session = SessionStructure {
-        session_version: CIPHERTEXT_MESSAGE_CURRENT_VERSION as u32,
+        session_version: config.session_version(),
         local_identity_public: local_identity.public_key().serialize().to_vec(),
         remote_identity_public: parameters.their_identity_key().serialize().to_vec(),
         root_key: root_key.key().to_vec(),
@@ -143,6 +197,10 @@ pub(crate) fn initialize_bob_session(
         local_registration_id: 0,
         needs_refresh: false,
         alice_base_key: vec![],
+        last_used_at: 0,
+        capabilities: 0,
+        created_at: current_time_millis(),
+        highest_version_seen: config.session_version(),
     };
 
     let mut session = SessionState::new(session);
@@ -155,14 +213,33 @@ pub(crate) fn initialize_bob_session(
 pub fn initialize_alice_session_record<R: Rng + CryptoRng>(
     parameters: &AliceSignalProtocolParameters,
     csprng: &mut R,
+) -> Result<SessionRecord> {
+    initialize_alice_session_record_with_config(parameters, &ProtocolConfig::default(), csprng)
+}
+
+/// Like [`initialize_alice_session_record`], but with non-default protocol parameters.
+pub fn initialize_alice_session_record_with_config<R: Rng + CryptoRng>(
+    parameters: &AliceSignalProtocolParameters,
+    config: &ProtocolConfig,
+    csprng: &mut R,
 ) -> Result<SessionRecord> {
     Ok(SessionRecord::new(initialize_alice_session(
-        parameters, csprng,
+        parameters, config, csprng,
     )?))
 }
 
 pub fn initialize_bob_session_record(
     parameters: &BobSignalProtocolParameters,
 ) -> Result<SessionRecord> {
-    Ok(SessionRecord::new(initialize_bob_session(parameters)?))
+    initialize_bob_session_record_with_config(parameters, &ProtocolConfig::default())
+}
+
+/// Like [`initialize_bob_session_record`], but with non-default protocol parameters.
+pub fn initialize_bob_session_record_with_config(
+    parameters: &BobSignalProtocolParameters,
+    config: &ProtocolConfig,
+) -> Result<SessionRecord> {
+    Ok(SessionRecord::new(initialize_bob_session(
+        parameters, config,
+    )?))
 }
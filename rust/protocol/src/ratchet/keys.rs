@@ -5,8 +5,11 @@
 
 use arrayref::array_ref;
 
+use crate::consts::MAX_FORWARD_JUMPS;
 use crate::crypto;
 use crate::{PrivateKey, PublicKey, Result, SignalProtocolError};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
 use std::fmt;
 
 pub struct MessageKeys {
@@ -55,6 +58,20 @@ impl MessageKeys {
         &self.cipher_key
     }
 
+    /// Returns the portion of the cipher key used to encrypt message bodies in a session with the
+    /// given version.
+    ///
+    /// This is the full 32-byte key, except for experimental AES-128 sessions (only possible with
+    /// the `weak-ciphers` feature), which use its first 16 bytes.
+    pub(crate) fn cipher_key_for_version(&self, session_version: u32) -> &[u8] {
+        #[cfg(feature = "weak-ciphers")]
+        if session_version == crate::protocol::CIPHERTEXT_MESSAGE_AES_128_VERSION as u32 {
+            return &self.cipher_key[..16];
+        }
+        let _ = session_version;
+        &self.cipher_key
+    }
+
     #[inline]
     pub fn mac_key(&self) -> &[u8; 32] {
         &self.mac_key
@@ -80,6 +97,7 @@ pub struct ChainKey {
 impl ChainKey {
     const MESSAGE_KEY_SEED: [u8; 1] = [0x01u8];
     const CHAIN_KEY_SEED: [u8; 1] = [0x02u8];
+    const KEEPALIVE_KEY_SEED: [u8; 1] = [0x03u8];
 
     pub fn new(key: &[u8], index: u32) -> Result<Self> {
         if key.len() != 32 {
@@ -103,9 +121,12 @@ impl ChainKey {
     }
 
     pub fn next_chain_key(&self) -> Result<Self> {
+        let index = self.index.checked_add(1).ok_or_else(|| {
+            SignalProtocolError::InvalidState("next_chain_key", "chain is exhausted".to_string())
+        })?;
         Ok(Self {
             key: self.calculate_base_material(Self::CHAIN_KEY_SEED)?,
-            index: self.index + 1,
+            index,
         })
     }
 
@@ -116,6 +137,84 @@ impl ChainKey {
         )
     }
 
+    /// Derives the key that authenticates a [`KeepAliveMessage`](crate::KeepAliveMessage) sent at
+    /// this chain key's index.
+    ///
+    /// This is independent of the message keys, so it can be used without advancing the chain.
+    pub(crate) fn keepalive_mac_key(&self) -> Result<[u8; 32]> {
+        self.calculate_base_material(Self::KEEPALIVE_KEY_SEED)
+    }
+
+    /// Derives the message keys for every index in `from..to`, in order.
+    ///
+    /// This produces the same keys as repeatedly calling [`message_keys`](Self::message_keys) and
+    /// [`next_chain_key`](Self::next_chain_key), but only computes the HMAC key schedule once per
+    /// step instead of twice.
+    ///
+    /// Fails with [`SignalProtocolError::MessageGapTooLarge`] if `to` is further ahead of this
+    /// chain key than a received message may skip.
+    pub fn derive_message_keys_range(&self, from: u32, to: u32) -> Result<Vec<MessageKeys>> {
+        if from < self.index || from > to {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "cannot derive message keys {}..{} from chain key at index {}",
+                from, to, self.index
+            )));
+        }
+        let gap = to - self.index;
+        if gap as usize > MAX_FORWARD_JUMPS {
+            return Err(SignalProtocolError::MessageGapTooLarge { gap });
+        }
+
+        let mut keys = Vec::with_capacity((to - from) as usize);
+        self.step_to(from, to, |message_keys| {
+            keys.push(message_keys);
+            Ok(())
+        })?;
+        Ok(keys)
+    }
+
+    /// Advances this chain key to index `to`, passing the message keys for every index skipped
+    /// along the way to `skipped` one at a time, and returns the new chain key.
+    pub(crate) fn derive_message_keys_until(
+        &self,
+        to: u32,
+        skipped: impl FnMut(MessageKeys) -> Result<()>,
+    ) -> Result<Self> {
+        self.step_to(self.index, to, skipped)
+    }
+
+    fn step_to(
+        &self,
+        from: u32,
+        to: u32,
+        mut on_message_keys: impl FnMut(MessageKeys) -> Result<()>,
+    ) -> Result<Self> {
+        let mut chain_key = self.clone();
+
+        while chain_key.index < to {
+            let mut message_key_mac = Hmac::<Sha256>::new_from_slice(&chain_key.key)
+                .expect("HMAC-SHA256 should accept any size key");
+            let mut chain_key_mac = message_key_mac.clone();
+            message_key_mac.update(&Self::MESSAGE_KEY_SEED);
+            chain_key_mac.update(&Self::CHAIN_KEY_SEED);
+
+            if chain_key.index >= from {
+                let message_key_material: [u8; 32] = message_key_mac.finalize().into_bytes().into();
+                on_message_keys(MessageKeys::derive_keys(
+                    &message_key_material,
+                    chain_key.index,
+                )?)?;
+            }
+
+            chain_key = Self {
+                key: chain_key_mac.finalize().into_bytes().into(),
+                index: chain_key.index + 1,
+            };
+        }
+
+        Ok(chain_key)
+    }
+
     fn calculate_base_material(&self, seed: [u8; 1]) -> Result<[u8; 32]> {
         crypto::hmac_sha256(&self.key, &seed)
     }
@@ -207,4 +306,39 @@ mod tests {
         assert_eq!(1, chain_key.next_chain_key()?.message_keys()?.counter());
         Ok(())
     }
+
+    #[test]
+    fn test_chain_key_range_derivation() -> Result<()> {
+        let chain_key = ChainKey::new(&[0x42u8; 32], 3)?;
+
+        let range = chain_key.derive_message_keys_range(5, 20)?;
+        assert_eq!(range.len(), 15);
+
+        let mut stepped = chain_key.next_chain_key()?.next_chain_key()?;
+        for keys in &range {
+            let expected = stepped.message_keys()?;
+            assert_eq!(expected.counter(), keys.counter());
+            assert_eq!(expected.cipher_key(), keys.cipher_key());
+            assert_eq!(expected.mac_key(), keys.mac_key());
+            assert_eq!(expected.iv(), keys.iv());
+            stepped = stepped.next_chain_key()?;
+        }
+
+        let mut skipped = 0;
+        let advanced = chain_key.derive_message_keys_until(20, |_| {
+            skipped += 1;
+            Ok(())
+        })?;
+        assert_eq!(skipped, 17);
+        assert_eq!(advanced.index(), stepped.index());
+        assert_eq!(advanced.key(), stepped.key());
+
+        assert!(chain_key.derive_message_keys_range(2, 20).is_err());
+        assert!(chain_key.derive_message_keys_range(20, 5).is_err());
+        assert!(matches!(
+            chain_key.derive_message_keys_range(3, u32::MAX),
+            Err(SignalProtocolError::MessageGapTooLarge { gap }) if gap == u32::MAX - 3
+        ));
+        Ok(())
+    }
 }
@@ -4,14 +4,16 @@
 //
 
 use crate::{
-    Context, Direction, IdentityKeyStore, KeyPair, PreKeyBundle, PreKeySignalMessage, PreKeyStore,
-    ProtocolAddress, Result, SessionRecord, SessionStore, SignalProtocolError, SignedPreKeyStore,
+    Context, Direction, IdentityKey, IdentityKeyPair, IdentityKeyStore, KeyPair, PreKeyBundle,
+    PreKeySignalMessage, PreKeyStore, ProtocolAddress, PublicKey, Result, SessionRecord,
+    SessionStore, SignalProtocolError, SignedPreKeyRecord, SignedPreKeyStore,
 };
 
 use crate::ratchet;
-use crate::ratchet::{AliceSignalProtocolParameters, BobSignalProtocolParameters};
-use crate::state::PreKeyId;
+use crate::ratchet::{AliceSignalProtocolParameters, BobSignalProtocolParameters, ProtocolConfig};
+use crate::state::{PreKeyId, SessionState, SignedPreKeyId};
 use rand::{CryptoRng, Rng};
+use std::collections::HashMap;
 
 /*
 These functions are on SessionBuilder in Java
@@ -22,6 +24,68 @@ its reference to the various data stores, instead the functions are
 free standing.
  */
 
+/// Returns the IDs of the signed pre-key and the one-time pre-key (if any) that must be available
+/// to process `message`.
+///
+/// This allows checking that the keys are still present, or loading them ahead of time, before
+/// attempting a decryption that would consume the one-time pre-key.
+pub fn required_keys(message: &PreKeySignalMessage) -> (SignedPreKeyId, Option<PreKeyId>) {
+    (message.signed_pre_key_id(), message.pre_key_id())
+}
+
+/// Saves `new_key` as the current signed pre-key, keeping the `retain_previous` most recent older
+/// signed pre-keys and removing the rest.
+///
+/// Keeping a few previous keys lets pre-key messages that were sent before the rotation still be
+/// processed. Keys are ordered by [timestamp](SignedPreKeyRecord::timestamp), then by ID. Returns
+/// the IDs of the removed keys, oldest first.
+///
+/// The store must support [`signed_pre_key_ids`](SignedPreKeyStore::signed_pre_key_ids) and
+/// [`remove_signed_pre_key`](SignedPreKeyStore::remove_signed_pre_key).
+pub async fn rotate_signed_pre_key(
+    signed_prekey_store: &mut dyn SignedPreKeyStore,
+    new_key: &SignedPreKeyRecord,
+    retain_previous: usize,
+    ctx: Context,
+) -> Result<Vec<SignedPreKeyId>> {
+    let new_id = new_key.id()?;
+    signed_prekey_store
+        .save_signed_pre_key(new_id, new_key, ctx)
+        .await?;
+
+    let mut previous = Vec::new();
+    for id in signed_prekey_store.signed_pre_key_ids(ctx).await? {
+        if id != new_id {
+            let timestamp = signed_prekey_store
+                .get_signed_pre_key(id, ctx)
+                .await?
+                .timestamp()?;
+            previous.push((timestamp, id));
+        }
+    }
+    previous.sort_unstable();
+
+    let retired_count = previous.len().saturating_sub(retain_previous);
+    let retired = previous
+        .into_iter()
+        .take(retired_count)
+        .map(|(_, id)| id)
+        .collect::<Vec<_>>();
+    for &id in &retired {
+        signed_prekey_store.remove_signed_pre_key(id, ctx).await?;
+    }
+    Ok(retired)
+}
+
+/// Sets up a new session from an incoming pre-key message, unless `session_record` already
+/// contains the session it refers to.
+///
+/// If a new session was set up using a one-time pre-key, that pre-key's ID is returned.
+///
+/// Fails with [`SignalProtocolError::UntrustedIdentity`] if the sender's identity is not trusted,
+/// and with [`SignalProtocolError::SignedPreKeyNotFound`] or
+/// [`SignalProtocolError::PreKeyNotFound`] if the store reports the signed pre-key or one-time
+/// pre-key the message refers to as missing.
 pub async fn process_prekey(
     message: &PreKeySignalMessage,
     remote_address: &ProtocolAddress,
@@ -30,22 +94,34 @@ pub async fn process_prekey(
     pre_key_store: &mut dyn PreKeyStore,
     signed_prekey_store: &mut dyn SignedPreKeyStore,
     ctx: Context,
+) -> Result<Option<PreKeyId>> {
+    process_prekey_with_config(
+        message,
+        remote_address,
+        session_record,
+        identity_store,
+        pre_key_store,
+        signed_prekey_store,
+        &ProtocolConfig::default(),
+        ctx,
+    )
+    .await
+}
+
+/// Like [`process_prekey`], but sets up the session according to `config`.
+pub async fn process_prekey_with_config(
+    message: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    session_record: &mut SessionRecord,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_prekey_store: &mut dyn SignedPreKeyStore,
+    config: &ProtocolConfig,
+    ctx: Context,
 ) -> Result<Option<PreKeyId>> {
     let their_identity_key = message.identity_key();
 
-    if !identity_store
-        .is_trusted_identity(
-            remote_address,
-            their_identity_key,
-            Direction::Receiving,
-            ctx,
-        )
-        .await?
-    {
-        return Err(SignalProtocolError::UntrustedIdentity(
-            remote_address.clone(),
-        ));
-    }
+    check_prekey_sender_trusted(message, remote_address, identity_store, ctx).await?;
 
     let unsigned_pre_key_id = process_prekey_v3(
         message,
@@ -54,6 +130,7 @@ pub async fn process_prekey(
         signed_prekey_store,
         pre_key_store,
         identity_store,
+        config,
         ctx,
     )
     .await?;
@@ -65,6 +142,74 @@ pub async fn process_prekey(
     Ok(unsigned_pre_key_id)
 }
 
+/// Like [`process_prekey_with_config`], but [claims](PreKeyStore::claim_pre_key) the one-time
+/// pre-key instead of just loading it.
+///
+/// If a new session was set up using a one-time pre-key, that pre-key's ID is returned, and the
+/// caller must either remove it once the new session has been stored or
+/// [release](PreKeyStore::release_pre_key) it if the message is rejected. If this function fails,
+/// any claim it made has already been released.
+pub(crate) async fn process_prekey_claiming(
+    message: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    session_record: &mut SessionRecord,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_prekey_store: &mut dyn SignedPreKeyStore,
+    config: &ProtocolConfig,
+    ctx: Context,
+) -> Result<Option<PreKeyId>> {
+    let their_identity_key = message.identity_key();
+
+    check_prekey_sender_trusted(message, remote_address, identity_store, ctx).await?;
+
+    let unsigned_pre_key_id = process_prekey_v3_claiming(
+        message,
+        remote_address,
+        session_record,
+        signed_prekey_store,
+        pre_key_store,
+        identity_store,
+        config,
+        ctx,
+    )
+    .await?;
+
+    if let Err(e) = identity_store
+        .save_identity(remote_address, their_identity_key, ctx)
+        .await
+    {
+        if let Some(pre_key_id) = unsigned_pre_key_id {
+            pre_key_store.release_pre_key(pre_key_id, ctx).await?;
+        }
+        return Err(e);
+    }
+
+    Ok(unsigned_pre_key_id)
+}
+
+async fn check_prekey_sender_trusted(
+    message: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    identity_store: &mut dyn IdentityKeyStore,
+    ctx: Context,
+) -> Result<()> {
+    if !identity_store
+        .is_trusted_identity(
+            remote_address,
+            message.identity_key(),
+            Direction::Receiving,
+            ctx,
+        )
+        .await?
+    {
+        return Err(SignalProtocolError::UntrustedIdentity(
+            remote_address.clone(),
+        ));
+    }
+    Ok(())
+}
+
 async fn process_prekey_v3(
     message: &PreKeySignalMessage,
     remote_address: &ProtocolAddress,
@@ -72,6 +217,7 @@ async fn process_prekey_v3(
     signed_prekey_store: &mut dyn SignedPreKeyStore,
     pre_key_store: &mut dyn PreKeyStore,
     identity_store: &mut dyn IdentityKeyStore,
+    config: &ProtocolConfig,
     ctx: Context,
 ) -> Result<Option<PreKeyId>> {
     if session_record.has_session_state(
@@ -82,17 +228,16 @@ async fn process_prekey_v3(
         return Ok(None);
     }
 
-    let our_signed_pre_key_pair = signed_prekey_store
-        .get_signed_pre_key(message.signed_pre_key_id(), ctx)
-        .await?
-        .key_pair()?;
+    let our_signed_pre_key_pair =
+        load_signed_pre_key_pair(message, signed_prekey_store, ctx).await?;
 
     let our_one_time_pre_key_pair = if let Some(pre_key_id) = message.pre_key_id() {
         log::info!("processing PreKey message from {}", remote_address);
         Some(
             pre_key_store
                 .get_pre_key(pre_key_id, ctx)
-                .await?
+                .await
+                .map_err(|e| pre_key_not_found(e, pre_key_id))?
                 .key_pair()?,
         )
     } else {
@@ -103,8 +248,127 @@ async fn process_prekey_v3(
         None
     };
 
-    let parameters = BobSignalProtocolParameters::new(
+    let new_session = new_bob_session(
+        message,
         identity_store.get_identity_key_pair(ctx).await?,
+        our_signed_pre_key_pair,
+        our_one_time_pre_key_pair,
+        identity_store.get_local_registration_id(ctx).await?,
+        config,
+    )?;
+
+    session_record.archive_current_state()?;
+    session_record.promote_state(new_session)?;
+
+    Ok(message.pre_key_id())
+}
+
+async fn process_prekey_v3_claiming(
+    message: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    session_record: &mut SessionRecord,
+    signed_prekey_store: &mut dyn SignedPreKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    config: &ProtocolConfig,
+    ctx: Context,
+) -> Result<Option<PreKeyId>> {
+    if session_record.has_session_state(
+        message.message_version() as u32,
+        &message.base_key().serialize(),
+    )? {
+        // We've already setup a session for this V3 message, letting bundled message fall through
+        return Ok(None);
+    }
+
+    let our_signed_pre_key_pair =
+        load_signed_pre_key_pair(message, signed_prekey_store, ctx).await?;
+    let our_identity_key_pair = identity_store.get_identity_key_pair(ctx).await?;
+    let local_registration_id = identity_store.get_local_registration_id(ctx).await?;
+
+    // Claim the one-time pre-key last, so that nothing between here and the end of this function
+    // needs to go back to a store, and the claim can be released if setting up the session fails.
+    let our_one_time_pre_key = if let Some(pre_key_id) = message.pre_key_id() {
+        log::info!("processing PreKey message from {}", remote_address);
+        Some(
+            pre_key_store
+                .claim_pre_key(pre_key_id, ctx)
+                .await
+                .map_err(|e| pre_key_not_found(e, pre_key_id))?,
+        )
+    } else {
+        log::warn!(
+            "processing PreKey message from {} which had no one-time prekey",
+            remote_address
+        );
+        None
+    };
+
+    let new_session = our_one_time_pre_key
+        .map(|record| record.key_pair())
+        .transpose()
+        .and_then(|our_one_time_pre_key_pair| {
+            new_bob_session(
+                message,
+                our_identity_key_pair,
+                our_signed_pre_key_pair,
+                our_one_time_pre_key_pair,
+                local_registration_id,
+                config,
+            )
+        });
+
+    let new_session = match new_session {
+        Ok(new_session) => new_session,
+        Err(e) => {
+            if let Some(pre_key_id) = message.pre_key_id() {
+                pre_key_store.release_pre_key(pre_key_id, ctx).await?;
+            }
+            return Err(e);
+        }
+    };
+
+    session_record.archive_current_state()?;
+    session_record.promote_state(new_session)?;
+
+    Ok(message.pre_key_id())
+}
+
+async fn load_signed_pre_key_pair(
+    message: &PreKeySignalMessage,
+    signed_prekey_store: &mut dyn SignedPreKeyStore,
+    ctx: Context,
+) -> Result<KeyPair> {
+    let signed_pre_key_id = message.signed_pre_key_id();
+    signed_prekey_store
+        .get_signed_pre_key(signed_pre_key_id, ctx)
+        .await
+        .map_err(|e| match e {
+            SignalProtocolError::InvalidSignedPreKeyId => {
+                SignalProtocolError::SignedPreKeyNotFound(signed_pre_key_id)
+            }
+            e => e,
+        })?
+        .key_pair()
+}
+
+fn pre_key_not_found(e: SignalProtocolError, pre_key_id: PreKeyId) -> SignalProtocolError {
+    match e {
+        SignalProtocolError::InvalidPreKeyId => SignalProtocolError::PreKeyNotFound(pre_key_id),
+        e => e,
+    }
+}
+
+fn new_bob_session(
+    message: &PreKeySignalMessage,
+    our_identity_key_pair: IdentityKeyPair,
+    our_signed_pre_key_pair: KeyPair,
+    our_one_time_pre_key_pair: Option<KeyPair>,
+    local_registration_id: u32,
+    config: &ProtocolConfig,
+) -> Result<SessionState> {
+    let parameters = BobSignalProtocolParameters::new(
+        our_identity_key_pair,
         our_signed_pre_key_pair, // signed pre key
         our_one_time_pre_key_pair,
         our_signed_pre_key_pair, // ratchet key
@@ -112,25 +376,192 @@ async fn process_prekey_v3(
         *message.base_key(),
     );
 
-    session_record.archive_current_state()?;
+    let mut new_session = ratchet::initialize_bob_session(&parameters, config)?;
 
-    let mut new_session = ratchet::initialize_bob_session(&parameters)?;
-
-    new_session.set_local_registration_id(identity_store.get_local_registration_id(ctx).await?)?;
+    new_session.set_local_registration_id(local_registration_id)?;
     new_session.set_remote_registration_id(message.registration_id())?;
     new_session.set_alice_base_key(&message.base_key().serialize())?;
+    Ok(new_session)
+}
 
-    session_record.promote_state(new_session)?;
+pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    bundle: &PreKeyBundle,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<()> {
+    process_prekey_bundle_with_config(
+        remote_address,
+        session_store,
+        identity_store,
+        bundle,
+        csprng,
+        &ProtocolConfig::default(),
+        ctx,
+    )
+    .await
+}
 
-    Ok(message.pre_key_id())
+/// Like [`process_prekey_bundle`], but takes a bundle serialized with [`PreKeyBundle::serialize`].
+///
+/// Fails with [`SignalProtocolError::InvalidProtobufEncoding`] if the bundle is malformed, and
+/// with [`SignalProtocolError::SignatureValidationFailed`] if its signed pre-key is not signed by
+/// its identity key.
+pub async fn process_prekey_bundle_bytes<R: Rng + CryptoRng>(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    bundle: &[u8],
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<()> {
+    let bundle = PreKeyBundle::deserialize(bundle)?;
+    process_prekey_bundle(
+        remote_address,
+        session_store,
+        identity_store,
+        &bundle,
+        csprng,
+        ctx,
+    )
+    .await
 }
 
-pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
+/// Like [`process_prekey_bundle`], but sets up the session according to `config`.
+pub async fn process_prekey_bundle_with_config<R: Rng + CryptoRng>(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    bundle: &PreKeyBundle,
+    csprng: &mut R,
+    config: &ProtocolConfig,
+    ctx: Context,
+) -> Result<()> {
+    process_prekey_bundle_impl(
+        remote_address,
+        session_store,
+        identity_store,
+        bundle,
+        csprng,
+        config,
+        None,
+        ctx,
+    )
+    .await
+}
+
+/// Remembers which signed pre-keys have already been verified, so that processing another bundle
+/// with the same keys skips the signature check.
+///
+/// For each address, the cache holds the identity key and signed pre-key (with its signature) of
+/// the last bundle that passed verification. A bundle with exactly those values is accepted
+/// without verifying the signature again; anything else is verified as usual and, if valid,
+/// replaces the entry. A bundle with a different identity key discards the entry even if its
+/// signature turns out to be invalid. The trust check against the identity store is never
+/// skipped.
+#[derive(Debug, Default)]
+pub struct VerifiedBundleCache {
+    verified: HashMap<ProtocolAddress, VerifiedSignedPreKey>,
+}
+
+#[derive(Debug)]
+struct VerifiedSignedPreKey {
+    identity_key: IdentityKey,
+    signed_pre_key: PublicKey,
+    signature: Box<[u8]>,
+}
+
+impl VerifiedBundleCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Like [`process_prekey_bundle_with_config`], but uses and updates this cache for the
+    /// signature check.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
+        &mut self,
+        remote_address: &ProtocolAddress,
+        session_store: &mut dyn SessionStore,
+        identity_store: &mut dyn IdentityKeyStore,
+        bundle: &PreKeyBundle,
+        csprng: &mut R,
+        config: &ProtocolConfig,
+        ctx: Context,
+    ) -> Result<()> {
+        process_prekey_bundle_impl(
+            remote_address,
+            session_store,
+            identity_store,
+            bundle,
+            csprng,
+            config,
+            Some(self),
+            ctx,
+        )
+        .await
+    }
+
+    /// Discards the entry for `remote_address`, if any.
+    pub fn forget(&mut self, remote_address: &ProtocolAddress) {
+        self.verified.remove(remote_address);
+    }
+
+    pub fn clear(&mut self) {
+        self.verified.clear();
+    }
+
+    /// Whether the signed pre-key in `bundle` is signed by the bundle's identity key.
+    fn verify(&mut self, remote_address: &ProtocolAddress, bundle: &PreKeyBundle) -> Result<bool> {
+        let identity_key = bundle.identity_key()?;
+        let signed_pre_key = bundle.signed_pre_key_public()?;
+        let signature = bundle.signed_pre_key_signature()?;
+
+        if let Some(entry) = self.verified.get(remote_address) {
+            if entry.identity_key != *identity_key {
+                log::info!(
+                    "identity key for {} changed; discarding its verified signed pre-key",
+                    remote_address
+                );
+                self.verified.remove(remote_address);
+            } else if entry.signed_pre_key == signed_pre_key && *entry.signature == *signature {
+                return Ok(true);
+            }
+        }
+
+        if !verify_bundle_signature(bundle)? {
+            return Ok(false);
+        }
+        self.verified.insert(
+            remote_address.clone(),
+            VerifiedSignedPreKey {
+                identity_key: *identity_key,
+                signed_pre_key,
+                signature: signature.into(),
+            },
+        );
+        Ok(true)
+    }
+}
+
+fn verify_bundle_signature(bundle: &PreKeyBundle) -> Result<bool> {
+    bundle.identity_key()?.public_key().verify_signature(
+        &bundle.signed_pre_key_public()?.serialize(),
+        bundle.signed_pre_key_signature()?,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_prekey_bundle_impl<R: Rng + CryptoRng>(
     remote_address: &ProtocolAddress,
     session_store: &mut dyn SessionStore,
     identity_store: &mut dyn IdentityKeyStore,
     bundle: &PreKeyBundle,
     mut csprng: &mut R,
+    config: &ProtocolConfig,
+    cache: Option<&mut VerifiedBundleCache>,
     ctx: Context,
 ) -> Result<()> {
     let their_identity_key = bundle.identity_key()?;
@@ -144,10 +575,11 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
         ));
     }
 
-    if !their_identity_key.public_key().verify_signature(
-        &bundle.signed_pre_key_public()?.serialize(),
-        bundle.signed_pre_key_signature()?,
-    )? {
+    let signature_valid = match cache {
+        Some(cache) => cache.verify(remote_address, bundle)?,
+        None => verify_bundle_signature(bundle)?,
+    };
+    if !signature_valid {
         return Err(SignalProtocolError::SignatureValidationFailed);
     }
 
@@ -173,7 +605,7 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
         their_signed_prekey,
     );
 
-    let mut session = ratchet::initialize_alice_session(&parameters, csprng)?;
+    let mut session = ratchet::initialize_alice_session(&parameters, config, csprng)?;
 
     log::info!(
         "set_unacknowledged_pre_key_message for: {} with preKeyId: {}",
@@ -189,6 +621,7 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
 
     session.set_local_registration_id(identity_store.get_local_registration_id(ctx).await?)?;
     session.set_remote_registration_id(bundle.registration_id()?)?;
+    session.set_capabilities(bundle.capabilities()?)?;
     session.set_alice_base_key(&our_base_key_pair.public_key.serialize())?;
 
     identity_store
@@ -196,6 +629,7 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
         .await?;
 
     session_record.promote_state(session)?;
+    session_record.increment_record_version();
 
     session_store
         .store_session(remote_address, &session_record, ctx)
@@ -4,18 +4,324 @@
 //
 
 use crate::{
-    CiphertextMessage, Context, Direction, IdentityKeyStore, KeyPair, PreKeySignalMessage,
-    PreKeyStore, ProtocolAddress, PublicKey, Result, SessionRecord, SessionStore, SignalMessage,
-    SignalProtocolError, SignedPreKeyStore,
+    CiphertextMessage, Context, Direction, IdentityKey, IdentityKeyStore, KeepAliveMessage,
+    KeyPair, PreKeyBundle, PreKeySignalMessage, PreKeyStore, ProtocolAddress, PublicKey,
+    ReadOnlySessionRecord, Result, SessionRecord, SessionStore, SignalMessage, SignalProtocolError,
+    SignedPreKeyStore, SUPPORTED_SESSION_VERSIONS,
 };
 
-use crate::consts::MAX_FORWARD_JUMPS;
+#[cfg(feature = "compression")]
+use crate::consts::MAX_DECOMPRESSED_MESSAGE_LEN;
+use crate::consts::{MAX_FORWARD_JUMPS, MAX_SESSION_STORE_ATTEMPTS, MAX_STORE_RETRY_BACKOFF};
 use crate::crypto;
-use crate::ratchet::{ChainKey, MessageKeys};
+use crate::ratchet::{ChainKey, MessageKeys, ProtocolConfig};
 use crate::session;
-use crate::state::SessionState;
+use crate::state::{PreKeyId, SessionState, SignedPreKeyId};
+use crate::storage::{Instrumented, StoreInstrumentation};
+use crate::utils::current_time_millis;
 
 use rand::{CryptoRng, Rng};
+use std::fmt;
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// See [`DecryptionConfig::on_pre_key_consumed`].
+pub type PreKeyConsumedCallback = Arc<dyn Fn(Option<usize>) + Send + Sync>;
+
+/// See [`StoreRetry::sleep`].
+pub type StoreRetrySleep =
+    Arc<dyn Fn(Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// See [`DecryptionConfig::on_first_message`].
+pub type FirstMessageCallback = Arc<dyn Fn(&ProtocolAddress) + Send + Sync>;
+
+/// See [`DecryptionConfig::on_untrusted_identity`] and [`EncryptionConfig::on_untrusted_identity`].
+///
+/// Called with the peer's address, the identity key stored for it (if any), and the identity key
+/// that was rejected.
+pub type UntrustedIdentityCallback =
+    Arc<dyn Fn(&ProtocolAddress, Option<IdentityKey>, IdentityKey) + Send + Sync>;
+
+/// See [`DecryptionConfig::on_session_reset`].
+///
+/// Called with the peer's address, the identity key recorded by the session that was replaced
+/// (if any), and the identity key of the new session.
+pub type SessionResetCallback =
+    Arc<dyn Fn(&ProtocolAddress, Option<IdentityKey>, IdentityKey) + Send + Sync>;
+
+/// Options controlling the behavior of [`message_decrypt_with_config`].
+///
+/// The default configuration matches [`message_decrypt`].
+#[derive(Clone, Default)]
+pub struct DecryptionConfig {
+    /// If set, a failure to decrypt with one particular session state is logged at `trace` level
+    /// rather than `warn`.
+    ///
+    /// Failing against the current state and then succeeding with a previous one is normal for
+    /// messages that were in flight while the session was reset. A message that fails to decrypt
+    /// with *every* state is still logged at `error` level either way.
+    pub quiet_state_failures: bool,
+    /// If set, session store operations that fail with [`SignalProtocolError::StoreTransient`]
+    /// are retried according to this policy.
+    ///
+    /// Only the loading and storing of the session record are retried, never the decryption
+    /// itself, so no ratchet step is ever applied twice.
+    pub store_retry: Option<StoreRetry>,
+    /// Called after a pre-key message has consumed (removed) one of our one-time pre-keys, with
+    /// the number of one-time pre-keys left according to [`PreKeyStore::pre_key_count`].
+    ///
+    /// This is called synchronously on the decryption path, so it should only hand off any real
+    /// work (such as uploading new pre-keys) rather than perform it.
+    pub on_pre_key_consumed: Option<PreKeyConsumedCallback>,
+    /// The protocol parameters to use for sessions set up by incoming pre-key messages.
+    pub protocol: ProtocolConfig,
+    /// If set, a current session that does not record the remote identity is archived when a
+    /// message arrives for it, and decryption fails with
+    /// [`SignalProtocolError::SessionCorruptRecoverable`] instead of
+    /// [`SignalProtocolError::InvalidSessionStructure`].
+    ///
+    /// Archiving keeps the message keys stored in the broken session available for decrypting
+    /// delayed messages. The caller should fetch a new pre-key bundle to set up a fresh session.
+    pub recover_corrupt_sessions: bool,
+    /// If set, a pre-key message that would replace a current session with the same remote
+    /// identity fails with [`SignalProtocolError::UnexpectedPreKeyMessage`], leaving the session
+    /// and the pre-key untouched.
+    ///
+    /// Pre-key messages for a session that was already set up from the same base key (i.e.
+    /// repeated before the peer received a response) and pre-key messages carrying a different
+    /// identity key are processed as usual.
+    pub reject_redundant_prekey: bool,
+    /// If set, looking up the stored keys for an out-of-order message does the same work whether
+    /// the message turns out to be new or a duplicate, so that the two cannot be told apart by
+    /// timing.
+    ///
+    /// This covers the lookup of stored keys and adds a dummy chain step for duplicates, matching
+    /// the cost of receiving the next message in order. Messages that skip ahead on a chain still
+    /// take longer in proportion to the gap. Off by default because the lookup then always scans
+    /// every stored key on the chain.
+    pub constant_time_dedup: bool,
+    /// If set, a message that would skip more than this many messages on its chain fails with
+    /// [`SignalProtocolError::MessageGapTooLarge`] instead of storing keys for all of them, unless
+    /// another session state can decrypt it.
+    ///
+    /// This applies below the hard limit on forward jumps, and leaves the session unchanged so
+    /// that the message can still be decrypted later (e.g. after the missing messages arrive, or
+    /// with the option turned off).
+    pub max_gap: Option<u32>,
+    /// Called just before decryption fails with [`SignalProtocolError::UntrustedIdentity`], with
+    /// the identity key stored for the sender and the one the message was sent with.
+    pub on_untrusted_identity: Option<UntrustedIdentityCallback>,
+    /// The associated data the message is expected to have been encrypted with; see
+    /// [`EncryptionConfig::associated_data`].
+    ///
+    /// A message encrypted with different associated data fails the MAC check, exactly as if it
+    /// had been sent in another session.
+    pub associated_data: Vec<u8>,
+    /// If set, a message that decrypts with the first matching session state is also tried
+    /// against every other state, and fails with [`SignalProtocolError::AmbiguousSession`] if any
+    /// of them can decrypt it too. The session is left unchanged in that case.
+    ///
+    /// By default the first state that decrypts the message wins: the current state, then the
+    /// previous states from most to least recently archived. A second match should be impossible
+    /// outside of deliberately degenerate keys, so this is mainly useful in tests.
+    pub reject_ambiguous_sessions: bool,
+    /// Called after decrypting the first message of a session, once the session has been stored.
+    ///
+    /// That is either a pre-key message that set up a new session, or the first reply to a session
+    /// we set up from a pre-key bundle (which stops us from sending pre-key messages). Not called
+    /// by [`message_decrypt_deferred`], which does not store the session.
+    pub on_first_message: Option<FirstMessageCallback>,
+    /// Called after a pre-key message has set up a new session that replaced an existing current
+    /// session, once the new session has been stored.
+    ///
+    /// This distinguishes a peer resetting the session mid-conversation, which may be worth
+    /// surfacing to the user, from first contact (which only calls
+    /// [`on_first_message`](Self::on_first_message)). Repeated pre-key messages for a session that
+    /// was already set up do not count as a reset. Not called by [`message_decrypt_deferred`].
+    pub on_session_reset: Option<SessionResetCallback>,
+    /// If set, a session state whose remote identity is our own identity key is not used for
+    /// decryption, and a message that no other state can decrypt fails with
+    /// [`SignalProtocolError::ReflectedIdentity`].
+    ///
+    /// Sessions with our own other devices legitimately share our identity key, so this is off by
+    /// default. Set it for peers that are known not to be our own devices, where such a session
+    /// means our own messages are being reflected back at us.
+    pub reject_own_identity: bool,
+    /// Receives the time taken by each store operation; see [`StoreInstrumentation`].
+    pub instrumentation: Option<Arc<dyn StoreInstrumentation>>,
+    /// If set, messages whose [version](SignalMessage::message_version) is outside this range fail
+    /// with [`SignalProtocolError::UnrecognizedMessageVersion`] before the session is loaded.
+    ///
+    /// By default, any version the session can decrypt is accepted.
+    pub accepted_versions: Option<RangeInclusive<u32>>,
+    /// If set, a [`SignalMessage`] that no session state can decrypt archives the current session
+    /// state, and decryption fails with [`SignalProtocolError::DecryptFailedSessionArchived`]
+    /// instead of [`SignalProtocolError::InvalidMessage`].
+    ///
+    /// Archiving keeps the message keys stored in the session available for decrypting delayed
+    /// messages, while the next message we send will require a new session. Duplicates, pre-key
+    /// messages, and store errors never archive the session.
+    pub heal_on_failure: bool,
+    /// If set, only messages from a session with this remote identity are accepted; any other
+    /// fails with [`SignalProtocolError::PinnedIdentityMismatch`], whatever the identity store's
+    /// trust decision.
+    ///
+    /// Pre-key messages are checked before anything is processed, so no pre-key is consumed and
+    /// no session is replaced. Other messages are checked against the session that decrypted them,
+    /// which is then left unchanged.
+    pub pinned_identity: Option<IdentityKey>,
+}
+
+impl fmt::Debug for DecryptionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DecryptionConfig")
+            .field("quiet_state_failures", &self.quiet_state_failures)
+            .field("store_retry", &self.store_retry)
+            .field(
+                "on_pre_key_consumed",
+                &self.on_pre_key_consumed.as_ref().map(|_| "<callback>"),
+            )
+            .field("protocol", &self.protocol)
+            .field("recover_corrupt_sessions", &self.recover_corrupt_sessions)
+            .field("reject_redundant_prekey", &self.reject_redundant_prekey)
+            .field("constant_time_dedup", &self.constant_time_dedup)
+            .field("max_gap", &self.max_gap)
+            .field(
+                "on_untrusted_identity",
+                &self.on_untrusted_identity.as_ref().map(|_| "<callback>"),
+            )
+            .field(
+                "associated_data",
+                &format_args!("<{} bytes>", self.associated_data.len()),
+            )
+            .field("reject_ambiguous_sessions", &self.reject_ambiguous_sessions)
+            .field(
+                "on_first_message",
+                &self.on_first_message.as_ref().map(|_| "<callback>"),
+            )
+            .field(
+                "on_session_reset",
+                &self.on_session_reset.as_ref().map(|_| "<callback>"),
+            )
+            .field("reject_own_identity", &self.reject_own_identity)
+            .field(
+                "instrumentation",
+                &self.instrumentation.as_ref().map(|_| "<instrumentation>"),
+            )
+            .field("accepted_versions", &self.accepted_versions)
+            .field("heal_on_failure", &self.heal_on_failure)
+            .field("pinned_identity", &self.pinned_identity)
+            .finish()
+    }
+}
+
+/// A policy for retrying session store operations that fail with
+/// [`SignalProtocolError::StoreTransient`].
+#[derive(Clone)]
+pub struct StoreRetry {
+    /// The total number of attempts for each operation, including the first.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry; doubled for each retry after that, up to a
+    /// maximum of one second.
+    pub backoff: Duration,
+    /// Waits for the given duration, e.g. using the application's async runtime.
+    ///
+    /// The library never blocks on its own; without this, operations are retried immediately.
+    pub sleep: Option<StoreRetrySleep>,
+}
+
+impl fmt::Debug for StoreRetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StoreRetry")
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .field("sleep", &self.sleep.as_ref().map(|_| "<sleep>"))
+            .finish()
+    }
+}
+
+/// Options controlling the behavior of [`message_encrypt_with_config`].
+///
+/// The default configuration matches [`message_encrypt`].
+#[derive(Clone, Default)]
+pub struct EncryptionConfig {
+    /// The maximum number of messages to send on a single sender chain.
+    ///
+    /// Once this many messages have been sent since the last DH ratchet step, encryption fails
+    /// with [`SignalProtocolError::InvalidState`] until the peer replies (which ratchets the
+    /// session forward on the next send) or a new session is established.
+    ///
+    /// Note that the sender cannot simply generate a new ratchet key on its own: the peer derives
+    /// its receiving chain for a new ratchet key from its *current* root key and sending ratchet
+    /// key, which only line up with ours immediately after we have received from the peer.
+    pub max_messages_per_chain: Option<u32>,
+    /// Called just before encryption fails with [`SignalProtocolError::UntrustedIdentity`], with
+    /// the identity key stored for the recipient and the one the session was set up with.
+    pub on_untrusted_identity: Option<UntrustedIdentityCallback>,
+    /// Application data to bind to the message, such as a conversation ID.
+    ///
+    /// The associated data is authenticated by the message MAC but not included in the message:
+    /// the recipient must supply the same bytes as [`DecryptionConfig::associated_data`] for the
+    /// message to decrypt. Empty associated data (the default) is the same as none.
+    pub associated_data: Vec<u8>,
+    /// Whether to compress the plaintext before encrypting it.
+    ///
+    /// Compression is skipped for a message when it would not make the body smaller. Messages are
+    /// flagged as compressed, and every decryption function inflates flagged messages, failing with
+    /// [`SignalProtocolError::MessageTooLong`] for bodies that expand past 1 MiB. Older versions of
+    /// this library ignore the flag, and builds without the `compression` feature reject flagged
+    /// messages, so only enable this for recipients known to support it.
+    ///
+    /// **Compression leaks information about the plaintext through the message length.** If an
+    /// attacker can get their own data into messages alongside a secret and observe the resulting
+    /// sizes, they can recover the secret a few bytes at a time, as in the CRIME and BREACH attacks
+    /// on TLS. Do not compress messages that mix secrets with content others can influence.
+    pub compression: Compression,
+    /// Receives the time taken by each store operation; see [`StoreInstrumentation`].
+    pub instrumentation: Option<Arc<dyn StoreInstrumentation>>,
+}
+
+/// See [`EncryptionConfig::compression`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Plaintexts are encrypted as is.
+    None,
+    /// Plaintexts are compressed with DEFLATE when that makes them smaller.
+    ///
+    /// Only available with the `compression` feature.
+    #[cfg(feature = "compression")]
+    Deflate,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl fmt::Debug for EncryptionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EncryptionConfig")
+            .field("max_messages_per_chain", &self.max_messages_per_chain)
+            .field(
+                "on_untrusted_identity",
+                &self.on_untrusted_identity.as_ref().map(|_| "<callback>"),
+            )
+            .field(
+                "associated_data",
+                &format_args!("<{} bytes>", self.associated_data.len()),
+            )
+            .field("compression", &self.compression)
+            .field(
+                "instrumentation",
+                &self.instrumentation.as_ref().map(|_| "<instrumentation>"),
+            )
+            .finish()
+    }
+}
 
 pub async fn message_encrypt(
     ptext: &[u8],
@@ -24,26 +330,341 @@ pub async fn message_encrypt(
     identity_store: &mut dyn IdentityKeyStore,
     ctx: Context,
 ) -> Result<CiphertextMessage> {
-    let mut session_record = session_store
+    message_encrypt_with_config(
+        ptext,
+        remote_address,
+        session_store,
+        identity_store,
+        &EncryptionConfig::default(),
+        ctx,
+    )
+    .await
+}
+
+/// Like [`message_encrypt`], but binds `associated_data` to the message; see
+/// [`EncryptionConfig::associated_data`].
+pub async fn message_encrypt_with_aad(
+    ptext: &[u8],
+    associated_data: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    ctx: Context,
+) -> Result<CiphertextMessage> {
+    message_encrypt_with_config(
+        ptext,
+        remote_address,
+        session_store,
+        identity_store,
+        &EncryptionConfig {
+            associated_data: associated_data.to_vec(),
+            ..Default::default()
+        },
+        ctx,
+    )
+    .await
+}
+
+/// Like [`message_encrypt_with_config`], but also returns the [unique
+/// ID](crate::message_unique_id) of the encrypted message and whether it was sent as a
+/// [`PreKeySignalMessage`].
+///
+/// The recipient gets the same ID from [`SignalMessage::unique_id`] (for a pre-key message, of
+/// the [inner message](PreKeySignalMessage::message)). Messages are sent as pre-key messages
+/// until the peer has acknowledged the session by replying, so the flag stays set for every
+/// message up to that point, not just the first.
+pub async fn message_encrypt_with_metadata(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    config: &EncryptionConfig,
+    ctx: Context,
+) -> Result<(CiphertextMessage, [u8; 16], bool)> {
+    let message = message_encrypt_with_config(
+        ptext,
+        remote_address,
+        session_store,
+        identity_store,
+        config,
+        ctx,
+    )
+    .await?;
+    let (unique_id, was_prekey) = match &message {
+        CiphertextMessage::SignalMessage(m) => (m.unique_id(), false),
+        CiphertextMessage::PreKeySignalMessage(m) => (m.message().unique_id(), true),
+        _ => {
+            return Err(SignalProtocolError::InvalidState(
+                "message_encrypt_with_metadata",
+                "unexpected message type".to_owned(),
+            ))
+        }
+    };
+    Ok((message, unique_id, was_prekey))
+}
+
+/// Encrypts a message with no content, such as an acknowledgement.
+///
+/// This is an ordinary message as far as the session is concerned: it advances the sender chain,
+/// and decrypting it yields an empty plaintext. The ciphertext body is a single block of padding.
+/// Use [`encrypt_keepalive`] for frames that should not advance the chain.
+pub async fn encrypt_empty(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    ctx: Context,
+) -> Result<CiphertextMessage> {
+    message_encrypt(&[], remote_address, session_store, identity_store, ctx).await
+}
+
+/// Creates a [`KeepAliveMessage`] for the current session with `remote_address`.
+///
+/// The keepalive is authenticated with the current sender chain key but does not consume a
+/// message key, so the session is not modified and the next message is sent with the same counter
+/// as if no keepalive had been sent. It can only be created once the peer has acknowledged the
+/// session; before that, this fails with [`SignalProtocolError::InvalidState`].
+///
+/// Like [`message_encrypt`], this fails with [`SignalProtocolError::UntrustedIdentity`] if the
+/// peer's identity is not trusted for sending, but it does not save the identity.
+pub async fn encrypt_keepalive(
+    remote_address: &ProtocolAddress,
+    session_store: &dyn SessionStore,
+    identity_store: &dyn IdentityKeyStore,
+    ctx: Context,
+) -> Result<KeepAliveMessage> {
+    let session_record = session_store
         .load_session(remote_address, ctx)
         .await?
+        .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.to_string()))?;
+    let session_state = session_record.session_state()?;
+
+    let session_version = session_state.session_version()?;
+    if !SUPPORTED_SESSION_VERSIONS.contains(&session_version) {
+        return Err(SignalProtocolError::UnsupportedSessionVersion(
+            session_version,
+        ));
+    }
+    if session_state
+        .unacknowledged_pre_key_message_items()?
+        .is_some()
+    {
+        return Err(SignalProtocolError::InvalidState(
+            "encrypt_keepalive",
+            "session has not been acknowledged by the peer yet".to_string(),
+        ));
+    }
+
+    let their_identity_key = session_state
+        .remote_identity_key()?
+        .ok_or(SignalProtocolError::InvalidSessionStructure)?;
+    if !identity_store
+        .is_trusted_identity(remote_address, &their_identity_key, Direction::Sending, ctx)
+        .await?
+    {
+        return Err(SignalProtocolError::UntrustedIdentity(
+            remote_address.clone(),
+        ));
+    }
+
+    let chain_key = session_state.get_sender_chain_key()?;
+    KeepAliveMessage::new(
+        session_version as u8,
+        &chain_key.keepalive_mac_key()?,
+        session_state.sender_ratchet_key()?,
+        chain_key.index(),
+        &session_state.local_identity_key()?,
+        &their_identity_key,
+    )
+}
+
+pub async fn message_encrypt_with_config(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    config: &EncryptionConfig,
+    ctx: Context,
+) -> Result<CiphertextMessage> {
+    match &config.instrumentation {
+        None => {
+            encrypt_with_stores(
+                ptext,
+                remote_address,
+                session_store,
+                identity_store,
+                config,
+                ctx,
+            )
+            .await
+        }
+        Some(instrumentation) => {
+            encrypt_with_stores(
+                ptext,
+                remote_address,
+                &mut Instrumented::new(session_store, instrumentation.as_ref()),
+                &mut Instrumented::new(identity_store, instrumentation.as_ref()),
+                config,
+                ctx,
+            )
+            .await
+        }
+    }
+}
+
+async fn encrypt_with_stores(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    config: &EncryptionConfig,
+    ctx: Context,
+) -> Result<CiphertextMessage> {
+    for _ in 0..MAX_SESSION_STORE_ATTEMPTS {
+        let mut session_record = session_store
+            .load_session(remote_address, ctx)
+            .await?
+            .ok_or_else(|| SignalProtocolError::SessionNotFound(format!("{}", remote_address)))?;
+        let message = encrypt_with_record(
+            ptext,
+            remote_address,
+            &mut session_record,
+            identity_store,
+            config,
+            ctx,
+        )
+        .await?;
+
+        if store_session_checked(
+            remote_address,
+            &mut session_record,
+            session_store,
+            None,
+            ctx,
+        )
+        .await?
+        {
+            return Ok(message);
+        }
+        log::info!(
+            "session for {} was modified concurrently; retrying",
+            remote_address
+        );
+    }
+
+    Err(SignalProtocolError::SessionConcurrentlyModified(
+        remote_address.clone(),
+    ))
+}
+
+/// Like [`message_encrypt_with_config`], but returns the updated session record instead of
+/// storing it.
+///
+/// The caller is responsible for persisting the returned record before encrypting or decrypting
+/// anything else with this session. Its [record version][SessionRecord::record_version] has
+/// already been advanced; the version of the record as loaded is returned alongside it, so to
+/// detect concurrent modification it can be stored with
+/// [`SessionStore::store_session_if_version`] expecting that version.
+pub async fn message_encrypt_deferred(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    config: &EncryptionConfig,
+    ctx: Context,
+) -> Result<(CiphertextMessage, SessionRecord, u64)> {
+    // The session store is only borrowed immutably here, so it can't be wrapped in Instrumented.
+    let start = Instant::now();
+    let loaded = session_store.load_session(remote_address, ctx).await;
+    if let Some(instrumentation) = &config.instrumentation {
+        instrumentation.on_load_session_done(start.elapsed());
+    }
+    let mut session_record = loaded?
         .ok_or_else(|| SignalProtocolError::SessionNotFound(format!("{}", remote_address)))?;
+    let expected_version = session_record.record_version();
+
+    let message = match &config.instrumentation {
+        None => {
+            encrypt_with_record(
+                ptext,
+                remote_address,
+                &mut session_record,
+                identity_store,
+                config,
+                ctx,
+            )
+            .await?
+        }
+        Some(instrumentation) => {
+            encrypt_with_record(
+                ptext,
+                remote_address,
+                &mut session_record,
+                &mut Instrumented::new(identity_store, instrumentation.as_ref()),
+                config,
+                ctx,
+            )
+            .await?
+        }
+    };
+    session_record.increment_record_version();
+    Ok((message, session_record, expected_version))
+}
+
+/// Encrypts `ptext` with the current session in `session_record`, advancing its sender chain.
+async fn encrypt_with_record(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_record: &mut SessionRecord,
+    identity_store: &mut dyn IdentityKeyStore,
+    config: &EncryptionConfig,
+    ctx: Context,
+) -> Result<CiphertextMessage> {
     let session_state = session_record.session_state_mut()?;
 
+    let session_version = session_state.session_version()?;
+    if !SUPPORTED_SESSION_VERSIONS.contains(&session_version) {
+        return Err(SignalProtocolError::UnsupportedSessionVersion(
+            session_version,
+        ));
+    }
+
     let chain_key = session_state.get_sender_chain_key()?;
 
+    if let Some(limit) = config.max_messages_per_chain {
+        if chain_key.index() >= limit {
+            return Err(SignalProtocolError::InvalidState(
+                "message_encrypt",
+                format!(
+                    "sent {} messages since the last ratchet step (limit {})",
+                    chain_key.index(),
+                    limit
+                ),
+            ));
+        }
+    }
+
     let message_keys = chain_key.message_keys()?;
 
     let sender_ephemeral = session_state.sender_ratchet_key()?;
     let previous_counter = session_state.previous_counter()?;
-    let session_version = session_state.session_version()? as u8;
+    let session_version = session_version as u8;
 
     let local_identity_key = session_state.local_identity_key()?;
     let their_identity_key = session_state
         .remote_identity_key()?
         .ok_or(SignalProtocolError::InvalidSessionStructure)?;
 
-    let ctext = crypto::aes_256_cbc_encrypt(ptext, message_keys.cipher_key(), message_keys.iv())?;
+    let compressed_ptext = match config.compression {
+        Compression::None => None,
+        #[cfg(feature = "compression")]
+        Compression::Deflate => Some(miniz_oxide::deflate::compress_to_vec(ptext, 6))
+            .filter(|compressed| compressed.len() < ptext.len()),
+    };
+    let ctext = crypto::aes_cbc_encrypt(
+        compressed_ptext.as_deref().unwrap_or(ptext),
+        message_keys.cipher_key_for_version(session_version as u32),
+        message_keys.iv(),
+    )?;
 
     let message = if let Some(items) = session_state.unacknowledged_pre_key_message_items()? {
         let local_registration_id = session_state.local_registration_id()?;
@@ -56,7 +677,7 @@ pub async fn message_encrypt(
                 .map_or_else(|| "<none>".to_string(), |id| id.to_string())
         );
 
-        let message = SignalMessage::new(
+        let message = SignalMessage::new_with_flags(
             session_version,
             message_keys.mac_key(),
             sender_ephemeral,
@@ -65,6 +686,8 @@ pub async fn message_encrypt(
             &ctext,
             &local_identity_key,
             &their_identity_key,
+            &config.associated_data,
+            compressed_ptext.is_some(),
         )?;
 
         CiphertextMessage::PreKeySignalMessage(PreKeySignalMessage::new(
@@ -77,7 +700,7 @@ pub async fn message_encrypt(
             message,
         )?)
     } else {
-        CiphertextMessage::SignalMessage(SignalMessage::new(
+        CiphertextMessage::SignalMessage(SignalMessage::new_with_flags(
             session_version,
             message_keys.mac_key(),
             sender_ephemeral,
@@ -86,10 +709,13 @@ pub async fn message_encrypt(
             &ctext,
             &local_identity_key,
             &their_identity_key,
+            &config.associated_data,
+            compressed_ptext.is_some(),
         )?)
     };
 
     session_state.set_sender_chain_key(&chain_key.next_chain_key()?)?;
+    session_state.set_last_used_at(current_time_millis())?;
 
     // XXX why is this check after everything else?!!
     if !identity_store
@@ -104,6 +730,14 @@ pub async fn message_encrypt(
                 .map_or_else(|e| format!("<error: {}>", e), hex::encode),
             remote_address,
         );
+        report_untrusted_identity(
+            config.on_untrusted_identity.as_ref(),
+            remote_address,
+            &their_identity_key,
+            identity_store,
+            ctx,
+        )
+        .await;
         return Err(SignalProtocolError::UntrustedIdentity(
             remote_address.clone(),
         ));
@@ -114,9 +748,6 @@ pub async fn message_encrypt(
         .save_identity(remote_address, &their_identity_key, ctx)
         .await?;
 
-    session_store
-        .store_session(remote_address, &session_record, ctx)
-        .await?;
     Ok(message)
 }
 
@@ -130,257 +761,2253 @@ pub async fn message_decrypt<R: Rng + CryptoRng>(
     csprng: &mut R,
     ctx: Context,
 ) -> Result<Vec<u8>> {
-    match ciphertext {
-        CiphertextMessage::SignalMessage(m) => {
-            message_decrypt_signal(
-                m,
-                remote_address,
-                session_store,
-                identity_store,
-                csprng,
-                ctx,
-            )
-            .await
-        }
-        CiphertextMessage::PreKeySignalMessage(m) => {
-            message_decrypt_prekey(
-                m,
-                remote_address,
-                session_store,
-                identity_store,
-                pre_key_store,
-                signed_pre_key_store,
-                csprng,
-                ctx,
-            )
-            .await
-        }
-        _ => Err(SignalProtocolError::InvalidArgument(
-            "SessionCipher::decrypt cannot decrypt this message type".to_owned(),
-        )),
-    }
+    message_decrypt_with_config(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        csprng,
+        &DecryptionConfig::default(),
+        ctx,
+    )
+    .await
 }
 
-pub async fn message_decrypt_prekey<R: Rng + CryptoRng>(
-    ciphertext: &PreKeySignalMessage,
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt_with_config<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
     remote_address: &ProtocolAddress,
     session_store: &mut dyn SessionStore,
     identity_store: &mut dyn IdentityKeyStore,
     pre_key_store: &mut dyn PreKeyStore,
     signed_pre_key_store: &mut dyn SignedPreKeyStore,
     csprng: &mut R,
+    config: &DecryptionConfig,
     ctx: Context,
 ) -> Result<Vec<u8>> {
-    let mut session_record = session_store
-        .load_session(remote_address, ctx)
-        .await?
-        .unwrap_or_else(SessionRecord::new_fresh);
-
-    // Make sure we log the session state if we fail to process the pre-key.
-    let pre_key_id_or_err = session::process_prekey(
+    let mut ptext = Vec::new();
+    decrypt_into_with_config(
         ciphertext,
         remote_address,
-        &mut session_record,
+        session_store,
         identity_store,
         pre_key_store,
         signed_pre_key_store,
+        csprng,
+        config,
+        &mut ptext,
         ctx,
     )
-    .await;
-
-    let pre_key_id = match pre_key_id_or_err {
-        Ok(id) => id,
-        Err(e) => {
-            let errs = [e];
-            log::error!(
-                "{}",
-                create_decryption_failure_log(
-                    remote_address,
-                    &errs,
-                    &session_record,
-                    ciphertext.message()
-                )?
-            );
-            let [e] = errs;
-            return Err(e);
-        }
-    };
-
-    let ptext = decrypt_message_with_record(
-        remote_address,
-        &mut session_record,
-        ciphertext.message(),
-        csprng,
-    )?;
-
-    session_store
-        .store_session(remote_address, &session_record, ctx)
-        .await?;
-
-    if let Some(pre_key_id) = pre_key_id {
-        pre_key_store.remove_pre_key(pre_key_id, ctx).await?;
-    }
-
+    .await?;
     Ok(ptext)
 }
 
-pub async fn message_decrypt_signal<R: Rng + CryptoRng>(
-    ciphertext: &SignalMessage,
+/// Like [`message_decrypt`], but also returns the version of the session that decrypted the
+/// message.
+///
+/// This is the version of the session state that was current once the message had been processed,
+/// read from the same record that was stored, so no separate lookup is needed.
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt_with_version<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
     remote_address: &ProtocolAddress,
     session_store: &mut dyn SessionStore,
     identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<(Vec<u8>, u32)> {
+    let mut ptext = Vec::new();
+    let session_version = decrypt_into_with_config(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        csprng,
+        &DecryptionConfig::default(),
+        &mut ptext,
+        ctx,
+    )
+    .await?;
+    Ok((ptext, session_version))
+}
+
+/// Like [`message_decrypt`], but writes the plaintext into `out` instead of allocating a new
+/// buffer.
+///
+/// `out` is cleared first, so a buffer reused across calls keeps its capacity. If decryption
+/// fails, `out` is left empty.
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt_into<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    csprng: &mut R,
+    out: &mut Vec<u8>,
+    ctx: Context,
+) -> Result<()> {
+    let result = decrypt_into_with_config(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        csprng,
+        &DecryptionConfig::default(),
+        out,
+        ctx,
+    )
+    .await;
+    // A message from an untrusted identity is rejected only after it has been decrypted.
+    if result.is_err() {
+        out.clear();
+    }
+    result.map(|_| ())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn decrypt_into_with_config<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    csprng: &mut R,
+    config: &DecryptionConfig,
+    out: &mut Vec<u8>,
+    ctx: Context,
+) -> Result<u32> {
+    match &config.instrumentation {
+        None => {
+            decrypt_into_with_stores(
+                ciphertext,
+                remote_address,
+                session_store,
+                identity_store,
+                pre_key_store,
+                signed_pre_key_store,
+                csprng,
+                config,
+                out,
+                ctx,
+            )
+            .await
+        }
+        Some(instrumentation) => {
+            let instrumentation = instrumentation.as_ref();
+            decrypt_into_with_stores(
+                ciphertext,
+                remote_address,
+                &mut Instrumented::new(session_store, instrumentation),
+                &mut Instrumented::new(identity_store, instrumentation),
+                &mut Instrumented::new(pre_key_store, instrumentation),
+                &mut Instrumented::new(signed_pre_key_store, instrumentation),
+                csprng,
+                config,
+                out,
+                ctx,
+            )
+            .await
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn decrypt_into_with_stores<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    csprng: &mut R,
+    config: &DecryptionConfig,
+    out: &mut Vec<u8>,
+    ctx: Context,
+) -> Result<u32> {
+    match ciphertext {
+        CiphertextMessage::SignalMessage(m) => {
+            decrypt_signal_with_config(
+                m,
+                remote_address,
+                session_store,
+                identity_store,
+                csprng,
+                config,
+                out,
+                ctx,
+            )
+            .await
+        }
+        CiphertextMessage::PreKeySignalMessage(m) => {
+            decrypt_prekey_with_config(
+                m,
+                remote_address,
+                session_store,
+                identity_store,
+                pre_key_store,
+                signed_pre_key_store,
+                csprng,
+                config,
+                out,
+                ctx,
+            )
+            .await
+        }
+        _ => Err(SignalProtocolError::InvalidArgument(
+            "SessionCipher::decrypt cannot decrypt this message type".to_owned(),
+        )),
+    }
+}
+
+pub async fn message_decrypt_prekey<R: Rng + CryptoRng>(
+    ciphertext: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    let mut ptext = Vec::new();
+    decrypt_prekey_with_config(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        csprng,
+        &DecryptionConfig::default(),
+        &mut ptext,
+        ctx,
+    )
+    .await?;
+    Ok(ptext)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn decrypt_prekey_with_config<R: Rng + CryptoRng>(
+    ciphertext: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    csprng: &mut R,
+    config: &DecryptionConfig,
+    out: &mut Vec<u8>,
+    ctx: Context,
+) -> Result<u32> {
+    check_accepted_version(config, ciphertext.message_version())?;
+
+    for _ in 0..MAX_SESSION_STORE_ATTEMPTS {
+        let mut session_record = load_session_with_retry(
+            remote_address,
+            session_store,
+            config.store_retry.as_ref(),
+            ctx,
+        )
+        .await?
+        .unwrap_or_else(SessionRecord::new_fresh);
+
+        let replaced_identity = match &config.on_session_reset {
+            Some(_) => replaced_session_identity(ciphertext, &session_record)?,
+            None => None,
+        };
+
+        let (pre_key_id, first_message) = decrypt_prekey_with_record(
+            ciphertext,
+            remote_address,
+            &mut session_record,
+            identity_store,
+            pre_key_store,
+            signed_pre_key_store,
+            csprng,
+            config,
+            out,
+            ctx,
+        )
+        .await?;
+
+        // If a one-time pre-key was claimed, it must be released unless the new session is stored.
+        let stored = match store_session_checked(
+            remote_address,
+            &mut session_record,
+            session_store,
+            config.store_retry.as_ref(),
+            ctx,
+        )
+        .await
+        {
+            Ok(true) => true,
+            outcome => {
+                if let Some(pre_key_id) = pre_key_id {
+                    pre_key_store.release_pre_key(pre_key_id, ctx).await?;
+                }
+                outcome?;
+                false
+            }
+        };
+
+        if stored {
+            if let Some(pre_key_id) = pre_key_id {
+                pre_key_store.remove_pre_key(pre_key_id, ctx).await?;
+
+                if let Some(on_pre_key_consumed) = &config.on_pre_key_consumed {
+                    let remaining = pre_key_store.pre_key_count(ctx).await.unwrap_or_else(|e| {
+                        log::warn!("failed to count remaining pre-keys: {}", e);
+                        None
+                    });
+                    on_pre_key_consumed(remaining);
+                }
+            }
+            if first_message {
+                notify_first_message(config, remote_address);
+            }
+            if let (Some(on_session_reset), Some(old_identity)) =
+                (&config.on_session_reset, replaced_identity)
+            {
+                log::info!("session with {} was reset by the peer", remote_address);
+                on_session_reset(remote_address, old_identity, *ciphertext.identity_key());
+            }
+
+            return session_record.session_version();
+        }
+        log::info!(
+            "session for {} was modified concurrently; retrying",
+            remote_address
+        );
+    }
+
+    Err(SignalProtocolError::SessionConcurrentlyModified(
+        remote_address.clone(),
+    ))
+}
+
+pub async fn message_decrypt_signal<R: Rng + CryptoRng>(
+    ciphertext: &SignalMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    let mut ptext = Vec::new();
+    decrypt_signal_with_config(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        csprng,
+        &DecryptionConfig::default(),
+        &mut ptext,
+        ctx,
+    )
+    .await?;
+    Ok(ptext)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn decrypt_signal_with_config<R: Rng + CryptoRng>(
+    ciphertext: &SignalMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    csprng: &mut R,
+    config: &DecryptionConfig,
+    out: &mut Vec<u8>,
+    ctx: Context,
+) -> Result<u32> {
+    check_accepted_version(config, ciphertext.message_version())?;
+
+    for _ in 0..MAX_SESSION_STORE_ATTEMPTS {
+        let mut session_record = load_session_with_retry(
+            remote_address,
+            session_store,
+            config.store_retry.as_ref(),
+            ctx,
+        )
+        .await?
+        .ok_or_else(|| SignalProtocolError::SessionNotFound(format!("{}", remote_address)))?;
+
+        if config.recover_corrupt_sessions && is_missing_remote_identity(&session_record)? {
+            log::warn!(
+                "current session with {} has no remote identity; archiving it",
+                remote_address
+            );
+            session_record.archive_current_state()?;
+            if store_session_checked(
+                remote_address,
+                &mut session_record,
+                session_store,
+                config.store_retry.as_ref(),
+                ctx,
+            )
+            .await?
+            {
+                return Err(SignalProtocolError::SessionCorruptRecoverable(
+                    remote_address.clone(),
+                ));
+            }
+            log::info!(
+                "session for {} was modified concurrently; retrying",
+                remote_address
+            );
+            continue;
+        }
+
+        let first_message = match decrypt_signal_with_record(
+            ciphertext,
+            remote_address,
+            &mut session_record,
+            identity_store,
+            csprng,
+            config,
+            out,
+            ctx,
+        )
+        .await
+        {
+            Err(SignalProtocolError::InvalidMessage(DECRYPTION_FAILED))
+                if config.heal_on_failure && session_record.has_current_session_state() =>
+            {
+                log::warn!(
+                    "no session state with {} could decrypt the message; archiving the current one",
+                    remote_address
+                );
+                session_record.archive_current_state()?;
+                if store_session_checked(
+                    remote_address,
+                    &mut session_record,
+                    session_store,
+                    config.store_retry.as_ref(),
+                    ctx,
+                )
+                .await?
+                {
+                    return Err(SignalProtocolError::DecryptFailedSessionArchived(
+                        remote_address.clone(),
+                    ));
+                }
+                log::info!(
+                    "session for {} was modified concurrently; retrying",
+                    remote_address
+                );
+                continue;
+            }
+            result => result?,
+        };
+
+        if store_session_checked(
+            remote_address,
+            &mut session_record,
+            session_store,
+            config.store_retry.as_ref(),
+            ctx,
+        )
+        .await?
+        {
+            if first_message {
+                notify_first_message(config, remote_address);
+            }
+            return session_record.session_version();
+        }
+        log::info!(
+            "session for {} was modified concurrently; retrying",
+            remote_address
+        );
+    }
+
+    Err(SignalProtocolError::SessionConcurrentlyModified(
+        remote_address.clone(),
+    ))
+}
+
+/// Decrypts several [`SignalMessage`]s from `remote_address`, loading and storing the session only
+/// once.
+///
+/// The messages may come from several of the sender's ratchet chains, in any order. They are
+/// grouped by [sender ratchet key](SignalMessage::sender_ratchet_key): chains the current session
+/// already knows are processed first, then the others in the order in which they first appear in
+/// `ciphertexts`, so that DH ratchet steps are taken in the order the sender took them. Within a
+/// chain, messages are processed in counter order.
+///
+/// Returns one result per message, in the order of `ciphertexts`. A message that fails to decrypt
+/// leaves the session as it was before that message. Fails as a whole only if the session cannot
+/// be loaded or stored, or has no state recording the sender's identity.
+///
+/// The sender's identity is checked and saved once, after every message has been processed. If it
+/// is not trusted, the session is not stored and every message that did decrypt fails with
+/// [`SignalProtocolError::UntrustedIdentity`] instead, so the whole batch can be retried once the
+/// identity has been accepted.
+pub async fn message_decrypt_batch<R: Rng + CryptoRng>(
+    ciphertexts: &[SignalMessage],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<Vec<Result<Vec<u8>>>> {
+    let config = DecryptionConfig::default();
+
+    for _ in 0..MAX_SESSION_STORE_ATTEMPTS {
+        let mut session_record = session_store
+            .load_session(remote_address, ctx)
+            .await?
+            .ok_or_else(|| SignalProtocolError::SessionNotFound(format!("{}", remote_address)))?;
+
+        let (local_identity_key, their_identity_key) = record_identity_keys(&session_record)?;
+
+        let mut results = Vec::with_capacity(ciphertexts.len());
+        results.resize_with(ciphertexts.len(), || None);
+        let mut any_decrypted = false;
+        let mut first_message = false;
+
+        for index in batch_order(ciphertexts, &session_record)? {
+            // A failed message leaves the record untouched, so nothing needs to be rolled back.
+            let mut ptext = Vec::new();
+            let result = decrypt_message_with_identities(
+                remote_address,
+                &mut session_record,
+                &ciphertexts[index],
+                &local_identity_key,
+                &their_identity_key,
+                csprng,
+                &config,
+                &mut ptext,
+            );
+            results[index] = Some(match result {
+                Ok(first) => {
+                    session_record
+                        .session_state_mut()?
+                        .set_last_used_at(current_time_millis())?;
+                    any_decrypted = true;
+                    first_message |= first;
+                    Ok(ptext)
+                }
+                Err(e) => Err(e),
+            });
+        }
+        if !any_decrypted {
+            return Ok(results
+                .into_iter()
+                .map(|result| result.expect("every message is processed"))
+                .collect());
+        }
+
+        if !is_trusted_for_receiving(
+            remote_address,
+            &their_identity_key,
+            identity_store,
+            config.on_untrusted_identity.as_ref(),
+            ctx,
+        )
+        .await?
+        {
+            return Ok(results
+                .into_iter()
+                .map(|result| match result.expect("every message is processed") {
+                    Ok(_) => Err(SignalProtocolError::UntrustedIdentity(
+                        remote_address.clone(),
+                    )),
+                    Err(e) => Err(e),
+                })
+                .collect());
+        }
+        identity_store
+            .save_identity(remote_address, &their_identity_key, ctx)
+            .await?;
+
+        if store_session_checked(
+            remote_address,
+            &mut session_record,
+            session_store,
+            None,
+            ctx,
+        )
+        .await?
+        {
+            if first_message {
+                notify_first_message(&config, remote_address);
+            }
+            return Ok(results
+                .into_iter()
+                .map(|result| result.expect("every message is processed"))
+                .collect());
+        }
+        log::info!(
+            "session for {} was modified concurrently; retrying",
+            remote_address
+        );
+    }
+
+    Err(SignalProtocolError::SessionConcurrentlyModified(
+        remote_address.clone(),
+    ))
+}
+
+/// The order in which [`message_decrypt_batch`] processes `ciphertexts`, as indexes into it.
+fn batch_order(ciphertexts: &[SignalMessage], record: &SessionRecord) -> Result<Vec<usize>> {
+    let mut chains: Vec<(&PublicKey, Vec<usize>)> = Vec::new();
+    for (index, ciphertext) in ciphertexts.iter().enumerate() {
+        let sender = ciphertext.sender_ratchet_key();
+        match chains.iter_mut().find(|(key, _)| *key == sender) {
+            Some((_, indexes)) => indexes.push(index),
+            None => chains.push((sender, vec![index])),
+        }
+    }
+
+    let mut known = Vec::with_capacity(chains.len());
+    for (sender, _) in &chains {
+        known.push(record.knows_ratchet_key(sender)?);
+    }
+    let (known_chains, new_chains): (Vec<_>, Vec<_>) =
+        chains.into_iter().zip(known).partition(|(_, known)| *known);
+
+    let mut order = Vec::with_capacity(ciphertexts.len());
+    for ((_, mut indexes), _) in known_chains.into_iter().chain(new_chains) {
+        indexes.sort_by_key(|&index| ciphertexts[index].counter());
+        order.extend(indexes);
+    }
+    Ok(order)
+}
+
+/// Fails with [`SignalProtocolError::UnrecognizedMessageVersion`] if `message_version` is not
+/// allowed by [`DecryptionConfig::accepted_versions`].
+fn check_accepted_version(config: &DecryptionConfig, message_version: u8) -> Result<()> {
+    match &config.accepted_versions {
+        Some(accepted) if !accepted.contains(&(message_version as u32)) => Err(
+            SignalProtocolError::UnrecognizedMessageVersion(message_version as u32),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Fails with [`SignalProtocolError::PinnedIdentityMismatch`] if `their_identity_key` is not
+/// [`DecryptionConfig::pinned_identity`].
+fn check_pinned_identity(
+    config: &DecryptionConfig,
+    remote_address: &ProtocolAddress,
+    their_identity_key: &IdentityKey,
+) -> Result<()> {
+    match &config.pinned_identity {
+        Some(pinned) if pinned != their_identity_key => {
+            log::warn!(
+                "Identity key for {} does not match the pinned identity",
+                remote_address
+            );
+            Err(SignalProtocolError::PinnedIdentityMismatch(
+                remote_address.clone(),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// If `ciphertext` will set up a new session that replaces the current one in `session_record`,
+/// returns the remote identity recorded by the current one.
+fn replaced_session_identity(
+    ciphertext: &PreKeySignalMessage,
+    session_record: &SessionRecord,
+) -> Result<Option<Option<IdentityKey>>> {
+    if !session_record.has_current_session_state()
+        || session_record.has_session_state(
+            ciphertext.message_version() as u32,
+            &ciphertext.base_key().serialize(),
+        )?
+    {
+        return Ok(None);
+    }
+    Ok(Some(session_record.session_state()?.remote_identity_key()?))
+}
+
+fn notify_first_message(config: &DecryptionConfig, remote_address: &ProtocolAddress) {
+    log::info!("session with {} is now established", remote_address);
+    if let Some(on_first_message) = &config.on_first_message {
+        on_first_message(remote_address);
+    }
+}
+
+/// Decrypts a [`SignalMessage`] with `session_record`, without loading or storing the record.
+///
+/// The MAC is checked against the identity keys recorded in the session (see
+/// [`record_identity_keys`]); the remote one must then be trusted by `identity_store`, which saves
+/// it.
+///
+/// Returns whether this is the first message in the session; see
+/// [`DecryptionConfig::on_first_message`].
+#[allow(clippy::too_many_arguments)]
+async fn decrypt_signal_with_record<R: Rng + CryptoRng>(
+    ciphertext: &SignalMessage,
+    remote_address: &ProtocolAddress,
+    session_record: &mut SessionRecord,
+    identity_store: &mut dyn IdentityKeyStore,
+    csprng: &mut R,
+    config: &DecryptionConfig,
+    out: &mut Vec<u8>,
+    ctx: Context,
+) -> Result<bool> {
+    let (local_identity_key, their_identity_key) = record_identity_keys(session_record)?;
+    let first_message = decrypt_message_with_identities(
+        remote_address,
+        session_record,
+        ciphertext,
+        &local_identity_key,
+        &their_identity_key,
+        csprng,
+        config,
+        out,
+    )?;
+    session_record
+        .session_state_mut()?
+        .set_last_used_at(current_time_millis())?;
+
+    // Why are we performing this check after decryption instead of before?
+    check_pinned_identity(config, remote_address, &their_identity_key)?;
+
+    if !is_trusted_for_receiving(
+        remote_address,
+        &their_identity_key,
+        identity_store,
+        config.on_untrusted_identity.as_ref(),
+        ctx,
+    )
+    .await?
+    {
+        return Err(SignalProtocolError::UntrustedIdentity(
+            remote_address.clone(),
+        ));
+    }
+
+    identity_store
+        .save_identity(remote_address, &their_identity_key, ctx)
+        .await?;
+
+    Ok(first_message)
+}
+
+/// Processes and decrypts a [`PreKeySignalMessage`] with `session_record`, without loading or
+/// storing the record.
+///
+/// If a one-time pre-key was used, its ID is returned and it remains
+/// [claimed](PreKeyStore::claim_pre_key); the caller must remove or release it. Also returns
+/// whether this is the first message in the session; see [`DecryptionConfig::on_first_message`].
+#[allow(clippy::too_many_arguments)]
+async fn decrypt_prekey_with_record<R: Rng + CryptoRng>(
+    ciphertext: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    session_record: &mut SessionRecord,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    csprng: &mut R,
+    config: &DecryptionConfig,
+    out: &mut Vec<u8>,
+    ctx: Context,
+) -> Result<(Option<PreKeyId>, bool)> {
+    check_pinned_identity(config, remote_address, ciphertext.identity_key())?;
+
+    if config.reject_redundant_prekey && is_redundant_prekey(ciphertext, session_record)? {
+        return Err(SignalProtocolError::UnexpectedPreKeyMessage(
+            remote_address.clone(),
+        ));
+    }
+
+    let new_session = !session_record.has_session_state(
+        ciphertext.message_version() as u32,
+        &ciphertext.base_key().serialize(),
+    )?;
+
+    // Make sure we log the session state if we fail to process the pre-key.
+    let pre_key_id_or_err = session::process_prekey_claiming(
+        ciphertext,
+        remote_address,
+        session_record,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        &config.protocol,
+        ctx,
+    )
+    .await;
+
+    let pre_key_id = match pre_key_id_or_err {
+        Ok(id) => id,
+        Err(e) => {
+            if let SignalProtocolError::UntrustedIdentity(_) = e {
+                report_untrusted_identity(
+                    config.on_untrusted_identity.as_ref(),
+                    remote_address,
+                    ciphertext.identity_key(),
+                    identity_store,
+                    ctx,
+                )
+                .await;
+            }
+            let errs = [e];
+            log::error!(
+                "{}",
+                create_decryption_failure_log(
+                    remote_address,
+                    &errs,
+                    session_record,
+                    ciphertext.message()
+                )?
+            );
+            let [e] = errs;
+            return Err(e);
+        }
+    };
+
+    let decrypted = decrypt_message_with_record(
+        remote_address,
+        session_record,
+        ciphertext.message(),
+        csprng,
+        config,
+        out,
+    )
+    .and_then(|acknowledged| {
+        session_record
+            .session_state_mut()?
+            .set_last_used_at(current_time_millis())?;
+        Ok(acknowledged)
+    });
+
+    match decrypted {
+        Ok(acknowledged) => Ok((pre_key_id, new_session || acknowledged)),
+        Err(e) => {
+            if let Some(pre_key_id) = pre_key_id {
+                pre_key_store.release_pre_key(pre_key_id, ctx).await?;
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Decrypts `ciphertext` with `session_record`, leaving it to the caller to load and store the
+/// record.
+///
+/// On success, `session_record` has been updated and must be persisted before it is used again.
+/// If the message used one of our one-time pre-keys, its ID is returned: the pre-key has been
+/// [claimed](PreKeyStore::claim_pre_key) but not removed, so the caller should call
+/// [`PreKeyStore::remove_pre_key`] once the record is stored, or
+/// [`PreKeyStore::release_pre_key`] if it is not.
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt_deferred<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    session_record: &mut SessionRecord,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<(Vec<u8>, Option<PreKeyId>)> {
+    let config = DecryptionConfig::default();
+    let mut ptext = Vec::new();
+    match ciphertext {
+        CiphertextMessage::SignalMessage(m) => {
+            decrypt_signal_with_record(
+                m,
+                remote_address,
+                session_record,
+                identity_store,
+                csprng,
+                &config,
+                &mut ptext,
+                ctx,
+            )
+            .await?;
+            Ok((ptext, None))
+        }
+        CiphertextMessage::PreKeySignalMessage(m) => {
+            let (pre_key_id, _) = decrypt_prekey_with_record(
+                m,
+                remote_address,
+                session_record,
+                identity_store,
+                pre_key_store,
+                signed_pre_key_store,
+                csprng,
+                &config,
+                &mut ptext,
+                ctx,
+            )
+            .await?;
+            Ok((ptext, pre_key_id))
+        }
+        _ => Err(SignalProtocolError::InvalidArgument(
+            "SessionCipher::decrypt cannot decrypt this message type".to_owned(),
+        )),
+    }
+}
+
+/// Decrypts `ciphertext` from `from_address` and encrypts its plaintext for `to_address`, e.g. to
+/// relay a message to another device of the same account.
+///
+/// The plaintext is not returned, and is overwritten before this returns. The usual trust checks
+/// apply to both sides: the sender's identity must be trusted for receiving and the recipient's
+/// for sending. Both sessions are stored only if decrypting and encrypting succeed, so a failure
+/// leaves neither session advanced and the message can be resealed again.
+///
+/// The two sessions are stored one after the other. If storing the second one fails, the first is
+/// restored to the state it was loaded in; should that fail as well, its error is returned and the
+/// message has been consumed without being forwarded. Fails with
+/// [`SignalProtocolError::InvalidArgument`] if both addresses are the same.
+#[allow(clippy::too_many_arguments)]
+pub async fn reseal<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    from_address: &ProtocolAddress,
+    to_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
     csprng: &mut R,
     ctx: Context,
-) -> Result<Vec<u8>> {
-    let mut session_record = session_store
-        .load_session(remote_address, ctx)
-        .await?
-        .ok_or_else(|| SignalProtocolError::SessionNotFound(format!("{}", remote_address)))?;
+) -> Result<CiphertextMessage> {
+    if from_address == to_address {
+        return Err(SignalProtocolError::InvalidArgument(
+            "cannot reseal a message for its own sender".to_owned(),
+        ));
+    }
+
+    for _ in 0..MAX_SESSION_STORE_ATTEMPTS {
+        let original_from_record = match session_store.load_session(from_address, ctx).await? {
+            Some(record) => record,
+            None if matches!(ciphertext, CiphertextMessage::PreKeySignalMessage(_)) => {
+                SessionRecord::new_fresh()
+            }
+            None => {
+                return Err(SignalProtocolError::SessionNotFound(
+                    from_address.to_string(),
+                ))
+            }
+        };
+        let mut to_record = session_store
+            .load_session(to_address, ctx)
+            .await?
+            .ok_or_else(|| SignalProtocolError::SessionNotFound(to_address.to_string()))?;
+
+        let mut from_record = original_from_record.clone();
+        let (mut ptext, pre_key_id) = message_decrypt_deferred(
+            ciphertext,
+            from_address,
+            identity_store,
+            pre_key_store,
+            signed_pre_key_store,
+            &mut from_record,
+            csprng,
+            ctx,
+        )
+        .await?;
+        let encrypted = encrypt_with_record(
+            &ptext,
+            to_address,
+            &mut to_record,
+            identity_store,
+            &EncryptionConfig::default(),
+            ctx,
+        )
+        .await;
+        ptext.iter_mut().for_each(|b| *b = 0);
+
+        let outcome = match encrypted {
+            Ok(message) => store_resealed_sessions(
+                from_address,
+                &mut from_record,
+                original_from_record,
+                to_address,
+                &mut to_record,
+                session_store,
+                ctx,
+            )
+            .await
+            .map(|stored| if stored { Some(message) } else { None }),
+            Err(e) => Err(e),
+        };
+
+        // If a one-time pre-key was claimed, it must be released unless both sessions are stored.
+        match outcome {
+            Ok(Some(message)) => {
+                if let Some(pre_key_id) = pre_key_id {
+                    pre_key_store.remove_pre_key(pre_key_id, ctx).await?;
+                }
+                return Ok(message);
+            }
+            outcome => {
+                if let Some(pre_key_id) = pre_key_id {
+                    pre_key_store.release_pre_key(pre_key_id, ctx).await?;
+                }
+                outcome?;
+            }
+        }
+        log::info!(
+            "sessions for {} and {} were modified concurrently; retrying",
+            from_address,
+            to_address
+        );
+    }
+
+    Err(SignalProtocolError::SessionConcurrentlyModified(
+        from_address.clone(),
+    ))
+}
+
+/// Stores the sessions updated by [`reseal`], returning `false` if either was modified
+/// concurrently.
+///
+/// If the first session was stored but the second could not be, the first is put back to
+/// `original_from_record`.
+async fn store_resealed_sessions(
+    from_address: &ProtocolAddress,
+    from_record: &mut SessionRecord,
+    original_from_record: SessionRecord,
+    to_address: &ProtocolAddress,
+    to_record: &mut SessionRecord,
+    session_store: &mut dyn SessionStore,
+    ctx: Context,
+) -> Result<bool> {
+    if !store_session_checked(from_address, from_record, session_store, None, ctx).await? {
+        return Ok(false);
+    }
+
+    let stored = store_session_checked(to_address, to_record, session_store, None, ctx).await;
+    if let Ok(true) = stored {
+        return Ok(true);
+    }
+
+    // The restored record must replace the revision that was just stored.
+    let mut restored = original_from_record;
+    restored.increment_record_version();
+    if !store_session_checked(from_address, &mut restored, session_store, None, ctx).await? {
+        log::error!(
+            "session for {} was modified while resealing a message for {}; it cannot be restored",
+            from_address,
+            to_address
+        );
+        return Err(SignalProtocolError::SessionConcurrentlyModified(
+            from_address.clone(),
+        ));
+    }
+    stored
+}
+
+/// Marks the current session with `remote_address` as used now, without encrypting or decrypting
+/// anything.
+///
+/// See [`SessionRecord::last_used_at`]. Fails with [`SignalProtocolError::SessionNotFound`] if
+/// there is no current session.
+pub async fn touch_session(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    ctx: Context,
+) -> Result<()> {
+    for _ in 0..MAX_SESSION_STORE_ATTEMPTS {
+        let mut session_record = session_store
+            .load_session(remote_address, ctx)
+            .await?
+            .filter(SessionRecord::has_current_session_state)
+            .ok_or_else(|| SignalProtocolError::SessionNotFound(format!("{}", remote_address)))?;
+        session_record
+            .session_state_mut()?
+            .set_last_used_at(current_time_millis())?;
+
+        if store_session_checked(
+            remote_address,
+            &mut session_record,
+            session_store,
+            None,
+            ctx,
+        )
+        .await?
+        {
+            return Ok(());
+        }
+    }
+
+    Err(SignalProtocolError::SessionConcurrentlyModified(
+        remote_address.clone(),
+    ))
+}
+
+/// Removes every stored message key in the sessions with `remote_address`, returning how many were
+/// removed.
+///
+/// This is meant for responding to a compromise: messages that were skipped or are still in
+/// flight can no longer be decrypted, but the session keeps working for new messages. See
+/// [`SessionRecord::purge_stored_message_keys`]. Fails with
+/// [`SignalProtocolError::SessionNotFound`] if there is no session record.
+pub async fn purge_message_keys(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    ctx: Context,
+) -> Result<usize> {
+    for _ in 0..MAX_SESSION_STORE_ATTEMPTS {
+        let mut session_record = session_store
+            .load_session(remote_address, ctx)
+            .await?
+            .ok_or_else(|| SignalProtocolError::SessionNotFound(format!("{}", remote_address)))?;
+        let purged = session_record.purge_stored_message_keys()?;
+        if purged == 0 {
+            return Ok(0);
+        }
+
+        if store_session_checked(
+            remote_address,
+            &mut session_record,
+            session_store,
+            None,
+            ctx,
+        )
+        .await?
+        {
+            log::info!(
+                "Purged {} stored message key(s) for {}",
+                purged,
+                remote_address
+            );
+            return Ok(purged);
+        }
+    }
+
+    Err(SignalProtocolError::SessionConcurrentlyModified(
+        remote_address.clone(),
+    ))
+}
+
+/// Whether `their_identity_key` is trusted for receiving from `remote_address`.
+///
+/// An untrusted key is logged and passed to `on_untrusted_identity`.
+async fn is_trusted_for_receiving(
+    remote_address: &ProtocolAddress,
+    their_identity_key: &IdentityKey,
+    identity_store: &dyn IdentityKeyStore,
+    on_untrusted_identity: Option<&UntrustedIdentityCallback>,
+    ctx: Context,
+) -> Result<bool> {
+    if identity_store
+        .is_trusted_identity(
+            remote_address,
+            their_identity_key,
+            Direction::Receiving,
+            ctx,
+        )
+        .await?
+    {
+        return Ok(true);
+    }
+    log::warn!(
+        "Identity key {} is not trusted for remote address {}",
+        their_identity_key
+            .public_key()
+            .public_key_bytes()
+            .map_or_else(|e| format!("<error: {}>", e), hex::encode),
+        remote_address,
+    );
+    report_untrusted_identity(
+        on_untrusted_identity,
+        remote_address,
+        their_identity_key,
+        identity_store,
+        ctx,
+    )
+    .await;
+    Ok(false)
+}
+
+/// Passes the identity key stored for `remote_address` and the rejected `presented_key` to
+/// `callback`, if there is one.
+///
+/// A failure to load the stored key is logged and reported as `None`, so that it does not replace
+/// the [`SignalProtocolError::UntrustedIdentity`] error the caller is about to return.
+async fn report_untrusted_identity(
+    callback: Option<&UntrustedIdentityCallback>,
+    remote_address: &ProtocolAddress,
+    presented_key: &IdentityKey,
+    identity_store: &dyn IdentityKeyStore,
+    ctx: Context,
+) {
+    if let Some(callback) = callback {
+        let stored_key = identity_store
+            .get_identity(remote_address, ctx)
+            .await
+            .unwrap_or_else(|e| {
+                log::warn!("failed to load identity key for {}: {}", remote_address, e);
+                None
+            });
+        callback(remote_address, stored_key, *presented_key);
+    }
+}
+
+/// Whether `ciphertext` would replace the current session in `session_record` even though the
+/// peer's identity is unchanged; see [`DecryptionConfig::reject_redundant_prekey`].
+fn is_redundant_prekey(
+    ciphertext: &PreKeySignalMessage,
+    session_record: &SessionRecord,
+) -> Result<bool> {
+    if !session_record.has_current_session_state() {
+        return Ok(false);
+    }
+    let same_identity =
+        session_record.session_state()?.remote_identity_key()? == Some(*ciphertext.identity_key());
+    Ok(same_identity
+        && !session_record.has_session_state(
+            ciphertext.message_version() as u32,
+            &ciphertext.base_key().serialize(),
+        )?)
+}
+
+fn is_missing_remote_identity(session_record: &SessionRecord) -> Result<bool> {
+    Ok(session_record.has_current_session_state()
+        && session_record
+            .session_state()?
+            .remote_identity_key()?
+            .is_none())
+}
+
+/// Decides whether a store operation that failed with `err` on its `attempt`th try (starting at 1)
+/// should be tried again.
+fn should_retry_store_error(
+    retry: Option<&StoreRetry>,
+    attempt: u32,
+    err: &SignalProtocolError,
+) -> bool {
+    retry.map_or(false, |retry| {
+        matches!(err, SignalProtocolError::StoreTransient(_)) && attempt < retry.max_attempts
+    })
+}
+
+/// Waits out the backoff after the `attempt`th try (starting at 1), if the policy can wait.
+async fn wait_before_store_retry(
+    retry: Option<&StoreRetry>,
+    attempt: u32,
+    err: &SignalProtocolError,
+) {
+    let retry = match retry {
+        Some(retry) => retry,
+        None => return,
+    };
+    let sleep = match &retry.sleep {
+        Some(sleep) => sleep,
+        None => {
+            log::warn!("{}; retrying", err);
+            return;
+        }
+    };
+
+    let delay = retry
+        .backoff
+        .checked_mul(2u32.saturating_pow(attempt - 1))
+        .map_or(MAX_STORE_RETRY_BACKOFF, |delay| {
+            delay.min(MAX_STORE_RETRY_BACKOFF)
+        });
+    log::warn!("{}; retrying in {:?}", err, delay);
+    sleep(delay).await;
+}
+
+async fn load_session_with_retry(
+    remote_address: &ProtocolAddress,
+    session_store: &dyn SessionStore,
+    retry: Option<&StoreRetry>,
+    ctx: Context,
+) -> Result<Option<SessionRecord>> {
+    let mut attempt = 1;
+    loop {
+        match session_store.load_session(remote_address, ctx).await {
+            Err(e) if should_retry_store_error(retry, attempt, &e) => {
+                wait_before_store_retry(retry, attempt, &e).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Persists `record`, unless the stored copy has been modified since `record` was loaded.
+///
+/// Returns `false` if the caller should reload the session and try again.
+async fn store_session_checked(
+    remote_address: &ProtocolAddress,
+    record: &mut SessionRecord,
+    session_store: &mut dyn SessionStore,
+    retry: Option<&StoreRetry>,
+    ctx: Context,
+) -> Result<bool> {
+    let expected_version = record.record_version();
+    record.increment_record_version();
+
+    let mut attempt = 1;
+    loop {
+        match session_store
+            .store_session_if_version(remote_address, record, expected_version, ctx)
+            .await
+        {
+            Err(e) if should_retry_store_error(retry, attempt, &e) => {
+                wait_before_store_retry(retry, attempt, &e).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns the identity key of `remote_address` that the current session with them is pinned to.
+///
+/// Returns `None` if there is no current session, or if the session does not (yet) record a
+/// remote identity.
+pub async fn remote_identity_key(
+    remote_address: &ProtocolAddress,
+    session_store: &dyn SessionStore,
+    ctx: Context,
+) -> Result<Option<IdentityKey>> {
+    match session_store.load_session(remote_address, ctx).await? {
+        Some(session_record) if session_record.has_current_session_state() => {
+            session_record.session_state()?.remote_identity_key()
+        }
+        _ => Ok(None),
+    }
+}
 
-    let ptext =
-        decrypt_message_with_record(remote_address, &mut session_record, ciphertext, csprng)?;
+/// Returns our identity key as recorded in the current session with `remote_address`, which is
+/// the key outgoing messages on that session are authenticated with.
+///
+/// After rotating our identity, this can differ from the key in the identity store until the
+/// session is replaced. Fails with [`SignalProtocolError::SessionNotFound`] if there is no current
+/// session.
+pub async fn local_identity_key(
+    remote_address: &ProtocolAddress,
+    session_store: &dyn SessionStore,
+    ctx: Context,
+) -> Result<IdentityKey> {
+    match session_store.load_session(remote_address, ctx).await? {
+        Some(session_record) if session_record.has_current_session_state() => {
+            session_record.session_state()?.local_identity_key()
+        }
+        _ => Err(SignalProtocolError::SessionNotFound(
+            remote_address.to_string(),
+        )),
+    }
+}
 
-    // Why are we performing this check after decryption instead of before?
-    let their_identity_key = session_record
-        .session_state()?
-        .remote_identity_key()?
-        .ok_or(SignalProtocolError::InvalidSessionStructure)?;
+/// Accepts `new_identity` as the identity of `remote_address`, e.g. after the peer re-registered.
+///
+/// If the current session with `remote_address` was set up with a different identity, it is
+/// archived rather than deleted, so its state (including any stored message keys) stays in the
+/// record. The new identity is saved in the identity store only once the session has been stored,
+/// so if this fails, neither has changed. Returns whether a session was archived.
+///
+/// This does not establish a new session: that still requires processing a pre-key bundle for the
+/// new identity, or receiving a pre-key message from the peer.
+pub async fn accept_identity_change(
+    remote_address: &ProtocolAddress,
+    new_identity: &IdentityKey,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    ctx: Context,
+) -> Result<bool> {
+    let archived =
+        archive_session_with_other_identity(remote_address, new_identity, session_store, ctx)
+            .await?;
+    identity_store
+        .save_identity(remote_address, new_identity, ctx)
+        .await?;
+    Ok(archived)
+}
 
-    if !identity_store
-        .is_trusted_identity(
+/// Archives the current session with `remote_address` if it is pinned to an identity other than
+/// `identity`, returning whether it was archived.
+async fn archive_session_with_other_identity(
+    remote_address: &ProtocolAddress,
+    identity: &IdentityKey,
+    session_store: &mut dyn SessionStore,
+    ctx: Context,
+) -> Result<bool> {
+    for _ in 0..MAX_SESSION_STORE_ATTEMPTS {
+        let mut session_record = match session_store.load_session(remote_address, ctx).await? {
+            Some(session_record) if session_record.has_current_session_state() => session_record,
+            _ => return Ok(false),
+        };
+        if session_record
+            .session_state()?
+            .remote_identity_key()?
+            .as_ref()
+            == Some(identity)
+        {
+            return Ok(false);
+        }
+
+        session_record.archive_current_state()?;
+        if store_session_checked(
             remote_address,
-            &their_identity_key,
-            Direction::Receiving,
+            &mut session_record,
+            session_store,
+            None,
             ctx,
         )
         .await?
-    {
-        log::warn!(
-            "Identity key {} is not trusted for remote address {}",
-            their_identity_key
-                .public_key()
-                .public_key_bytes()
-                .map_or_else(|e| format!("<error: {}>", e), hex::encode),
-            remote_address,
-        );
-        return Err(SignalProtocolError::UntrustedIdentity(
-            remote_address.clone(),
-        ));
+        {
+            log::info!(
+                "archived session with {} after accepting a new identity",
+                remote_address
+            );
+            return Ok(true);
+        }
     }
 
+    Err(SignalProtocolError::SessionConcurrentlyModified(
+        remote_address.clone(),
+    ))
+}
+
+/// Returns whether the current sessions with `address_a` and `address_b` are pinned to the same
+/// remote identity key, e.g. because they are two devices of the same account.
+///
+/// Returns `false` if either address has no current session or its session does not record a
+/// remote identity.
+pub async fn same_identity(
+    address_a: &ProtocolAddress,
+    address_b: &ProtocolAddress,
+    session_store: &dyn SessionStore,
+    ctx: Context,
+) -> Result<bool> {
+    let identity_a = remote_identity_key(address_a, session_store, ctx).await?;
+    let identity_b = remote_identity_key(address_b, session_store, ctx).await?;
+    Ok(identity_a.is_some() && identity_a == identity_b)
+}
+
+/// Returns whether the current session with `remote_address` is pinned to `expected`, e.g. an
+/// identity key just fetched from the server.
+///
+/// Returns `false` if there is no current session or it does not record a remote identity, so a
+/// `false` result should always block sending until the identity has been re-verified. The
+/// session is not modified.
+pub async fn verify_session_identity(
+    remote_address: &ProtocolAddress,
+    expected: &IdentityKey,
+    session_store: &dyn SessionStore,
+    ctx: Context,
+) -> Result<bool> {
+    Ok(remote_identity_key(remote_address, session_store, ctx).await? == Some(*expected))
+}
+
+/// Returns whether `identity_store` trusts the identity the current session with
+/// `remote_address` is pinned to, for messages in `direction`.
+///
+/// This is the check [`message_encrypt`] (for [`Direction::Sending`]) and [`message_decrypt`]
+/// (for [`Direction::Receiving`]) perform, without encrypting or decrypting anything, so callers
+/// can ask the user to verify a changed identity before attempting to send. Nothing is modified,
+/// and [`EncryptionConfig::on_untrusted_identity`] is not called.
+///
+/// Fails with [`SignalProtocolError::SessionNotFound`] if there is no current session, and with
+/// [`SignalProtocolError::InvalidSessionStructure`] if it does not record a remote identity.
+pub async fn check_trust(
+    remote_address: &ProtocolAddress,
+    direction: Direction,
+    session_store: &dyn SessionStore,
+    identity_store: &dyn IdentityKeyStore,
+    ctx: Context,
+) -> Result<bool> {
+    let their_identity_key = match session_store.load_session(remote_address, ctx).await? {
+        Some(session_record) if session_record.has_current_session_state() => session_record
+            .session_state()?
+            .remote_identity_key()?
+            .ok_or(SignalProtocolError::InvalidSessionStructure)?,
+        _ => {
+            return Err(SignalProtocolError::SessionNotFound(
+                remote_address.to_string(),
+            ))
+        }
+    };
     identity_store
-        .save_identity(remote_address, &their_identity_key, ctx)
-        .await?;
+        .is_trusted_identity(remote_address, &their_identity_key, direction, ctx)
+        .await
+}
 
-    session_store
-        .store_session(remote_address, &session_record, ctx)
-        .await?;
+/// The result of checking a single session with [`audit_sessions`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionHealth {
+    /// The session can be used to send and receive messages.
+    Healthy,
+    /// There is no current session, or it has no sender chain, so no messages can be sent.
+    NoSenderChain,
+    /// The current session does not record the remote identity key.
+    MissingIdentity,
+    /// The session record could not be loaded or its current session is malformed.
+    Corrupt,
+}
 
-    Ok(ptext)
+/// Checks every session in `session_store` and reports the health of each one.
+///
+/// This requires the store to implement [`SessionStore::all_addresses`]. A record that fails to
+/// load is reported as [`SessionHealth::Corrupt`] rather than failing the whole audit.
+pub async fn audit_sessions(
+    session_store: &dyn SessionStore,
+    ctx: Context,
+) -> Result<Vec<(ProtocolAddress, SessionHealth)>> {
+    let mut report = Vec::new();
+    for address in session_store.all_addresses(ctx).await? {
+        let health = match session_store.load_session(&address, ctx).await {
+            Ok(Some(session_record)) => session_health(&session_record),
+            Ok(None) => continue,
+            Err(e) => {
+                log::warn!("failed to load session for {}: {}", address, e);
+                SessionHealth::Corrupt
+            }
+        };
+        report.push((address, health));
+    }
+    Ok(report)
 }
 
-fn create_decryption_failure_log(
+fn session_health(session_record: &SessionRecord) -> SessionHealth {
+    if !session_record.has_current_session_state() {
+        return SessionHealth::NoSenderChain;
+    }
+    let state = match session_record.session_state() {
+        Ok(state) => state,
+        Err(_) => return SessionHealth::Corrupt,
+    };
+    match state.has_sender_chain() {
+        Ok(true) => {}
+        Ok(false) => return SessionHealth::NoSenderChain,
+        Err(_) => return SessionHealth::Corrupt,
+    }
+    match state.remote_identity_key() {
+        Ok(Some(_)) => SessionHealth::Healthy,
+        Ok(None) => SessionHealth::MissingIdentity,
+        Err(_) => SessionHealth::Corrupt,
+    }
+}
+
+/// Whether a session can still be used without a new pre-key bundle, as reported by
+/// [`session_readiness`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionReadiness {
+    /// The current session has a sender chain and a supported version.
+    pub can_send: bool,
+    /// The current session has the root key and ratchet key needed to accept a new receiver chain.
+    pub can_receive: bool,
+    /// A new session has to be set up before messages can be exchanged in both directions.
+    pub needs_bundle: bool,
+}
+
+/// Checks whether the current session with `remote_address` can still send and receive messages.
+///
+/// This does not modify the session. A missing or malformed session is reported as unable to send
+/// or receive rather than as an error; only failures of the store itself are returned.
+pub async fn session_readiness(
     remote_address: &ProtocolAddress,
-    mut errs: &[SignalProtocolError],
-    record: &SessionRecord,
+    session_store: &dyn SessionStore,
+    ctx: Context,
+) -> Result<SessionReadiness> {
+    let (can_send, can_receive) = match session_store.load_session(remote_address, ctx).await? {
+        Some(session_record) if session_record.has_current_session_state() => {
+            match session_record.session_state() {
+                Ok(state) => {
+                    let can_send = state.has_sender_chain().unwrap_or(false)
+                        && state.get_sender_chain_key().is_ok()
+                        && state
+                            .session_version()
+                            .map_or(false, |v| SUPPORTED_SESSION_VERSIONS.contains(&v));
+                    let can_receive =
+                        state.root_key().is_ok() && state.sender_ratchet_private_key().is_ok();
+                    (can_send, can_receive)
+                }
+                Err(_) => (false, false),
+            }
+        }
+        _ => (false, false),
+    };
+    Ok(SessionReadiness {
+        can_send,
+        can_receive,
+        needs_bundle: !can_send || !can_receive,
+    })
+}
+
+/// Returns the session version shared by the current sessions with every device of `name`, or
+/// `None` if they differ or there are none.
+///
+/// Devices whose records have no current session are skipped. This requires the store to implement
+/// [`SessionStore::all_addresses`].
+pub async fn version_consensus(
+    name: &str,
+    session_store: &dyn SessionStore,
+    ctx: Context,
+) -> Result<Option<u32>> {
+    let mut consensus = None;
+    for address in session_store.all_addresses(ctx).await? {
+        if address.name() != name {
+            continue;
+        }
+        let session_record = match session_store.load_session(&address, ctx).await? {
+            Some(session_record) if session_record.has_current_session_state() => session_record,
+            _ => continue,
+        };
+        let version = session_record.session_version()?;
+        match consensus {
+            None => consensus = Some(version),
+            Some(v) if v == version => {}
+            Some(v) => {
+                log::info!(
+                    "sessions with {} disagree on version ({} vs. {} for device {})",
+                    name,
+                    v,
+                    version,
+                    address.device_id()
+                );
+                return Ok(None);
+            }
+        }
+    }
+    Ok(consensus)
+}
+
+/// Returns whether the current session with `remote_address` was already established with the
+/// identity in `bundle`, in which case there is no need to process the bundle.
+///
+/// The session must be pinned to the bundle's identity key. If the session has not yet been
+/// acknowledged by the other side, it must also have been built on the bundle's signed pre-key;
+/// otherwise our pending pre-key messages may refer to a signed pre-key the recipient no longer
+/// has. Once acknowledged, later signed pre-key rotations do not affect the session.
+///
+/// Neither the session nor the bundle is modified.
+pub async fn is_session_for_bundle(
+    remote_address: &ProtocolAddress,
+    bundle: &PreKeyBundle,
+    session_store: &dyn SessionStore,
+    ctx: Context,
+) -> Result<bool> {
+    let session_record = match session_store.load_session(remote_address, ctx).await? {
+        Some(session_record) if session_record.has_current_session_state() => session_record,
+        _ => return Ok(false),
+    };
+    let state = session_record.session_state()?;
+
+    if !state.has_sender_chain()?
+        || state.remote_identity_key()?.as_ref() != Some(bundle.identity_key()?)
+    {
+        return Ok(false);
+    }
+
+    match state.unacknowledged_pre_key_message_items()? {
+        Some(items) => Ok(items.signed_pre_key_id()? == bundle.signed_pre_key_id()?
+            && state
+                .get_receiver_chain_key(&bundle.signed_pre_key_public()?)?
+                .is_some()),
+        None => Ok(true),
+    }
+}
+
+/// The pre-keys a session we set up from a pre-key bundle was built on, as returned by
+/// [`pending_prekey_info`].
+///
+/// These are the values sent in every [`PreKeySignalMessage`] until the other side replies.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingPreKeyInfo {
+    /// The recipient's one-time pre-key, if the bundle included one.
+    pub pre_key_id: Option<PreKeyId>,
+    pub signed_pre_key_id: SignedPreKeyId,
+    /// Our base key for the session.
+    pub base_key: PublicKey,
+}
+
+/// Returns the pre-keys the current session with `remote_address` is waiting to have
+/// acknowledged, for diagnosing sessions that never complete their handshake.
+///
+/// Returns `None` if there is no current session, or if the other side has already replied on it
+/// (or it was set up by an incoming pre-key message). The session is not modified.
+pub async fn pending_prekey_info(
+    remote_address: &ProtocolAddress,
+    session_store: &dyn SessionStore,
+    ctx: Context,
+) -> Result<Option<PendingPreKeyInfo>> {
+    let session_record = match session_store.load_session(remote_address, ctx).await? {
+        Some(session_record) if session_record.has_current_session_state() => session_record,
+        _ => return Ok(None),
+    };
+    match session_record
+        .session_state()?
+        .unacknowledged_pre_key_message_items()?
+    {
+        Some(items) => Ok(Some(PendingPreKeyInfo {
+            pre_key_id: items.pre_key_id()?,
+            signed_pre_key_id: items.signed_pre_key_id()?,
+            base_key: *items.base_key()?,
+        })),
+        None => Ok(None),
+    }
+}
+
+/// Decrypts `ciphertext` with `record` without consulting any store, checking its MAC against
+/// `local_identity` and `remote_identity`.
+///
+/// The keys recorded in the session are not used. The MAC covers both identity keys, so a message
+/// sent between any other pair of identities fails to decrypt. No trust decision is made and
+/// nothing is saved: on success `record` is updated and must be persisted by the caller, and on
+/// failure it is left unchanged.
+///
+/// [`message_decrypt_signal`] decrypts through the same code once it has resolved the keys from the
+/// stored session.
+pub fn decrypt_message_with_keys<R: Rng + CryptoRng>(
     ciphertext: &SignalMessage,
-) -> Result<String> {
-    fn append_session_summary(
-        lines: &mut Vec<String>,
-        idx: usize,
-        state: Result<&SessionState>,
-        err: Option<&SignalProtocolError>,
-    ) {
-        let chains = state.and_then(|state| state.all_receiver_chain_logging_info());
-        match (err, &chains) {
-            (Some(err), Ok(chains)) => {
-                lines.push(format!(
-                    "Candidate session {} failed with '{}', had {} receiver chains",
-                    idx,
-                    err,
-                    chains.len()
+    remote_address: &ProtocolAddress,
+    record: &mut SessionRecord,
+    local_identity: &IdentityKey,
+    remote_identity: &IdentityKey,
+    csprng: &mut R,
+) -> Result<Vec<u8>> {
+    let mut ptext = Vec::new();
+    decrypt_message_with_identities(
+        remote_address,
+        record,
+        ciphertext,
+        local_identity,
+        remote_identity,
+        csprng,
+        &DecryptionConfig::default(),
+        &mut ptext,
+    )?;
+    record
+        .session_state_mut()?
+        .set_last_used_at(current_time_millis())?;
+    Ok(ptext)
+}
+
+/// Decrypts `ciphertext` using only the message keys already stored in `record`.
+///
+/// This never advances a ratchet or consumes a stored key, so the same message can be decrypted
+/// any number of times. If decrypting the message would require deriving new keys, this fails with
+/// [`SignalProtocolError::WouldMutate`].
+pub fn message_decrypt_readonly(
+    ciphertext: &SignalMessage,
+    record: &ReadOnlySessionRecord,
+) -> Result<Vec<u8>> {
+    let their_ephemeral = ciphertext.sender_ratchet_key();
+    let counter = ciphertext.counter();
+
+    for state in record.states() {
+        let state = state?;
+        if let Some(message_keys) = state.message_keys_for(their_ephemeral, counter)? {
+            let their_identity_key = state
+                .remote_identity_key()?
+                .ok_or(SignalProtocolError::InvalidSessionStructure)?;
+            let mut ptext = Vec::new();
+            verify_and_decrypt_body(
+                ciphertext,
+                &message_keys,
+                &state.local_identity_key()?,
+                &their_identity_key,
+                state.session_version()?,
+                &[],
+                &mut ptext,
+            )?;
+            return Ok(ptext);
+        }
+        if let Some(chain_key) = state.get_receiver_chain_key(their_ephemeral)? {
+            if chain_key.index() > counter {
+                return Err(SignalProtocolError::DuplicatedMessage(
+                    chain_key.index(),
+                    counter,
                 ));
             }
-            (Some(err), Err(state_err)) => {
-                lines.push(format!(
-                    "Candidate session {} failed with '{}'; cannot get receiver chain info ({})",
+        }
+    }
+
+    Err(SignalProtocolError::WouldMutate)
+}
+
+/// Decrypts `ciphertext` with the stored session for `remote_address` without modifying it, like
+/// [`message_decrypt_readonly`].
+///
+/// Only messages whose keys are stored can be decrypted this way, i.e. messages that were skipped
+/// over and have not arrived yet. Keys are deleted once their message is decrypted, so a message
+/// that was already decrypted fails with [`SignalProtocolError::DuplicatedMessage`]; a message
+/// that would advance a chain fails with [`SignalProtocolError::WouldMutate`]. Fails with
+/// [`SignalProtocolError::SessionNotFound`] if there is no stored session.
+pub async fn peek_decrypt(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &dyn SessionStore,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    let message = match ciphertext {
+        CiphertextMessage::SignalMessage(m) => m,
+        CiphertextMessage::PreKeySignalMessage(m) => m.message(),
+        _ => {
+            return Err(SignalProtocolError::InvalidArgument(
+                "peek_decrypt cannot decrypt this message type".to_owned(),
+            ))
+        }
+    };
+    let session_record = session_store
+        .load_session(remote_address, ctx)
+        .await?
+        .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.to_string()))?;
+    message_decrypt_readonly(message, &session_record.into_readonly())
+}
+
+/// The result of successfully checking a [`KeepAliveMessage`] with [`decrypt_keepalive`].
+///
+/// A keepalive has no content; receiving one only shows that the peer still holds the session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeepAlive;
+
+/// Checks that `message` is a keepalive sent by `remote_address` with one of the stored sessions.
+///
+/// The session is never modified: no chain is advanced and no message keys are stored, even if
+/// the keepalive was sent with a ratchet key or counter this side has not seen yet. The caller
+/// should simply drop the keepalive afterwards. Since keepalives carry no content, they are not
+/// protected against replay.
+///
+/// Fails with [`SignalProtocolError::InvalidMessage`] if the keepalive does not verify with any
+/// session, and with [`SignalProtocolError::UntrustedIdentity`] if the session that verifies it
+/// belongs to an identity that is not trusted for receiving.
+pub async fn decrypt_keepalive(
+    message: &KeepAliveMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &dyn SessionStore,
+    identity_store: &dyn IdentityKeyStore,
+    ctx: Context,
+) -> Result<KeepAlive> {
+    let session_record = session_store
+        .load_session(remote_address, ctx)
+        .await?
+        .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.to_string()))?;
+
+    for state in session_record.into_readonly().states() {
+        let state = state?;
+        if !keepalive_verifies(&state, message)? {
+            continue;
+        }
+        let their_identity_key = state
+            .remote_identity_key()?
+            .ok_or(SignalProtocolError::InvalidSessionStructure)?;
+        if !identity_store
+            .is_trusted_identity(
+                remote_address,
+                &their_identity_key,
+                Direction::Receiving,
+                ctx,
+            )
+            .await?
+        {
+            return Err(SignalProtocolError::UntrustedIdentity(
+                remote_address.clone(),
+            ));
+        }
+        return Ok(KeepAlive);
+    }
+
+    Err(SignalProtocolError::InvalidMessage(
+        "keepalive did not verify with any session",
+    ))
+}
+
+/// Whether `message` was sent with `state`, deriving (but not storing) the sender's chain key if
+/// necessary.
+fn keepalive_verifies(state: &SessionState, message: &KeepAliveMessage) -> Result<bool> {
+    if !state.has_sender_chain()? || message.message_version() as u32 != state.session_version()? {
+        return Ok(false);
+    }
+    let their_identity_key = match state.remote_identity_key()? {
+        Some(key) => key,
+        None => return Ok(false),
+    };
+
+    let their_ephemeral = message.sender_ratchet_key();
+    let mut chain_key = match state.get_receiver_chain_key(their_ephemeral)? {
+        Some(chain_key) => chain_key,
+        // The sender has ratcheted since its last message; this is the chain a message with the
+        // new ratchet key would create.
+        None => {
+            state
+                .root_key()?
+                .create_chain(their_ephemeral, &state.sender_ratchet_private_key()?)?
+                .1
+        }
+    };
+
+    // A keepalive is stale once a message has been received at its counter; one that claims to be
+    // too far ahead is rejected just like a message would be.
+    if chain_key.index() > message.counter()
+        || (message.counter() - chain_key.index()) as usize > MAX_FORWARD_JUMPS
+    {
+        return Ok(false);
+    }
+    while chain_key.index() < message.counter() {
+        chain_key = chain_key.next_chain_key()?;
+    }
+
+    message.verify_mac(
+        &their_identity_key,
+        &state.local_identity_key()?,
+        &chain_key.keepalive_mac_key()?,
+    )
+}
+
+/// A structured description of a message that failed to decrypt, for logging.
+///
+/// [`message_decrypt`] logs this report in its [`Display`](fmt::Display) form; with the `serde`
+/// feature it can also be serialized, e.g. as JSON.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DecryptionFailureReport {
+    pub remote_address: String,
+    /// The hex-encoded sender ratchet public key of the message.
+    pub sender_ratchet_key: String,
+    pub counter: u32,
+    pub has_current_session: bool,
+    /// The current session (index 0, if there is one) followed by every previous session.
+    pub candidates: Vec<CandidateSessionReport>,
+}
+
+/// One session in a [`DecryptionFailureReport`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CandidateSessionReport {
+    pub index: usize,
+    /// Why decrypting with this session failed, if it was tried.
+    pub error: Option<String>,
+    pub receiver_chains: Vec<ReceiverChainReport>,
+    /// Set (and `receiver_chains` left empty) if the session's receiver chains could not be read.
+    pub receiver_chains_error: Option<String>,
+}
+
+/// One receiver chain in a [`CandidateSessionReport`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ReceiverChainReport {
+    /// The hex-encoded sender ratchet public key of the chain.
+    pub sender_ratchet_key: String,
+    /// `None` if the chain key is missing from the stored session.
+    pub chain_key_index: Option<u32>,
+}
+
+impl fmt::Display for DecryptionFailureReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Message from {} failed to decrypt; sender ratchet public key {} message counter {}",
+            self.remote_address, self.sender_ratchet_key, self.counter
+        )?;
+        if !self.has_current_session {
+            write!(f, "\nNo current session")?;
+        }
+        for candidate in &self.candidates {
+            let idx = candidate.index;
+            match (&candidate.error, &candidate.receiver_chains_error) {
+                (Some(err), None) => write!(
+                    f,
+                    "\nCandidate session {} failed with '{}', had {} receiver chains",
+                    idx,
+                    err,
+                    candidate.receiver_chains.len()
+                )?,
+                (Some(err), Some(state_err)) => write!(
+                    f,
+                    "\nCandidate session {} failed with '{}'; cannot get receiver chain info ({})",
                     idx, err, state_err,
-                ));
-            }
-            (None, Ok(chains)) => {
-                lines.push(format!(
-                    "Candidate session {} had {} receiver chains",
+                )?,
+                (None, None) => write!(
+                    f,
+                    "\nCandidate session {} had {} receiver chains",
                     idx,
-                    chains.len()
-                ));
-            }
-            (None, Err(state_err)) => {
-                lines.push(format!(
-                    "Candidate session {}: cannot get receiver chain info ({})",
+                    candidate.receiver_chains.len()
+                )?,
+                (None, Some(state_err)) => write!(
+                    f,
+                    "\nCandidate session {}: cannot get receiver chain info ({})",
                     idx, state_err,
-                ));
+                )?,
             }
-        }
-
-        if let Ok(chains) = chains {
-            for chain in chains {
-                let chain_idx = match chain.1 {
+            for chain in &candidate.receiver_chains {
+                let chain_idx = match chain.chain_key_index {
                     Some(i) => i.to_string(),
                     None => "missing in protobuf".to_string(),
                 };
-
-                lines.push(format!(
-                    "Receiver chain with sender ratchet public key {} chain key index {}",
-                    hex::encode(chain.0),
-                    chain_idx
-                ));
+                write!(
+                    f,
+                    "\nReceiver chain with sender ratchet public key {} chain key index {}",
+                    chain.sender_ratchet_key, chain_idx
+                )?;
             }
         }
+        Ok(())
+    }
+}
+
+/// Describes why `ciphertext` from `remote_address` could not be decrypted with `record`.
+///
+/// `errs` holds the error from each session that was tried, in order: the current session (if
+/// any) followed by the previous sessions. Sessions beyond the end of `errs` are reported without
+/// an error.
+pub fn create_decryption_failure_report(
+    remote_address: &ProtocolAddress,
+    mut errs: &[SignalProtocolError],
+    record: &SessionRecord,
+    ciphertext: &SignalMessage,
+) -> Result<DecryptionFailureReport> {
+    fn candidate_report(
+        index: usize,
+        state: Result<&SessionState>,
+        err: Option<&SignalProtocolError>,
+    ) -> CandidateSessionReport {
+        let (receiver_chains, receiver_chains_error) =
+            match state.and_then(|state| state.all_receiver_chain_logging_info()) {
+                Ok(chains) => (
+                    chains
+                        .into_iter()
+                        .map(|(key, index)| ReceiverChainReport {
+                            sender_ratchet_key: hex::encode(key),
+                            chain_key_index: index,
+                        })
+                        .collect(),
+                    None,
+                ),
+                Err(state_err) => (vec![], Some(state_err.to_string())),
+            };
+        CandidateSessionReport {
+            index,
+            error: err.map(|err| err.to_string()),
+            receiver_chains,
+            receiver_chains_error,
+        }
+    }
+
+    let mut candidates = vec![];
+
+    let has_current_session = if let Ok(current_session) = record.session_state() {
+        let err = errs.first();
+        if err.is_some() {
+            errs = &errs[1..];
+        }
+        candidates.push(candidate_report(0, Ok(current_session), err));
+        true
+    } else {
+        false
+    };
+
+    for (idx, (state, err)) in record
+        .previous_session_states()
+        .zip(errs.iter().map(Some).chain(std::iter::repeat(None)))
+        .enumerate()
+    {
+        let state = match state {
+            Ok(ref state) => Ok(state),
+            Err(err) => Err(err),
+        };
+        candidates.push(candidate_report(idx + 1, state, err));
     }
 
-    let mut lines = vec![];
+    Ok(DecryptionFailureReport {
+        remote_address: remote_address.to_string(),
+        sender_ratchet_key: hex::encode(ciphertext.sender_ratchet_key().public_key_bytes()?),
+        counter: ciphertext.counter(),
+        has_current_session,
+        candidates,
+    })
+}
+
+/// A message decrypted with a copy of its session, which is only stored on
+/// [`commit`](Self::commit).
+///
+/// This allows decrypting a message ahead of time (e.g. on a background thread) and only
+/// advancing the session once higher layers have accepted it. Committing stores the updated
+/// session, so decrypting the same message again afterwards fails with
+/// [`SignalProtocolError::DuplicatedMessage`] as usual.
+pub struct SpeculativeDecrypt {
+    remote_address: ProtocolAddress,
+    record: SessionRecord,
+    plaintext: Vec<u8>,
+}
+
+impl SpeculativeDecrypt {
+    /// Decrypts `ciphertext` with a copy of the session for `remote_address`, without storing
+    /// anything.
+    ///
+    /// As with [`message_decrypt_signal`], the sender's identity is only checked after decryption,
+    /// here on [`commit`](Self::commit); the plaintext must not be trusted until then.
+    pub async fn new<R: Rng + CryptoRng>(
+        ciphertext: &SignalMessage,
+        remote_address: &ProtocolAddress,
+        session_store: &dyn SessionStore,
+        csprng: &mut R,
+        ctx: Context,
+    ) -> Result<Self> {
+        let mut record = session_store
+            .load_session(remote_address, ctx)
+            .await?
+            .ok_or_else(|| SignalProtocolError::SessionNotFound(format!("{}", remote_address)))?;
+        let mut plaintext = Vec::new();
+        decrypt_message_with_record(
+            remote_address,
+            &mut record,
+            ciphertext,
+            csprng,
+            &DecryptionConfig::default(),
+            &mut plaintext,
+        )?;
+        Ok(Self {
+            remote_address: remote_address.clone(),
+            record,
+            plaintext,
+        })
+    }
+
+    pub fn plaintext(&self) -> &[u8] {
+        &self.plaintext
+    }
+
+    /// The session as it will be stored by [`commit`](Self::commit).
+    pub fn record(&self) -> &SessionRecord {
+        &self.record
+    }
+
+    /// Checks the sender's identity and stores the updated session, returning the plaintext.
+    ///
+    /// Fails with [`SignalProtocolError::SessionConcurrentlyModified`] if the stored session has
+    /// changed since it was copied, e.g. because another message was decrypted in the meantime;
+    /// the message can then be decrypted again normally.
+    pub async fn commit(
+        mut self,
+        session_store: &mut dyn SessionStore,
+        identity_store: &mut dyn IdentityKeyStore,
+        ctx: Context,
+    ) -> Result<Vec<u8>> {
+        let their_identity_key = self
+            .record
+            .session_state()?
+            .remote_identity_key()?
+            .ok_or(SignalProtocolError::InvalidSessionStructure)?;
+
+        if !identity_store
+            .is_trusted_identity(
+                &self.remote_address,
+                &their_identity_key,
+                Direction::Receiving,
+                ctx,
+            )
+            .await?
+        {
+            return Err(SignalProtocolError::UntrustedIdentity(self.remote_address));
+        }
 
-    lines.push(format!(
-        "Message from {} failed to decrypt; sender ratchet public key {} message counter {}",
-        remote_address,
-        hex::encode(ciphertext.sender_ratchet_key().public_key_bytes()?),
-        ciphertext.counter()
-    ));
+        identity_store
+            .save_identity(&self.remote_address, &their_identity_key, ctx)
+            .await?;
 
-    if let Ok(current_session) = record.session_state() {
-        let err = errs.first();
-        if err.is_some() {
-            errs = &errs[1..];
+        if !store_session_checked(
+            &self.remote_address,
+            &mut self.record,
+            session_store,
+            None,
+            ctx,
+        )
+        .await?
+        {
+            return Err(SignalProtocolError::SessionConcurrentlyModified(
+                self.remote_address,
+            ));
         }
-        append_session_summary(&mut lines, 0, Ok(current_session), err);
-    } else {
-        lines.push("No current session".to_string());
+        Ok(self.plaintext)
     }
+}
 
-    for (idx, (state, err)) in record
-        .previous_session_states()
-        .zip(errs.iter().map(Some).chain(std::iter::repeat(None)))
-        .enumerate()
-    {
-        let state = match state {
-            Ok(ref state) => Ok(state),
-            Err(err) => Err(err),
-        };
-        append_session_summary(&mut lines, idx + 1, state, err);
-    }
+fn create_decryption_failure_log(
+    remote_address: &ProtocolAddress,
+    errs: &[SignalProtocolError],
+    record: &SessionRecord,
+    ciphertext: &SignalMessage,
+) -> Result<String> {
+    Ok(create_decryption_failure_report(remote_address, errs, record, ciphertext)?.to_string())
+}
 
-    Ok(lines.join("\n"))
+/// The error message when no session state can decrypt a message.
+const DECRYPTION_FAILED: &str = "Message decryption failed";
+
+/// The local and remote identity keys recorded by the current session state in `record`, or by the
+/// most recently archived one if there is no current state.
+fn record_identity_keys(record: &SessionRecord) -> Result<(IdentityKey, IdentityKey)> {
+    let (local, remote) = if record.has_current_session_state() {
+        let state = record.session_state()?;
+        (state.local_identity_key()?, state.remote_identity_key()?)
+    } else {
+        match record.previous_session_states().next() {
+            Some(state) => {
+                let state = state?;
+                (state.local_identity_key()?, state.remote_identity_key()?)
+            }
+            None => return Err(SignalProtocolError::InvalidMessage(DECRYPTION_FAILED)),
+        }
+    };
+    Ok((
+        local,
+        remote.ok_or(SignalProtocolError::InvalidSessionStructure)?,
+    ))
 }
 
+/// Like [`decrypt_message_with_identities`], with the identity keys recorded in `record`.
 fn decrypt_message_with_record<R: Rng + CryptoRng>(
     remote_address: &ProtocolAddress,
     record: &mut SessionRecord,
     ciphertext: &SignalMessage,
     csprng: &mut R,
-) -> Result<Vec<u8>> {
+    config: &DecryptionConfig,
+    out: &mut Vec<u8>,
+) -> Result<bool> {
+    let (local_identity_key, their_identity_key) = record_identity_keys(record)?;
+    decrypt_message_with_identities(
+        remote_address,
+        record,
+        ciphertext,
+        &local_identity_key,
+        &their_identity_key,
+        csprng,
+        config,
+        out,
+    )
+}
+
+/// Decrypts `ciphertext` with the first state in `record` that can, checking the MAC against
+/// `local_identity` and `remote_identity`. `record` is only modified if decryption succeeds.
+///
+/// Returns whether the message was the first response to a pre-key message we sent with the state
+/// that decrypted it.
+#[allow(clippy::too_many_arguments)]
+fn decrypt_message_with_identities<R: Rng + CryptoRng>(
+    remote_address: &ProtocolAddress,
+    record: &mut SessionRecord,
+    ciphertext: &SignalMessage,
+    local_identity: &IdentityKey,
+    remote_identity: &IdentityKey,
+    csprng: &mut R,
+    config: &DecryptionConfig,
+    out: &mut Vec<u8>,
+) -> Result<bool> {
+    // A warning rather than an error because we try multiple sessions.
+    let failure_level = if config.quiet_state_failures {
+        log::Level::Trace
+    } else {
+        log::Level::Warn
+    };
     let log_decryption_failure = |state: &SessionState, error: &SignalProtocolError| {
-        // A warning rather than an error because we try multiple sessions.
-        log::warn!(
+        log::log!(
+            failure_level,
             "Failed to decrypt whisper message with ratchet key: {} and counter: {}. \
              Session loaded for {}. Local session has base key: {} and counter: {}. {}",
             ciphertext
@@ -401,11 +3028,19 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
 
     if let Ok(current_state) = record.session_state() {
         let mut current_state = current_state.clone();
-        let result =
-            decrypt_message_with_state(&mut current_state, ciphertext, remote_address, csprng);
+        let result = decrypt_message_with_state(
+            &mut current_state,
+            ciphertext,
+            remote_address,
+            local_identity,
+            remote_identity,
+            csprng,
+            config,
+            out,
+        );
 
         match result {
-            Ok(ptext) => {
+            Ok(acknowledged) => {
                 log::info!(
                     "decrypted message from {} with current session state (base key {})",
                     remote_address,
@@ -413,10 +3048,28 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
                         .sender_ratchet_key_for_logging()
                         .expect("successful decrypt always has a valid base key"),
                 );
+                if config.reject_ambiguous_sessions
+                    && decrypts_with_later_state(
+                        record,
+                        None,
+                        ciphertext,
+                        remote_address,
+                        local_identity,
+                        remote_identity,
+                        csprng,
+                        config,
+                    )?
+                {
+                    return Err(SignalProtocolError::AmbiguousSession(
+                        remote_address.clone(),
+                    ));
+                }
                 record.set_session_state(current_state)?; // update the state
-                return Ok(ptext);
+                return Ok(acknowledged);
             }
-            Err(SignalProtocolError::DuplicatedMessage(_, _)) => {
+            Err(SignalProtocolError::DuplicatedMessage(_, _))
+            | Err(SignalProtocolError::MessageKeysWrapped)
+            | Err(SignalProtocolError::MessageTooLong(_)) => {
                 return result;
             }
             Err(e) => {
@@ -426,27 +3079,40 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
         }
     }
 
-    // Try some old sessions:
+    // Try some old sessions, most recently archived first. The first one that decrypts the
+    // message wins; see DecryptionConfig::reject_ambiguous_sessions.
     let mut updated_session = None;
 
     for (idx, previous) in record.previous_session_states().enumerate() {
         let mut previous = previous?;
 
-        let result = decrypt_message_with_state(&mut previous, ciphertext, remote_address, csprng);
+        let result = decrypt_message_with_state(
+            &mut previous,
+            ciphertext,
+            remote_address,
+            local_identity,
+            remote_identity,
+            csprng,
+            config,
+            out,
+        );
 
         match result {
-            Ok(ptext) => {
+            Ok(acknowledged) => {
                 log::info!(
-                    "decrypted message from {} with PREVIOUS session state (base key {})",
+                    "decrypted message from {} with PREVIOUS session state {} (base key {})",
                     remote_address,
+                    idx,
                     previous
                         .sender_ratchet_key_for_logging()
                         .expect("successful decrypt always has a valid base key"),
                 );
-                updated_session = Some((ptext, idx, previous));
+                updated_session = Some((idx, previous, acknowledged));
                 break;
             }
-            Err(SignalProtocolError::DuplicatedMessage(_, _)) => {
+            Err(SignalProtocolError::DuplicatedMessage(_, _))
+            | Err(SignalProtocolError::MessageKeysWrapped)
+            | Err(SignalProtocolError::MessageTooLong(_)) => {
                 return result;
             }
             Err(e) => {
@@ -456,85 +3122,300 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
         }
     }
 
-    if let Some((ptext, idx, updated_session)) = updated_session {
+    if let Some((idx, updated_session, acknowledged)) = updated_session {
+        if config.reject_ambiguous_sessions
+            && decrypts_with_later_state(
+                record,
+                Some(idx),
+                ciphertext,
+                remote_address,
+                local_identity,
+                remote_identity,
+                csprng,
+                config,
+            )?
+        {
+            return Err(SignalProtocolError::AmbiguousSession(
+                remote_address.clone(),
+            ));
+        }
         record.promote_old_session(idx, updated_session)?;
-        Ok(ptext)
+        Ok(acknowledged)
     } else {
-        let previous_state_count = || record.previous_session_states().len();
-
         if let Ok(current_state) = record.session_state() {
             log::error!(
                 "No valid session for recipient: {}, current session base key {}, number of previous states: {}",
                 remote_address,
                 current_state.sender_ratchet_key_for_logging()
                 .unwrap_or_else(|e| format!("<error: {}>", e)),
-                previous_state_count(),
+                record.previous_state_count(),
             );
         } else {
             log::error!(
                 "No valid session for recipient: {}, (no current session state), number of previous states: {}",
                 remote_address,
-                previous_state_count(),
+                record.previous_state_count(),
             );
         }
         log::error!(
             "{}",
             create_decryption_failure_log(remote_address, &errs, record, ciphertext)?
         );
-        Err(SignalProtocolError::InvalidMessage(
-            "Message decryption failed",
-        ))
+        // The body length does not depend on the session state, so report it as such.
+        if errs
+            .iter()
+            .any(|e| matches!(e, SignalProtocolError::InvalidCiphertextLength { .. }))
+        {
+            return Err(SignalProtocolError::InvalidCiphertextLength {
+                len: ciphertext.body().len(),
+            });
+        }
+        if errs
+            .iter()
+            .any(|e| matches!(e, SignalProtocolError::ReflectedIdentity(_)))
+        {
+            return Err(SignalProtocolError::ReflectedIdentity(
+                remote_address.clone(),
+            ));
+        }
+        // Another state may have been able to decrypt the message without such a gap, so this is
+        // only reported once all of them have failed.
+        if let Some(gap) = errs.iter().find_map(|e| match e {
+            SignalProtocolError::MessageGapTooLarge { gap } => Some(*gap),
+            _ => None,
+        }) {
+            return Err(SignalProtocolError::MessageGapTooLarge { gap });
+        }
+        // Report a downgrade of the current session as such. Delayed messages for archived
+        // sessions of a lower version have already been decrypted by those sessions above.
+        match errs.into_iter().next() {
+            Some(e @ SignalProtocolError::VersionDowngrade { .. })
+                if record.has_current_session_state() =>
+            {
+                Err(e)
+            }
+            _ => Err(SignalProtocolError::InvalidMessage(DECRYPTION_FAILED)),
+        }
+    }
+}
+
+/// Whether a previous state after the one that decrypted `ciphertext` (`None` for the current
+/// state) could also decrypt it. The states before it have already failed.
+///
+/// Only copies of the states are used, so `record` is not modified.
+#[allow(clippy::too_many_arguments)]
+fn decrypts_with_later_state<R: Rng + CryptoRng>(
+    record: &SessionRecord,
+    decrypted_with: Option<usize>,
+    ciphertext: &SignalMessage,
+    remote_address: &ProtocolAddress,
+    local_identity: &IdentityKey,
+    remote_identity: &IdentityKey,
+    csprng: &mut R,
+    config: &DecryptionConfig,
+) -> Result<bool> {
+    let mut scratch = Vec::new();
+    let first_unchecked = decrypted_with.map_or(0, |idx| idx + 1);
+    for previous in record.previous_session_states().skip(first_unchecked) {
+        let mut previous = previous?;
+        if decrypt_message_with_state(
+            &mut previous,
+            ciphertext,
+            remote_address,
+            local_identity,
+            remote_identity,
+            csprng,
+            config,
+            &mut scratch,
+        )
+        .is_ok()
+        {
+            return Ok(true);
+        }
     }
+    Ok(false)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn decrypt_message_with_state<R: Rng + CryptoRng>(
     state: &mut SessionState,
     ciphertext: &SignalMessage,
     remote_address: &ProtocolAddress,
+    local_identity: &IdentityKey,
+    remote_identity: &IdentityKey,
     csprng: &mut R,
-) -> Result<Vec<u8>> {
+    config: &DecryptionConfig,
+    out: &mut Vec<u8>,
+) -> Result<bool> {
     if !state.has_sender_chain()? {
         return Err(SignalProtocolError::InvalidMessage(
             "No session available to decrypt",
         ));
     }
 
+    if config.reject_own_identity && remote_identity == local_identity {
+        return Err(SignalProtocolError::ReflectedIdentity(
+            remote_address.clone(),
+        ));
+    }
+
     let ciphertext_version = ciphertext.message_version() as u32;
+    let highest_version_seen = state.highest_version_seen()?;
+    // Both sides have already used this version, so a message claiming a lower one may have been
+    // downgraded in transit.
+    if ciphertext_version < highest_version_seen {
+        return Err(SignalProtocolError::VersionDowngrade {
+            message_version: ciphertext_version,
+            highest_version_seen,
+        });
+    }
     if ciphertext_version != state.session_version()? {
         return Err(SignalProtocolError::UnrecognizedMessageVersion(
             ciphertext_version,
         ));
     }
 
+    // The IV comes from the message keys and always has the right length, but the body is
+    // attacker-controlled. Reject bad framing before doing any ratchet work.
+    let body_len = ciphertext.body().len();
+    if body_len == 0 || body_len % 16 != 0 {
+        return Err(SignalProtocolError::InvalidCiphertextLength { len: body_len });
+    }
+
     let their_ephemeral = ciphertext.sender_ratchet_key();
     let counter = ciphertext.counter();
     let chain_key = get_or_create_chain_key(state, their_ephemeral, remote_address, csprng)?;
-    let message_keys =
-        get_or_create_message_key(state, their_ephemeral, remote_address, &chain_key, counter)?;
+    let message_keys = get_or_create_message_key(
+        state,
+        their_ephemeral,
+        remote_address,
+        &chain_key,
+        counter,
+        config,
+    )?;
 
-    let their_identity_key = state
-        .remote_identity_key()?
-        .ok_or(SignalProtocolError::InvalidSessionStructure)?;
+    verify_and_decrypt_body(
+        ciphertext,
+        &message_keys,
+        local_identity,
+        remote_identity,
+        ciphertext_version,
+        &config.associated_data,
+        out,
+    )?;
 
-    let mac_valid = ciphertext.verify_mac(
-        &their_identity_key,
-        &state.local_identity_key()?,
+    state.record_version_seen(ciphertext_version)?;
+    state.clear_unacknowledged_pre_key_message()
+}
+
+/// Checks the MAC of `ciphertext` against the given identity keys and decrypts its body into
+/// `out`.
+///
+/// Every decryption path ends here once it has resolved the identity keys and message keys, so the
+/// session's ratchet state never takes part in the cryptography itself.
+#[allow(clippy::too_many_arguments)]
+fn verify_and_decrypt_body(
+    ciphertext: &SignalMessage,
+    message_keys: &MessageKeys,
+    local_identity: &IdentityKey,
+    remote_identity: &IdentityKey,
+    session_version: u32,
+    associated_data: &[u8],
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    let mac_valid = ciphertext.verify_mac_with_associated_data(
+        remote_identity,
+        local_identity,
         message_keys.mac_key(),
+        associated_data,
     )?;
 
     if !mac_valid {
         return Err(SignalProtocolError::InvalidCiphertext);
     }
 
-    let ptext = crypto::aes_256_cbc_decrypt(
-        ciphertext.body(),
-        message_keys.cipher_key(),
-        message_keys.iv(),
-    )?;
+    let cipher_key = message_keys.cipher_key_for_version(session_version);
+    if !ciphertext.is_compressed() {
+        return crypto::aes_cbc_decrypt_into(ciphertext.body(), cipher_key, message_keys.iv(), out);
+    }
+
+    #[cfg(feature = "compression")]
+    {
+        let mut compressed = Vec::new();
+        crypto::aes_cbc_decrypt_into(
+            ciphertext.body(),
+            cipher_key,
+            message_keys.iv(),
+            &mut compressed,
+        )?;
+        inflate_into(&compressed, out)
+    }
+    #[cfg(not(feature = "compression"))]
+    Err(SignalProtocolError::InvalidMessage(
+        "compressed messages require the compression feature",
+    ))
+}
 
-    state.clear_unacknowledged_pre_key_message()?;
+/// Inflates `compressed` into `out`, reusing its allocation.
+///
+/// The MAC has been checked by the time a body is inflated, so the sender is authentic, but the
+/// output is still limited to [`MAX_DECOMPRESSED_MESSAGE_LEN`] bytes. `out` is left empty on error.
+#[cfg(feature = "compression")]
+fn inflate_into(compressed: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+    use miniz_oxide::inflate::TINFLStatus;
 
-    Ok(ptext)
+    let mut decompressor = Box::<DecompressorOxide>::default();
+    let mut in_pos = 0;
+    let mut out_pos = 0;
+    out.clear();
+    out.resize(
+        compressed
+            .len()
+            .saturating_mul(2)
+            .min(MAX_DECOMPRESSED_MESSAGE_LEN),
+        0,
+    );
+
+    let result = loop {
+        let (status, in_consumed, out_consumed) = decompress(
+            &mut decompressor,
+            &compressed[in_pos..],
+            out.as_mut_slice(),
+            out_pos,
+            inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+        );
+        in_pos += in_consumed;
+        out_pos += out_consumed;
+
+        match status {
+            TINFLStatus::Done => break Ok(()),
+            TINFLStatus::HasMoreOutput if out.len() < MAX_DECOMPRESSED_MESSAGE_LEN => {
+                let new_len = out
+                    .len()
+                    .saturating_mul(2)
+                    .max(64)
+                    .min(MAX_DECOMPRESSED_MESSAGE_LEN);
+                out.resize(new_len, 0);
+            }
+            TINFLStatus::HasMoreOutput => {
+                break Err(SignalProtocolError::MessageTooLong(
+                    MAX_DECOMPRESSED_MESSAGE_LEN,
+                ))
+            }
+            _ => {
+                break Err(SignalProtocolError::InvalidMessage(
+                    "invalid compressed message body",
+                ))
+            }
+        }
+    };
+
+    match result {
+        Ok(()) => out.truncate(out_pos),
+        Err(_) => out.clear(),
+    }
+    result
 }
 
 fn get_or_create_chain_key<R: Rng + CryptoRng>(
@@ -579,9 +3460,37 @@ fn get_or_create_message_key(
     remote_address: &ProtocolAddress,
     chain_key: &ChainKey,
     counter: u32,
+    config: &DecryptionConfig,
 ) -> Result<MessageKeys> {
     let chain_index = chain_key.index();
 
+    // (A chain at u32::MAX has no next step; it is handled as a malformed chain below.)
+    if config.constant_time_dedup && chain_index < u32::MAX {
+        // Always look for stored keys, even when the counter shows there can't be any, and take a
+        // chain step even when it won't be used.
+        let stored_keys = state.get_message_keys_constant_time(their_ephemeral, counter)?;
+        let next_keys = chain_key.message_keys();
+        let next_chain_key = chain_key.next_chain_key();
+        if let Some(keys) = stored_keys {
+            return Ok(keys);
+        }
+        if chain_index > counter {
+            log::info!(
+                "{} Duplicate message for counter: {}",
+                remote_address,
+                counter
+            );
+            return Err(SignalProtocolError::DuplicatedMessage(chain_index, counter));
+        }
+        if counter == chain_index {
+            state.set_receiver_chain_key(their_ephemeral, &next_chain_key?)?;
+            return next_keys;
+        }
+    }
+
+    // Message keys are deleted as soon as they are used, so a stored key always belongs to a
+    // message that has not been decrypted yet. A real duplicate has no key left to decrypt it
+    // with; callers that need its content again must keep the plaintext themselves.
     if chain_index > counter {
         return match state.get_message_keys(their_ephemeral, counter)? {
             Some(keys) => Ok(keys),
@@ -596,8 +3505,7 @@ fn get_or_create_message_key(
         };
     }
 
-    assert!(chain_index <= counter);
-
+    // Counters behind the chain were handled above.
     let jump = (counter - chain_index) as usize;
 
     if jump > MAX_FORWARD_JUMPS {
@@ -623,14 +3531,656 @@ fn get_or_create_message_key(
         }
     }
 
-    let mut chain_key = chain_key.clone();
-
-    while chain_key.index() < counter {
-        let message_keys = chain_key.message_keys()?;
-        state.set_message_keys(their_ephemeral, &message_keys)?;
-        chain_key = chain_key.next_chain_key()?;
+    if let Some(max_gap) = config.max_gap {
+        if jump > max_gap as usize {
+            log::warn!(
+                "{} Message gap {} exceeds configured maximum {} (index: {}, counter: {})",
+                remote_address,
+                jump,
+                max_gap,
+                chain_index,
+                counter
+            );
+            return Err(SignalProtocolError::MessageGapTooLarge { gap: jump as u32 });
+        }
     }
 
+    // Skipped keys go straight into the state, which keeps at most MAX_MESSAGE_KEYS of them, so
+    // even the unlimited jumps of a session with ourselves don't need a matching allocation.
+    let chain_key = chain_key.derive_message_keys_until(counter, |message_keys| {
+        state.set_message_keys(their_ephemeral, &message_keys)
+    })?;
+
     state.set_receiver_chain_key(their_ephemeral, &chain_key.next_chain_key()?)?;
     chain_key.message_keys()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::storage::SessionStructure;
+    use crate::ratchet::{self, BobSignalProtocolParameters};
+    use crate::{IdentityKeyPair, InMemIdentityKeyStore};
+    use futures_util::FutureExt;
+    use rand::rngs::OsRng;
+
+    fn bob_session_state() -> Result<SessionState> {
+        let mut csprng = OsRng;
+        let signed_pre_key_pair = KeyPair::generate(&mut csprng);
+        let parameters = BobSignalProtocolParameters::new(
+            IdentityKeyPair::generate(&mut csprng),
+            signed_pre_key_pair,
+            None,
+            signed_pre_key_pair,
+            *IdentityKeyPair::generate(&mut csprng).identity_key(),
+            KeyPair::generate(&mut csprng).public_key,
+        );
+        ratchet::initialize_bob_session(&parameters, &ProtocolConfig::default())
+    }
+
+    /// Sets up a session from Alice to Bob, returning Alice's record and Bob's state.
+    fn initialize_sessions(
+        alice_identity: IdentityKeyPair,
+        bob_identity: IdentityKeyPair,
+    ) -> Result<(SessionRecord, SessionState)> {
+        let mut csprng = OsRng;
+        let alice_base_key = KeyPair::generate(&mut csprng);
+        let bob_signed_pre_key = KeyPair::generate(&mut csprng);
+
+        let alice_record = SessionRecord::new(ratchet::initialize_alice_session(
+            &ratchet::AliceSignalProtocolParameters::new(
+                alice_identity,
+                alice_base_key,
+                *bob_identity.identity_key(),
+                bob_signed_pre_key.public_key,
+                None,
+                bob_signed_pre_key.public_key,
+            ),
+            &ProtocolConfig::default(),
+            &mut csprng,
+        )?);
+        let bob_state = ratchet::initialize_bob_session(
+            &BobSignalProtocolParameters::new(
+                bob_identity,
+                bob_signed_pre_key,
+                None,
+                bob_signed_pre_key,
+                *alice_identity.identity_key(),
+                alice_base_key.public_key,
+            ),
+            &ProtocolConfig::default(),
+        )?;
+        Ok((alice_record, bob_state))
+    }
+
+    fn encrypt_signal_message(
+        ptext: &[u8],
+        record: &mut SessionRecord,
+        identity: IdentityKeyPair,
+    ) -> Result<SignalMessage> {
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+        let mut identity_store = InMemIdentityKeyStore::new(identity, 1);
+        match encrypt_with_record(
+            ptext,
+            &bob_address,
+            record,
+            &mut identity_store,
+            &EncryptionConfig::default(),
+            None,
+        )
+        .now_or_never()
+        .expect("sync")?
+        {
+            CiphertextMessage::SignalMessage(message) => Ok(message),
+            _ => panic!("expected a SignalMessage"),
+        }
+    }
+
+    /// Collects the messages logged on the current thread, so that tests running in parallel
+    /// don't see each other's output.
+    struct CapturingLogger;
+
+    thread_local! {
+        static CAPTURED_LOGS: std::cell::RefCell<Vec<(log::Level, String)>> = Default::default();
+    }
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED_LOGS.with(|logs| {
+                logs.borrow_mut()
+                    .push((record.level(), record.args().to_string()))
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_logs<T>(f: impl FnOnce() -> T) -> (T, Vec<(log::Level, String)>) {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CapturingLogger).expect("no other logger installed");
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        CAPTURED_LOGS.with(|logs| logs.borrow_mut().clear());
+        let result = f();
+        (result, CAPTURED_LOGS.with(|logs| logs.take()))
+    }
+
+    #[test]
+    #[cfg(feature = "test-internals")]
+    fn test_advance_sender_chain() -> Result<()> {
+        let mut state = bob_session_state()?;
+        let initial = state.get_sender_chain_key()?;
+
+        state.advance_sender_chain(3)?;
+        let advanced = state.get_sender_chain_key()?;
+        assert_eq!(advanced.index(), initial.index() + 3);
+        assert_eq!(
+            advanced.key(),
+            initial
+                .next_chain_key()?
+                .next_chain_key()?
+                .next_chain_key()?
+                .key()
+        );
+
+        state.set_sender_chain_key(&ChainKey::new(&[0x55; 32], u32::MAX - 1)?)?;
+        assert!(matches!(
+            state.advance_sender_chain(2),
+            Err(SignalProtocolError::InvalidState("next_chain_key", _))
+        ));
+        assert_eq!(state.get_sender_chain_key()?.index(), u32::MAX - 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_key_for_malformed_chain_fails_gracefully() -> Result<()> {
+        let mut state = bob_session_state()?;
+        let remote_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let their_ephemeral = KeyPair::generate(&mut OsRng).public_key;
+
+        // A chain that claims to be far ahead but has none of the skipped keys stored.
+        let chain_key = ChainKey::new(&[0x55; 32], u32::MAX)?;
+        state.add_receiver_chain(&their_ephemeral, &chain_key)?;
+
+        for counter in [0, u32::MAX - 1] {
+            assert!(matches!(
+                get_or_create_message_key(
+                    &mut state,
+                    &their_ephemeral,
+                    &remote_address,
+                    &chain_key,
+                    counter,
+                    &DecryptionConfig::default(),
+                ),
+                Err(SignalProtocolError::DuplicatedMessage(u32::MAX, c)) if c == counter
+            ));
+        }
+
+        // Jumping from the start of a chain to its very end is refused rather than attempted.
+        let chain_key = ChainKey::new(&[0x55; 32], 0)?;
+        assert!(matches!(
+            get_or_create_message_key(
+                &mut state,
+                &their_ephemeral,
+                &remote_address,
+                &chain_key,
+                u32::MAX,
+                &DecryptionConfig::default(),
+            ),
+            Err(SignalProtocolError::InvalidMessage(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_constant_time_dedup_matches_default_lookup() -> Result<()> {
+        let remote_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let their_ephemeral = KeyPair::generate(&mut OsRng).public_key;
+
+        let mut state = bob_session_state()?;
+        let chain_key = ChainKey::new(&[0x55; 32], 0)?;
+        state.add_receiver_chain(&their_ephemeral, &chain_key)?;
+
+        let mut ct_state = state.clone();
+        let ct_config = DecryptionConfig {
+            constant_time_dedup: true,
+            ..Default::default()
+        };
+        let mut chain_key = chain_key;
+        for counter in [3, 1, 1, 4, 0, 4, 2, 5] {
+            let expected = get_or_create_message_key(
+                &mut state,
+                &their_ephemeral,
+                &remote_address,
+                &chain_key,
+                counter,
+                &DecryptionConfig::default(),
+            );
+            let actual = get_or_create_message_key(
+                &mut ct_state,
+                &their_ephemeral,
+                &remote_address,
+                &chain_key,
+                counter,
+                &ct_config,
+            );
+            match (expected, actual) {
+                (Ok(expected), Ok(actual)) => {
+                    assert_eq!(expected.cipher_key(), actual.cipher_key());
+                    assert_eq!(expected.counter(), actual.counter());
+                }
+                (
+                    Err(SignalProtocolError::DuplicatedMessage(i, c)),
+                    Err(SignalProtocolError::DuplicatedMessage(ct_i, ct_c)),
+                ) => assert_eq!((i, c), (ct_i, ct_c)),
+                (expected, actual) => panic!("{:?} vs {:?}", expected.err(), actual.err()),
+            }
+            assert_eq!(
+                SessionStructure::from(&state),
+                SessionStructure::from(&ct_state)
+            );
+            chain_key = state
+                .get_receiver_chain_key(&their_ephemeral)?
+                .expect("chain exists");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup_receiver_chains_keeps_all_message_keys() -> Result<()> {
+        let their_ephemeral = KeyPair::generate(&mut OsRng).public_key;
+        let other_ephemeral = KeyPair::generate(&mut OsRng).public_key;
+        let message_keys =
+            |counter: u32| MessageKeys::new(&[counter as u8; 32], &[0; 32], &[0; 16], counter);
+
+        let mut state = bob_session_state()?;
+        assert_eq!(state.dedup_receiver_chains()?, 0);
+
+        state.add_receiver_chain(&their_ephemeral, &ChainKey::new(&[0x55; 32], 3)?)?;
+        state.set_message_keys(&their_ephemeral, &message_keys(0)?)?;
+        state.set_message_keys(&their_ephemeral, &message_keys(2)?)?;
+        state.add_receiver_chain(&other_ephemeral, &ChainKey::new(&[0x66; 32], 1)?)?;
+
+        // A duplicate of the first chain that has advanced further, storing keys 5 and 2.
+        let mut structure = SessionStructure::from(&state);
+        let mut duplicate = structure.receiver_chains[0].clone();
+        duplicate.chain_key = Some(crate::proto::storage::session_structure::chain::ChainKey {
+            index: 6,
+            key: vec![0x77; 32],
+        });
+        let mut skipped = duplicate.message_keys[0].clone();
+        skipped.index = 5;
+        duplicate.message_keys = vec![skipped, duplicate.message_keys[0].clone()];
+        structure.receiver_chains.push(duplicate);
+        let mut state = SessionState::from(structure);
+        assert_eq!(state.all_receiver_chain_logging_info()?.len(), 3);
+
+        assert_eq!(state.dedup_receiver_chains()?, 1);
+        assert_eq!(state.dedup_receiver_chains()?, 0);
+
+        let chains = state.all_receiver_chain_logging_info()?;
+        assert_eq!(chains.len(), 2);
+        assert_eq!(chains[0], (their_ephemeral.serialize().to_vec(), Some(6)));
+        assert_eq!(chains[1], (other_ephemeral.serialize().to_vec(), Some(1)));
+
+        let mut counters = state
+            .export_message_keys()?
+            .into_iter()
+            .map(|(_, counter, _)| counter)
+            .collect::<Vec<_>>();
+        counters.sort_unstable();
+        assert_eq!(counters, [0, 2, 5]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_gap_leaves_state_unchanged() -> Result<()> {
+        let remote_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let their_ephemeral = KeyPair::generate(&mut OsRng).public_key;
+
+        let mut state = bob_session_state()?;
+        let chain_key = ChainKey::new(&[0x55; 32], 0)?;
+        state.add_receiver_chain(&their_ephemeral, &chain_key)?;
+        let before = SessionStructure::from(&state);
+
+        let config = DecryptionConfig {
+            max_gap: Some(3),
+            ..Default::default()
+        };
+        assert!(matches!(
+            get_or_create_message_key(
+                &mut state,
+                &their_ephemeral,
+                &remote_address,
+                &chain_key,
+                4,
+                &config,
+            ),
+            Err(SignalProtocolError::MessageGapTooLarge { gap: 4 })
+        ));
+        assert_eq!(SessionStructure::from(&state), before);
+
+        let keys = get_or_create_message_key(
+            &mut state,
+            &their_ephemeral,
+            &remote_address,
+            &chain_key,
+            3,
+            &config,
+        )?;
+        assert_eq!(keys.counter(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_gap_tries_previous_states() -> Result<()> {
+        let mut csprng = OsRng;
+        let alice_identity = IdentityKeyPair::generate(&mut csprng);
+        let (mut alice_record, bob_state) =
+            initialize_sessions(alice_identity, IdentityKeyPair::generate(&mut csprng))?;
+        let messages = (0..6)
+            .map(|_| encrypt_signal_message(b"hi", &mut alice_record, alice_identity))
+            .collect::<Result<Vec<_>>>()?;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let mut out = Vec::new();
+        let mut caught_up = SessionRecord::new(bob_state.clone());
+        for message in &messages[..5] {
+            decrypt_message_with_record(
+                &alice_address,
+                &mut caught_up,
+                message,
+                &mut csprng,
+                &DecryptionConfig::default(),
+                &mut out,
+            )?;
+        }
+
+        // The current state would have to skip five messages, but an archived one has caught up.
+        let config = DecryptionConfig {
+            max_gap: Some(3),
+            ..Default::default()
+        };
+        let mut bob_record = SessionRecord::new(caught_up.session_state()?.clone());
+        bob_record.archive_current_state()?;
+        bob_record.set_session_state(bob_state.clone())?;
+        decrypt_message_with_record(
+            &alice_address,
+            &mut bob_record,
+            &messages[5],
+            &mut csprng,
+            &config,
+            &mut out,
+        )?;
+        assert_eq!(out, b"hi");
+
+        // Without such a state, the gap is reported.
+        assert!(matches!(
+            decrypt_message_with_record(
+                &alice_address,
+                &mut SessionRecord::new(bob_state),
+                &messages[5],
+                &mut csprng,
+                &config,
+                &mut out,
+            ),
+            Err(SignalProtocolError::MessageGapTooLarge { gap: 5 })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_ambiguous_sessions() -> Result<()> {
+        let mut csprng = OsRng;
+        let alice_identity = IdentityKeyPair::generate(&mut csprng);
+        let (mut alice_record, bob_state) =
+            initialize_sessions(alice_identity, IdentityKeyPair::generate(&mut csprng))?;
+        let message = encrypt_signal_message(b"hi", &mut alice_record, alice_identity)?;
+
+        // The same state is both current and archived, so either could decrypt the message.
+        let mut bob_record = SessionRecord::new(bob_state.clone());
+        bob_record.archive_current_state()?;
+        bob_record.set_session_state(bob_state.clone())?;
+        let original = bob_record.serialize()?;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let mut out = Vec::new();
+        let strict_config = DecryptionConfig {
+            reject_ambiguous_sessions: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            decrypt_message_with_record(
+                &alice_address,
+                &mut bob_record,
+                &message,
+                &mut csprng,
+                &strict_config,
+                &mut out,
+            ),
+            Err(SignalProtocolError::AmbiguousSession(_))
+        ));
+        assert_eq!(bob_record.serialize()?, original);
+
+        // By default the current state wins, leaving the archived copy untouched.
+        decrypt_message_with_record(
+            &alice_address,
+            &mut bob_record,
+            &message,
+            &mut csprng,
+            &DecryptionConfig::default(),
+            &mut out,
+        )?;
+        assert_eq!(out, b"hi");
+        let archived = bob_record
+            .previous_session_states()
+            .next()
+            .expect("archived")?;
+        assert_eq!(
+            SessionStructure::from(&archived),
+            SessionStructure::from(&bob_state)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quiet_state_failures() -> Result<()> {
+        let mut csprng = OsRng;
+        let alice_identity = IdentityKeyPair::generate(&mut csprng);
+        let (mut alice_record, bob_state) =
+            initialize_sessions(alice_identity, IdentityKeyPair::generate(&mut csprng))?;
+        let message = encrypt_signal_message(b"hi", &mut alice_record, alice_identity)?;
+
+        // Only the archived state can decrypt the message; the current one is unrelated.
+        let mut bob_record = SessionRecord::new(bob_state);
+        bob_record.archive_current_state()?;
+        bob_record.set_session_state(bob_session_state()?)?;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let quiet_config = DecryptionConfig {
+            quiet_state_failures: true,
+            ..Default::default()
+        };
+        // MAC failures are also logged as warnings, independently of this option.
+        let logged_state_failure = |logs: &[(log::Level, String)], level: log::Level| {
+            logs.iter().any(|(logged_level, message)| {
+                *logged_level == level && message.starts_with("Failed to decrypt whisper message")
+            })
+        };
+        let mut out = Vec::new();
+
+        let (result, logs) = capture_logs(|| {
+            decrypt_message_with_record(
+                &alice_address,
+                &mut bob_record.clone(),
+                &message,
+                &mut csprng,
+                &DecryptionConfig::default(),
+                &mut out,
+            )
+        });
+        result?;
+        assert_eq!(out, b"hi");
+        assert!(logged_state_failure(&logs, log::Level::Warn));
+
+        let (result, logs) = capture_logs(|| {
+            decrypt_message_with_record(
+                &alice_address,
+                &mut bob_record.clone(),
+                &message,
+                &mut csprng,
+                &quiet_config,
+                &mut out,
+            )
+        });
+        result?;
+        assert_eq!(out, b"hi");
+        assert!(!logged_state_failure(&logs, log::Level::Warn));
+        assert!(logged_state_failure(&logs, log::Level::Trace));
+
+        // Failing with every state is still reported.
+        let mut unrelated_record = SessionRecord::new(bob_session_state()?);
+        let (result, logs) = capture_logs(|| {
+            decrypt_message_with_record(
+                &alice_address,
+                &mut unrelated_record,
+                &message,
+                &mut csprng,
+                &quiet_config,
+                &mut out,
+            )
+        });
+        assert!(matches!(
+            result,
+            Err(SignalProtocolError::InvalidMessage(_))
+        ));
+        assert!(logs.iter().any(|(level, _)| *level == log::Level::Error));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_own_identity() -> Result<()> {
+        let mut csprng = OsRng;
+        // Two devices of the same account share an identity key.
+        let identity = IdentityKeyPair::generate(&mut csprng);
+        let (mut sibling_record, own_state) = initialize_sessions(identity, identity)?;
+        let message = encrypt_signal_message(b"hi", &mut sibling_record, identity)?;
+
+        let sibling_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let mut out = Vec::new();
+        let mut own_record = SessionRecord::new(own_state);
+        decrypt_message_with_record(
+            &sibling_address,
+            &mut own_record.clone(),
+            &message,
+            &mut csprng,
+            &DecryptionConfig::default(),
+            &mut out,
+        )?;
+        assert_eq!(out, b"hi");
+
+        let strict_config = DecryptionConfig {
+            reject_own_identity: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            decrypt_message_with_record(
+                &sibling_address,
+                &mut own_record,
+                &message,
+                &mut csprng,
+                &strict_config,
+                &mut out,
+            ),
+            Err(SignalProtocolError::ReflectedIdentity(_))
+        ));
+
+        // A reflected state does not stop other states from decrypting the message.
+        let other_identity = IdentityKeyPair::generate(&mut csprng);
+        let (mut alice_record, bob_state) = initialize_sessions(other_identity, identity)?;
+        let message = encrypt_signal_message(b"hello", &mut alice_record, other_identity)?;
+        let mut bob_record = SessionRecord::new(bob_state);
+        bob_record.archive_current_state()?;
+        bob_record.set_session_state(own_record.session_state()?.clone())?;
+        decrypt_message_with_record(
+            &sibling_address,
+            &mut bob_record,
+            &message,
+            &mut csprng,
+            &strict_config,
+            &mut out,
+        )?;
+        assert_eq!(out, b"hello");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_inflate_into_reuses_buffer_and_limits_size() -> Result<()> {
+        let ptext = vec![b'a'; 100_000];
+        let compressed = miniz_oxide::deflate::compress_to_vec(&ptext, 6);
+        let mut out = Vec::with_capacity(200_000);
+        let buffer = out.as_ptr();
+        inflate_into(&compressed, &mut out)?;
+        assert_eq!(out, ptext);
+        assert_eq!(out.as_ptr(), buffer);
+
+        let bomb =
+            miniz_oxide::deflate::compress_to_vec(&vec![0; MAX_DECOMPRESSED_MESSAGE_LEN + 1], 6);
+        assert!(matches!(
+            inflate_into(&bomb, &mut out),
+            Err(SignalProtocolError::MessageTooLong(
+                MAX_DECOMPRESSED_MESSAGE_LEN
+            ))
+        ));
+        assert!(out.is_empty());
+
+        assert!(matches!(
+            inflate_into(b"not deflate", &mut out),
+            Err(SignalProtocolError::InvalidMessage(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypt_rejects_unsupported_session_version() -> Result<()> {
+        let mut structure = SessionStructure::from(bob_session_state()?);
+        structure.session_version = 2;
+        let mut record = SessionRecord::new(SessionState::from(structure));
+
+        let remote_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let mut identity_store =
+            InMemIdentityKeyStore::new(IdentityKeyPair::generate(&mut OsRng), 1);
+        let result = encrypt_with_record(
+            b"hi",
+            &remote_address,
+            &mut record,
+            &mut identity_store,
+            &EncryptionConfig::default(),
+            None,
+        )
+        .now_or_never()
+        .expect("sync");
+
+        assert!(matches!(
+            result,
+            Err(SignalProtocolError::UnsupportedSessionVersion(2))
+        ));
+        Ok(())
+    }
+}
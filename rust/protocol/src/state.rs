@@ -10,6 +10,9 @@ mod signed_prekey;
 
 pub use bundle::PreKeyBundle;
 pub use prekey::{PreKeyId, PreKeyRecord};
-pub use session::SessionRecord;
 pub(crate) use session::SessionState;
+pub use session::{
+    ClassifyResult, KeyWrapper, ProtobufSessionSerializer, ReadOnlySessionRecord, SessionMetrics,
+    SessionRecord, SessionSerializer,
+};
 pub use signed_prekey::{SignedPreKeyId, SignedPreKeyRecord};
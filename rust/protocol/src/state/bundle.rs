@@ -3,8 +3,11 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use crate::proto;
 use crate::state::{PreKeyId, SignedPreKeyId};
-use crate::{IdentityKey, PublicKey, Result};
+use crate::{IdentityKey, PublicKey, Result, SignalProtocolError};
+
+use prost::Message;
 
 #[derive(Debug, Clone)]
 pub struct PreKeyBundle {
@@ -16,6 +19,7 @@ pub struct PreKeyBundle {
     signed_pre_key_public: PublicKey,
     signed_pre_key_signature: Vec<u8>,
     identity_key: IdentityKey,
+    capabilities: u32,
 }
 
 impl PreKeyBundle {
@@ -42,9 +46,20 @@ impl PreKeyBundle {
             signed_pre_key_public,
             signed_pre_key_signature,
             identity_key,
+            capabilities: 0,
         })
     }
 
+    /// Records the features the bundle's owner advertises, as an application-defined bitmap.
+    ///
+    /// Sessions set up from this bundle remember these; see [`SessionRecord::peer_supports`].
+    ///
+    /// [`SessionRecord::peer_supports`]: crate::SessionRecord::peer_supports
+    pub fn with_capabilities(mut self, capabilities: u32) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
     pub fn registration_id(&self) -> Result<u32> {
         Ok(self.registration_id)
     }
@@ -76,4 +91,65 @@ impl PreKeyBundle {
     pub fn identity_key(&self) -> Result<&IdentityKey> {
         Ok(&self.identity_key)
     }
+
+    pub fn capabilities(&self) -> Result<u32> {
+        Ok(self.capabilities)
+    }
+
+    /// Parses a bundle written by [`serialize`](Self::serialize).
+    ///
+    /// Only the structure of the bundle is checked here; the signature on its signed pre-key is
+    /// verified when the bundle is processed.
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        let bundle = proto::wire::PreKeyBundle::decode(data)?;
+
+        let pre_key = match (bundle.pre_key_id, bundle.pre_key_public) {
+            (None, None) => None,
+            (Some(id), Some(key)) => Some((id, PublicKey::deserialize(&key)?)),
+            _ => return Err(SignalProtocolError::InvalidProtobufEncoding),
+        };
+        let signed_pre_key_public = bundle
+            .signed_pre_key_public
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let identity_key = bundle
+            .identity_key
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+
+        Ok(Self::new(
+            bundle
+                .registration_id
+                .ok_or(SignalProtocolError::InvalidProtobufEncoding)?,
+            bundle
+                .device_id
+                .ok_or(SignalProtocolError::InvalidProtobufEncoding)?,
+            pre_key,
+            bundle
+                .signed_pre_key_id
+                .ok_or(SignalProtocolError::InvalidProtobufEncoding)?,
+            PublicKey::deserialize(&signed_pre_key_public)?,
+            bundle
+                .signed_pre_key_signature
+                .ok_or(SignalProtocolError::InvalidProtobufEncoding)?,
+            IdentityKey::decode(&identity_key)?,
+        )?
+        .with_capabilities(bundle.capabilities.unwrap_or(0)))
+    }
+
+    /// Serializes the bundle as a protobuf message, so that it can be handed out as a single
+    /// blob and later passed to [`deserialize`](Self::deserialize) or
+    /// [`process_prekey_bundle_bytes`](crate::process_prekey_bundle_bytes).
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let bundle = proto::wire::PreKeyBundle {
+            registration_id: Some(self.registration_id),
+            device_id: Some(self.device_id),
+            pre_key_id: self.pre_key_id,
+            pre_key_public: self.pre_key_public.map(|key| key.serialize().into_vec()),
+            signed_pre_key_id: Some(self.signed_pre_key_id),
+            signed_pre_key_public: Some(self.signed_pre_key_public.serialize().into_vec()),
+            signed_pre_key_signature: Some(self.signed_pre_key_signature.clone()),
+            identity_key: Some(self.identity_key.serialize().into_vec()),
+            capabilities: Some(self.capabilities).filter(|&capabilities| capabilities != 0),
+        };
+        Ok(bundle.encode_to_vec())
+    }
 }
@@ -23,10 +23,18 @@ impl PreKeyRecord {
                 id,
                 public_key,
                 private_key,
+                timestamp: 0,
             },
         }
     }
 
+    /// Records when this pre-key was uploaded, for use with
+    /// [`PreKeyStore::expired_pre_keys`](crate::PreKeyStore::expired_pre_keys).
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.pre_key.timestamp = timestamp;
+        self
+    }
+
     pub fn deserialize(data: &[u8]) -> Result<Self> {
         Ok(Self {
             pre_key: PreKeyRecordStructure::decode(data)?,
@@ -37,6 +45,11 @@ impl PreKeyRecord {
         Ok(self.pre_key.id)
     }
 
+    /// When this pre-key was uploaded, or 0 if that was not recorded.
+    pub fn timestamp(&self) -> Result<u64> {
+        Ok(self.pre_key.timestamp)
+    }
+
     pub fn key_pair(&self) -> Result<KeyPair> {
         KeyPair::from_public_and_private(&self.pre_key.public_key, &self.pre_key.private_key)
     }
@@ -3,7 +3,13 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use arrayref::array_ref;
 use prost::Message;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
 
 use crate::ratchet::{ChainKey, MessageKeys, RootKey};
 use crate::{IdentityKey, KeyPair, PrivateKey, PublicKey, Result, SignalProtocolError};
@@ -13,6 +19,17 @@ use crate::proto::storage::session_structure;
 use crate::proto::storage::{RecordStructure, SessionStructure};
 use crate::state::{PreKeyId, SignedPreKeyId};
 
+/// The chain index of a receiver chain created only to hold imported message keys; see
+/// [`SessionState::import_message_keys`]. Such chains never derive further keys.
+const IMPORTED_CHAIN_INDEX: u32 = u32::MAX;
+
+fn is_imported_chain(chain: &session_structure::Chain) -> bool {
+    chain
+        .chain_key
+        .as_ref()
+        .map_or(false, |chain_key| chain_key.index == IMPORTED_CHAIN_INDEX)
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct UnacknowledgedPreKeyMessageItems {
     pre_key_id: Option<PreKeyId>,
@@ -91,6 +108,28 @@ impl SessionState {
         IdentityKey::decode(&self.session.local_identity_public)
     }
 
+    /// An identifier for this session that does not change as it ratchets forward; see
+    /// [`SessionRecord::stable_id`].
+    pub(crate) fn stable_id(&self) -> Result<[u8; 16]> {
+        let base_key = self.alice_base_key()?;
+        if base_key.is_empty() {
+            return Err(SignalProtocolError::InvalidState(
+                "stable_id",
+                "session does not record its base key".to_string(),
+            ));
+        }
+        let remote_identity = self
+            .remote_identity_key()?
+            .ok_or(SignalProtocolError::InvalidSessionStructure)?;
+
+        let digest = Sha256::new()
+            .chain(b"Signal_SessionStableId")
+            .chain(remote_identity.serialize())
+            .chain(base_key)
+            .finalize();
+        Ok(*array_ref![digest, 0, 16])
+    }
+
     pub(crate) fn local_identity_key_bytes(&self) -> Result<Vec<u8>> {
         Ok(self.local_identity_key()?.serialize().to_vec())
     }
@@ -199,6 +238,83 @@ impl SessionState {
         }
     }
 
+    /// Whether this session already has a receiver chain for `their_ephemeral`, so that a message
+    /// with that sender ratchet key can be decrypted without a DH ratchet step.
+    pub(crate) fn knows_ratchet_key(&self, their_ephemeral: &PublicKey) -> Result<bool> {
+        Ok(self.get_receiver_chain_key(their_ephemeral)?.is_some())
+    }
+
+    /// The index of the next message expected on the receiver chain for `sender`, i.e. how many
+    /// messages have been processed (or skipped) on that chain, or `None` if there is no such
+    /// chain.
+    pub(crate) fn receiver_chain_index(&self, sender: &PublicKey) -> Result<Option<u32>> {
+        Ok(self
+            .get_receiver_chain_key(sender)?
+            .map(|chain_key| chain_key.index()))
+    }
+
+    /// See [`SessionRecord::forward_secrecy_window`].
+    pub(crate) fn forward_secrecy_window(&self) -> Result<Option<(u32, u32)>> {
+        let chain = match self.session.receiver_chains.last() {
+            Some(chain) => chain,
+            None => return Ok(None),
+        };
+        let current = chain
+            .chain_key
+            .as_ref()
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?
+            .index;
+        let oldest_stored = chain
+            .message_keys
+            .iter()
+            .map(|message_key| message_key.index)
+            .min()
+            .unwrap_or(current);
+        Ok(Some((oldest_stored, current)))
+    }
+
+    pub(crate) fn classify_messages(
+        &self,
+        messages: &[(PublicKey, u32)],
+    ) -> Result<ClassifyResult> {
+        let session_with_self = self.session_with_self()?;
+        let mut seen = HashSet::with_capacity(messages.len());
+        let mut result = ClassifyResult::default();
+        for (sender, counter) in messages {
+            let counter = *counter;
+            if !seen.insert((sender.serialize(), counter)) {
+                result.duplicates += 1;
+                continue;
+            }
+            // A message on an unknown chain starts a new one at index 0.
+            let (chain_index, has_stored_key) = match self.get_receiver_chain(sender)? {
+                Some((chain, _)) => (
+                    chain
+                        .chain_key
+                        .as_ref()
+                        .ok_or(SignalProtocolError::InvalidProtobufEncoding)?
+                        .index,
+                    chain.message_keys.iter().any(|m| m.index == counter),
+                ),
+                None => (0, false),
+            };
+            if counter < chain_index {
+                if has_stored_key {
+                    result.cached += 1;
+                } else {
+                    result.duplicates += 1;
+                }
+            } else if (counter - chain_index) as usize > consts::MAX_FORWARD_JUMPS
+                && !session_with_self
+            {
+                result.too_far += 1;
+            } else {
+                result.needs_ratchet += 1;
+            }
+        }
+        Ok(result)
+    }
+
     pub(crate) fn add_receiver_chain(
         &mut self,
         sender: &PublicKey,
@@ -218,14 +334,27 @@ impl SessionState {
 
         self.session.receiver_chains.push(chain);
 
-        if self.session.receiver_chains.len() > consts::MAX_RECEIVER_CHAINS {
+        // Chains holding only imported keys are capped separately and never push out live ones.
+        let live_chains = self
+            .session
+            .receiver_chains
+            .iter()
+            .filter(|chain| !is_imported_chain(chain))
+            .count();
+        if live_chains > consts::MAX_RECEIVER_CHAINS {
             log::info!(
                 "Trimming excessive receiver_chain for session with base key {}, chain count: {}",
                 self.sender_ratchet_key_for_logging()
                     .unwrap_or_else(|e| format!("<error: {}>", e)),
-                self.session.receiver_chains.len()
+                live_chains
             );
-            self.session.receiver_chains.remove(0);
+            let oldest = self
+                .session
+                .receiver_chains
+                .iter()
+                .position(|chain| !is_imported_chain(chain))
+                .expect("counted above");
+            self.session.receiver_chains.remove(oldest);
         }
 
         Ok(())
@@ -265,6 +394,39 @@ impl SessionState {
         ChainKey::new(&chain_key.key, chain_key.index)
     }
 
+    /// The number of messages sent on the current sender chain, i.e. since our last DH ratchet
+    /// step.
+    pub(crate) fn messages_since_ratchet(&self) -> Result<u32> {
+        Ok(self.get_sender_chain_key()?.index())
+    }
+
+    /// See [`SessionRecord::is_likely_desynced`].
+    pub(crate) fn is_likely_desynced(&self) -> Result<bool> {
+        if !self.has_sender_chain()? {
+            return Ok(false);
+        }
+        let heard_from_peer = self.session.receiver_chains.iter().any(|chain| {
+            chain
+                .chain_key
+                .as_ref()
+                .map_or(false, |chain_key| chain_key.index > 0)
+        });
+        Ok(!heard_from_peer && self.messages_since_ratchet()? as usize >= consts::MAX_MESSAGE_KEYS)
+    }
+
+    /// Steps the sender chain forward `steps` times, as if that many messages had been sent,
+    /// without deriving or storing any message keys.
+    ///
+    /// Fails without changing the state if the chain would run past `u32::MAX`.
+    #[cfg(feature = "test-internals")]
+    pub(crate) fn advance_sender_chain(&mut self, steps: u32) -> Result<()> {
+        let mut chain_key = self.get_sender_chain_key()?;
+        for _ in 0..steps {
+            chain_key = chain_key.next_chain_key()?;
+        }
+        self.set_sender_chain_key(&chain_key)
+    }
+
     pub(crate) fn get_sender_chain_key_bytes(&self) -> Result<Vec<u8>> {
         Ok(self.get_sender_chain_key()?.key().to_vec())
     }
@@ -307,6 +469,9 @@ impl SessionState {
                 .iter()
                 .position(|m| m.index == counter);
             if let Some(position) = message_key_idx {
+                if !chain_and_index.0.message_keys[position].wrapped.is_empty() {
+                    return Err(SignalProtocolError::MessageKeysWrapped);
+                }
                 let message_key = chain_and_index.0.message_keys.remove(position);
 
                 let keys = MessageKeys::new(
@@ -325,6 +490,80 @@ impl SessionState {
         Ok(None)
     }
 
+    /// Like [`get_message_keys`](Self::get_message_keys), but does the same work whether or not
+    /// the keys for `counter` are stored, and wherever they are in the chain.
+    ///
+    /// Only the lookup within the chain for `sender` is timing-normalized; finding the chain
+    /// itself is not, since the sender ratchet key is public.
+    pub(crate) fn get_message_keys_constant_time(
+        &mut self,
+        sender: &PublicKey,
+        counter: u32,
+    ) -> Result<Option<MessageKeys>> {
+        let (mut chain, chain_idx) = match self.get_receiver_chain(sender)? {
+            Some(chain_and_index) => chain_and_index,
+            None => return Ok(None),
+        };
+
+        let mut found = Choice::from(0);
+        let mut position = 0u32;
+        for (i, message_key) in chain.message_keys.iter().enumerate() {
+            let is_match = message_key.index.ct_eq(&counter);
+            position.conditional_assign(&(i as u32), is_match);
+            found |= is_match;
+        }
+
+        // Build keys from whichever entry was selected (or from zeros for an empty chain) so
+        // that the work is the same when nothing matched.
+        let keys = match chain.message_keys.get(position as usize) {
+            Some(message_key) => MessageKeys::new(
+                &message_key.cipher_key,
+                &message_key.mac_key,
+                &message_key.iv,
+                counter,
+            ),
+            None => MessageKeys::new(&[0; 32], &[0; 32], &[0; 16], counter),
+        };
+
+        let found: bool = found.into();
+        if found {
+            if !chain.message_keys[position as usize].wrapped.is_empty() {
+                return Err(SignalProtocolError::MessageKeysWrapped);
+            }
+            chain.message_keys.remove(position as usize);
+        }
+        self.session.receiver_chains[chain_idx] = chain;
+
+        if found {
+            Ok(Some(keys?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Like [`get_message_keys`](Self::get_message_keys), but leaves the keys in place.
+    pub(crate) fn message_keys_for(
+        &self,
+        sender: &PublicKey,
+        counter: u32,
+    ) -> Result<Option<MessageKeys>> {
+        if let Some((chain, _)) = self.get_receiver_chain(sender)? {
+            if let Some(message_key) = chain.message_keys.iter().find(|m| m.index == counter) {
+                if !message_key.wrapped.is_empty() {
+                    return Err(SignalProtocolError::MessageKeysWrapped);
+                }
+                return Ok(Some(MessageKeys::new(
+                    &message_key.cipher_key,
+                    &message_key.mac_key,
+                    &message_key.iv,
+                    counter,
+                )?));
+            }
+        }
+
+        Ok(None)
+    }
+
     pub(crate) fn set_message_keys(
         &mut self,
         sender: &PublicKey,
@@ -335,6 +574,7 @@ impl SessionState {
             mac_key: message_keys.mac_key().to_vec(),
             iv: message_keys.iv().to_vec(),
             index: message_keys.counter(),
+            wrapped: vec![],
         };
 
         if let Some(chain_and_index) = self.get_receiver_chain(sender)? {
@@ -355,6 +595,199 @@ impl SessionState {
         }
     }
 
+    /// Returns every message key stored on this session's receiver chains, along with the sender
+    /// ratchet key and counter it belongs to.
+    pub(crate) fn export_message_keys(&self) -> Result<Vec<(PublicKey, u32, MessageKeys)>> {
+        let mut exported = Vec::new();
+        for chain in &self.session.receiver_chains {
+            let sender = PublicKey::deserialize(&chain.sender_ratchet_key)?;
+            for message_key in &chain.message_keys {
+                exported.push((
+                    sender,
+                    message_key.index,
+                    MessageKeys::new(
+                        &message_key.cipher_key,
+                        &message_key.mac_key,
+                        &message_key.iv,
+                        message_key.index,
+                    )?,
+                ));
+            }
+        }
+        Ok(exported)
+    }
+
+    /// Stores message keys previously returned by [`export_message_keys`](Self::export_message_keys).
+    ///
+    /// Keys that are already stored are skipped. Keys for a sender ratchet key this session has
+    /// no chain for are stored on a new, exhausted chain, which can only be used to decrypt the
+    /// imported messages. These chains do not count towards the limit on receiver chains, so
+    /// importing never evicts a live chain; instead, at most
+    /// [`consts::MAX_IMPORTED_RECEIVER_CHAINS`] of them are kept, and an import that would need
+    /// more fails with [`SignalProtocolError::InvalidArgument`] without changing the session.
+    pub(crate) fn import_message_keys(
+        &mut self,
+        message_keys: &[(PublicKey, u32, MessageKeys)],
+    ) -> Result<()> {
+        let mut new_senders: Vec<&PublicKey> = Vec::new();
+        for (sender, counter, keys) in message_keys {
+            if *counter != keys.counter() {
+                return Err(SignalProtocolError::InvalidArgument(format!(
+                    "message keys for counter {} imported as counter {}",
+                    keys.counter(),
+                    counter
+                )));
+            }
+            if self.get_receiver_chain(sender)?.is_none() && !new_senders.contains(&sender) {
+                new_senders.push(sender);
+            }
+        }
+        let imported_chains = self
+            .session
+            .receiver_chains
+            .iter()
+            .filter(|chain| is_imported_chain(chain))
+            .count();
+        if imported_chains + new_senders.len() > consts::MAX_IMPORTED_RECEIVER_CHAINS {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "importing keys for {} more sender ratchet keys would exceed the limit of {}",
+                new_senders.len(),
+                consts::MAX_IMPORTED_RECEIVER_CHAINS
+            )));
+        }
+
+        // Stored keys are kept newest-first, so insert in reverse to preserve the exported order.
+        for (sender, counter, keys) in message_keys.iter().rev() {
+            if self.get_receiver_chain(sender)?.is_none() {
+                self.add_receiver_chain(sender, &ChainKey::new(&[0; 32], IMPORTED_CHAIN_INDEX)?)?;
+            }
+            if self.message_keys_for(sender, *counter)?.is_none() {
+                self.set_message_keys(sender, keys)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges receiver chains that belong to the same sender ratchet key, returning how many
+    /// chains were removed.
+    ///
+    /// Each merged chain keeps the position of the first duplicate (the one lookups find), the
+    /// chain key of whichever duplicate is further along, and every message key stored on any of
+    /// them. Keys stored on more than one duplicate are kept once. No keys are evicted, even if the
+    /// merged chain ends up holding more than [`consts::MAX_MESSAGE_KEYS`].
+    pub(crate) fn dedup_receiver_chains(&mut self) -> Result<usize> {
+        let mut merged: Vec<(Vec<u8>, session_structure::Chain)> =
+            Vec::with_capacity(self.session.receiver_chains.len());
+
+        for chain in &self.session.receiver_chains {
+            // Compare canonical encodings, as get_receiver_chain does.
+            let sender = PublicKey::deserialize(&chain.sender_ratchet_key)?
+                .serialize()
+                .into_vec();
+            let existing = match merged.iter().position(|(s, _)| *s == sender) {
+                Some(position) => &mut merged[position].1,
+                None => {
+                    merged.push((sender, chain.clone()));
+                    continue;
+                }
+            };
+
+            let index = |chain: &session_structure::Chain| {
+                chain
+                    .chain_key
+                    .as_ref()
+                    .map(|chain_key| chain_key.index)
+                    .ok_or(SignalProtocolError::InvalidProtobufEncoding)
+            };
+            if index(chain)? > index(&*existing)? {
+                existing.chain_key = chain.chain_key.clone();
+            }
+            for message_key in &chain.message_keys {
+                if !existing
+                    .message_keys
+                    .iter()
+                    .any(|m| m.index == message_key.index)
+                {
+                    existing.message_keys.push(message_key.clone());
+                }
+            }
+        }
+
+        let removed = self.session.receiver_chains.len() - merged.len();
+        if removed > 0 {
+            log::info!(
+                "Merged {} duplicate receiver chain(s) for session with base key {}",
+                removed,
+                self.sender_ratchet_key_for_logging()
+                    .unwrap_or_else(|e| format!("<error: {}>", e)),
+            );
+            self.session.receiver_chains = merged.into_iter().map(|(_, chain)| chain).collect();
+        }
+        Ok(removed)
+    }
+
+    /// Evicts receiver chains other than the newest one, oldest first, and then the newest chain's
+    /// skipped message keys, oldest first, until at least `excess` bytes of the encoded session
+    /// have been freed.
+    ///
+    /// Returns how many of the `excess` bytes could not be freed. The root key, the sender chain,
+    /// and the newest receiver chain's chain key are never evicted.
+    pub(crate) fn evict_receiver_data(&mut self, mut excess: usize) -> usize {
+        let chains = &mut self.session.receiver_chains;
+        let mut evicted_chains = 0;
+        while excess > 0 && chains.len() > 1 {
+            let chain = chains.remove(0);
+            excess = excess.saturating_sub(prost::encoding::message::encoded_len(7, &chain));
+            evicted_chains += 1;
+        }
+
+        let mut evicted_keys = 0;
+        if let Some(newest) = chains.last_mut() {
+            while excess > 0 {
+                // Stored keys are kept newest-first.
+                match newest.message_keys.pop() {
+                    Some(message_key) => {
+                        excess = excess
+                            .saturating_sub(prost::encoding::message::encoded_len(4, &message_key));
+                        evicted_keys += 1;
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        if evicted_chains > 0 || evicted_keys > 0 {
+            log::info!(
+                "Evicted {} receiver chain(s) and {} message key(s) for session with base key {}",
+                evicted_chains,
+                evicted_keys,
+                self.sender_ratchet_key_for_logging()
+                    .unwrap_or_else(|e| format!("<error: {}>", e)),
+            );
+        }
+        excess
+    }
+
+    /// Overwrites and removes every stored message key on every receiver chain, returning how many
+    /// were removed. The chain keys themselves are kept, so later messages still decrypt.
+    pub(crate) fn purge_stored_message_keys(&mut self) -> usize {
+        let mut purged = 0;
+        for chain in &mut self.session.receiver_chains {
+            for mut message_key in chain.message_keys.drain(..) {
+                for bytes in [
+                    &mut message_key.cipher_key,
+                    &mut message_key.mac_key,
+                    &mut message_key.iv,
+                    &mut message_key.wrapped,
+                ] {
+                    bytes.iter_mut().for_each(|b| *b = 0);
+                }
+                purged += 1;
+            }
+        }
+        purged
+    }
+
     pub(crate) fn set_receiver_chain_key(
         &mut self,
         sender: &PublicKey,
@@ -409,9 +842,9 @@ impl SessionState {
         }
     }
 
-    pub(crate) fn clear_unacknowledged_pre_key_message(&mut self) -> Result<()> {
-        self.session.pending_pre_key = None;
-        Ok(())
+    /// Returns whether there was an unacknowledged pre-key message to clear.
+    pub(crate) fn clear_unacknowledged_pre_key_message(&mut self) -> Result<bool> {
+        Ok(self.session.pending_pre_key.take().is_some())
     }
 
     pub(crate) fn set_remote_registration_id(&mut self, registration_id: u32) -> Result<()> {
@@ -431,6 +864,75 @@ impl SessionState {
     pub(crate) fn local_registration_id(&self) -> Result<u32> {
         Ok(self.session.local_registration_id)
     }
+
+    pub(crate) fn last_used_at(&self) -> Result<u64> {
+        Ok(self.session.last_used_at)
+    }
+
+    pub(crate) fn set_last_used_at(&mut self, timestamp: u64) -> Result<()> {
+        self.session.last_used_at = timestamp;
+        Ok(())
+    }
+
+    /// When this session was initialized, in milliseconds since the Unix epoch, or `None` if it
+    /// was created before this was recorded.
+    pub(crate) fn created_at(&self) -> Option<u64> {
+        match self.session.created_at {
+            0 => None,
+            t => Some(t),
+        }
+    }
+
+    /// The lowest message version this session accepts: the highest version it has used, which is
+    /// at least the version negotiated when it was set up.
+    pub(crate) fn highest_version_seen(&self) -> Result<u32> {
+        Ok(std::cmp::max(
+            self.session.highest_version_seen,
+            self.session_version()?,
+        ))
+    }
+
+    pub(crate) fn record_version_seen(&mut self, version: u32) -> Result<()> {
+        self.session.highest_version_seen = std::cmp::max(self.highest_version_seen()?, version);
+        Ok(())
+    }
+
+    pub(crate) fn set_capabilities(&mut self, capabilities: u32) -> Result<()> {
+        self.session.capabilities = capabilities;
+        Ok(())
+    }
+
+    /// Serializes the session's public parameters in a fixed format; see
+    /// [`SessionRecord::public_params_canonical`].
+    pub fn public_params_canonical(&self) -> Result<Vec<u8>> {
+        fn put_key(out: &mut Vec<u8>, key: Option<&[u8]>) {
+            let key = key.unwrap_or_default();
+            out.extend_from_slice(&(key.len() as u16).to_be_bytes());
+            out.extend_from_slice(key);
+        }
+
+        let remote_identity = self.remote_identity_key()?.map(|key| key.serialize());
+        let (sender_ratchet_key, sender_chain_index) = if self.has_sender_chain()? {
+            (
+                Some(self.sender_ratchet_key()?.serialize()),
+                self.get_sender_chain_key()?.index(),
+            )
+        } else {
+            (None, 0)
+        };
+
+        let mut out = Vec::with_capacity(4 + 2 + 33 + 2 + 33 + 4 + 4);
+        out.extend_from_slice(&self.session_version()?.to_be_bytes());
+        put_key(&mut out, remote_identity.as_deref());
+        put_key(&mut out, sender_ratchet_key.as_deref());
+        out.extend_from_slice(&sender_chain_index.to_be_bytes());
+        out.extend_from_slice(&self.previous_counter()?.to_be_bytes());
+        Ok(out)
+    }
+
+    pub fn peer_supports(&self, flag: u32) -> bool {
+        self.session.capabilities & flag == flag
+    }
 }
 
 impl From<SessionStructure> for SessionState {
@@ -451,10 +953,162 @@ impl From<&SessionState> for SessionStructure {
     }
 }
 
-#[derive(Clone, Debug)]
+/// A snapshot of the size and progress of a [`SessionRecord`]; see [`SessionRecord::metrics`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionMetrics {
+    /// The index of the next message to be sent, if the current session can send.
+    pub sender_chain_index: Option<u32>,
+    /// The index of the next message expected on the most recent receiver chain, if any.
+    pub receiver_chain_index: Option<u32>,
+    /// The number of receiver chains in the current session.
+    pub receiver_chain_count: usize,
+    /// The number of skipped message keys stored across the current session's receiver chains.
+    pub stored_message_key_count: usize,
+    /// The number of archived previous sessions.
+    pub previous_state_count: usize,
+}
+
+/// How the current session of a [`SessionRecord`] would decrypt a batch of messages; see
+/// [`SessionRecord::classify_messages`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClassifyResult {
+    /// Messages whose keys are stored, which decrypt without advancing any chain.
+    pub cached: usize,
+    /// Messages at or ahead of their chain's index, or on a chain the session does not have yet.
+    pub needs_ratchet: usize,
+    /// Messages whose keys have already been used, including repeats within the batch.
+    pub duplicates: usize,
+    /// Messages too far ahead of their chain to be decrypted.
+    pub too_far: usize,
+}
+
+/// Encrypts the message keys a [`SessionRecord`] stores for messages that have not arrived yet,
+/// whenever the record is serialized.
+///
+/// See [`SessionRecord::set_key_wrapper`].
+pub trait KeyWrapper: Send + Sync {
+    /// Encrypts `keys`; the result is stored in the serialized record in their place.
+    fn wrap(&self, keys: &[u8]) -> Result<Vec<u8>>;
+
+    /// Decrypts the output of [`wrap`](Self::wrap).
+    ///
+    /// This should fail rather than return garbage if `wrapped` was not produced by this wrapper,
+    /// e.g. by using an authenticated cipher.
+    fn unwrap(&self, wrapped: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Converts a [`SessionRecord`] to and from bytes.
+///
+/// [`SessionRecord::serialize`] and [`SessionRecord::deserialize`] use
+/// [`ProtobufSessionSerializer`]. Another implementation can be used to store records in a
+/// different format, typically by translating the protobuf encoding.
+pub trait SessionSerializer: Send + Sync {
+    fn serialize(&self, record: &SessionRecord) -> Result<Vec<u8>>;
+
+    /// Decodes the output of [`serialize`](Self::serialize).
+    fn deserialize(&self, bytes: &[u8]) -> Result<SessionRecord>;
+}
+
+/// The protobuf encoding used by [`SessionRecord::serialize`].
+///
+/// Deserialized records have no [key wrapper](SessionRecord::set_key_wrapper); it must be set
+/// again after loading.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProtobufSessionSerializer;
+
+impl SessionSerializer for ProtobufSessionSerializer {
+    fn serialize(&self, record: &SessionRecord) -> Result<Vec<u8>> {
+        let mut structure = RecordStructure {
+            current_session: record.current_session.as_ref().map(|s| s.into()),
+            previous_sessions: record.previous_sessions.clone(),
+            record_version: record.record_version,
+            app_label: record.app_label.clone().unwrap_or_default(),
+        };
+        if let Some(key_wrapper) = &record.key_wrapper {
+            if let Some(session) = &mut structure.current_session {
+                wrap_message_keys(session, key_wrapper.as_ref())?;
+            }
+            for bytes in &mut structure.previous_sessions {
+                let mut session = SessionStructure::decode(&bytes[..])?;
+                wrap_message_keys(&mut session, key_wrapper.as_ref())?;
+                *bytes = session.encode_to_vec();
+            }
+        }
+        Ok(structure.encode_to_vec())
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<SessionRecord> {
+        let structure = RecordStructure::decode(bytes)?;
+
+        Ok(SessionRecord {
+            current_session: structure.current_session.map(|s| s.into()),
+            previous_sessions: structure.previous_sessions,
+            record_version: structure.record_version,
+            app_label: Some(structure.app_label).filter(|label| !label.is_empty()),
+            key_wrapper: None,
+        })
+    }
+}
+
+const WRAPPED_MESSAGE_KEYS_LEN: usize = 32 + 32 + 16;
+
+fn wrap_message_keys(session: &mut SessionStructure, key_wrapper: &dyn KeyWrapper) -> Result<()> {
+    for chain in &mut session.receiver_chains {
+        for message_key in &mut chain.message_keys {
+            if !message_key.wrapped.is_empty() {
+                continue;
+            }
+            let mut keys = Vec::with_capacity(WRAPPED_MESSAGE_KEYS_LEN);
+            keys.append(&mut message_key.cipher_key);
+            keys.append(&mut message_key.mac_key);
+            keys.append(&mut message_key.iv);
+            message_key.wrapped = key_wrapper.wrap(&keys)?;
+        }
+    }
+    Ok(())
+}
+
+fn unwrap_message_keys(session: &mut SessionStructure, key_wrapper: &dyn KeyWrapper) -> Result<()> {
+    for chain in &mut session.receiver_chains {
+        for message_key in &mut chain.message_keys {
+            if message_key.wrapped.is_empty() {
+                continue;
+            }
+            let keys = key_wrapper.unwrap(&message_key.wrapped)?;
+            if keys.len() != WRAPPED_MESSAGE_KEYS_LEN {
+                return Err(SignalProtocolError::InvalidSessionStructure);
+            }
+            message_key.cipher_key = keys[..32].to_vec();
+            message_key.mac_key = keys[32..64].to_vec();
+            message_key.iv = keys[64..].to_vec();
+            message_key.wrapped.clear();
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
 pub struct SessionRecord {
     current_session: Option<SessionState>,
     previous_sessions: Vec<Vec<u8>>,
+    record_version: u64,
+    app_label: Option<Vec<u8>>,
+    key_wrapper: Option<Arc<dyn KeyWrapper>>,
+}
+
+impl fmt::Debug for SessionRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionRecord")
+            .field("current_session", &self.current_session)
+            .field("previous_sessions", &self.previous_sessions)
+            .field("record_version", &self.record_version)
+            .field("app_label", &self.app_label)
+            .field(
+                "key_wrapper",
+                &self.key_wrapper.as_ref().map(|_| "<wrapper>"),
+            )
+            .finish()
+    }
 }
 
 impl SessionRecord {
@@ -462,6 +1116,9 @@ impl SessionRecord {
         Self {
             current_session: None,
             previous_sessions: Vec::new(),
+            record_version: 0,
+            app_label: None,
+            key_wrapper: None,
         }
     }
 
@@ -469,16 +1126,20 @@ impl SessionRecord {
         Self {
             current_session: Some(state),
             previous_sessions: Vec::new(),
+            record_version: 0,
+            app_label: None,
+            key_wrapper: None,
         }
     }
 
     pub fn deserialize(bytes: &[u8]) -> Result<Self> {
-        let record = RecordStructure::decode(bytes)?;
+        Self::deserialize_with(bytes, &ProtobufSessionSerializer)
+    }
 
-        Ok(Self {
-            current_session: record.current_session.map(|s| s.into()),
-            previous_sessions: record.previous_sessions,
-        })
+    /// Decodes a record produced by [`serialize_with`](Self::serialize_with) with the same
+    /// `serializer`.
+    pub fn deserialize_with(bytes: &[u8], serializer: &dyn SessionSerializer) -> Result<Self> {
+        serializer.deserialize(bytes)
     }
 
     pub fn from_single_session_state(bytes: &[u8]) -> Result<Self> {
@@ -486,6 +1147,9 @@ impl SessionRecord {
         Ok(Self {
             current_session: Some(session),
             previous_sessions: Vec::new(),
+            record_version: 0,
+            app_label: None,
+            key_wrapper: None,
         })
     }
 
@@ -510,10 +1174,46 @@ impl SessionRecord {
         Ok(false)
     }
 
+    /// An opaque token identifying this revision of the record.
+    ///
+    /// The token changes every time the record is updated by this library, so a store can use it
+    /// to detect concurrent modification; see [`SessionStore::store_session_if_version`].
+    ///
+    /// [`SessionStore::store_session_if_version`]: crate::SessionStore::store_session_if_version
+    pub fn record_version(&self) -> u64 {
+        self.record_version
+    }
+
+    pub(crate) fn increment_record_version(&mut self) {
+        self.record_version = self.record_version.wrapping_add(1);
+    }
+
+    /// The label set with [`set_app_label`](Self::set_app_label), if any.
+    pub fn app_label(&self) -> Option<&[u8]> {
+        self.app_label.as_deref()
+    }
+
+    /// Attaches an opaque label to this record, e.g. to tell apart sessions used for different
+    /// purposes.
+    ///
+    /// The label is kept across serialization and archiving but is otherwise ignored by this
+    /// library. An empty label is the same as no label.
+    pub fn set_app_label(&mut self, app_label: Option<Vec<u8>>) {
+        self.app_label = app_label.filter(|label| !label.is_empty());
+    }
+
     pub fn has_current_session_state(&self) -> bool {
         self.current_session.is_some()
     }
 
+    /// The number of archived previous sessions.
+    ///
+    /// Unlike [`metrics`](Self::metrics), this does not decode any session state, so it is cheap
+    /// enough to call on every session in a store.
+    pub fn previous_state_count(&self) -> usize {
+        self.previous_sessions.len()
+    }
+
     pub(crate) fn session_state(&self) -> Result<&SessionState> {
         if let Some(ref session) = self.current_session {
             Ok(session)
@@ -549,6 +1249,10 @@ impl SessionRecord {
             .map(|bytes| Ok(SessionStructure::decode(&bytes[..])?.into()))
     }
 
+    /// Makes `updated_session` (the new contents of previous state `old_session`) current.
+    ///
+    /// The state that was current is archived rather than discarded; since the promoted state is
+    /// removed from the previous states first, no state is dropped, even when the archive is full.
     pub(crate) fn promote_old_session(
         &mut self,
         old_session: usize,
@@ -570,6 +1274,29 @@ impl SessionRecord {
         Ok(())
     }
 
+    /// Advances the sender chain of the current session by `steps` messages without encrypting
+    /// anything, so tests can set up large counter gaps or a nearly exhausted chain.
+    #[cfg(feature = "test-internals")]
+    pub fn advance_sender_chain(&mut self, steps: u32) -> Result<()> {
+        self.session_state_mut()?.advance_sender_chain(steps)
+    }
+
+    /// The sender chain key of the current session, so tests can compare it with the other side's
+    /// [receiver chain](Self::get_receiver_chain_key) for [`sender_ratchet_key`].
+    ///
+    /// [`sender_ratchet_key`]: Self::sender_ratchet_key
+    #[cfg(feature = "test-internals")]
+    pub fn sender_chain_key(&self) -> Result<ChainKey> {
+        self.session_state()?.get_sender_chain_key()
+    }
+
+    /// Our current ratchet public key in the current session, which the other side uses to look
+    /// up its receiver chain for our messages.
+    #[cfg(feature = "test-internals")]
+    pub fn sender_ratchet_key(&self) -> Result<PublicKey> {
+        self.session_state()?.sender_ratchet_key()
+    }
+
     pub fn archive_current_state(&mut self) -> Result<()> {
         if let Some(current_session) = self.current_session.take() {
             if self.previous_sessions.len() >= consts::ARCHIVED_STATES_MAX_LENGTH {
@@ -585,17 +1312,62 @@ impl SessionRecord {
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>> {
-        let record = RecordStructure {
-            current_session: self.current_session.as_ref().map(|s| s.into()),
-            previous_sessions: self.previous_sessions.clone(),
-        };
-        Ok(record.encode_to_vec())
+        self.serialize_with(&ProtobufSessionSerializer)
+    }
+
+    /// Like [`serialize`](Self::serialize), but in the format of `serializer`.
+    pub fn serialize_with(&self, serializer: &dyn SessionSerializer) -> Result<Vec<u8>> {
+        serializer.serialize(self)
+    }
+
+    /// Encrypts the stored message keys with `key_wrapper` whenever this record is serialized.
+    ///
+    /// Message keys that were wrapped when the record was serialized stay wrapped after
+    /// [`deserialize`](Self::deserialize), and the messages they belong to fail to decrypt with
+    /// [`SignalProtocolError::MessageKeysWrapped`] until the same wrapper is set again; this
+    /// unwraps them in place. Fails without changing the record if any of them cannot be
+    /// unwrapped.
+    ///
+    /// Only the skipped message keys are wrapped. Root and chain keys, from which future message
+    /// keys are derived, are still serialized as is.
+    pub fn set_key_wrapper(&mut self, key_wrapper: Box<dyn KeyWrapper>) -> Result<()> {
+        let mut current_session = self.current_session.clone();
+        if let Some(state) = &mut current_session {
+            unwrap_message_keys(&mut state.session, key_wrapper.as_ref())?;
+        }
+        let previous_sessions: Vec<Vec<u8>> = self
+            .previous_sessions
+            .iter()
+            .map(|bytes| {
+                let mut session = SessionStructure::decode(&bytes[..])?;
+                unwrap_message_keys(&mut session, key_wrapper.as_ref())?;
+                Ok(session.encode_to_vec())
+            })
+            .collect::<Result<_>>()?;
+
+        self.current_session = current_session;
+        self.previous_sessions = previous_sessions;
+        self.key_wrapper = Some(key_wrapper.into());
+        Ok(())
     }
 
     pub fn remote_registration_id(&self) -> Result<u32> {
         self.session_state()?.remote_registration_id()
     }
 
+    /// Like [`remote_registration_id`](Self::remote_registration_id), but returns `None` instead
+    /// of failing if there is no current session, and also if the current session does not record
+    /// a registration ID (such as one set up without a pre-key bundle).
+    pub fn remote_registration_id_opt(&self) -> Result<Option<u32>> {
+        match &self.current_session {
+            None => Ok(None),
+            Some(session) => match session.remote_registration_id()? {
+                0 => Ok(None),
+                id => Ok(Some(id)),
+            },
+        }
+    }
+
     pub fn local_registration_id(&self) -> Result<u32> {
         self.session_state()?.local_registration_id()
     }
@@ -623,18 +1395,472 @@ impl SessionRecord {
         self.session_state()?.alice_base_key()
     }
 
+    /// A 16-byte identifier for the current session, suitable for correlating log messages.
+    ///
+    /// It is derived from the remote identity key and the base key the session was set up with,
+    /// so it stays the same as the session ratchets forward, but differs between sessions (and
+    /// between the two ends of one session). Fails for sessions that were not set up from a
+    /// pre-key bundle or message, which do not record a base key.
+    pub fn stable_id(&self) -> Result<[u8; 16]> {
+        self.session_state()?.stable_id()
+    }
+
+    /// The base keys identifying the current session followed by each previous session, newest
+    /// first.
+    ///
+    /// Sessions that do not record a base key are skipped.
+    pub fn all_base_keys(&self) -> Result<Vec<PublicKey>> {
+        let mut base_keys = Vec::with_capacity(1 + self.previous_sessions.len());
+        if let Some(state) = &self.current_session {
+            let base_key = state.alice_base_key()?;
+            if !base_key.is_empty() {
+                base_keys.push(PublicKey::deserialize(base_key)?);
+            }
+        }
+        for state in self.previous_session_states() {
+            let state = state?;
+            let base_key = state.alice_base_key()?;
+            if !base_key.is_empty() {
+                base_keys.push(PublicKey::deserialize(base_key)?);
+            }
+        }
+        Ok(base_keys)
+    }
+
+    /// Returns the keys stored in the current session for message `counter` on the chain for
+    /// `sender`, without removing them or advancing any chain.
+    ///
+    /// Returns `None` if the keys are not stored, e.g. because the message was already decrypted
+    /// or its keys were evicted, or because it has not been received yet.
+    pub fn message_keys_for(
+        &self,
+        sender: &PublicKey,
+        counter: u32,
+    ) -> Result<Option<MessageKeys>> {
+        self.session_state()?.message_keys_for(sender, counter)
+    }
+
+    /// Returns every message key stored in the current session for messages that were skipped
+    /// over and not yet received, keyed by sender ratchet key and counter.
+    ///
+    /// The result can be backed up and later restored with
+    /// [`import_message_keys`](Self::import_message_keys).
+    pub fn export_message_keys(&self) -> Result<Vec<(PublicKey, u32, MessageKeys)>> {
+        self.session_state()?.export_message_keys()
+    }
+
+    /// Stores message keys previously returned by [`export_message_keys`](Self::export_message_keys)
+    /// in the current session, so that the corresponding messages can be decrypted again.
+    ///
+    /// Keys that are already stored are skipped. Keys for a sender ratchet key the current session
+    /// has no chain for are stored on a new chain that can only be used for the imported keys;
+    /// these chains never replace the session's live receiver chains, but only a limited number
+    /// are kept, and an import that would exceed it fails with
+    /// [`SignalProtocolError::InvalidArgument`] without changing the session. Decrypting with
+    /// imported keys still requires the current session to have the same identity keys as the one
+    /// they were exported from.
+    pub fn import_message_keys(
+        &mut self,
+        message_keys: &[(PublicKey, u32, MessageKeys)],
+    ) -> Result<()> {
+        self.session_state_mut()?.import_message_keys(message_keys)
+    }
+
     pub fn get_receiver_chain_key(&self, sender: &PublicKey) -> Result<Option<ChainKey>> {
         self.session_state()?.get_receiver_chain_key(sender)
     }
 
+    /// Repairs a current session that has more than one receiver chain for the same sender ratchet
+    /// key, returning how many chains were removed.
+    ///
+    /// Duplicates are merged into one chain that is as far along as the most advanced of them and
+    /// holds every message key stored on any of them, so no skipped message becomes undecryptable.
+    /// Sessions without duplicates are left unchanged.
+    pub fn dedup_receiver_chains(&mut self) -> Result<usize> {
+        self.session_state_mut()?.dedup_receiver_chains()
+    }
+
+    /// Evicts old data until this record serializes to at most `max_bytes`, returning how many
+    /// bytes were reclaimed.
+    ///
+    /// Archived sessions are evicted first, oldest first. Then, in the current session, whole
+    /// receiver chains other than the newest one are evicted oldest first, as when a session has
+    /// too many receiver chains, followed by the newest chain's skipped message keys, oldest
+    /// first, as when a chain has too many skipped keys. Messages that depended on evicted data
+    /// can no longer be decrypted.
+    ///
+    /// The current session's root key, sender chain, and newest receiver chain key are never
+    /// evicted, so the record may still be larger than `max_bytes` afterwards. Sizes are measured
+    /// without [key wrapping](Self::set_key_wrapper). Slightly more than necessary may be evicted.
+    pub fn enforce_size_limit(&mut self, max_bytes: usize) -> Result<usize> {
+        let original_len = self.serialized_len();
+        let mut excess = original_len.saturating_sub(max_bytes);
+
+        while excess > 0 {
+            match self.previous_sessions.pop() {
+                Some(oldest) => {
+                    excess = excess.saturating_sub(prost::encoding::bytes::encoded_len(2, &oldest))
+                }
+                None => break,
+            }
+        }
+        if excess > 0 {
+            if let Some(current_session) = &mut self.current_session {
+                current_session.evict_receiver_data(excess);
+            }
+        }
+
+        Ok(original_len - self.serialized_len())
+    }
+
+    /// Removes every stored message key from the current session and all archived sessions,
+    /// returning how many were removed.
+    ///
+    /// Messages that were skipped or have not arrived yet can no longer be decrypted afterwards,
+    /// but the sessions themselves stay usable. The keys are overwritten in this record before
+    /// being dropped; copies held elsewhere, such as previously stored serializations of the
+    /// record, are not affected.
+    pub fn purge_stored_message_keys(&mut self) -> Result<usize> {
+        let mut purged = 0;
+        if let Some(current_session) = &mut self.current_session {
+            purged += current_session.purge_stored_message_keys();
+        }
+        for bytes in &mut self.previous_sessions {
+            let mut state = SessionState::new(SessionStructure::decode(&bytes[..])?);
+            let count = state.purge_stored_message_keys();
+            if count > 0 {
+                bytes.iter_mut().for_each(|b| *b = 0);
+                *bytes = state.session.encode_to_vec();
+                purged += count;
+            }
+        }
+        Ok(purged)
+    }
+
+    /// The length of [`serialize`](Self::serialize)'s output if no key wrapper is set.
+    fn serialized_len(&self) -> usize {
+        // Mirrors the encoding of RecordStructure without building one.
+        let mut len = prost::encoding::bytes::encoded_len_repeated(2, &self.previous_sessions);
+        if let Some(current_session) = &self.current_session {
+            len += prost::encoding::message::encoded_len(1, &current_session.session);
+        }
+        if self.record_version != 0 {
+            len += prost::encoding::uint64::encoded_len(3, &self.record_version);
+        }
+        if let Some(app_label) = &self.app_label {
+            len += prost::encoding::bytes::encoded_len(4, app_label);
+        }
+        len
+    }
+
+    /// The index of the next message expected from `sender` in the current session; see
+    /// [`SessionMetrics::receiver_chain_index`].
+    ///
+    /// Unlike the metrics, this looks up the chain for a specific sender ratchet key, which need
+    /// not be the most recent one. Returns `None` if the session has no chain for `sender`.
+    pub fn receiver_chain_index(&self, sender: &PublicKey) -> Result<Option<u32>> {
+        self.session_state()?.receiver_chain_index(sender)
+    }
+
+    /// The range of counters on the most recent receiver chain of the current session that may
+    /// still be decryptable from this record, as `(oldest_stored_counter, current_counter)`.
+    ///
+    /// `current_counter` is the index of the next message expected on the chain, and
+    /// `oldest_stored_counter` the lowest counter whose message key is stored (or
+    /// `current_counter` if none is). Of the messages before `current_counter`, only those whose
+    /// keys are stored can be decrypted; everything before `oldest_stored_counter` is forward
+    /// secret. Returns `None` if the session has no receiver chain yet.
+    pub fn forward_secrecy_window(&self) -> Result<Option<(u32, u32)>> {
+        self.session_state()?.forward_secrecy_window()
+    }
+
+    /// Counts how the current session would decrypt `messages`, each given as its sender ratchet
+    /// key and counter, without decrypting or changing anything.
+    ///
+    /// Every message is classified against the record as it is now. Decrypting some of them first
+    /// can change the result for the rest; for example, advancing a chain stores the keys of the
+    /// messages it skips over.
+    pub fn classify_messages(&self, messages: &[(PublicKey, u32)]) -> Result<ClassifyResult> {
+        self.session_state()?.classify_messages(messages)
+    }
+
     pub fn get_sender_chain_key_bytes(&self) -> Result<Vec<u8>> {
         self.session_state()?.get_sender_chain_key_bytes()
     }
 
+    pub fn messages_since_ratchet(&self) -> Result<u32> {
+        self.session_state()?.messages_since_ratchet()
+    }
+
+    /// When the current session was last used to encrypt or decrypt a message, or was
+    /// [touched](crate::touch_session), in milliseconds since the Unix epoch.
+    ///
+    /// This is 0 if the session has never been used.
+    pub fn last_used_at(&self) -> Result<u64> {
+        self.session_state()?.last_used_at()
+    }
+
+    /// When the current session was first established, in milliseconds since the Unix epoch.
+    ///
+    /// This is `None` for sessions created before this was recorded.
+    pub fn created_at(&self) -> Result<Option<u64>> {
+        Ok(self.session_state()?.created_at())
+    }
+
+    /// Serializes the public parameters of the current session in a canonical form, suitable for
+    /// signing or publishing.
+    ///
+    /// The output contains no secret material. Its layout is fixed, with all integers big-endian:
+    ///
+    /// 1. the session version (4 bytes)
+    /// 2. the remote identity key, as a 2-byte length followed by the serialized key (empty if
+    ///    the session does not record one)
+    /// 3. our current sender ratchet public key, in the same form (empty if there is no sender
+    ///    chain)
+    /// 4. the index of the next message on the sender chain (4 bytes; 0 if there is none)
+    /// 5. the length of the previous sender chain (4 bytes)
+    ///
+    /// Keys are re-serialized after parsing, so equivalent sessions always produce the same bytes.
+    pub fn public_params_canonical(&self) -> Result<Vec<u8>> {
+        self.session_state()?.public_params_canonical()
+    }
+
+    /// Whether the peer advertised every feature in `flag` when the current session was set up.
+    ///
+    /// Capabilities come from [`PreKeyBundle::with_capabilities`]; sessions set up any other way,
+    /// including all sessions created before capabilities were recorded, support no features.
+    ///
+    /// [`PreKeyBundle::with_capabilities`]: crate::PreKeyBundle::with_capabilities
+    pub fn peer_supports(&self, flag: u32) -> Result<bool> {
+        Ok(self.session_state()?.peer_supports(flag))
+    }
+
+    /// Whether the current session already has a receiver chain for `their_ephemeral`.
+    ///
+    /// A message whose [sender ratchet key](crate::SignalMessage::sender_ratchet_key) is known
+    /// continues an existing chain; any other message would make the current session perform a DH
+    /// ratchet step and start a new chain. This only reads the record. Returns `false` if there is
+    /// no current session.
+    pub fn knows_ratchet_key(&self, their_ephemeral: &PublicKey) -> Result<bool> {
+        match &self.current_session {
+            Some(session) => session.knows_ratchet_key(their_ephemeral),
+            None => Ok(false),
+        }
+    }
+
+    /// A heuristic for whether the current session has stopped working in at least one direction,
+    /// so that the application may want to reset it.
+    ///
+    /// This is the case when we have sent many messages (at least as many as a receiver keeps
+    /// skipped message keys for) without the session ever receiving a message from the peer, as
+    /// happens when the peer lost or never set up its side of the session. It is not a proof: the
+    /// peer may simply not have replied, and a session can fail in ways that leave no trace in
+    /// the record, such as both sides ratcheting from states the other never saw. Returns `false`
+    /// if there is no current session.
+    pub fn is_likely_desynced(&self) -> Result<bool> {
+        match &self.current_session {
+            Some(session) => session.is_likely_desynced(),
+            None => Ok(false),
+        }
+    }
+
     pub fn current_ratchet_key_matches(&self, key: &PublicKey) -> Result<bool> {
         match &self.current_session {
             Some(session) => Ok(&session.sender_ratchet_key()? == key),
             None => Ok(false),
         }
     }
+
+    /// Collects a [`SessionMetrics`] snapshot of this record.
+    pub fn metrics(&self) -> Result<SessionMetrics> {
+        let mut metrics = SessionMetrics {
+            previous_state_count: self.previous_state_count(),
+            ..Default::default()
+        };
+        if let Some(state) = &self.current_session {
+            let session = &state.session;
+            if state.has_sender_chain()? {
+                metrics.sender_chain_index = Some(state.get_sender_chain_key()?.index());
+            }
+            metrics.receiver_chain_index = session
+                .receiver_chains
+                .last()
+                .and_then(|chain| chain.chain_key.as_ref())
+                .map(|chain_key| chain_key.index);
+            metrics.receiver_chain_count = session.receiver_chains.len();
+            metrics.stored_message_key_count = session
+                .receiver_chains
+                .iter()
+                .map(|chain| chain.message_keys.len())
+                .sum();
+        }
+        Ok(metrics)
+    }
+
+    /// Converts this record into one that can only decrypt messages whose keys are already stored.
+    pub fn into_readonly(self) -> ReadOnlySessionRecord {
+        ReadOnlySessionRecord { record: self }
+    }
+}
+
+/// A [`SessionRecord`] whose ratchets can never be advanced.
+///
+/// It can still be used to decrypt previously-received messages whose keys are stored in the
+/// session, using [`message_decrypt_readonly`](crate::message_decrypt_readonly).
+#[derive(Clone, Debug)]
+pub struct ReadOnlySessionRecord {
+    record: SessionRecord,
+}
+
+impl ReadOnlySessionRecord {
+    /// Looks up the stored keys for message `counter` on the chain for `sender`, in the current
+    /// session state or any previous one.
+    pub fn get_message_keys(
+        &self,
+        sender: &PublicKey,
+        counter: u32,
+    ) -> Result<Option<MessageKeys>> {
+        for state in self.states() {
+            if let Some(keys) = state?.message_keys_for(sender, counter)? {
+                return Ok(Some(keys));
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn states(&self) -> impl Iterator<Item = Result<SessionState>> + '_ {
+        self.record
+            .current_session
+            .iter()
+            .cloned()
+            .map(Ok)
+            .chain(self.record.previous_session_states())
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+mod fuzzing {
+    use super::*;
+    use arbitrary::{Arbitrary, Unstructured};
+
+    /// Chain indices are kept within the range a real session could reach without hitting
+    /// [`consts::MAX_FORWARD_JUMPS`].
+    fn chain_index(u: &mut Unstructured<'_>) -> arbitrary::Result<u32> {
+        u.int_in_range(0..=consts::MAX_FORWARD_JUMPS as u32)
+    }
+
+    fn key_pair(u: &mut Unstructured<'_>) -> arbitrary::Result<KeyPair> {
+        let private_key = PrivateKey::deserialize(&<[u8; 32]>::arbitrary(u)?)
+            .expect("any 32 bytes form a valid private key");
+        let public_key = private_key.public_key().expect("valid private key");
+        Ok(KeyPair::new(public_key, private_key))
+    }
+
+    fn public_key_bytes(u: &mut Unstructured<'_>) -> arbitrary::Result<Vec<u8>> {
+        Ok(key_pair(u)?.public_key.serialize().to_vec())
+    }
+
+    fn chain(
+        u: &mut Unstructured<'_>,
+        with_private_key: bool,
+    ) -> arbitrary::Result<session_structure::Chain> {
+        let ratchet_key = key_pair(u)?;
+        let index = chain_index(u)?;
+
+        let mut message_keys = Vec::new();
+        if !with_private_key && index > 0 {
+            for _ in 0..u.int_in_range(0..=consts::MAX_MESSAGE_KEYS.min(index as usize))? {
+                message_keys.push(session_structure::chain::MessageKey {
+                    index: u.int_in_range(0..=index - 1)?,
+                    cipher_key: <[u8; 32]>::arbitrary(u)?.to_vec(),
+                    mac_key: <[u8; 32]>::arbitrary(u)?.to_vec(),
+                    iv: <[u8; 16]>::arbitrary(u)?.to_vec(),
+                    wrapped: vec![],
+                });
+            }
+        }
+
+        Ok(session_structure::Chain {
+            sender_ratchet_key: ratchet_key.public_key.serialize().to_vec(),
+            sender_ratchet_key_private: if with_private_key {
+                ratchet_key.private_key.serialize()
+            } else {
+                vec![]
+            },
+            chain_key: Some(session_structure::chain::ChainKey {
+                index,
+                key: <[u8; 32]>::arbitrary(u)?.to_vec(),
+            }),
+            message_keys,
+        })
+    }
+
+    impl<'a> Arbitrary<'a> for SessionState {
+        fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+            let sender_chain = if u.arbitrary()? {
+                Some(chain(u, true)?)
+            } else {
+                None
+            };
+            let mut receiver_chains = Vec::new();
+            for _ in 0..u.int_in_range(0..=consts::MAX_RECEIVER_CHAINS)? {
+                receiver_chains.push(chain(u, false)?);
+            }
+            let pending_pre_key = if u.arbitrary()? {
+                Some(session_structure::PendingPreKey {
+                    pre_key_id: u.arbitrary()?,
+                    signed_pre_key_id: u.arbitrary()?,
+                    base_key: public_key_bytes(u)?,
+                })
+            } else {
+                None
+            };
+
+            Ok(SessionState::new(SessionStructure {
+                session_version: 3,
+                local_identity_public: public_key_bytes(u)?,
+                remote_identity_public: if u.arbitrary()? {
+                    public_key_bytes(u)?
+                } else {
+                    vec![]
+                },
+                root_key: <[u8; 32]>::arbitrary(u)?.to_vec(),
+                previous_counter: chain_index(u)?,
+                sender_chain,
+                receiver_chains,
+                pending_pre_key,
+                remote_registration_id: u.arbitrary()?,
+                local_registration_id: u.arbitrary()?,
+                needs_refresh: false,
+                alice_base_key: public_key_bytes(u)?,
+                last_used_at: u.arbitrary()?,
+                capabilities: u.arbitrary()?,
+                created_at: u.arbitrary()?,
+                highest_version_seen: 3,
+            }))
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for SessionRecord {
+        fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+            let current_session = if u.arbitrary()? {
+                Some(SessionState::arbitrary(u)?)
+            } else {
+                None
+            };
+            let mut previous_sessions = Vec::new();
+            for _ in 0..u.int_in_range(0..=consts::ARCHIVED_STATES_MAX_LENGTH)? {
+                previous_sessions.push(SessionState::arbitrary(u)?.session.encode_to_vec());
+            }
+            Ok(Self {
+                current_session,
+                previous_sessions,
+                record_version: u.arbitrary()?,
+                app_label: u
+                    .arbitrary::<Option<Vec<u8>>>()?
+                    .filter(|label| !label.is_empty()),
+                key_wrapper: None,
+            })
+        }
+    }
 }
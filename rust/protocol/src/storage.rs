@@ -4,15 +4,21 @@
 //
 
 mod inmem;
+mod instrumented;
+mod tofu;
 mod traits;
 
+pub(crate) use instrumented::Instrumented;
+
 pub use {
     inmem::{
         InMemIdentityKeyStore, InMemPreKeyStore, InMemSenderKeyStore, InMemSessionStore,
         InMemSignalProtocolStore, InMemSignedPreKeyStore,
     },
+    instrumented::StoreInstrumentation,
+    tofu::{IdentityChangeCallback, TofuIdentityStore},
     traits::{
         Context, Direction, IdentityKeyStore, PreKeyStore, ProtocolStore, SenderKeyStore,
-        SessionStore, SignedPreKeyStore,
+        SessionStore, SignedPreKeyStore, TrustStatus,
     },
 };
@@ -14,7 +14,7 @@ use crate::storage::Context;
 
 use async_trait::async_trait;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use uuid::Uuid;
 
 #[derive(Clone)]
@@ -94,17 +94,35 @@ impl traits::IdentityKeyStore for InMemIdentityKeyStore {
             Some(k) => Ok(Some(k.to_owned())),
         }
     }
+
+    /// Identities are returned sorted by address.
+    async fn all_identities(
+        &self,
+        ctx: Context,
+    ) -> Result<Vec<(ProtocolAddress, IdentityKey, traits::TrustStatus)>> {
+        let mut known_keys = self.known_keys.iter().collect::<Vec<_>>();
+        known_keys.sort_by_key(|(address, _)| *address);
+
+        let mut identities = Vec::with_capacity(known_keys.len());
+        for (address, identity) in known_keys {
+            let status = traits::trust_status(self, address, identity, ctx).await?;
+            identities.push((address.clone(), *identity, status));
+        }
+        Ok(identities)
+    }
 }
 
 #[derive(Clone)]
 pub struct InMemPreKeyStore {
     pre_keys: HashMap<PreKeyId, PreKeyRecord>,
+    claimed: HashSet<PreKeyId>,
 }
 
 impl InMemPreKeyStore {
     pub fn new() -> Self {
         Self {
             pre_keys: HashMap::new(),
+            claimed: HashSet::new(),
         }
     }
 }
@@ -139,8 +157,47 @@ impl traits::PreKeyStore for InMemPreKeyStore {
     async fn remove_pre_key(&mut self, id: PreKeyId, _ctx: Context) -> Result<()> {
         // If id does not exist this silently does nothing
         self.pre_keys.remove(&id);
+        self.claimed.remove(&id);
+        Ok(())
+    }
+
+    async fn pre_key_count(&self, _ctx: Context) -> Result<Option<usize>> {
+        Ok(Some(self.pre_keys.len()))
+    }
+
+    async fn pre_key_ids(&self, _ctx: Context) -> Result<Vec<PreKeyId>> {
+        let mut ids = self.pre_keys.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    async fn claim_pre_key(&mut self, id: PreKeyId, _ctx: Context) -> Result<PreKeyRecord> {
+        let record = self
+            .pre_keys
+            .get(&id)
+            .ok_or(SignalProtocolError::InvalidPreKeyId)?;
+        if !self.claimed.insert(id) {
+            return Err(SignalProtocolError::PreKeyAlreadyConsumed(id));
+        }
+        Ok(record.clone())
+    }
+
+    async fn release_pre_key(&mut self, id: PreKeyId, _ctx: Context) -> Result<()> {
+        self.claimed.remove(&id);
         Ok(())
     }
+
+    async fn expired_pre_keys(&self, before: u64, _ctx: Context) -> Result<Vec<PreKeyId>> {
+        let mut expired = Vec::new();
+        for (id, record) in &self.pre_keys {
+            let timestamp = record.timestamp()?;
+            if timestamp != 0 && timestamp < before && !self.claimed.contains(id) {
+                expired.push(*id);
+            }
+        }
+        expired.sort_unstable();
+        Ok(expired)
+    }
 }
 
 #[derive(Clone)]
@@ -186,6 +243,17 @@ impl traits::SignedPreKeyStore for InMemSignedPreKeyStore {
         self.signed_pre_keys.insert(id, record.to_owned());
         Ok(())
     }
+
+    async fn signed_pre_key_ids(&self, _ctx: Context) -> Result<Vec<SignedPreKeyId>> {
+        let mut ids = self.signed_pre_keys.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    async fn remove_signed_pre_key(&mut self, id: SignedPreKeyId, _ctx: Context) -> Result<()> {
+        self.signed_pre_keys.remove(&id);
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
@@ -248,6 +316,28 @@ impl traits::SessionStore for InMemSessionStore {
         self.sessions.insert(address.clone(), record.clone());
         Ok(())
     }
+
+    async fn store_session_if_version(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        expected_version: u64,
+        _ctx: Context,
+    ) -> Result<bool> {
+        let current_version = self
+            .sessions
+            .get(address)
+            .map_or(0, SessionRecord::record_version);
+        if current_version != expected_version {
+            return Ok(false);
+        }
+        self.sessions.insert(address.clone(), record.clone());
+        Ok(true)
+    }
+
+    async fn all_addresses(&self, _ctx: Context) -> Result<Vec<ProtocolAddress>> {
+        Ok(self.sessions.keys().cloned().collect())
+    }
 }
 
 #[derive(Clone)]
@@ -361,6 +451,13 @@ impl traits::IdentityKeyStore for InMemSignalProtocolStore {
     ) -> Result<Option<IdentityKey>> {
         self.identity_store.get_identity(address, ctx).await
     }
+
+    async fn all_identities(
+        &self,
+        ctx: Context,
+    ) -> Result<Vec<(ProtocolAddress, IdentityKey, traits::TrustStatus)>> {
+        self.identity_store.all_identities(ctx).await
+    }
 }
 
 #[async_trait(?Send)]
@@ -381,6 +478,30 @@ impl traits::PreKeyStore for InMemSignalProtocolStore {
     async fn remove_pre_key(&mut self, id: PreKeyId, ctx: Context) -> Result<()> {
         self.pre_key_store.remove_pre_key(id, ctx).await
     }
+
+    async fn pre_key_count(&self, ctx: Context) -> Result<Option<usize>> {
+        self.pre_key_store.pre_key_count(ctx).await
+    }
+
+    async fn pre_key_ids(&self, ctx: Context) -> Result<Vec<PreKeyId>> {
+        self.pre_key_store.pre_key_ids(ctx).await
+    }
+
+    async fn claim_pre_key(&mut self, id: PreKeyId, ctx: Context) -> Result<PreKeyRecord> {
+        self.pre_key_store.claim_pre_key(id, ctx).await
+    }
+
+    async fn release_pre_key(&mut self, id: PreKeyId, ctx: Context) -> Result<()> {
+        self.pre_key_store.release_pre_key(id, ctx).await
+    }
+
+    async fn expired_pre_keys(&self, before: u64, ctx: Context) -> Result<Vec<PreKeyId>> {
+        self.pre_key_store.expired_pre_keys(before, ctx).await
+    }
+
+    async fn remove_pre_keys(&mut self, ids: &[PreKeyId], ctx: Context) -> Result<()> {
+        self.pre_key_store.remove_pre_keys(ids, ctx).await
+    }
 }
 
 #[async_trait(?Send)]
@@ -403,6 +524,16 @@ impl traits::SignedPreKeyStore for InMemSignalProtocolStore {
             .save_signed_pre_key(id, record, ctx)
             .await
     }
+
+    async fn signed_pre_key_ids(&self, ctx: Context) -> Result<Vec<SignedPreKeyId>> {
+        self.signed_pre_key_store.signed_pre_key_ids(ctx).await
+    }
+
+    async fn remove_signed_pre_key(&mut self, id: SignedPreKeyId, ctx: Context) -> Result<()> {
+        self.signed_pre_key_store
+            .remove_signed_pre_key(id, ctx)
+            .await
+    }
 }
 
 #[async_trait(?Send)]
@@ -423,6 +554,22 @@ impl traits::SessionStore for InMemSignalProtocolStore {
     ) -> Result<()> {
         self.session_store.store_session(address, record, ctx).await
     }
+
+    async fn store_session_if_version(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        expected_version: u64,
+        ctx: Context,
+    ) -> Result<bool> {
+        self.session_store
+            .store_session_if_version(address, record, expected_version, ctx)
+            .await
+    }
+
+    async fn all_addresses(&self, ctx: Context) -> Result<Vec<ProtocolAddress>> {
+        self.session_store.all_addresses(ctx).await
+    }
 }
 
 #[async_trait(?Send)]
@@ -0,0 +1,267 @@
+//
+// Copyright 2021 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use crate::state::{PreKeyId, SignedPreKeyId};
+use crate::storage::traits::{self, Context};
+use crate::{
+    IdentityKey, IdentityKeyPair, PreKeyRecord, ProtocolAddress, Result, SessionRecord,
+    SignedPreKeyRecord,
+};
+
+use async_trait::async_trait;
+use std::time::{Duration, Instant};
+
+/// Receives the time taken by each store operation performed while encrypting or decrypting.
+///
+/// Set it as [`EncryptionConfig::instrumentation`](crate::EncryptionConfig::instrumentation) or
+/// [`DecryptionConfig::instrumentation`](crate::DecryptionConfig::instrumentation). Each method is
+/// called once per store call, whether or not the call succeeded, and does nothing by default.
+/// Operations the cipher never performs (such as saving pre-keys) are not reported.
+///
+/// The callbacks are called synchronously on the encryption or decryption path, so they should
+/// only record the measurement.
+pub trait StoreInstrumentation: Send + Sync {
+    fn on_load_session_done(&self, _elapsed: Duration) {}
+    /// Also covers [`SessionStore::store_session_if_version`](traits::SessionStore::store_session_if_version).
+    fn on_store_session_done(&self, _elapsed: Duration) {}
+    fn on_get_identity_key_pair_done(&self, _elapsed: Duration) {}
+    fn on_get_local_registration_id_done(&self, _elapsed: Duration) {}
+    fn on_save_identity_done(&self, _elapsed: Duration) {}
+    fn on_is_trusted_identity_done(&self, _elapsed: Duration) {}
+    fn on_get_identity_done(&self, _elapsed: Duration) {}
+    fn on_get_pre_key_done(&self, _elapsed: Duration) {}
+    fn on_claim_pre_key_done(&self, _elapsed: Duration) {}
+    fn on_release_pre_key_done(&self, _elapsed: Duration) {}
+    fn on_remove_pre_key_done(&self, _elapsed: Duration) {}
+    fn on_pre_key_count_done(&self, _elapsed: Duration) {}
+    fn on_get_signed_pre_key_done(&self, _elapsed: Duration) {}
+}
+
+/// Wraps a store so that every operation is reported to a [`StoreInstrumentation`].
+pub(crate) struct Instrumented<'a, S: ?Sized> {
+    inner: &'a mut S,
+    instrumentation: &'a dyn StoreInstrumentation,
+}
+
+impl<'a, S: ?Sized> Instrumented<'a, S> {
+    pub(crate) fn new(inner: &'a mut S, instrumentation: &'a dyn StoreInstrumentation) -> Self {
+        Self {
+            inner,
+            instrumentation,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: traits::SessionStore + ?Sized> traits::SessionStore for Instrumented<'_, S> {
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<SessionRecord>> {
+        let start = Instant::now();
+        let result = self.inner.load_session(address, ctx).await;
+        self.instrumentation.on_load_session_done(start.elapsed());
+        result
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.store_session(address, record, ctx).await;
+        self.instrumentation.on_store_session_done(start.elapsed());
+        result
+    }
+
+    async fn store_session_if_version(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        expected_version: u64,
+        ctx: Context,
+    ) -> Result<bool> {
+        let start = Instant::now();
+        let result = self
+            .inner
+            .store_session_if_version(address, record, expected_version, ctx)
+            .await;
+        self.instrumentation.on_store_session_done(start.elapsed());
+        result
+    }
+
+    async fn all_addresses(&self, ctx: Context) -> Result<Vec<ProtocolAddress>> {
+        self.inner.all_addresses(ctx).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: traits::IdentityKeyStore + ?Sized> traits::IdentityKeyStore for Instrumented<'_, S> {
+    async fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair> {
+        let start = Instant::now();
+        let result = self.inner.get_identity_key_pair(ctx).await;
+        self.instrumentation
+            .on_get_identity_key_pair_done(start.elapsed());
+        result
+    }
+
+    async fn get_local_registration_id(&self, ctx: Context) -> Result<u32> {
+        let start = Instant::now();
+        let result = self.inner.get_local_registration_id(ctx).await;
+        self.instrumentation
+            .on_get_local_registration_id_done(start.elapsed());
+        result
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        ctx: Context,
+    ) -> Result<bool> {
+        let start = Instant::now();
+        let result = self.inner.save_identity(address, identity, ctx).await;
+        self.instrumentation.on_save_identity_done(start.elapsed());
+        result
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: traits::Direction,
+        ctx: Context,
+    ) -> Result<bool> {
+        let start = Instant::now();
+        let result = self
+            .inner
+            .is_trusted_identity(address, identity, direction, ctx)
+            .await;
+        self.instrumentation
+            .on_is_trusted_identity_done(start.elapsed());
+        result
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<IdentityKey>> {
+        let start = Instant::now();
+        let result = self.inner.get_identity(address, ctx).await;
+        self.instrumentation.on_get_identity_done(start.elapsed());
+        result
+    }
+
+    async fn all_identities(
+        &self,
+        ctx: Context,
+    ) -> Result<Vec<(ProtocolAddress, IdentityKey, traits::TrustStatus)>> {
+        self.inner.all_identities(ctx).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: traits::PreKeyStore + ?Sized> traits::PreKeyStore for Instrumented<'_, S> {
+    async fn get_pre_key(&self, prekey_id: PreKeyId, ctx: Context) -> Result<PreKeyRecord> {
+        let start = Instant::now();
+        let result = self.inner.get_pre_key(prekey_id, ctx).await;
+        self.instrumentation.on_get_pre_key_done(start.elapsed());
+        result
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        prekey_id: PreKeyId,
+        record: &PreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.inner.save_pre_key(prekey_id, record, ctx).await
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: PreKeyId, ctx: Context) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.remove_pre_key(prekey_id, ctx).await;
+        self.instrumentation.on_remove_pre_key_done(start.elapsed());
+        result
+    }
+
+    async fn pre_key_count(&self, ctx: Context) -> Result<Option<usize>> {
+        let start = Instant::now();
+        let result = self.inner.pre_key_count(ctx).await;
+        self.instrumentation.on_pre_key_count_done(start.elapsed());
+        result
+    }
+
+    async fn pre_key_ids(&self, ctx: Context) -> Result<Vec<PreKeyId>> {
+        self.inner.pre_key_ids(ctx).await
+    }
+
+    async fn claim_pre_key(&mut self, prekey_id: PreKeyId, ctx: Context) -> Result<PreKeyRecord> {
+        let start = Instant::now();
+        let result = self.inner.claim_pre_key(prekey_id, ctx).await;
+        self.instrumentation.on_claim_pre_key_done(start.elapsed());
+        result
+    }
+
+    async fn release_pre_key(&mut self, prekey_id: PreKeyId, ctx: Context) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.release_pre_key(prekey_id, ctx).await;
+        self.instrumentation
+            .on_release_pre_key_done(start.elapsed());
+        result
+    }
+
+    async fn expired_pre_keys(&self, before: u64, ctx: Context) -> Result<Vec<PreKeyId>> {
+        self.inner.expired_pre_keys(before, ctx).await
+    }
+
+    async fn remove_pre_keys(&mut self, prekey_ids: &[PreKeyId], ctx: Context) -> Result<()> {
+        self.inner.remove_pre_keys(prekey_ids, ctx).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: traits::SignedPreKeyStore + ?Sized> traits::SignedPreKeyStore for Instrumented<'_, S> {
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: SignedPreKeyId,
+        ctx: Context,
+    ) -> Result<SignedPreKeyRecord> {
+        let start = Instant::now();
+        let result = self.inner.get_signed_pre_key(signed_prekey_id, ctx).await;
+        self.instrumentation
+            .on_get_signed_pre_key_done(start.elapsed());
+        result
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.inner
+            .save_signed_pre_key(signed_prekey_id, record, ctx)
+            .await
+    }
+
+    async fn signed_pre_key_ids(&self, ctx: Context) -> Result<Vec<SignedPreKeyId>> {
+        self.inner.signed_pre_key_ids(ctx).await
+    }
+
+    async fn remove_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        ctx: Context,
+    ) -> Result<()> {
+        self.inner
+            .remove_signed_pre_key(signed_prekey_id, ctx)
+            .await
+    }
+}
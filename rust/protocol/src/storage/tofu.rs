@@ -0,0 +1,129 @@
+//
+// Copyright 2021 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use crate::storage::traits::{self, Context};
+use crate::{IdentityKey, IdentityKeyPair, ProtocolAddress, Result};
+
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// See [`TofuIdentityStore::with_identity_change_callback`].
+///
+/// Called with the peer's address, the identity key stored for it, and the different identity key
+/// that was just presented.
+pub type IdentityChangeCallback =
+    Arc<dyn Fn(&ProtocolAddress, IdentityKey, IdentityKey) + Send + Sync>;
+
+/// An [`IdentityKeyStore`](traits::IdentityKeyStore) adapter that applies a "trust on first use"
+/// policy on top of any other identity store.
+///
+/// An identity is trusted if no identity is stored for its address yet, or if it is the stored
+/// one; the trust decision of the wrapped store is never consulted. Since
+/// [`message_encrypt`](crate::message_encrypt), [`message_decrypt`](crate::message_decrypt), and
+/// [`process_prekey_bundle`](crate::process_prekey_bundle) save an identity once it has been
+/// trusted, the first key seen for an address is recorded and any different key is rejected with
+/// [`SignalProtocolError::UntrustedIdentity`](crate::SignalProtocolError::UntrustedIdentity)
+/// from then on.
+///
+/// All other operations, including [`save_identity`](traits::IdentityKeyStore::save_identity),
+/// are passed through unchanged; saving a new key for an address is how an application accepts an
+/// identity change.
+pub struct TofuIdentityStore<S> {
+    inner: S,
+    on_identity_change: Option<IdentityChangeCallback>,
+}
+
+impl<S: traits::IdentityKeyStore> TofuIdentityStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            on_identity_change: None,
+        }
+    }
+
+    /// Calls `callback` whenever an identity that differs from the stored one is rejected.
+    ///
+    /// This is called synchronously from [`is_trusted_identity`], possibly more than once for the
+    /// same change, so it should only record or hand off the event (e.g. to show a "safety number
+    /// changed" notice).
+    ///
+    /// [`is_trusted_identity`]: traits::IdentityKeyStore::is_trusted_identity
+    pub fn with_identity_change_callback(mut self, callback: IdentityChangeCallback) -> Self {
+        self.on_identity_change = Some(callback);
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: traits::IdentityKeyStore> traits::IdentityKeyStore for TofuIdentityStore<S> {
+    async fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair> {
+        self.inner.get_identity_key_pair(ctx).await
+    }
+
+    async fn get_local_registration_id(&self, ctx: Context) -> Result<u32> {
+        self.inner.get_local_registration_id(ctx).await
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        ctx: Context,
+    ) -> Result<bool> {
+        self.inner.save_identity(address, identity, ctx).await
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        _direction: traits::Direction,
+        ctx: Context,
+    ) -> Result<bool> {
+        match self.inner.get_identity(address, ctx).await? {
+            None => Ok(true),
+            Some(stored) if stored == *identity => Ok(true),
+            Some(stored) => {
+                if let Some(on_identity_change) = &self.on_identity_change {
+                    on_identity_change(address, stored, *identity);
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<IdentityKey>> {
+        self.inner.get_identity(address, ctx).await
+    }
+
+    /// Lists the identities stored in the wrapped store, with their status under this store's
+    /// policy rather than the wrapped store's.
+    async fn all_identities(
+        &self,
+        ctx: Context,
+    ) -> Result<Vec<(ProtocolAddress, IdentityKey, traits::TrustStatus)>> {
+        let mut identities = self.inner.all_identities(ctx).await?;
+        for (address, identity, status) in &mut identities {
+            *status = traits::trust_status(self, address, identity, ctx).await?;
+        }
+        Ok(identities)
+    }
+}
@@ -9,7 +9,7 @@ use uuid::Uuid;
 use crate::state::{PreKeyId, SignedPreKeyId};
 use crate::{
     IdentityKey, IdentityKeyPair, PreKeyRecord, ProtocolAddress, Result, SenderKeyRecord,
-    SessionRecord, SignedPreKeyRecord,
+    SessionRecord, SignalProtocolError, SignedPreKeyRecord,
 };
 
 pub type Context = Option<*mut std::ffi::c_void>;
@@ -20,6 +20,39 @@ pub enum Direction {
     Receiving,
 }
 
+/// How an [`IdentityKeyStore`] currently regards an identity it has stored; see
+/// [`IdentityKeyStore::all_identities`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TrustStatus {
+    /// Trusted for both sending and receiving.
+    Trusted,
+    /// Trusted for receiving only, e.g. a changed identity the user has not approved yet.
+    ReceiveOnly,
+    /// Not trusted for receiving.
+    Untrusted,
+}
+
+/// Classifies `identity` according to [`IdentityKeyStore::is_trusted_identity`] in both
+/// directions.
+pub(crate) async fn trust_status(
+    store: &dyn IdentityKeyStore,
+    address: &ProtocolAddress,
+    identity: &IdentityKey,
+    ctx: Context,
+) -> Result<TrustStatus> {
+    let receiving = store
+        .is_trusted_identity(address, identity, Direction::Receiving, ctx)
+        .await?;
+    let sending = store
+        .is_trusted_identity(address, identity, Direction::Sending, ctx)
+        .await?;
+    Ok(match (receiving, sending) {
+        (true, true) => TrustStatus::Trusted,
+        (true, false) => TrustStatus::ReceiveOnly,
+        (false, _) => TrustStatus::Untrusted,
+    })
+}
+
 #[async_trait(?Send)]
 pub trait IdentityKeyStore {
     async fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair>;
@@ -46,6 +79,22 @@ pub trait IdentityKeyStore {
         address: &ProtocolAddress,
         ctx: Context,
     ) -> Result<Option<IdentityKey>>;
+
+    /// Returns every stored identity, with how [`is_trusted_identity`] currently treats it.
+    ///
+    /// The default implementation fails with [`SignalProtocolError::InvalidState`], since not
+    /// every store can enumerate its contents.
+    ///
+    /// [`is_trusted_identity`]: Self::is_trusted_identity
+    async fn all_identities(
+        &self,
+        _ctx: Context,
+    ) -> Result<Vec<(ProtocolAddress, IdentityKey, TrustStatus)>> {
+        Err(SignalProtocolError::InvalidState(
+            "all_identities",
+            "identity store cannot enumerate its identities".to_string(),
+        ))
+    }
 }
 
 #[async_trait(?Send)]
@@ -60,6 +109,70 @@ pub trait PreKeyStore {
     ) -> Result<()>;
 
     async fn remove_pre_key(&mut self, prekey_id: PreKeyId, ctx: Context) -> Result<()>;
+
+    /// The number of pre-keys currently in the store, or `None` if the store cannot tell cheaply.
+    ///
+    /// The default implementation returns `None`.
+    async fn pre_key_count(&self, _ctx: Context) -> Result<Option<usize>> {
+        Ok(None)
+    }
+
+    /// Returns the IDs of every pre-key in the store, including [claimed](Self::claim_pre_key) ones.
+    ///
+    /// The default implementation fails with [`SignalProtocolError::InvalidState`], since not
+    /// every store can enumerate its contents.
+    async fn pre_key_ids(&self, _ctx: Context) -> Result<Vec<PreKeyId>> {
+        Err(SignalProtocolError::InvalidState(
+            "pre_key_ids",
+            "pre-key store cannot enumerate its pre-keys".to_string(),
+        ))
+    }
+
+    /// Loads a pre-key and marks it as being consumed by an incoming pre-key message.
+    ///
+    /// Until the claim is [released](Self::release_pre_key) or the pre-key is
+    /// [removed](Self::remove_pre_key), further claims must fail with
+    /// [`SignalProtocolError::PreKeyAlreadyConsumed`]. This ensures that only one of several
+    /// messages racing for the same pre-key can set up a session with it.
+    ///
+    /// The default implementation does not track claims and simply forwards to
+    /// [`get_pre_key`](Self::get_pre_key).
+    async fn claim_pre_key(&mut self, prekey_id: PreKeyId, ctx: Context) -> Result<PreKeyRecord> {
+        self.get_pre_key(prekey_id, ctx).await
+    }
+
+    /// Gives up a claim made by [`claim_pre_key`](Self::claim_pre_key) without removing the
+    /// pre-key, because the message that claimed it could not be processed.
+    ///
+    /// The default implementation does nothing.
+    async fn release_pre_key(&mut self, _prekey_id: PreKeyId, _ctx: Context) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the IDs of pre-keys uploaded before `before` that are still waiting to be used.
+    ///
+    /// Pre-keys without a [timestamp](PreKeyRecord::timestamp) and pre-keys currently
+    /// [claimed](Self::claim_pre_key) by an incoming message are not included.
+    ///
+    /// The default implementation fails with [`SignalProtocolError::InvalidState`], since not
+    /// every store can enumerate its contents.
+    async fn expired_pre_keys(&self, _before: u64, _ctx: Context) -> Result<Vec<PreKeyId>> {
+        Err(SignalProtocolError::InvalidState(
+            "expired_pre_keys",
+            "pre-key store cannot enumerate its pre-keys".to_string(),
+        ))
+    }
+
+    /// Removes every pre-key in `prekey_ids`, e.g. those returned by
+    /// [`expired_pre_keys`](Self::expired_pre_keys).
+    ///
+    /// The default implementation calls [`remove_pre_key`](Self::remove_pre_key) for each ID.
+    async fn remove_pre_keys(&mut self, prekey_ids: &[PreKeyId], ctx: Context) -> Result<()> {
+        for &prekey_id in prekey_ids {
+            self.remove_pre_key(prekey_id, ctx).await?;
+        }
+        Ok(())
+    }
 }
 
 #[async_trait(?Send)]
@@ -76,8 +189,38 @@ pub trait SignedPreKeyStore {
         record: &SignedPreKeyRecord,
         ctx: Context,
     ) -> Result<()>;
+
+    /// Returns the IDs of every signed pre-key in the store.
+    ///
+    /// The default implementation fails with [`SignalProtocolError::InvalidState`], since not
+    /// every store can enumerate its contents.
+    async fn signed_pre_key_ids(&self, _ctx: Context) -> Result<Vec<SignedPreKeyId>> {
+        Err(SignalProtocolError::InvalidState(
+            "signed_pre_key_ids",
+            "signed pre-key store cannot enumerate its signed pre-keys".to_string(),
+        ))
+    }
+
+    /// Removes a signed pre-key; removing one that is not in the store is not an error.
+    ///
+    /// The default implementation fails with [`SignalProtocolError::InvalidState`].
+    async fn remove_signed_pre_key(
+        &mut self,
+        _signed_prekey_id: SignedPreKeyId,
+        _ctx: Context,
+    ) -> Result<()> {
+        Err(SignalProtocolError::InvalidState(
+            "remove_signed_pre_key",
+            "signed pre-key store does not support removal".to_string(),
+        ))
+    }
 }
 
+/// Persists [`SessionRecord`]s.
+///
+/// Stores that use [key wrapping](SessionRecord::set_key_wrapper) must set the wrapper on every
+/// record they load, before returning it: deserialized records have none, and message keys that
+/// are still wrapped cannot be used to decrypt.
 #[async_trait(?Send)]
 pub trait SessionStore {
     async fn load_session(
@@ -92,6 +235,36 @@ pub trait SessionStore {
         record: &SessionRecord,
         ctx: Context,
     ) -> Result<()>;
+
+    /// Stores `record` only if the record currently stored for `address` has the
+    /// [record version][SessionRecord::record_version] `expected_version`.
+    ///
+    /// Returns `false` without storing anything if the versions do not match. A missing record is
+    /// treated as having version 0.
+    ///
+    /// The default implementation does not check the version at all and unconditionally forwards
+    /// to [`store_session`](Self::store_session).
+    async fn store_session_if_version(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        _expected_version: u64,
+        ctx: Context,
+    ) -> Result<bool> {
+        self.store_session(address, record, ctx).await?;
+        Ok(true)
+    }
+
+    /// Returns the address of every session record in the store.
+    ///
+    /// The default implementation fails with [`SignalProtocolError::InvalidState`], since not
+    /// every store can enumerate its contents.
+    async fn all_addresses(&self, _ctx: Context) -> Result<Vec<ProtocolAddress>> {
+        Err(SignalProtocolError::InvalidState(
+            "all_addresses",
+            "session store cannot enumerate its sessions".to_string(),
+        ))
+    }
 }
 
 #[async_trait(?Send)]
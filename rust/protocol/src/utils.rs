@@ -4,6 +4,7 @@
 //
 
 use std::cmp::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn expand_top_bit(a: u8) -> u8 {
     //if (a >> 7) == 1 { 0xFF } else { 0 }
@@ -81,6 +82,13 @@ pub(crate) fn constant_time_cmp(x: &[u8], y: &[u8]) -> Ordering {
     }
 }
 
+/// The current time in milliseconds since the Unix epoch, or 0 if the clock is before it.
+pub(crate) fn current_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -171,3 +171,54 @@ fn test_ratcheting_session_as_alice() -> Result<(), SignalProtocolError> {
 
     Ok(())
 }
+
+#[test]
+#[cfg(feature = "test-internals")]
+fn test_initialized_sessions_have_matching_chains() -> Result<(), SignalProtocolError> {
+    let mut csprng = rand::rngs::OsRng;
+
+    let alice_identity = IdentityKeyPair::generate(&mut csprng);
+    let bob_identity = IdentityKeyPair::generate(&mut csprng);
+    let alice_base_key = KeyPair::generate(&mut csprng);
+    let bob_signed_pre_key = KeyPair::generate(&mut csprng);
+
+    let alice_record = initialize_alice_session_record(
+        &AliceSignalProtocolParameters::new(
+            alice_identity,
+            alice_base_key,
+            *bob_identity.identity_key(),
+            bob_signed_pre_key.public_key,
+            None,
+            bob_signed_pre_key.public_key,
+        ),
+        &mut csprng,
+    )?;
+    let bob_record = initialize_bob_session_record(&BobSignalProtocolParameters::new(
+        bob_identity,
+        bob_signed_pre_key,
+        None,
+        bob_signed_pre_key,
+        *alice_identity.identity_key(),
+        alice_base_key.public_key,
+    ))?;
+
+    // Bob's first sending chain is the one Alice set up to receive on.
+    let bob_sender_chain = bob_record.sender_chain_key()?;
+    let alice_receiver_chain = alice_record
+        .get_receiver_chain_key(&bob_record.sender_ratchet_key()?)?
+        .expect("value exists");
+    assert_eq!(bob_sender_chain.key(), alice_receiver_chain.key());
+    assert_eq!(bob_sender_chain.index(), alice_receiver_chain.index());
+
+    // Alice has already ratcheted to a sending chain of her own, which Bob only derives once he
+    // receives her ratchet key.
+    assert!(bob_record
+        .get_receiver_chain_key(&alice_record.sender_ratchet_key()?)?
+        .is_none());
+    assert_ne!(
+        alice_record.sender_chain_key()?.key(),
+        alice_receiver_chain.key()
+    );
+
+    Ok(())
+}
@@ -9,6 +9,7 @@ use futures_util::FutureExt;
 use libsignal_protocol::*;
 use rand::rngs::OsRng;
 use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
 use support::*;
 
 #[test]
@@ -935,6 +936,5102 @@ fn message_key_limits() -> Result<(), SignalProtocolError> {
     .expect("sync")
 }
 
+#[test]
+fn stale_session_store_is_rejected() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, _bob_session_record) = initialize_sessions_v3()?;
+
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+
+        let stale_record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+
+        encrypt(&mut alice_store, &bob_address, "move along").await?;
+
+        let current_record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert_ne!(
+            stale_record.record_version(),
+            current_record.record_version()
+        );
+
+        assert!(
+            !alice_store
+                .store_session_if_version(
+                    &bob_address,
+                    &stale_record,
+                    stale_record.record_version(),
+                    None
+                )
+                .await?
+        );
+        assert!(
+            alice_store
+                .store_session_if_version(
+                    &bob_address,
+                    &current_record,
+                    current_record.record_version(),
+                    None
+                )
+                .await?
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn sender_chain_message_limit() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let config = EncryptionConfig {
+            max_messages_per_chain: Some(2),
+        };
+
+        for _ in 0..2 {
+            let message = message_encrypt_with_config(
+                b"limited",
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &config,
+                None,
+            )
+            .await?;
+            decrypt(&mut bob_store, &alice_address, &message).await?;
+        }
+
+        let alice_session = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(alice_session.messages_since_ratchet()?, 2);
+
+        assert!(matches!(
+            message_encrypt_with_config(
+                b"one too many",
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &config,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::InvalidState(..))
+        ));
+
+        // Once Bob replies, Alice ratchets forward and can send again.
+        let reply = encrypt(&mut bob_store, &alice_address, "reply").await?;
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+
+        let message = message_encrypt_with_config(
+            b"fresh chain",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &config,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &message).await?,
+            b"fresh chain"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn readonly_session_uses_only_stored_keys() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let mut messages = Vec::new();
+        for i in 0..4 {
+            let message =
+                encrypt(&mut alice_store, &bob_address, &format!("archived {}", i)).await?;
+            messages.push(match message {
+                CiphertextMessage::SignalMessage(m) => m,
+                _ => panic!("unexpected message type"),
+            });
+        }
+
+        // Skipping ahead stores the keys for messages 0 and 1.
+        decrypt(
+            &mut bob_store,
+            &alice_address,
+            &CiphertextMessage::SignalMessage(messages[2].clone()),
+        )
+        .await?;
+
+        let bob_session_record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(
+            bob_session_record.metrics()?,
+            SessionMetrics {
+                sender_chain_index: Some(0),
+                receiver_chain_index: Some(3),
+                receiver_chain_count: 1,
+                stored_message_key_count: 2,
+                previous_state_count: 0,
+            }
+        );
+        assert_eq!(
+            bob_session_record.receiver_chain_index(messages[0].sender_ratchet_key())?,
+            Some(3)
+        );
+        assert_eq!(
+            bob_session_record.receiver_chain_index(&KeyPair::generate(&mut OsRng).public_key)?,
+            None
+        );
+        let stored_keys = bob_session_record
+            .message_keys_for(messages[0].sender_ratchet_key(), 1)?
+            .expect("skipped keys are stored");
+        assert_eq!(stored_keys.counter(), 1);
+        let alice_identity = IdentityKey::decode(
+            &bob_session_record
+                .remote_identity_key_bytes()?
+                .expect("session records the remote identity"),
+        )?;
+        let bob_identity = IdentityKey::decode(&bob_session_record.local_identity_key_bytes()?)?;
+        assert!(messages[1].verify_mac(&alice_identity, &bob_identity, stored_keys.mac_key())?);
+        for consumed_or_unseen in [2, 3] {
+            assert!(bob_session_record
+                .message_keys_for(messages[0].sender_ratchet_key(), consumed_or_unseen)?
+                .is_none());
+        }
+
+        let archived = bob_session_record.into_readonly();
+
+        for _ in 0..2 {
+            assert_eq!(
+                message_decrypt_readonly(&messages[0], &archived)?,
+                b"archived 0"
+            );
+        }
+        assert!(matches!(
+            message_decrypt_readonly(&messages[2], &archived),
+            Err(SignalProtocolError::DuplicatedMessage(3, 2))
+        ));
+        assert!(matches!(
+            message_decrypt_readonly(&messages[3], &archived),
+            Err(SignalProtocolError::WouldMutate)
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn custom_protocol_info_isolates_sessions() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let config = ProtocolConfig {
+            protocol_info: b"ExampleFederation".to_vec(),
+            ..Default::default()
+        };
+
+        for (bob_config, should_decrypt) in [(config.clone(), true), (Default::default(), false)] {
+            let mut alice_store = support::test_in_memory_protocol_store()?;
+            let mut bob_store = support::test_in_memory_protocol_store()?;
+
+            let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+
+            process_prekey_bundle_with_config(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                &config,
+                None,
+            )
+            .await?;
+
+            let outgoing_message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+
+            let decrypt_config = DecryptionConfig {
+                protocol: bob_config,
+                ..Default::default()
+            };
+            let result = message_decrypt_with_config(
+                &outgoing_message,
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut csprng,
+                &decrypt_config,
+                None,
+            )
+            .await;
+
+            if should_decrypt {
+                assert_eq!(result?, b"hi");
+            } else {
+                assert!(matches!(
+                    result,
+                    Err(SignalProtocolError::InvalidMessage(_))
+                ));
+            }
+        }
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn audit_sessions_reports_unusable_sessions() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, _) = initialize_sessions_v3()?;
+
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+        let carol_address = ProtocolAddress::new("+14157777777".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        alice_store
+            .store_session(&carol_address, &SessionRecord::new_fresh(), None)
+            .await?;
+
+        let mut report = audit_sessions(&alice_store, None).await?;
+        report.sort_by(|a, b| a.0.name().cmp(b.0.name()));
+        assert_eq!(
+            report,
+            vec![
+                (carol_address, SessionHealth::NoSenderChain),
+                (bob_address, SessionHealth::Healthy),
+            ]
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn session_readiness_reports_usable_sessions() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, _) = initialize_sessions_v3()?;
+
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+        let carol_address = ProtocolAddress::new("+14157777777".to_owned(), 1);
+        let dave_address = ProtocolAddress::new("+14156666666".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        let mut archived_record = alice_session_record.clone();
+        archived_record.archive_current_state()?;
+        alice_store
+            .store_session(&carol_address, &archived_record, None)
+            .await?;
+
+        assert_eq!(
+            session_readiness(&bob_address, &alice_store.session_store, None).await?,
+            SessionReadiness {
+                can_send: true,
+                can_receive: true,
+                needs_bundle: false,
+            }
+        );
+
+        let unusable = SessionReadiness {
+            can_send: false,
+            can_receive: false,
+            needs_bundle: true,
+        };
+        assert_eq!(
+            session_readiness(&carol_address, &alice_store.session_store, None).await?,
+            unusable
+        );
+        assert_eq!(
+            session_readiness(&dave_address, &alice_store.session_store, None).await?,
+            unusable
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn touch_session_updates_last_used() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, _) = initialize_sessions_v3()?;
+        assert_eq!(alice_session_record.last_used_at()?, 0);
+        assert!(alice_session_record.created_at()?.is_some());
+        assert_eq!(alice_session_record.remote_registration_id()?, 0);
+        assert_eq!(alice_session_record.remote_registration_id_opt()?, None);
+        assert!(SessionRecord::new_fresh().remote_registration_id().is_err());
+        assert_eq!(
+            SessionRecord::new_fresh().remote_registration_id_opt()?,
+            None
+        );
+
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+
+        touch_session(&bob_address, &mut alice_store, None).await?;
+
+        let touched = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert!(touched.last_used_at()? > 0);
+        assert_eq!(touched.created_at()?, alice_session_record.created_at()?);
+        assert_eq!(
+            touched.get_sender_chain_key_bytes()?,
+            alice_session_record.get_sender_chain_key_bytes()?
+        );
+
+        let carol_address = ProtocolAddress::new("+14157777777".to_owned(), 1);
+        assert!(matches!(
+            touch_session(&carol_address, &mut alice_store, None).await,
+            Err(SignalProtocolError::SessionNotFound(_))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn deferred_encryption_leaves_store_untouched() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let (message, updated_record, expected_version) = message_encrypt_deferred(
+            b"deferred",
+            &bob_address,
+            &alice_store.session_store,
+            &mut alice_store.identity_store,
+            &EncryptionConfig {
+                associated_data: b"context".to_vec(),
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+        assert_eq!(expected_version, alice_session_record.record_version());
+
+        let stored_record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(
+            stored_record.get_sender_chain_key_bytes()?,
+            alice_session_record.get_sender_chain_key_bytes()?
+        );
+
+        assert!(
+            alice_store
+                .store_session_if_version(&bob_address, &updated_record, expected_version, None,)
+                .await?
+        );
+
+        let mut csprng = OsRng;
+        assert_eq!(
+            message_decrypt_with_config(
+                &message,
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut csprng,
+                &DecryptionConfig {
+                    associated_data: b"context".to_vec(),
+                    ..Default::default()
+                },
+                None,
+            )
+            .await?,
+            b"deferred"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn claimed_pre_key_cannot_be_used_twice() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        let pre_key_id = bob_pre_key_bundle
+            .pre_key_id()?
+            .expect("has one-time pre-key");
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let outgoing_message = encrypt(&mut alice_store, &bob_address, "first").await?;
+
+        // Simulate a concurrent decryption that has claimed the pre-key but not yet finished.
+        bob_store.claim_pre_key(pre_key_id, None).await?;
+        assert!(matches!(
+            decrypt(&mut bob_store, &alice_address, &outgoing_message).await,
+            Err(SignalProtocolError::PreKeyAlreadyConsumed(id)) if id == pre_key_id
+        ));
+        assert!(bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .is_none());
+
+        // Once the other decryption gives up, the message can be processed.
+        bob_store.release_pre_key(pre_key_id, None).await?;
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &outgoing_message).await?,
+            b"first"
+        );
+        assert!(matches!(
+            bob_store.claim_pre_key(pre_key_id, None).await,
+            Err(SignalProtocolError::InvalidPreKeyId)
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn empty_messages_round_trip() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        for expected_counter in 0..3 {
+            let message = encrypt_empty(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                None,
+            )
+            .await?;
+            match &message {
+                CiphertextMessage::SignalMessage(m) => {
+                    assert_eq!(m.counter(), expected_counter);
+                    assert_eq!(m.body().len(), 16);
+                }
+                _ => panic!("unexpected message type"),
+            }
+            assert!(decrypt(&mut bob_store, &alice_address, &message)
+                .await?
+                .is_empty());
+        }
+
+        let reply = encrypt(&mut bob_store, &alice_address, "").await?;
+        assert!(decrypt(&mut alice_store, &bob_address, &reply)
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn public_params_are_canonical() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, _) = initialize_sessions_v3()?;
+
+        let params = alice_session_record.public_params_canonical()?;
+        assert_eq!(params.len(), 4 + (2 + 33) * 2 + 4 + 4);
+        assert_eq!(params[..4], [0, 0, 0, 3]);
+        assert_eq!(
+            params,
+            SessionRecord::deserialize(&alice_session_record.serialize()?)?
+                .public_params_canonical()?
+        );
+
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        encrypt(&mut alice_store, &bob_address, "hello").await?;
+
+        let updated_params = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found")
+            .public_params_canonical()?;
+        assert_eq!(updated_params[..74], params[..74]);
+        assert_eq!(updated_params[74..78], [0, 0, 0, 1]);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn deferred_decryption_reports_pre_key_use() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        let pre_key_id = bob_pre_key_bundle.pre_key_id()?;
+        assert!(pre_key_id.is_some());
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let mut bob_session_record = SessionRecord::new_fresh();
+        for (i, expected_pre_key_id) in [pre_key_id, None].iter().enumerate() {
+            let text = format!("deferred {}", i);
+            let message = encrypt(&mut alice_store, &bob_address, &text).await?;
+            let (ptext, used_pre_key_id) = message_decrypt_deferred(
+                &message,
+                &alice_address,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut bob_session_record,
+                &mut csprng,
+                None,
+            )
+            .await?;
+            assert_eq!(ptext, text.as_bytes());
+            assert_eq!(used_pre_key_id, *expected_pre_key_id);
+        }
+
+        // Nothing was stored or removed on Bob's behalf.
+        assert!(bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .is_none());
+        assert!(bob_store
+            .get_pre_key(pre_key_id.expect("checked above"), None)
+            .await
+            .is_ok());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[cfg(feature = "weak-ciphers")]
+#[test]
+fn aes_128_sessions_require_both_peers() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let config = ProtocolConfig {
+            aes_128: true,
+            ..Default::default()
+        };
+
+        for (bob_config, should_decrypt) in [(config.clone(), true), (Default::default(), false)] {
+            let mut alice_store = support::test_in_memory_protocol_store()?;
+            let mut bob_store = support::test_in_memory_protocol_store()?;
+
+            let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+
+            process_prekey_bundle_with_config(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                &config,
+                None,
+            )
+            .await?;
+
+            let outgoing_message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+
+            let decrypt_config = DecryptionConfig {
+                protocol: bob_config,
+                ..Default::default()
+            };
+            let result = message_decrypt_with_config(
+                &outgoing_message,
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut csprng,
+                &decrypt_config,
+                None,
+            )
+            .await;
+
+            if should_decrypt {
+                assert_eq!(result?, b"hi");
+                assert_eq!(
+                    bob_store
+                        .load_session(&alice_address, None)
+                        .await?
+                        .expect("session established")
+                        .session_version()?,
+                    CIPHERTEXT_MESSAGE_AES_128_VERSION as u32
+                );
+            } else {
+                assert!(result.is_err());
+            }
+        }
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn expired_pre_keys_exclude_consumed_ones() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        let used_id = bob_pre_key_bundle
+            .pre_key_id()?
+            .expect("has one-time pre-key");
+        let unused_id = used_id.wrapping_add(1);
+        let fresh_id = used_id.wrapping_add(2);
+        let untimed_id = used_id.wrapping_add(3);
+
+        let used_record = bob_store
+            .get_pre_key(used_id, None)
+            .await?
+            .with_timestamp(100);
+        bob_store.save_pre_key(used_id, &used_record, None).await?;
+        for (id, timestamp) in [(unused_id, 100), (fresh_id, 500), (untimed_id, 0)] {
+            let record =
+                PreKeyRecord::new(id, &KeyPair::generate(&mut csprng)).with_timestamp(timestamp);
+            bob_store.save_pre_key(id, &record, None).await?;
+        }
+
+        let mut expected = vec![used_id, unused_id];
+        expected.sort_unstable();
+        assert_eq!(bob_store.expired_pre_keys(200, None).await?, expected);
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let outgoing_message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+        decrypt(&mut bob_store, &alice_address, &outgoing_message).await?;
+
+        let expired = bob_store.expired_pre_keys(200, None).await?;
+        assert_eq!(expired, vec![unused_id]);
+
+        bob_store.remove_pre_keys(&expired, None).await?;
+        assert_eq!(bob_store.pre_key_count(None).await?, Some(2));
+        assert!(bob_store.expired_pre_keys(200, None).await?.is_empty());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn redundant_pre_key_messages_can_be_rejected() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let config = DecryptionConfig {
+            reject_redundant_prekey: true,
+            ..Default::default()
+        };
+
+        let mut decrypt_with_config =
+            |store: &mut InMemSignalProtocolStore,
+             message: &CiphertextMessage,
+             config: &DecryptionConfig| {
+                message_decrypt_with_config(
+                    message,
+                    &alice_address,
+                    &mut store.session_store,
+                    &mut store.identity_store,
+                    &mut store.pre_key_store,
+                    &mut store.signed_pre_key_store,
+                    &mut csprng,
+                    config,
+                    None,
+                )
+                .now_or_never()
+                .expect("sync")
+            };
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut OsRng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut OsRng,
+            None,
+        )
+        .await?;
+
+        // The first pre-key message, and repeats of it, set up the session as usual.
+        let first = encrypt(&mut alice_store, &bob_address, "first").await?;
+        assert_eq!(
+            decrypt_with_config(&mut bob_store, &first, &config)?,
+            b"first"
+        );
+        let second = encrypt(&mut alice_store, &bob_address, "second").await?;
+        assert_eq!(
+            decrypt_with_config(&mut bob_store, &second, &config)?,
+            b"second"
+        );
+
+        // Alice starts over with a new bundle while Bob still has a working session.
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut OsRng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut OsRng,
+            None,
+        )
+        .await?;
+        let restart = encrypt(&mut alice_store, &bob_address, "restart").await?;
+        assert_eq!(restart.message_type(), CiphertextMessageType::PreKey);
+
+        let bob_session_before = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session established")
+            .serialize()?;
+        assert!(matches!(
+            decrypt_with_config(&mut bob_store, &restart, &config),
+            Err(SignalProtocolError::UnexpectedPreKeyMessage(_))
+        ));
+        assert_eq!(
+            bob_store
+                .load_session(&alice_address, None)
+                .await?
+                .expect("session kept")
+                .serialize()?,
+            bob_session_before
+        );
+        assert_eq!(bob_store.pre_key_count(None).await?, Some(1));
+
+        // Without the option, the session is reset.
+        assert_eq!(
+            decrypt_with_config(&mut bob_store, &restart, &Default::default())?,
+            b"restart"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn session_pairs_for_each_supported_version() -> Result<(), SignalProtocolError> {
+    async {
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        for version in SUPPORTED_SESSION_VERSIONS {
+            let (alice_session_record, bob_session_record) =
+                establish_session_pair(&mut OsRng, version)?;
+            assert_eq!(alice_session_record.session_version()?, version);
+            assert_eq!(bob_session_record.session_version()?, version);
+
+            let mut alice_store = support::test_in_memory_protocol_store()?;
+            let mut bob_store = support::test_in_memory_protocol_store()?;
+            alice_store
+                .store_session(&bob_address, &alice_session_record, None)
+                .await?;
+            bob_store
+                .store_session(&alice_address, &bob_session_record, None)
+                .await?;
+
+            let message = encrypt(&mut alice_store, &bob_address, "hello").await?;
+            assert_eq!(
+                decrypt(&mut bob_store, &alice_address, &message).await?,
+                b"hello"
+            );
+            let reply = encrypt(&mut bob_store, &alice_address, "hi").await?;
+            assert_eq!(
+                decrypt(&mut alice_store, &bob_address, &reply).await?,
+                b"hi"
+            );
+        }
+
+        assert!(matches!(
+            establish_session_pair(&mut OsRng, 2),
+            Err(SignalProtocolError::UnsupportedSessionVersion(2))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn untrusted_identity_callback_reports_both_keys() -> Result<(), SignalProtocolError> {
+    async {
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+        let alice_identity =
+            IdentityKey::decode(&alice_session_record.local_identity_key_bytes()?)?;
+        let bob_identity = IdentityKey::decode(&bob_session_record.local_identity_key_bytes()?)?;
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let reports = Arc::new(Mutex::new(vec![]));
+        let callback: UntrustedIdentityCallback = {
+            let reports = reports.clone();
+            Arc::new(
+                move |address: &ProtocolAddress,
+                      stored: Option<IdentityKey>,
+                      presented: IdentityKey| {
+                    reports
+                        .lock()
+                        .expect("not poisoned")
+                        .push((address.clone(), stored, presented))
+                },
+            )
+        };
+
+        let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+
+        // Bob has since been told of a different identity for Alice.
+        let other_identity = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+        bob_store
+            .save_identity(&alice_address, &other_identity, None)
+            .await?;
+        let decrypt_config = DecryptionConfig {
+            on_untrusted_identity: Some(callback.clone()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            message_decrypt_with_config(
+                &message,
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut OsRng,
+                &decrypt_config,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::UntrustedIdentity(_))
+        ));
+        assert_eq!(
+            reports
+                .lock()
+                .expect("not poisoned")
+                .drain(..)
+                .collect::<Vec<_>>(),
+            vec![(alice_address.clone(), Some(other_identity), alice_identity)]
+        );
+
+        // Likewise for Alice sending to Bob.
+        alice_store
+            .save_identity(&bob_address, &other_identity, None)
+            .await?;
+        let encrypt_config = EncryptionConfig {
+            on_untrusted_identity: Some(callback),
+            ..Default::default()
+        };
+        assert!(matches!(
+            message_encrypt_with_config(
+                b"hi again",
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &encrypt_config,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::UntrustedIdentity(_))
+        ));
+        assert_eq!(
+            reports
+                .lock()
+                .expect("not poisoned")
+                .drain(..)
+                .collect::<Vec<_>>(),
+            vec![(bob_address, Some(other_identity), bob_identity)]
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn exported_message_keys_can_be_restored() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let mut messages = Vec::new();
+        for i in 0..4 {
+            messages
+                .push(encrypt(&mut alice_store, &bob_address, &format!("message {}", i)).await?);
+        }
+
+        // Skipping ahead stores the keys for messages 0 to 2.
+        decrypt(&mut bob_store, &alice_address, &messages[3]).await?;
+
+        let summarize = |keys: &[(PublicKey, u32, MessageKeys)]| {
+            keys.iter()
+                .map(|(sender, counter, keys)| {
+                    (
+                        *sender,
+                        *counter,
+                        keys.cipher_key().to_vec(),
+                        keys.mac_key().to_vec(),
+                        keys.iv().to_vec(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let exported = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found")
+            .export_message_keys()?;
+        assert_eq!(exported.len(), 3);
+
+        for message in &messages[..3] {
+            decrypt(&mut bob_store, &alice_address, message).await?;
+        }
+        let mut bob_session_record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+        assert!(bob_session_record.export_message_keys()?.is_empty());
+
+        // Restoring the keys makes the same messages decryptable again.
+        // Importing twice is harmless.
+        bob_session_record.import_message_keys(&exported)?;
+        bob_session_record.import_message_keys(&exported)?;
+        assert_eq!(
+            summarize(&bob_session_record.export_message_keys()?),
+            summarize(&exported)
+        );
+
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+        for (i, message) in messages[..3].iter().enumerate() {
+            assert_eq!(
+                decrypt(&mut bob_store, &alice_address, message).await?,
+                format!("message {}", i).as_bytes()
+            );
+        }
+
+        // Keys can also be imported into a session that has no chain for them.
+        let (_, mut other_session_record) = initialize_sessions_v3()?;
+        other_session_record.import_message_keys(&exported)?;
+        assert_eq!(
+            summarize(&other_session_record.export_message_keys()?),
+            summarize(&exported)
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn importing_message_keys_keeps_live_receiver_chains() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let first = encrypt(&mut alice_store, &bob_address, "first").await?;
+        decrypt(&mut bob_store, &alice_address, &first).await?;
+
+        // Keys for more old ratchet keys than a session keeps receiver chains for.
+        let old_keys = |count: u32, csprng: &mut OsRng| {
+            (0..count)
+                .map(|counter| {
+                    Ok((
+                        KeyPair::generate(csprng).public_key,
+                        counter,
+                        MessageKeys::new(&[1; 32], &[2; 32], &[3; 16], counter)?,
+                    ))
+                })
+                .collect::<Result<Vec<_>, SignalProtocolError>>()
+        };
+        let mut bob_session_record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+        bob_session_record.import_message_keys(&old_keys(10, &mut csprng)?)?;
+        assert_eq!(bob_session_record.export_message_keys()?.len(), 10);
+
+        // Past the limit on imported chains, nothing is imported.
+        assert!(matches!(
+            bob_session_record.import_message_keys(&old_keys(100, &mut csprng)?),
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+        assert_eq!(bob_session_record.export_message_keys()?.len(), 10);
+
+        // The live chain is still there, so new messages on it decrypt.
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+        let second = encrypt(&mut alice_store, &bob_address, "second").await?;
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &second).await?,
+            b"second"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+#[test]
+fn interleaved_sessions_keep_all_states() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        // Three sessions from the same sender, e.g. set up concurrently after a reinstall.
+        let alice_identity = IdentityKeyPair::generate(&mut csprng);
+        let mut alice_stores = vec![];
+        for _ in 0..3 {
+            let mut alice_store = InMemSignalProtocolStore::new(alice_identity, 5)?;
+            let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                None,
+            )
+            .await?;
+            alice_stores.push(alice_store);
+        }
+
+        // Each message is decrypted with a different state than the previous one, so every
+        // decryption after the first three promotes a previous state.
+        for round in 0..3 {
+            for (i, alice_store) in alice_stores.iter_mut().enumerate() {
+                let ptext = format!("round {} from session {}", round, i);
+                let message = encrypt(alice_store, &bob_address, &ptext).await?;
+                assert_eq!(
+                    decrypt(&mut bob_store, &alice_address, &message).await?,
+                    ptext.as_bytes()
+                );
+            }
+        }
+
+        let bob_session_record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(bob_session_record.metrics()?.previous_state_count, 2);
+        assert_eq!(bob_session_record.previous_state_count(), 2);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn decrypt_into_reuses_the_output_buffer() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let mut out = Vec::with_capacity(1024);
+        let capacity = out.capacity();
+
+        for message in [
+            "a fairly long first message, sent as a pre-key message",
+            "hi",
+        ] {
+            let ctext = encrypt(&mut alice_store, &bob_address, message).await?;
+            message_decrypt_into(
+                &ctext,
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut csprng,
+                &mut out,
+                None,
+            )
+            .await?;
+            assert_eq!(out, message.as_bytes());
+            assert_eq!(out.capacity(), capacity);
+        }
+
+        // A failed decryption does not leave stale plaintext behind.
+        let ctext = encrypt(&mut alice_store, &bob_address, "replayed").await?;
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &ctext).await?,
+            b"replayed"
+        );
+        assert!(matches!(
+            message_decrypt_into(
+                &ctext,
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut csprng,
+                &mut out,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::DuplicatedMessage(_, _))
+        ));
+        assert!(out.is_empty());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn associated_data_must_match_to_decrypt() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let decrypt_with_associated_data =
+            |store: &mut InMemSignalProtocolStore, message: &CiphertextMessage, ad: &[u8]| {
+                message_decrypt_with_config(
+                    message,
+                    &alice_address,
+                    &mut store.session_store,
+                    &mut store.identity_store,
+                    &mut store.pre_key_store,
+                    &mut store.signed_pre_key_store,
+                    &mut OsRng,
+                    &DecryptionConfig {
+                        associated_data: ad.to_vec(),
+                        ..Default::default()
+                    },
+                    None,
+                )
+                .now_or_never()
+                .expect("sync")
+            };
+
+        let message = message_encrypt_with_aad(
+            b"hello",
+            b"conversation 1",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            None,
+        )
+        .await?;
+
+        // The associated data is not part of the message.
+        assert!(!message
+            .serialize()
+            .windows(b"conversation 1".len())
+            .any(|w| w == b"conversation 1"));
+
+        for wrong in [&b""[..], &b"conversation 2"[..]] {
+            assert!(decrypt_with_associated_data(&mut bob_store, &message, wrong).is_err());
+        }
+        assert_eq!(
+            decrypt_with_associated_data(&mut bob_store, &message, b"conversation 1")?,
+            b"hello"
+        );
+
+        // Messages without associated data are unaffected.
+        let message = encrypt(&mut alice_store, &bob_address, "no associated data").await?;
+        assert_eq!(
+            decrypt_with_associated_data(&mut bob_store, &message, b"")?,
+            b"no associated data"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn protocol_snapshot_round_trip() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "before backup").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+
+        let snapshot = backup::snapshot(
+            &bob_store.session_store,
+            &bob_store.identity_store,
+            &bob_store.pre_key_store,
+            &bob_store.signed_pre_key_store,
+            None,
+        )
+        .await?;
+        let mut restored = backup::restore_in_memory(&snapshot).await?;
+
+        assert_eq!(
+            restored.get_identity_key_pair(None).await?.serialize(),
+            bob_store.get_identity_key_pair(None).await?.serialize()
+        );
+        assert_eq!(
+            restored.get_local_registration_id(None).await?,
+            bob_store.get_local_registration_id(None).await?
+        );
+        assert_eq!(
+            restored.get_identity(&alice_address, None).await?,
+            bob_store.get_identity(&alice_address, None).await?
+        );
+        assert_eq!(
+            restored
+                .get_signed_pre_key(bob_pre_key_bundle.signed_pre_key_id()?, None)
+                .await?
+                .serialize()?,
+            bob_store
+                .get_signed_pre_key(bob_pre_key_bundle.signed_pre_key_id()?, None)
+                .await?
+                .serialize()?
+        );
+        assert_eq!(
+            backup::snapshot(
+                &restored.session_store,
+                &restored.identity_store,
+                &restored.pre_key_store,
+                &restored.signed_pre_key_store,
+                None,
+            )
+            .await?,
+            snapshot
+        );
+
+        // Restoring through the store traits needs a matching local identity.
+        let mut copy = InMemSignalProtocolStore::new(
+            bob_store.get_identity_key_pair(None).await?,
+            bob_store.get_local_registration_id(None).await?,
+        )?;
+        backup::restore(
+            &snapshot,
+            &mut copy.session_store,
+            &mut copy.identity_store,
+            &mut copy.pre_key_store,
+            &mut copy.signed_pre_key_store,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            backup::snapshot(
+                &copy.session_store,
+                &copy.identity_store,
+                &copy.pre_key_store,
+                &copy.signed_pre_key_store,
+                None,
+            )
+            .await?,
+            snapshot
+        );
+        assert!(matches!(
+            backup::restore(
+                &snapshot,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &mut alice_store.pre_key_store,
+                &mut alice_store.signed_pre_key_store,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+
+        // The restored session carries on where the original left off.
+        let message = encrypt(&mut alice_store, &bob_address, "after backup").await?;
+        assert_eq!(
+            decrypt(&mut restored, &alice_address, &message).await?,
+            b"after backup"
+        );
+
+        // Sessions must agree with the identity store.
+        bob_store
+            .save_identity(
+                &alice_address,
+                &IdentityKey::new(KeyPair::generate(&mut csprng).public_key),
+                None,
+            )
+            .await?;
+        let mismatched = backup::snapshot(
+            &bob_store.session_store,
+            &bob_store.identity_store,
+            &bob_store.pre_key_store,
+            &bob_store.signed_pre_key_store,
+            None,
+        )
+        .await?;
+        assert!(matches!(
+            backup::restore_in_memory(&mismatched).await,
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+
+        let mut wrong_version = snapshot;
+        wrong_version[0] += 1;
+        assert!(matches!(
+            backup::restore_in_memory(&wrong_version).await,
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+        assert!(backup::restore_in_memory(&[]).await.is_err());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn tofu_identity_store_rejects_changed_identities() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let alice_identity = alice_store.get_identity_key_pair(None).await?;
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let callback: IdentityChangeCallback = {
+            let changes = changes.clone();
+            Arc::new(
+                move |address: &ProtocolAddress, stored: IdentityKey, presented: IdentityKey| {
+                    changes
+                        .lock()
+                        .expect("not poisoned")
+                        .push((address.clone(), stored, presented))
+                },
+            )
+        };
+        let mut alice_identity_store = TofuIdentityStore::new(InMemIdentityKeyStore::new(
+            alice_identity,
+            alice_store.get_local_registration_id(None).await?,
+        ))
+        .with_identity_change_callback(callback);
+
+        // The first identity seen is trusted and recorded.
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        let bob_identity = *bob_store.get_identity_key_pair(None).await?.identity_key();
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            alice_identity_store
+                .get_identity(&bob_address, None)
+                .await?,
+            Some(bob_identity)
+        );
+        message_encrypt(
+            b"hi",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_identity_store,
+            None,
+        )
+        .await?;
+        assert!(changes.lock().expect("not poisoned").is_empty());
+
+        // A different identity for the same address is rejected and reported.
+        let mut new_bob_store = support::test_in_memory_protocol_store()?;
+        let new_bob_identity = *new_bob_store
+            .get_identity_key_pair(None)
+            .await?
+            .identity_key();
+        let new_bob_pre_key_bundle = create_pre_key_bundle(&mut new_bob_store, &mut csprng).await?;
+        assert!(matches!(
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_identity_store,
+                &new_bob_pre_key_bundle,
+                &mut csprng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::UntrustedIdentity(_))
+        ));
+        assert_eq!(
+            *changes.lock().expect("not poisoned"),
+            [(bob_address.clone(), bob_identity, new_bob_identity)]
+        );
+
+        // Saving the new identity accepts the change.
+        alice_identity_store
+            .save_identity(&bob_address, &new_bob_identity, None)
+            .await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_identity_store,
+            &new_bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn accepting_an_identity_change_archives_the_old_session() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let old_base_key = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found")
+            .alice_base_key()?
+            .to_vec();
+
+        // Bob re-registers with a new identity.
+        let mut new_bob_store = support::test_in_memory_protocol_store()?;
+        let new_bob_identity = *new_bob_store
+            .get_identity_key_pair(None)
+            .await?
+            .identity_key();
+        let new_bob_pre_key_bundle = create_pre_key_bundle(&mut new_bob_store, &mut csprng).await?;
+        assert!(matches!(
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &new_bob_pre_key_bundle,
+                &mut csprng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::UntrustedIdentity(_))
+        ));
+
+        assert!(
+            accept_identity_change(
+                &bob_address,
+                &new_bob_identity,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                None,
+            )
+            .await?
+        );
+        let record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert!(!record.has_current_session_state());
+        assert_eq!(record.previous_state_count(), 1);
+        assert_eq!(
+            alice_store.get_identity(&bob_address, None).await?,
+            Some(new_bob_identity)
+        );
+
+        // Accepting the same identity again changes nothing.
+        assert!(
+            !accept_identity_change(
+                &bob_address,
+                &new_bob_identity,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                None,
+            )
+            .await?
+        );
+
+        // A session with the new identity can now be set up, and the old one is still archived.
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &new_bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(
+            record
+                .all_base_keys()?
+                .iter()
+                .map(|key| key.serialize().into_vec())
+                .collect::<Vec<_>>(),
+            [record.alice_base_key()?.to_vec(), old_base_key]
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn failed_identity_change_changes_nothing() -> Result<(), SignalProtocolError> {
+    /// Fails every write.
+    struct ReadOnlySessionStore(InMemSessionStore);
+
+    #[async_trait::async_trait(?Send)]
+    impl SessionStore for ReadOnlySessionStore {
+        async fn load_session(
+            &self,
+            address: &ProtocolAddress,
+            ctx: Context,
+        ) -> Result<Option<SessionRecord>, SignalProtocolError> {
+            self.0.load_session(address, ctx).await
+        }
+
+        async fn store_session(
+            &mut self,
+            _address: &ProtocolAddress,
+            _record: &SessionRecord,
+            _ctx: Context,
+        ) -> Result<(), SignalProtocolError> {
+            Err(SignalProtocolError::InvalidState(
+                "store_session",
+                "read-only".to_owned(),
+            ))
+        }
+    }
+
+    async {
+        let (alice_session_record, _) = initialize_sessions_v3()?;
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut session_store = InMemSessionStore::new();
+        session_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        let mut session_store = ReadOnlySessionStore(session_store);
+
+        let new_bob_identity = *support::test_in_memory_protocol_store()?
+            .get_identity_key_pair(None)
+            .await?
+            .identity_key();
+        assert!(accept_identity_change(
+            &bob_address,
+            &new_bob_identity,
+            &mut session_store,
+            &mut alice_store.identity_store,
+            None,
+        )
+        .await
+        .is_err());
+
+        assert_eq!(alice_store.get_identity(&bob_address, None).await?, None);
+        assert!(session_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found")
+            .has_current_session_state());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+#[test]
+fn version_consensus_across_devices() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+
+        for device_id in [1, 2] {
+            let bob_address = ProtocolAddress::new("+14151111112".to_owned(), device_id);
+            let mut bob_store = support::test_in_memory_protocol_store()?;
+            let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                None,
+            )
+            .await?;
+        }
+
+        // A device with only archived sessions does not count.
+        let archived_address = ProtocolAddress::new("+14151111112".to_owned(), 3);
+        let mut archived_record = alice_store
+            .load_session(&ProtocolAddress::new("+14151111112".to_owned(), 1), None)
+            .await?
+            .expect("session found");
+        archived_record.archive_current_state()?;
+        alice_store
+            .store_session(&archived_address, &archived_record, None)
+            .await?;
+
+        assert_eq!(
+            version_consensus("+14151111112", &alice_store.session_store, None).await?,
+            Some(3)
+        );
+        assert_eq!(
+            version_consensus("+14151111113", &alice_store.session_store, None).await?,
+            None
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn key_wrapper_encrypts_stored_message_keys() -> Result<(), SignalProtocolError> {
+    struct XorWrapper(u8);
+
+    impl KeyWrapper for XorWrapper {
+        fn wrap(&self, keys: &[u8]) -> Result<Vec<u8>, SignalProtocolError> {
+            let mut wrapped = vec![self.0];
+            wrapped.extend(keys.iter().map(|b| b ^ self.0));
+            Ok(wrapped)
+        }
+
+        fn unwrap(&self, wrapped: &[u8]) -> Result<Vec<u8>, SignalProtocolError> {
+            match wrapped.split_first() {
+                Some((&tag, keys)) if tag == self.0 => {
+                    Ok(keys.iter().map(|b| b ^ self.0).collect())
+                }
+                _ => Err(SignalProtocolError::InvalidArgument(
+                    "wrapped with a different key".to_owned(),
+                )),
+            }
+        }
+    }
+
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let skipped = encrypt(&mut alice_store, &bob_address, "first").await?;
+        let received = encrypt(&mut alice_store, &bob_address, "second").await?;
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &received).await?,
+            b"second"
+        );
+
+        let mut record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+        let plain_bytes = record.serialize()?;
+        record.set_key_wrapper(Box::new(XorWrapper(0x5a)))?;
+        let wrapped_bytes = record.serialize()?;
+        assert_ne!(wrapped_bytes, plain_bytes);
+
+        // Without the wrapper, the stored keys cannot be used.
+        let mut wrapped_record = SessionRecord::deserialize(&wrapped_bytes)?;
+        assert!(wrapped_record
+            .set_key_wrapper(Box::new(XorWrapper(0x33)))
+            .is_err());
+        bob_store
+            .store_session(&alice_address, &wrapped_record, None)
+            .await?;
+        assert!(matches!(
+            decrypt(&mut bob_store, &alice_address, &skipped).await,
+            Err(SignalProtocolError::MessageKeysWrapped)
+        ));
+
+        wrapped_record.set_key_wrapper(Box::new(XorWrapper(0x5a)))?;
+        assert_eq!(wrapped_record.serialize()?, wrapped_bytes);
+        bob_store
+            .store_session(&alice_address, &wrapped_record, None)
+            .await?;
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &skipped).await?,
+            b"first"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn session_serializer_translates_records() -> Result<(), SignalProtocolError> {
+    struct HexSerializer;
+
+    impl SessionSerializer for HexSerializer {
+        fn serialize(&self, record: &SessionRecord) -> Result<Vec<u8>, SignalProtocolError> {
+            Ok(hex::encode(ProtobufSessionSerializer.serialize(record)?).into_bytes())
+        }
+
+        fn deserialize(&self, bytes: &[u8]) -> Result<SessionRecord, SignalProtocolError> {
+            let bytes = hex::decode(bytes)
+                .map_err(|e| SignalProtocolError::InvalidArgument(e.to_string()))?;
+            ProtobufSessionSerializer.deserialize(&bytes)
+        }
+    }
+
+    let (mut record, _) = initialize_sessions_v3()?;
+    record.set_app_label(Some(b"bridge".to_vec()));
+
+    let protobuf_bytes = record.serialize()?;
+    assert_eq!(
+        record.serialize_with(&ProtobufSessionSerializer)?,
+        protobuf_bytes
+    );
+
+    let hex_bytes = record.serialize_with(&HexSerializer)?;
+    assert_eq!(hex_bytes, hex::encode(&protobuf_bytes).into_bytes());
+    let restored = SessionRecord::deserialize_with(&hex_bytes, &HexSerializer)?;
+    assert_eq!(restored.serialize()?, protobuf_bytes);
+    assert!(SessionRecord::deserialize_with(&protobuf_bytes, &HexSerializer).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn first_message_callback_fires_once_per_side() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let established = Arc::new(Mutex::new(Vec::new()));
+        let on_first_message: FirstMessageCallback = {
+            let established = established.clone();
+            Arc::new(move |address: &ProtocolAddress| {
+                established
+                    .lock()
+                    .expect("not poisoned")
+                    .push(address.clone())
+            })
+        };
+        let config = DecryptionConfig {
+            on_first_message: Some(on_first_message),
+            ..Default::default()
+        };
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        // Both pre-key messages carry the same base key; only the first sets up Bob's session.
+        for text in ["hi", "are you there?"] {
+            let outgoing_message = encrypt(&mut alice_store, &bob_address, text).await?;
+            assert_eq!(
+                outgoing_message.message_type(),
+                CiphertextMessageType::PreKey
+            );
+            message_decrypt_with_config(
+                &outgoing_message,
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut csprng,
+                &config,
+                None,
+            )
+            .await?;
+        }
+        assert_eq!(
+            *established.lock().expect("not poisoned"),
+            [alice_address.clone()]
+        );
+
+        // Only Bob's first reply completes Alice's side.
+        for text in ["yes", "still here"] {
+            let reply = encrypt(&mut bob_store, &alice_address, text).await?;
+            message_decrypt_with_config(
+                &reply,
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &mut alice_store.pre_key_store,
+                &mut alice_store.signed_pre_key_store,
+                &mut csprng,
+                &config,
+                None,
+            )
+            .await?;
+        }
+        assert_eq!(
+            *established.lock().expect("not poisoned"),
+            [alice_address, bob_address]
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_messages_round_trip_with_bounded_output() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let config = EncryptionConfig {
+            compression: Compression::Deflate,
+            ..Default::default()
+        };
+        let encrypt_with_compression = |store: &mut InMemSignalProtocolStore,
+                                        ptext: &[u8]|
+         -> Result<SignalMessage, SignalProtocolError> {
+            match message_encrypt_with_config(
+                ptext,
+                &bob_address,
+                &mut store.session_store,
+                &mut store.identity_store,
+                &config,
+                None,
+            )
+            .now_or_never()
+            .expect("sync")?
+            {
+                CiphertextMessage::SignalMessage(message) => Ok(message),
+                _ => panic!("expected a SignalMessage"),
+            }
+        };
+
+        let ptext = "all work and no play makes jack a dull boy. ".repeat(50);
+        let message = encrypt_with_compression(&mut alice_store, ptext.as_bytes())?;
+        assert!(message.is_compressed());
+        assert!(message.body().len() < ptext.len() / 4);
+        assert_eq!(
+            decrypt(
+                &mut bob_store,
+                &alice_address,
+                &CiphertextMessage::SignalMessage(message)
+            )
+            .await?,
+            ptext.as_bytes()
+        );
+
+        // Text that does not compress is sent as is.
+        let message = encrypt_with_compression(&mut alice_store, b"hi")?;
+        assert!(!message.is_compressed());
+        assert_eq!(
+            decrypt(
+                &mut bob_store,
+                &alice_address,
+                &CiphertextMessage::SignalMessage(message)
+            )
+            .await?,
+            b"hi"
+        );
+
+        // A small message that would inflate past the limit is rejected.
+        let message = encrypt_with_compression(&mut alice_store, &vec![0; 2 << 20])?;
+        assert!(message.body().len() < 16 * 1024);
+        assert!(matches!(
+            decrypt(
+                &mut bob_store,
+                &alice_address,
+                &CiphertextMessage::SignalMessage(message)
+            )
+            .await,
+            Err(SignalProtocolError::MessageTooLong(_))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn verify_session_identity_checks_the_pinned_key() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        let bob_identity = *bob_store.get_identity_key_pair(None).await?.identity_key();
+        let other_identity = *IdentityKeyPair::generate(&mut csprng).identity_key();
+
+        assert!(
+            !verify_session_identity(
+                &bob_address,
+                &bob_identity,
+                &alice_store.session_store,
+                None
+            )
+            .await?
+        );
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        assert!(
+            verify_session_identity(
+                &bob_address,
+                &bob_identity,
+                &alice_store.session_store,
+                None
+            )
+            .await?
+        );
+        assert!(
+            !verify_session_identity(
+                &bob_address,
+                &other_identity,
+                &alice_store.session_store,
+                None
+            )
+            .await?
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn process_serialized_prekey_bundle() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+
+        let serialized = bob_pre_key_bundle.serialize()?;
+        let parsed = PreKeyBundle::deserialize(&serialized)?;
+        assert_eq!(parsed.serialize()?, serialized);
+        assert_eq!(parsed.pre_key_id()?, bob_pre_key_bundle.pre_key_id()?);
+        assert_eq!(parsed.identity_key()?, bob_pre_key_bundle.identity_key()?);
+        assert!(PreKeyBundle::deserialize(&serialized[..serialized.len() - 1]).is_err());
+
+        // A bundle whose signed pre-key signature does not verify is rejected.
+        let mut bad_signature = bob_pre_key_bundle.signed_pre_key_signature()?.to_vec();
+        bad_signature[0] ^= 1;
+        let tampered = PreKeyBundle::new(
+            bob_pre_key_bundle.registration_id()?,
+            bob_pre_key_bundle.device_id()?,
+            bob_pre_key_bundle
+                .pre_key_id()?
+                .zip(bob_pre_key_bundle.pre_key_public()?),
+            bob_pre_key_bundle.signed_pre_key_id()?,
+            bob_pre_key_bundle.signed_pre_key_public()?,
+            bad_signature,
+            *bob_pre_key_bundle.identity_key()?,
+        )?;
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        assert!(matches!(
+            process_prekey_bundle_bytes(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &tampered.serialize()?,
+                &mut csprng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::SignatureValidationFailed)
+        ));
+
+        process_prekey_bundle_bytes(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &serialized,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let outgoing_message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &outgoing_message).await?,
+            b"hi"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn all_identities_lists_stored_identities() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        assert!(alice_store.all_identities(None).await?.is_empty());
+
+        let carol_address = ProtocolAddress::new("+14151111113".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+        let carol_identity = *IdentityKeyPair::generate(&mut csprng).identity_key();
+        let bob_identity = *IdentityKeyPair::generate(&mut csprng).identity_key();
+        alice_store
+            .save_identity(&carol_address, &carol_identity, None)
+            .await?;
+        alice_store
+            .save_identity(&bob_address, &bob_identity, None)
+            .await?;
+
+        assert_eq!(
+            alice_store.all_identities(None).await?,
+            [
+                (bob_address.clone(), bob_identity, TrustStatus::Trusted),
+                (carol_address.clone(), carol_identity, TrustStatus::Trusted),
+            ]
+        );
+
+        let tofu_store = TofuIdentityStore::new(alice_store.identity_store.clone());
+        assert_eq!(
+            tofu_store.all_identities(None).await?,
+            alice_store.all_identities(None).await?
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn message_unique_ids_match_on_both_sides() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let (first, first_id, first_was_prekey) = message_encrypt_with_metadata(
+            b"hi",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &EncryptionConfig::default(),
+            None,
+        )
+        .await?;
+        let (second, second_id, second_was_prekey) = message_encrypt_with_metadata(
+            b"hi",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &EncryptionConfig::default(),
+            None,
+        )
+        .await?;
+        assert_ne!(first_id, second_id);
+        // Both go out as pre-key messages until Bob replies.
+        assert!(first_was_prekey);
+        assert!(second_was_prekey);
+
+        // The recipient derives the same ID from the received message, including a retransmission.
+        let received = PreKeySignalMessage::try_from(first.serialize())?;
+        assert_eq!(received.message().unique_id(), first_id);
+        let retransmitted = PreKeySignalMessage::try_from(first.serialize())?;
+        assert_eq!(retransmitted.message().unique_id(), first_id);
+        assert_eq!(
+            message_unique_id(
+                received.message().sender_ratchet_key(),
+                received.message().counter()
+            ),
+            first_id
+        );
+
+        decrypt(&mut bob_store, &alice_address, &first).await?;
+        decrypt(&mut bob_store, &alice_address, &second).await?;
+        let reply = encrypt(&mut bob_store, &alice_address, "hello").await?;
+        assert_ne!(
+            SignalMessage::try_from(reply.serialize())?.unique_id(),
+            first_id
+        );
+
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+        // The config is honoured, here by binding associated data to the message.
+        let (third, _, third_was_prekey) = message_encrypt_with_metadata(
+            b"hi",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &EncryptionConfig {
+                associated_data: b"context".to_vec(),
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+        assert!(!third_was_prekey);
+        assert_eq!(third.message_type(), CiphertextMessageType::Whisper);
+        let ptext = message_decrypt_with_config(
+            &third,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut csprng,
+            &DecryptionConfig {
+                associated_data: b"context".to_vec(),
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+        assert_eq!(ptext, b"hi");
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn peek_decrypt_never_modifies_the_session() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let skipped = encrypt(&mut alice_store, &bob_address, "skipped").await?;
+        let received = encrypt(&mut alice_store, &bob_address, "received").await?;
+        let unseen = encrypt(&mut alice_store, &bob_address, "unseen").await?;
+        decrypt(&mut bob_store, &alice_address, &received).await?;
+
+        let before = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found")
+            .serialize()?;
+
+        for _ in 0..2 {
+            assert_eq!(
+                peek_decrypt(&skipped, &alice_address, &bob_store.session_store, None).await?,
+                b"skipped"
+            );
+        }
+        assert!(matches!(
+            peek_decrypt(&received, &alice_address, &bob_store.session_store, None).await,
+            Err(SignalProtocolError::DuplicatedMessage(_, _))
+        ));
+        assert!(matches!(
+            peek_decrypt(&unseen, &alice_address, &bob_store.session_store, None).await,
+            Err(SignalProtocolError::WouldMutate)
+        ));
+        assert!(matches!(
+            peek_decrypt(&skipped, &bob_address, &bob_store.session_store, None).await,
+            Err(SignalProtocolError::SessionNotFound(_))
+        ));
+
+        let after = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found")
+            .serialize()?;
+        assert_eq!(before, after);
+
+        // The skipped message can still be decrypted normally afterwards.
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &skipped).await?,
+            b"skipped"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[derive(Default)]
+struct RecordingInstrumentation {
+    operations: Mutex<Vec<&'static str>>,
+}
+
+impl RecordingInstrumentation {
+    fn record(&self, operation: &'static str) {
+        self.operations
+            .lock()
+            .expect("not poisoned")
+            .push(operation)
+    }
+
+    fn take(&self) -> Vec<&'static str> {
+        std::mem::take(&mut *self.operations.lock().expect("not poisoned"))
+    }
+}
+
+impl StoreInstrumentation for RecordingInstrumentation {
+    fn on_load_session_done(&self, _elapsed: std::time::Duration) {
+        self.record("load_session")
+    }
+    fn on_store_session_done(&self, _elapsed: std::time::Duration) {
+        self.record("store_session")
+    }
+    fn on_get_pre_key_done(&self, _elapsed: std::time::Duration) {
+        self.record("get_pre_key")
+    }
+    fn on_claim_pre_key_done(&self, _elapsed: std::time::Duration) {
+        self.record("claim_pre_key")
+    }
+    fn on_get_signed_pre_key_done(&self, _elapsed: std::time::Duration) {
+        self.record("get_signed_pre_key")
+    }
+}
+
+#[test]
+fn store_instrumentation_reports_each_operation() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let instrumentation = Arc::new(RecordingInstrumentation::default());
+        let outgoing_message = message_encrypt_with_config(
+            b"hi",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &EncryptionConfig {
+                instrumentation: Some(instrumentation.clone()),
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+        let operations = instrumentation.take();
+        assert!(operations.contains(&"load_session"));
+        assert!(operations.contains(&"store_session"));
+
+        let decrypt_config = DecryptionConfig {
+            instrumentation: Some(instrumentation.clone()),
+            ..Default::default()
+        };
+        let ptext = message_decrypt_with_config(
+            &outgoing_message,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut csprng,
+            &decrypt_config,
+            None,
+        )
+        .await?;
+        assert_eq!(ptext, b"hi");
+        let operations = instrumentation.take();
+        assert!(operations.contains(&"load_session"));
+        assert!(operations.contains(&"store_session"));
+        assert!(operations.contains(&"get_signed_pre_key"));
+        assert!(operations
+            .iter()
+            .any(|op| *op == "get_pre_key" || *op == "claim_pre_key"));
+
+        // Without instrumentation nothing is reported.
+        let reply = encrypt(&mut bob_store, &alice_address, "hello").await?;
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+        assert!(instrumentation.take().is_empty());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn rotate_signed_pre_key_keeps_a_grace_window() -> Result<(), SignalProtocolError> {
+    async {
+        let mut store = InMemSignedPreKeyStore::new();
+
+        let new_key = |id: SignedPreKeyId, timestamp: u64| {
+            SignedPreKeyRecord::new(id, timestamp, &KeyPair::generate(&mut OsRng), &[0; 64])
+        };
+
+        // IDs are not in timestamp order; retention goes by timestamp.
+        assert!(rotate_signed_pre_key(&mut store, &new_key(7, 100), 2, None)
+            .await?
+            .is_empty());
+        assert!(rotate_signed_pre_key(&mut store, &new_key(3, 200), 2, None)
+            .await?
+            .is_empty());
+        assert!(rotate_signed_pre_key(&mut store, &new_key(5, 300), 2, None)
+            .await?
+            .is_empty());
+        assert_eq!(
+            rotate_signed_pre_key(&mut store, &new_key(1, 400), 2, None).await?,
+            [7]
+        );
+        assert_eq!(store.signed_pre_key_ids(None).await?, [1, 3, 5]);
+
+        // Shrinking the window retires several keys at once.
+        assert_eq!(
+            rotate_signed_pre_key(&mut store, &new_key(9, 500), 0, None).await?,
+            [3, 5, 1]
+        );
+        assert_eq!(store.signed_pre_key_ids(None).await?, [9]);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn enforce_size_limit_evicts_oldest_skipped_keys() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let mut messages = Vec::new();
+        for i in 0..50 {
+            messages.push(encrypt(&mut alice_store, &bob_address, &format!("msg {}", i)).await?);
+        }
+        let last = messages.pop().expect("sent");
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &last).await?,
+            b"msg 49"
+        );
+
+        let mut record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+        let original_len = record.serialize()?.len();
+        assert_eq!(record.enforce_size_limit(original_len)?, 0);
+
+        let reclaimed = record.enforce_size_limit(original_len - 500)?;
+        assert!(reclaimed >= 500);
+        assert_eq!(record.serialize()?.len(), original_len - reclaimed);
+        bob_store
+            .store_session(&alice_address, &record, None)
+            .await?;
+
+        // The newest skipped keys are kept, the oldest are gone.
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &messages[48]).await?,
+            b"msg 48"
+        );
+        assert!(decrypt(&mut bob_store, &alice_address, &messages[0])
+            .await
+            .is_err());
+
+        // Even an impossible limit keeps the session usable.
+        let mut record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+        assert!(record.enforce_size_limit(0)? > 0);
+        assert!(record.has_current_session_state());
+        bob_store
+            .store_session(&alice_address, &record, None)
+            .await?;
+        let next = encrypt(&mut alice_store, &bob_address, "msg 50").await?;
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &next).await?,
+            b"msg 50"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn message_decrypt_with_version_reports_the_session_version() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        for version in SUPPORTED_SESSION_VERSIONS {
+            let (alice_session_record, bob_session_record) =
+                establish_session_pair(&mut csprng, version)?;
+
+            let mut alice_store = support::test_in_memory_protocol_store()?;
+            let mut bob_store = support::test_in_memory_protocol_store()?;
+            alice_store
+                .store_session(&bob_address, &alice_session_record, None)
+                .await?;
+            bob_store
+                .store_session(&alice_address, &bob_session_record, None)
+                .await?;
+
+            let message = encrypt(&mut alice_store, &bob_address, "hello").await?;
+            assert_eq!(
+                message_decrypt_with_version(
+                    &message,
+                    &alice_address,
+                    &mut bob_store.session_store,
+                    &mut bob_store.identity_store,
+                    &mut bob_store.pre_key_store,
+                    &mut bob_store.signed_pre_key_store,
+                    &mut csprng,
+                    None,
+                )
+                .await?,
+                (b"hello".to_vec(), version)
+            );
+        }
+
+        // A pre-key message reports the version of the session it sets up.
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+        assert_eq!(message.message_type(), CiphertextMessageType::PreKey);
+        let (ptext, session_version) = message_decrypt_with_version(
+            &message,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(ptext, b"hi");
+        assert_eq!(session_version, 3);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn knows_ratchet_key_distinguishes_new_chains() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let sender_ratchet_key = |message: &CiphertextMessage| match message {
+            CiphertextMessage::SignalMessage(m) => *m.sender_ratchet_key(),
+            _ => panic!("expected a SignalMessage"),
+        };
+        let bob_knows = |store: &InMemSignalProtocolStore, key: &PublicKey| {
+            store
+                .load_session(&alice_address, None)
+                .now_or_never()
+                .expect("sync")?
+                .expect("session found")
+                .knows_ratchet_key(key)
+        };
+
+        let first = encrypt(&mut alice_store, &bob_address, "first").await?;
+        let second = encrypt(&mut alice_store, &bob_address, "second").await?;
+        let first_key = sender_ratchet_key(&first);
+        assert_eq!(first_key, sender_ratchet_key(&second));
+
+        assert!(!bob_knows(&bob_store, &first_key)?);
+        decrypt(&mut bob_store, &alice_address, &first).await?;
+        assert!(bob_knows(&bob_store, &first_key)?);
+
+        // After Bob replies, Alice's next message starts a new chain.
+        let reply = encrypt(&mut bob_store, &alice_address, "reply").await?;
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+        let third = encrypt(&mut alice_store, &bob_address, "third").await?;
+        let third_key = sender_ratchet_key(&third);
+        assert!(!bob_knows(&bob_store, &third_key)?);
+        assert!(bob_knows(&bob_store, &first_key)?);
+
+        decrypt(&mut bob_store, &alice_address, &third).await?;
+        assert!(bob_knows(&bob_store, &third_key)?);
+
+        // Checking does not change the record, so the delayed message still decrypts.
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &second).await?,
+            b"second"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn message_decrypt_batch_handles_interleaved_chains() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let signal_message = |message: CiphertextMessage| match message {
+            CiphertextMessage::SignalMessage(m) => m,
+            _ => panic!("expected a SignalMessage"),
+        };
+
+        let mut first_chain = Vec::new();
+        for text in ["a0", "a1", "a2"] {
+            first_chain.push(signal_message(
+                encrypt(&mut alice_store, &bob_address, text).await?,
+            ));
+        }
+        decrypt(
+            &mut bob_store,
+            &alice_address,
+            &CiphertextMessage::SignalMessage(first_chain[0].clone()),
+        )
+        .await?;
+
+        // Bob's reply makes Alice start a second chain.
+        let reply = encrypt(&mut bob_store, &alice_address, "reply").await?;
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+        let mut second_chain = Vec::new();
+        for text in ["b0", "b1"] {
+            second_chain.push(signal_message(
+                encrypt(&mut alice_store, &bob_address, text).await?,
+            ));
+        }
+
+        let batch = [
+            second_chain[1].clone(),
+            first_chain[2].clone(),
+            second_chain[0].clone(),
+            first_chain[0].clone(),
+            first_chain[1].clone(),
+        ];
+        let results = message_decrypt_batch(
+            &batch,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        assert_eq!(results.len(), batch.len());
+        assert_eq!(results[0].as_deref().expect("decrypted"), b"b1");
+        assert_eq!(results[1].as_deref().expect("decrypted"), b"a2");
+        assert_eq!(results[2].as_deref().expect("decrypted"), b"b0");
+        assert!(matches!(
+            results[3],
+            Err(SignalProtocolError::DuplicatedMessage(_, _))
+        ));
+        assert_eq!(results[4].as_deref().expect("decrypted"), b"a1");
+
+        // The stored session continues from the second chain.
+        let next = encrypt(&mut alice_store, &bob_address, "b2").await?;
+        assert_eq!(decrypt(&mut bob_store, &alice_address, &next).await?, b"b2");
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn message_decrypt_batch_rejects_an_untrusted_identity() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let mut batch = Vec::new();
+        for text in ["a0", "a1"] {
+            match encrypt(&mut alice_store, &bob_address, text).await? {
+                CiphertextMessage::SignalMessage(m) => batch.push(m),
+                _ => panic!("expected a SignalMessage"),
+            }
+        }
+
+        let alice_identity = remote_identity_key(&alice_address, &bob_store.session_store, None)
+            .await?
+            .expect("session has a remote identity");
+        bob_store
+            .save_identity(
+                &alice_address,
+                &IdentityKey::new(KeyPair::generate(&mut csprng).public_key),
+                None,
+            )
+            .await?;
+
+        let results = message_decrypt_batch(
+            &batch,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        for result in &results {
+            assert!(matches!(
+                result,
+                Err(SignalProtocolError::UntrustedIdentity(a)) if *a == alice_address
+            ));
+        }
+
+        // Nothing was stored, so the batch decrypts once the identity is accepted.
+        bob_store
+            .save_identity(&alice_address, &alice_identity, None)
+            .await?;
+        let results = message_decrypt_batch(
+            &batch,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(results[0].as_deref().expect("decrypted"), b"a0");
+        assert_eq!(results[1].as_deref().expect("decrypted"), b"a1");
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn check_trust_uses_the_session_identity() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        assert!(matches!(
+            check_trust(
+                &bob_address,
+                Direction::Sending,
+                &alice_store.session_store,
+                &alice_store.identity_store,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::SessionNotFound(_))
+        ));
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        for direction in [Direction::Sending, Direction::Receiving] {
+            assert!(
+                check_trust(
+                    &bob_address,
+                    direction,
+                    &alice_store.session_store,
+                    &alice_store.identity_store,
+                    None,
+                )
+                .await?
+            );
+        }
+
+        // Once another identity is stored for Bob, the session's identity is no longer trusted.
+        let other_identity = *IdentityKeyPair::generate(&mut csprng).identity_key();
+        alice_store
+            .save_identity(&bob_address, &other_identity, None)
+            .await?;
+        assert!(
+            !check_trust(
+                &bob_address,
+                Direction::Sending,
+                &alice_store.session_store,
+                &alice_store.identity_store,
+                None,
+            )
+            .await?
+        );
+        assert!(matches!(
+            encrypt(&mut alice_store, &bob_address, "hi").await,
+            Err(SignalProtocolError::UntrustedIdentity(_))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn prekey_message_failures_are_distinguished() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        // Each case starts from a fresh pair of stores and a pre-key message from Alice.
+        let setup = || -> Result<_, SignalProtocolError> {
+            let mut csprng = OsRng;
+            let mut alice_store = support::test_in_memory_protocol_store()?;
+            let mut bob_store = support::test_in_memory_protocol_store()?;
+            let bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)
+                .now_or_never()
+                .expect("sync")?;
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bundle,
+                &mut csprng,
+                None,
+            )
+            .now_or_never()
+            .expect("sync")?;
+            let message = encrypt(&mut alice_store, &bob_address, "hi")
+                .now_or_never()
+                .expect("sync")?;
+            Ok((bob_store, bundle, message))
+        };
+
+        let (mut bob_store, bundle, message) = setup()?;
+        let pre_key_id = bundle.pre_key_id()?.expect("has one-time pre-key");
+        bob_store.remove_pre_key(pre_key_id, None).await?;
+        assert!(matches!(
+            decrypt(&mut bob_store, &alice_address, &message).await,
+            Err(SignalProtocolError::PreKeyNotFound(id)) if id == pre_key_id
+        ));
+
+        let (mut bob_store, bundle, message) = setup()?;
+        let signed_pre_key_id = bundle.signed_pre_key_id()?;
+        bob_store
+            .remove_signed_pre_key(signed_pre_key_id, None)
+            .await?;
+        assert!(matches!(
+            decrypt(&mut bob_store, &alice_address, &message).await,
+            Err(SignalProtocolError::SignedPreKeyNotFound(id)) if id == signed_pre_key_id
+        ));
+        // The one-time pre-key was not claimed, so the message can be retried.
+        let bundle_pre_key_id = bundle.pre_key_id()?.expect("has one-time pre-key");
+        assert!(bob_store.get_pre_key(bundle_pre_key_id, None).await.is_ok());
+
+        let (mut bob_store, _, message) = setup()?;
+        let other_identity = *IdentityKeyPair::generate(&mut csprng).identity_key();
+        bob_store
+            .save_identity(&alice_address, &other_identity, None)
+            .await?;
+        assert!(matches!(
+            decrypt(&mut bob_store, &alice_address, &message).await,
+            Err(SignalProtocolError::UntrustedIdentity(address)) if address == alice_address
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn expected_ciphertext_len_bounds_encrypted_messages() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let sizes = [0, 1, 15, 16, 17, 100, 1000];
+        let check = |message: &CiphertextMessage, plaintext_len: usize, is_prekey: bool| {
+            let actual = message.serialize().len();
+            let expected =
+                expected_ciphertext_len(plaintext_len, 3, is_prekey).expect("supported version");
+            assert!(actual <= expected, "{} > {}", actual, expected);
+            assert!(
+                expected - actual <= 32,
+                "{} much less than {}",
+                actual,
+                expected
+            );
+        };
+
+        let mut first_message = None;
+        for &size in &sizes {
+            let message = encrypt(&mut alice_store, &bob_address, &"a".repeat(size)).await?;
+            assert_eq!(message.message_type(), CiphertextMessageType::PreKey);
+            check(&message, size, true);
+            first_message.get_or_insert(message);
+        }
+
+        decrypt(
+            &mut bob_store,
+            &alice_address,
+            &first_message.expect("encrypted at least once"),
+        )
+        .await?;
+        let reply = encrypt(&mut bob_store, &alice_address, "reply").await?;
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+
+        for &size in &sizes {
+            let message = encrypt(&mut alice_store, &bob_address, &"a".repeat(size)).await?;
+            assert_eq!(message.message_type(), CiphertextMessageType::Whisper);
+            check(&message, size, false);
+        }
+
+        assert!(matches!(
+            expected_ciphertext_len(10, 2, false),
+            Err(SignalProtocolError::UnsupportedSessionVersion(2))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn keepalive_does_not_advance_chains() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        // Bob has no session to check it against yet.
+        assert!(matches!(
+            encrypt_keepalive(
+                &bob_address,
+                &alice_store.session_store,
+                &alice_store.identity_store,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::InvalidState("encrypt_keepalive", _))
+        ));
+
+        let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+        let reply = encrypt(&mut bob_store, &alice_address, "hello").await?;
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+
+        let session_bytes = |store: &InMemSignalProtocolStore, address: &ProtocolAddress| {
+            store
+                .load_session(address, None)
+                .now_or_never()
+                .expect("sync")
+                .expect("can load")
+                .expect("has session")
+                .serialize()
+                .expect("can serialize")
+        };
+
+        // Alice has not sent anything with her new ratchet key yet, so Bob has to derive the chain
+        // to check the keepalive.
+        let alice_before = session_bytes(&alice_store, &bob_address);
+        let bob_before = session_bytes(&bob_store, &alice_address);
+        let keepalive = encrypt_keepalive(
+            &bob_address,
+            &alice_store.session_store,
+            &alice_store.identity_store,
+            None,
+        )
+        .await?;
+        let keepalive = KeepAliveMessage::try_from(keepalive.serialized())?;
+        assert_eq!(
+            decrypt_keepalive(
+                &keepalive,
+                &alice_address,
+                &bob_store.session_store,
+                &bob_store.identity_store,
+                None,
+            )
+            .await?,
+            KeepAlive
+        );
+        assert_eq!(session_bytes(&alice_store, &bob_address), alice_before);
+        assert_eq!(session_bytes(&bob_store, &alice_address), bob_before);
+
+        // Bob's sender chain is already known to Alice.
+        let keepalive = encrypt_keepalive(
+            &alice_address,
+            &bob_store.session_store,
+            &bob_store.identity_store,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            decrypt_keepalive(
+                &keepalive,
+                &bob_address,
+                &alice_store.session_store,
+                &alice_store.identity_store,
+                None,
+            )
+            .await?,
+            KeepAlive
+        );
+
+        // The next message uses the counter the keepalive was sent at.
+        let message = encrypt(&mut alice_store, &bob_address, "still here").await?;
+        let message = match message {
+            CiphertextMessage::SignalMessage(m) => m,
+            _ => panic!("unexpected message type"),
+        };
+        assert_eq!(message.counter(), 0);
+        decrypt(
+            &mut bob_store,
+            &alice_address,
+            &CiphertextMessage::SignalMessage(message),
+        )
+        .await?;
+
+        let mut tampered = keepalive.serialized().to_vec();
+        *tampered.last_mut().expect("not empty") ^= 1;
+        assert!(matches!(
+            decrypt_keepalive(
+                &KeepAliveMessage::try_from(&tampered[..])?,
+                &bob_address,
+                &alice_store.session_store,
+                &alice_store.identity_store,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::InvalidMessage(_))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn session_with_no_replies_is_likely_desynced() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let is_likely_desynced = |store: &InMemSignalProtocolStore| {
+            store
+                .load_session(&bob_address, None)
+                .now_or_never()
+                .expect("sync")
+                .expect("can load")
+                .expect("has session")
+                .is_likely_desynced()
+                .expect("valid session")
+        };
+        assert!(!is_likely_desynced(&alice_store));
+
+        // As many messages as Bob would keep skipped keys for, without a reply.
+        let mut last_message = None;
+        for i in 0..2000 {
+            assert!(!is_likely_desynced(&alice_store), "after {} messages", i);
+            last_message = Some(encrypt(&mut alice_store, &bob_address, "hello?").await?);
+        }
+        assert!(is_likely_desynced(&alice_store));
+
+        decrypt(
+            &mut bob_store,
+            &alice_address,
+            &last_message.expect("sent messages"),
+        )
+        .await?;
+        let reply = encrypt(&mut bob_store, &alice_address, "finally").await?;
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+        assert!(!is_likely_desynced(&alice_store));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn decryption_failure_report_describes_candidates() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = match encrypt(&mut alice_store, &bob_address, "hi").await? {
+            CiphertextMessage::PreKeySignalMessage(m) => m.message().clone(),
+            _ => panic!("unexpected message type"),
+        };
+
+        let mut record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        let report = create_decryption_failure_report(
+            &bob_address,
+            &[SignalProtocolError::InvalidCiphertext],
+            &record,
+            &message,
+        )?;
+        assert_eq!(report.remote_address, bob_address.to_string());
+        assert_eq!(
+            report.sender_ratchet_key,
+            hex::encode(message.sender_ratchet_key().public_key_bytes()?)
+        );
+        assert_eq!(report.counter, 0);
+        assert!(report.has_current_session);
+        assert_eq!(report.candidates.len(), 1);
+        let candidate = &report.candidates[0];
+        assert_eq!(candidate.index, 0);
+        assert_eq!(
+            candidate.error,
+            Some(SignalProtocolError::InvalidCiphertext.to_string())
+        );
+        assert_eq!(candidate.receiver_chains_error, None);
+        // Alice's session has a receiver chain for Bob's signed pre-key.
+        assert_eq!(
+            candidate.receiver_chains,
+            vec![ReceiverChainReport {
+                sender_ratchet_key: hex::encode(
+                    bob_pre_key_bundle.signed_pre_key_public()?.serialize()
+                ),
+                chain_key_index: Some(0),
+            }]
+        );
+        assert!(report.to_string().starts_with(&format!(
+            "Message from {} failed to decrypt; sender ratchet public key {} message counter 0\n\
+             Candidate session 0 failed with '{}', had 1 receiver chains",
+            bob_address,
+            report.sender_ratchet_key,
+            SignalProtocolError::InvalidCiphertext
+        )));
+
+        record.archive_current_state()?;
+        let report = create_decryption_failure_report(&bob_address, &[], &record, &message)?;
+        assert!(!report.has_current_session);
+        assert_eq!(report.candidates.len(), 1);
+        assert_eq!(report.candidates[0].index, 1);
+        assert_eq!(report.candidates[0].error, None);
+        assert!(report.to_string().contains("\nNo current session\n"));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn decrypt_with_saved_message_keys() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        // Save the keys the next message will be encrypted with, as a history backup would.
+        let record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        let chain_key = ChainKey::new(
+            &record.get_sender_chain_key_bytes()?,
+            record.messages_since_ratchet()?,
+        )?;
+        let message_keys = chain_key.message_keys()?;
+        let next_message_keys = chain_key.next_chain_key()?.message_keys()?;
+
+        let message = match encrypt(&mut alice_store, &bob_address, "from the archive").await? {
+            CiphertextMessage::PreKeySignalMessage(m) => m.message().clone(),
+            _ => panic!("unexpected message type"),
+        };
+
+        let alice_identity = *alice_store
+            .get_identity_key_pair(None)
+            .await?
+            .identity_key();
+        let bob_identity = *bob_store.get_identity_key_pair(None).await?.identity_key();
+
+        verify_message_mac(&message, &alice_identity, &bob_identity, &message_keys)?;
+        assert_eq!(
+            decrypt_with_message_keys(message.body(), &message_keys)?,
+            b"from the archive"
+        );
+
+        assert!(matches!(
+            verify_message_mac(&message, &bob_identity, &alice_identity, &message_keys),
+            Err(SignalProtocolError::InvalidCiphertext)
+        ));
+        assert!(matches!(
+            verify_message_mac(&message, &alice_identity, &bob_identity, &next_message_keys),
+            Err(SignalProtocolError::InvalidCiphertext)
+        ));
+        assert!(matches!(
+            decrypt_with_message_keys(&message.body()[..8], &message_keys),
+            Err(SignalProtocolError::InvalidCiphertext)
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn session_reset_callback_fires_only_for_replaced_sessions() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        let alice_identity = *alice_store
+            .get_identity_key_pair(None)
+            .await?
+            .identity_key();
+
+        let resets = Arc::new(Mutex::new(Vec::new()));
+        let on_session_reset: SessionResetCallback = {
+            let resets = resets.clone();
+            Arc::new(
+                move |address: &ProtocolAddress,
+                      old_identity: Option<IdentityKey>,
+                      new_identity: IdentityKey| {
+                    resets.lock().expect("not poisoned").push((
+                        address.clone(),
+                        old_identity,
+                        new_identity,
+                    ))
+                },
+            )
+        };
+        let config = DecryptionConfig {
+            on_session_reset: Some(on_session_reset),
+            ..Default::default()
+        };
+
+        // The first session, and a repeated pre-key message for it, are not resets.
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        for text in ["hi", "hi again"] {
+            let message = encrypt(&mut alice_store, &bob_address, text).await?;
+            message_decrypt_with_config(
+                &message,
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut csprng,
+                &config,
+                None,
+            )
+            .await?;
+        }
+        assert!(resets.lock().expect("not poisoned").is_empty());
+
+        // Alice starts over from a new bundle.
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "it's me again").await?;
+        message_decrypt_with_config(
+            &message,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut csprng,
+            &config,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            *resets.lock().expect("not poisoned"),
+            [(alice_address, Some(alice_identity), alice_identity)]
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn decryption_rejects_versions_outside_accepted_range() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+
+        let rejecting = DecryptionConfig {
+            accepted_versions: Some(4..=4),
+            ..Default::default()
+        };
+        assert!(matches!(
+            message_decrypt_with_config(
+                &message,
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut csprng,
+                &rejecting,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::UnrecognizedMessageVersion(3))
+        ));
+        // Nothing was processed.
+        assert!(bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .is_none());
+
+        let accepting = DecryptionConfig {
+            accepted_versions: Some(3..=3),
+            ..Default::default()
+        };
+        let ptext = message_decrypt_with_config(
+            &message,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut csprng,
+            &accepting,
+            None,
+        )
+        .await?;
+        assert_eq!(ptext, b"hi");
+
+        let reply = encrypt(&mut bob_store, &alice_address, "hello").await?;
+        assert!(matches!(
+            message_decrypt_with_config(
+                &reply,
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &mut alice_store.pre_key_store,
+                &mut alice_store.signed_pre_key_store,
+                &mut csprng,
+                &rejecting,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::UnrecognizedMessageVersion(3))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn heal_on_failure_archives_the_session() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+
+        let config = DecryptionConfig {
+            heal_on_failure: true,
+            ..Default::default()
+        };
+        let first = encrypt(&mut bob_store, &alice_address, "first").await?;
+        let second = encrypt(&mut bob_store, &alice_address, "second").await?;
+        let third = encrypt(&mut bob_store, &alice_address, "third").await?;
+
+        let ptext = message_decrypt_with_config(
+            &first,
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &mut alice_store.pre_key_store,
+            &mut alice_store.signed_pre_key_store,
+            &mut csprng,
+            &config,
+            None,
+        )
+        .await?;
+        assert_eq!(ptext, b"first");
+
+        // Duplicates never archive the session.
+        assert!(matches!(
+            message_decrypt_with_config(
+                &first,
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &mut alice_store.pre_key_store,
+                &mut alice_store.signed_pre_key_store,
+                &mut csprng,
+                &config,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::DuplicatedMessage(_, _))
+        ));
+        let record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert!(record.has_current_session_state());
+        assert_eq!(record.previous_state_count(), 0);
+
+        let mut corrupted = second.serialize().to_vec();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        let corrupted = CiphertextMessage::SignalMessage(SignalMessage::try_from(&corrupted[..])?);
+        assert!(matches!(
+            message_decrypt_with_config(
+                &corrupted,
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &mut alice_store.pre_key_store,
+                &mut alice_store.signed_pre_key_store,
+                &mut csprng,
+                &config,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::DecryptFailedSessionArchived(address)) if address == bob_address
+        ));
+        let record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert!(!record.has_current_session_state());
+        assert_eq!(record.previous_state_count(), 1);
+
+        // The archived state can still decrypt delayed messages.
+        assert_eq!(decrypt(&mut alice_store, &bob_address, &third).await?, b"third");
+        assert_eq!(decrypt(&mut alice_store, &bob_address, &second).await?, b"second");
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn pending_prekey_info_tracks_unacknowledged_sessions() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        assert_eq!(
+            pending_prekey_info(&bob_address, &alice_store.session_store, None).await?,
+            None
+        );
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+        let prekey_message = match &message {
+            CiphertextMessage::PreKeySignalMessage(m) => m,
+            _ => panic!("expected a pre-key message"),
+        };
+
+        let info = pending_prekey_info(&bob_address, &alice_store.session_store, None)
+            .await?
+            .expect("session is unacknowledged");
+        assert_eq!(info.pre_key_id, bob_pre_key_bundle.pre_key_id()?);
+        assert_eq!(
+            info.signed_pre_key_id,
+            bob_pre_key_bundle.signed_pre_key_id()?
+        );
+        assert_eq!(info.base_key, *prekey_message.base_key());
+
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+        assert_eq!(
+            pending_prekey_info(&alice_address, &bob_store.session_store, None).await?,
+            None
+        );
+
+        let reply = encrypt(&mut bob_store, &alice_address, "hello").await?;
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+        assert_eq!(
+            pending_prekey_info(&bob_address, &alice_store.session_store, None).await?,
+            None
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn app_label_survives_serialization_and_use() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let mut record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(record.app_label(), None);
+        record.set_app_label(Some(b"call".to_vec()));
+        let serialized = record.serialize()?;
+        assert_eq!(
+            SessionRecord::deserialize(&serialized)?.app_label(),
+            Some(&b"call"[..])
+        );
+        alice_store
+            .store_session(&bob_address, &record, None)
+            .await?;
+
+        // Using and archiving the session keeps the label.
+        let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+        let reply = encrypt(&mut bob_store, &alice_address, "hello").await?;
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+        let mut record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        record.archive_current_state()?;
+        let mut record = SessionRecord::deserialize(&record.serialize()?)?;
+        assert_eq!(record.app_label(), Some(&b"call"[..]));
+
+        record.set_app_label(Some(Vec::new()));
+        assert_eq!(record.app_label(), None);
+        assert_eq!(
+            SessionRecord::deserialize(&record.serialize()?)?.app_label(),
+            None
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn pinned_identity_rejects_other_identities() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        let alice_identity = *alice_store
+            .get_identity_key_pair(None)
+            .await?
+            .identity_key();
+
+        let wrong_pin = DecryptionConfig {
+            pinned_identity: Some(*IdentityKeyPair::generate(&mut csprng).identity_key()),
+            ..Default::default()
+        };
+        let right_pin = DecryptionConfig {
+            pinned_identity: Some(alice_identity),
+            ..Default::default()
+        };
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        // The first message is a pre-key message, the second a SignalMessage.
+        for text in ["first", "second"] {
+            let message = encrypt(&mut alice_store, &bob_address, text).await?;
+            assert!(matches!(
+                message_decrypt_with_config(
+                    &message,
+                    &alice_address,
+                    &mut bob_store.session_store,
+                    &mut bob_store.identity_store,
+                    &mut bob_store.pre_key_store,
+                    &mut bob_store.signed_pre_key_store,
+                    &mut csprng,
+                    &wrong_pin,
+                    None,
+                )
+                .await,
+                Err(SignalProtocolError::PinnedIdentityMismatch(address)) if address == alice_address
+            ));
+            // Nothing was processed, so the same message still decrypts with the right pin.
+            let ptext = message_decrypt_with_config(
+                &message,
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut csprng,
+                &right_pin,
+                None,
+            )
+            .await?;
+            assert_eq!(ptext, text.as_bytes());
+
+            if text == "first" {
+                let reply = encrypt(&mut bob_store, &alice_address, "reply").await?;
+                decrypt(&mut alice_store, &bob_address, &reply).await?;
+            }
+        }
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn purge_message_keys_drops_skipped_messages() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+
+        let skipped1 = encrypt(&mut bob_store, &alice_address, "skipped 1").await?;
+        let skipped2 = encrypt(&mut bob_store, &alice_address, "skipped 2").await?;
+        let received = encrypt(&mut bob_store, &alice_address, "received").await?;
+        decrypt(&mut alice_store, &bob_address, &received).await?;
+
+        assert_eq!(
+            purge_message_keys(&bob_address, &mut alice_store.session_store, None).await?,
+            2
+        );
+        assert_eq!(
+            purge_message_keys(&bob_address, &mut alice_store.session_store, None).await?,
+            0
+        );
+        for skipped in [&skipped1, &skipped2] {
+            assert!(matches!(
+                decrypt(&mut alice_store, &bob_address, skipped).await,
+                Err(SignalProtocolError::DuplicatedMessage(_, _))
+            ));
+        }
+
+        // The session still works.
+        let next = encrypt(&mut bob_store, &alice_address, "next").await?;
+        assert_eq!(
+            decrypt(&mut alice_store, &bob_address, &next).await?,
+            b"next"
+        );
+
+        assert!(matches!(
+            purge_message_keys(&alice_address, &mut alice_store.session_store, None).await,
+            Err(SignalProtocolError::SessionNotFound(_))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn verified_bundle_cache_still_rejects_bad_signatures() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        let mut cache = VerifiedBundleCache::new();
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        // The second time, the signature check is skipped.
+        for _ in 0..2 {
+            cache
+                .process_prekey_bundle(
+                    &bob_address,
+                    &mut alice_store.session_store,
+                    &mut alice_store.identity_store,
+                    &bob_pre_key_bundle,
+                    &mut csprng,
+                    &ProtocolConfig::default(),
+                    None,
+                )
+                .await?;
+        }
+
+        let mut bad_signature = bob_pre_key_bundle.signed_pre_key_signature()?.to_vec();
+        bad_signature[0] ^= 1;
+        let forged_bundle = PreKeyBundle::new(
+            bob_pre_key_bundle.registration_id()?,
+            bob_pre_key_bundle.device_id()?,
+            None,
+            bob_pre_key_bundle.signed_pre_key_id()?,
+            bob_pre_key_bundle.signed_pre_key_public()?,
+            bad_signature,
+            *bob_pre_key_bundle.identity_key()?,
+        )?;
+        assert!(matches!(
+            cache
+                .process_prekey_bundle(
+                    &bob_address,
+                    &mut alice_store.session_store,
+                    &mut alice_store.identity_store,
+                    &forged_bundle,
+                    &mut csprng,
+                    &ProtocolConfig::default(),
+                    None,
+                )
+                .await,
+            Err(SignalProtocolError::SignatureValidationFailed)
+        ));
+
+        // Bob re-registers with a new identity, which Alice accepts.
+        let mut new_bob_store = support::test_in_memory_protocol_store()?;
+        let new_bundle = create_pre_key_bundle(&mut new_bob_store, &mut csprng).await?;
+        alice_store
+            .save_identity(&bob_address, new_bundle.identity_key()?, None)
+            .await?;
+        cache
+            .process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &new_bundle,
+                &mut csprng,
+                &ProtocolConfig::default(),
+                None,
+            )
+            .await?;
+        assert_eq!(
+            remote_identity_key(&bob_address, &alice_store.session_store, None).await?,
+            Some(*new_bundle.identity_key()?)
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn reseal_forwards_only_when_both_sessions_succeed() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+        let carol_address = ProtocolAddress::new("+14151111113".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        let mut carol_store = support::test_in_memory_protocol_store()?;
+
+        let alice_pre_key_bundle = create_pre_key_bundle(&mut alice_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &alice_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut bob_store, &alice_address, "relay me").await?;
+
+        // Alice has no session with Carol yet, so nothing is stored and the pre-key is kept.
+        assert!(matches!(
+            reseal(
+                &message,
+                &bob_address,
+                &carol_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &mut alice_store.pre_key_store,
+                &mut alice_store.signed_pre_key_store,
+                &mut csprng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::SessionNotFound(_))
+        ));
+        assert!(alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .is_none());
+
+        let carol_pre_key_bundle = create_pre_key_bundle(&mut carol_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &carol_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &carol_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let resealed = reseal(
+            &message,
+            &bob_address,
+            &carol_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &mut alice_store.pre_key_store,
+            &mut alice_store.signed_pre_key_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            decrypt(&mut carol_store, &alice_address, &resealed).await?,
+            b"relay me"
+        );
+
+        // Both sessions were stored: Bob's message has been consumed.
+        assert!(matches!(
+            decrypt(&mut alice_store, &bob_address, &message).await,
+            Err(SignalProtocolError::DuplicatedMessage(_, _))
+        ));
+
+        assert!(matches!(
+            reseal(
+                &message,
+                &bob_address,
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &mut alice_store.pre_key_store,
+                &mut alice_store.signed_pre_key_store,
+                &mut csprng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn forward_secrecy_window_tracks_stored_keys() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+
+        let mut messages = Vec::new();
+        for i in 0..4 {
+            messages
+                .push(encrypt(&mut bob_store, &alice_address, &format!("message {}", i)).await?);
+        }
+
+        for (received, expected) in [(3, (0, 4)), (0, (1, 4)), (2, (1, 4)), (1, (4, 4))] {
+            decrypt(&mut alice_store, &bob_address, &messages[received]).await?;
+            let record = alice_store
+                .load_session(&bob_address, None)
+                .await?
+                .expect("session found");
+            assert_eq!(record.forward_secrecy_window()?, Some(expected));
+        }
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn classify_messages_counts_cached_keys() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let mut messages = Vec::new();
+        for i in 0..3 {
+            let message =
+                encrypt(&mut alice_store, &bob_address, &format!("message {}", i)).await?;
+            messages.push(match message {
+                CiphertextMessage::SignalMessage(m) => m,
+                _ => panic!("unexpected message type"),
+            });
+        }
+
+        // Skipping ahead stores the key for message 0.
+        decrypt(
+            &mut bob_store,
+            &alice_address,
+            &CiphertextMessage::SignalMessage(messages[1].clone()),
+        )
+        .await?;
+
+        let record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+        let before = record.serialize()?;
+
+        let sender = *messages[0].sender_ratchet_key();
+        let new_sender = KeyPair::generate(&mut OsRng).public_key;
+        let result = record.classify_messages(&[
+            (sender, 0),
+            (sender, 1),
+            (sender, 2),
+            (sender, 0),
+            (sender, 25_003),
+            (new_sender, 0),
+        ])?;
+        assert_eq!(
+            result,
+            ClassifyResult {
+                cached: 1,
+                needs_ratchet: 2,
+                duplicates: 2,
+                too_far: 1,
+            }
+        );
+        assert_eq!(record.serialize()?, before);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[cfg(feature = "weak-ciphers")]
+#[test]
+fn lower_version_messages_are_rejected_as_downgrades() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let protocol = ProtocolConfig {
+            aes_128: true,
+            ..Default::default()
+        };
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle_with_config(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            &protocol,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+        message_decrypt_with_config(
+            &message,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut csprng,
+            &DecryptionConfig {
+                protocol,
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+
+        let reply = match encrypt(&mut bob_store, &alice_address, "hello").await? {
+            CiphertextMessage::SignalMessage(m) => m,
+            _ => panic!("expected a SignalMessage"),
+        };
+        assert_eq!(reply.message_version(), CIPHERTEXT_MESSAGE_AES_128_VERSION);
+        let downgraded = SignalMessage::new(
+            3,
+            &[0; 32],
+            *reply.sender_ratchet_key(),
+            reply.counter(),
+            0,
+            reply.body(),
+            bob_store.get_identity_key_pair(None).await?.identity_key(),
+            alice_store
+                .get_identity_key_pair(None)
+                .await?
+                .identity_key(),
+        )?;
+        assert!(matches!(
+            decrypt(
+                &mut alice_store,
+                &bob_address,
+                &CiphertextMessage::SignalMessage(downgraded)
+            )
+            .await,
+            Err(SignalProtocolError::VersionDowngrade {
+                message_version: 3,
+                highest_version_seen: 4,
+            })
+        ));
+
+        // The session is unchanged.
+        assert_eq!(
+            decrypt(
+                &mut alice_store,
+                &bob_address,
+                &CiphertextMessage::SignalMessage(reply)
+            )
+            .await?,
+            b"hello"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn decrypt_message_with_keys_needs_no_stores() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        let alice_identity = *alice_store
+            .get_identity_key_pair(None)
+            .await?
+            .identity_key();
+        let bob_identity = *bob_store.get_identity_key_pair(None).await?.identity_key();
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+        let reply = match encrypt(&mut bob_store, &alice_address, "hello").await? {
+            CiphertextMessage::SignalMessage(m) => m,
+            _ => panic!("expected a SignalMessage"),
+        };
+
+        let mut record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        let original = record.serialize()?;
+
+        let other_identity = *IdentityKeyPair::generate(&mut csprng).identity_key();
+        assert!(matches!(
+            decrypt_message_with_keys(
+                &reply,
+                &bob_address,
+                &mut record,
+                &alice_identity,
+                &other_identity,
+                &mut csprng,
+            ),
+            Err(SignalProtocolError::InvalidMessage(_))
+        ));
+        assert_eq!(record.serialize()?, original);
+
+        let ptext = decrypt_message_with_keys(
+            &reply,
+            &bob_address,
+            &mut record,
+            &alice_identity,
+            &bob_identity,
+            &mut csprng,
+        )?;
+        assert_eq!(ptext, b"hello");
+        assert_ne!(record.serialize()?, original);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn remote_identity_key_reads_the_current_session() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        assert_eq!(
+            remote_identity_key(&alice_address, &bob_store.session_store, None).await?,
+            None
+        );
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "hello").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+
+        assert_eq!(
+            remote_identity_key(&alice_address, &bob_store.session_store, None).await?,
+            Some(
+                *alice_store
+                    .get_identity_key_pair(None)
+                    .await?
+                    .identity_key()
+            )
+        );
+        assert_eq!(
+            remote_identity_key(&bob_address, &alice_store.session_store, None).await?,
+            Some(*bob_store.get_identity_key_pair(None).await?.identity_key())
+        );
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn is_session_for_bundle_checks_the_bundle_identity() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        assert!(
+            !is_session_for_bundle(
+                &bob_address,
+                &bob_pre_key_bundle,
+                &alice_store.session_store,
+                None
+            )
+            .await?
+        );
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert!(
+            is_session_for_bundle(
+                &bob_address,
+                &bob_pre_key_bundle,
+                &alice_store.session_store,
+                None
+            )
+            .await?
+        );
+
+        // A bundle for a different identity, e.g. after Bob re-registered, needs a new session.
+        let mut new_bob_store = support::test_in_memory_protocol_store()?;
+        let new_bob_pre_key_bundle = create_pre_key_bundle(&mut new_bob_store, &mut csprng).await?;
+        assert!(
+            !is_session_for_bundle(
+                &bob_address,
+                &new_bob_pre_key_bundle,
+                &alice_store.session_store,
+                None
+            )
+            .await?
+        );
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn required_keys_names_the_pre_keys_from_the_bundle() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let outgoing_message = encrypt(&mut alice_store, &bob_address, "hello").await?;
+
+        let incoming_message = PreKeySignalMessage::try_from(outgoing_message.serialize())?;
+        assert_eq!(
+            required_keys(&incoming_message),
+            (
+                bob_pre_key_bundle.signed_pre_key_id()?,
+                bob_pre_key_bundle.pre_key_id()?
+            )
+        );
+        assert!(bob_pre_key_bundle.pre_key_id()?.is_some());
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn all_base_keys_lists_current_and_previous_sessions() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "hello").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+
+        let serialized =
+            |keys: Vec<PublicKey>| keys.iter().map(|key| key.serialize()).collect::<Vec<_>>();
+
+        let bobs_session_with_alice = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(
+            serialized(bobs_session_with_alice.all_base_keys()?),
+            vec![Box::<[u8]>::from(bobs_session_with_alice.alice_base_key()?)]
+        );
+
+        // Setting up a new session archives the old one, whose base key comes second.
+        let first_base_key = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found")
+            .alice_base_key()?
+            .to_vec();
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let alice_session_with_bob = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(
+            serialized(alice_session_with_bob.all_base_keys()?),
+            vec![
+                Box::<[u8]>::from(alice_session_with_bob.alice_base_key()?),
+                first_base_key.into_boxed_slice(),
+            ]
+        );
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn bundle_capabilities_are_recorded_in_the_session() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)
+            .await?
+            .with_capabilities(0b101);
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let alice_session_with_bob = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert!(alice_session_with_bob.peer_supports(0b100)?);
+        assert!(alice_session_with_bob.peer_supports(0b101)?);
+        assert!(!alice_session_with_bob.peer_supports(0b110)?);
+
+        // Sessions set up without a bundle support nothing.
+        let (alice_session_record, _) = initialize_sessions_v3()?;
+        assert!(!alice_session_record.peer_supports(0b001)?);
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn same_identity_compares_pinned_identities() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        // Two devices of Alice's account share an identity; Carol has her own.
+        let alice_identity = IdentityKeyPair::generate(&mut csprng);
+        let carol_identity = IdentityKeyPair::generate(&mut csprng);
+        let senders = [
+            (
+                ProtocolAddress::new("+14151111111".to_owned(), 1),
+                alice_identity,
+            ),
+            (
+                ProtocolAddress::new("+14151111111".to_owned(), 2),
+                alice_identity,
+            ),
+            (
+                ProtocolAddress::new("+14153333333".to_owned(), 1),
+                carol_identity,
+            ),
+        ];
+        for (address, identity) in &senders {
+            let mut store = InMemSignalProtocolStore::new(*identity, 5)?;
+            let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+            process_prekey_bundle(
+                &bob_address,
+                &mut store.session_store,
+                &mut store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                None,
+            )
+            .await?;
+            let message = encrypt(&mut store, &bob_address, "hello").await?;
+            decrypt(&mut bob_store, address, &message).await?;
+        }
+
+        assert!(same_identity(&senders[0].0, &senders[1].0, &bob_store.session_store, None).await?);
+        assert!(
+            !same_identity(&senders[0].0, &senders[2].0, &bob_store.session_store, None).await?
+        );
+        assert!(
+            !same_identity(
+                &senders[0].0,
+                &ProtocolAddress::new("+14155550000".to_owned(), 1),
+                &bob_store.session_store,
+                None
+            )
+            .await?
+        );
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn remote_registration_id_opt_is_none_when_unknown() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            alice_store
+                .load_session(&bob_address, None)
+                .await?
+                .expect("session found")
+                .remote_registration_id_opt()?,
+            Some(bob_store.get_local_registration_id(None).await?)
+        );
+
+        // Neither an empty record nor a session set up without a bundle has a registration ID.
+        assert_eq!(
+            SessionRecord::new_fresh().remote_registration_id_opt()?,
+            None
+        );
+        let (alice_session_record, _) = initialize_sessions_v3()?;
+        assert_eq!(alice_session_record.remote_registration_id_opt()?, None);
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn local_identity_key_reads_the_current_session() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "hello").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+
+        assert_eq!(
+            local_identity_key(&alice_address, &bob_store.session_store, None).await?,
+            *bob_store.get_identity_key_pair(None).await?.identity_key()
+        );
+        assert_eq!(
+            local_identity_key(&bob_address, &alice_store.session_store, None).await?,
+            *alice_store
+                .get_identity_key_pair(None)
+                .await?
+                .identity_key()
+        );
+        assert!(matches!(
+            local_identity_key(
+                &ProtocolAddress::new("+14155550000".to_owned(), 1),
+                &bob_store.session_store,
+                None
+            )
+            .await,
+            Err(SignalProtocolError::SessionNotFound(_))
+        ));
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn stable_id_survives_ratcheting() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "hello").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+
+        let alice_stable_id = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found")
+            .stable_id()?;
+        let bob_stable_id = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found")
+            .stable_id()?;
+        assert_ne!(alice_stable_id, bob_stable_id);
+
+        // The IDs stay the same as the session ratchets forward.
+        let reply = encrypt(&mut bob_store, &alice_address, "hi").await?;
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+        run_interaction(
+            &mut alice_store,
+            &alice_address,
+            &mut bob_store,
+            &bob_address,
+        )
+        .await?;
+        assert_eq!(
+            alice_store
+                .load_session(&bob_address, None)
+                .await?
+                .expect("session found")
+                .stable_id()?,
+            alice_stable_id
+        );
+        assert_eq!(
+            bob_store
+                .load_session(&alice_address, None)
+                .await?
+                .expect("session found")
+                .stable_id()?,
+            bob_stable_id
+        );
+
+        // A session set up without a pre-key bundle or message has no base key to derive it from.
+        assert!(initialize_sessions_v3()?.0.stable_id().is_err());
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
 #[allow(clippy::needless_range_loop)]
 fn run_session_interaction(
     alice_session: SessionRecord,
@@ -1988,3 +7085,321 @@ fn simultaneous_initiate_lost_message_repeated_messages() -> Result<(), SignalPr
     .now_or_never()
     .expect("sync")
 }
+
+#[test]
+fn speculative_decrypt_only_advances_session_on_commit() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let mut messages = Vec::new();
+        for i in 0..2 {
+            let message = encrypt(
+                &mut alice_store,
+                &bob_address,
+                &format!("speculative {}", i),
+            )
+            .await?;
+            messages.push(match message {
+                CiphertextMessage::SignalMessage(m) => m,
+                _ => panic!("unexpected message type"),
+            });
+        }
+
+        let stored_before = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found")
+            .serialize()?;
+        let speculative = SpeculativeDecrypt::new(
+            &messages[0],
+            &alice_address,
+            &bob_store.session_store,
+            &mut OsRng,
+            None,
+        )
+        .await?;
+        assert_eq!(speculative.plaintext(), b"speculative 0");
+        assert_ne!(speculative.record().serialize()?, stored_before);
+        assert_eq!(
+            bob_store
+                .load_session(&alice_address, None)
+                .await?
+                .expect("session found")
+                .serialize()?,
+            stored_before
+        );
+
+        assert_eq!(
+            speculative
+                .commit(
+                    &mut bob_store.session_store,
+                    &mut bob_store.identity_store,
+                    None
+                )
+                .await?,
+            b"speculative 0"
+        );
+        assert!(matches!(
+            decrypt(
+                &mut bob_store,
+                &alice_address,
+                &CiphertextMessage::SignalMessage(messages[0].clone()),
+            )
+            .await,
+            Err(SignalProtocolError::DuplicatedMessage(1, 0))
+        ));
+
+        // A speculative decrypt that has been overtaken is not stored.
+        let speculative = SpeculativeDecrypt::new(
+            &messages[1],
+            &alice_address,
+            &bob_store.session_store,
+            &mut OsRng,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            decrypt(
+                &mut bob_store,
+                &alice_address,
+                &CiphertextMessage::SignalMessage(messages[1].clone()),
+            )
+            .await?,
+            b"speculative 1"
+        );
+        assert!(matches!(
+            speculative
+                .commit(
+                    &mut bob_store.session_store,
+                    &mut bob_store.identity_store,
+                    None
+                )
+                .await,
+            Err(SignalProtocolError::SessionConcurrentlyModified(_))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn misframed_message_body_is_rejected_by_length() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let message = match encrypt(&mut alice_store, &bob_address, "framed").await? {
+            CiphertextMessage::SignalMessage(m) => m,
+            _ => panic!("unexpected message type"),
+        };
+
+        let sender_identity = IdentityKeyPair::generate(&mut OsRng);
+        let receiver_identity = IdentityKeyPair::generate(&mut OsRng);
+        for len in [0, 15, 33] {
+            let misframed = SignalMessage::new(
+                message.message_version(),
+                &[0; 32],
+                *message.sender_ratchet_key(),
+                message.counter(),
+                0,
+                &vec![0u8; len],
+                sender_identity.identity_key(),
+                receiver_identity.identity_key(),
+            )?;
+            assert!(matches!(
+                decrypt(
+                    &mut bob_store,
+                    &alice_address,
+                    &CiphertextMessage::SignalMessage(misframed),
+                )
+                .await,
+                Err(SignalProtocolError::InvalidCiphertextLength { len: l }) if l == len
+            ));
+        }
+
+        assert_eq!(
+            decrypt(
+                &mut bob_store,
+                &alice_address,
+                &CiphertextMessage::SignalMessage(message),
+            )
+            .await?,
+            b"framed"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn transient_store_errors_are_retried_without_blocking() -> Result<(), SignalProtocolError> {
+    /// Fails the next `failures` loads with a transient error.
+    struct FlakySessionStore {
+        inner: InMemSessionStore,
+        failures: std::cell::Cell<u32>,
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl SessionStore for FlakySessionStore {
+        async fn load_session(
+            &self,
+            address: &ProtocolAddress,
+            ctx: Context,
+        ) -> Result<Option<SessionRecord>, SignalProtocolError> {
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(SignalProtocolError::StoreTransient("busy".to_owned()));
+            }
+            self.inner.load_session(address, ctx).await
+        }
+
+        async fn store_session(
+            &mut self,
+            address: &ProtocolAddress,
+            record: &SessionRecord,
+            ctx: Context,
+        ) -> Result<(), SignalProtocolError> {
+            self.inner.store_session(address, record, ctx).await
+        }
+    }
+
+    async {
+        let mut csprng = OsRng;
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        let mut bob_session_store = FlakySessionStore {
+            inner: InMemSessionStore::new(),
+            failures: Default::default(),
+        };
+        bob_session_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let delays = Arc::new(Mutex::new(Vec::new()));
+        let recorded_delays = delays.clone();
+        let sleep: StoreRetrySleep = Arc::new(
+            move |delay| -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
+                recorded_delays.lock().expect("not poisoned").push(delay);
+                Box::pin(std::future::ready(()))
+            },
+        );
+        let config = DecryptionConfig {
+            store_retry: Some(StoreRetry {
+                max_attempts: 3,
+                backoff: std::time::Duration::from_millis(800),
+                sleep: Some(sleep),
+            }),
+            ..Default::default()
+        };
+
+        let message = encrypt(&mut alice_store, &bob_address, "retried").await?;
+
+        // Running out of attempts surfaces the store error.
+        bob_session_store.failures.set(3);
+        assert!(matches!(
+            message_decrypt_with_config(
+                &message,
+                &alice_address,
+                &mut bob_session_store,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut csprng,
+                &config,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::StoreTransient(_))
+        ));
+
+        // The second delay is capped.
+        delays.lock().expect("not poisoned").clear();
+        bob_session_store.failures.set(2);
+        let ptext = message_decrypt_with_config(
+            &message,
+            &alice_address,
+            &mut bob_session_store,
+            &mut bob_store.identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut csprng,
+            &config,
+            None,
+        )
+        .await?;
+        assert_eq!(ptext, b"retried");
+        assert_eq!(
+            *delays.lock().expect("not poisoned"),
+            [
+                std::time::Duration::from_millis(800),
+                std::time::Duration::from_secs(1)
+            ]
+        );
+
+        // Without a way to sleep, retries happen immediately, whatever the backoff.
+        let config = DecryptionConfig {
+            store_retry: Some(StoreRetry {
+                max_attempts: 2,
+                backoff: std::time::Duration::from_secs(3600),
+                sleep: None,
+            }),
+            ..Default::default()
+        };
+        let message = encrypt(&mut alice_store, &bob_address, "again").await?;
+        bob_session_store.failures.set(1);
+        let ptext = message_decrypt_with_config(
+            &message,
+            &alice_address,
+            &mut bob_session_store,
+            &mut bob_store.identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut csprng,
+            &config,
+            None,
+        )
+        .await?;
+        assert_eq!(ptext, b"again");
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
@@ -107,7 +107,30 @@ pub async fn create_pre_key_bundle<R: Rng + CryptoRng>(
 
 #[allow(dead_code)]
 pub fn initialize_sessions_v3() -> Result<(SessionRecord, SessionRecord), SignalProtocolError> {
-    let mut csprng = OsRng;
+    establish_session_pair(&mut OsRng, 3)
+}
+
+#[allow(dead_code)]
+/// Sets up matching Alice and Bob session records with the given session version, without going
+/// through a pre-key bundle.
+///
+/// Alice's record is ready for [`message_encrypt`]; Bob's is ready to decrypt what she sends
+/// (and, after that, to reply). Fails with [`SignalProtocolError::UnsupportedSessionVersion`] for
+/// versions outside [`SUPPORTED_SESSION_VERSIONS`].
+pub fn establish_session_pair<R: Rng + CryptoRng>(
+    mut csprng: &mut R,
+    version: u32,
+) -> Result<(SessionRecord, SessionRecord), SignalProtocolError> {
+    let config = match version {
+        3 => ProtocolConfig::default(),
+        #[cfg(feature = "weak-ciphers")]
+        v if v == CIPHERTEXT_MESSAGE_AES_128_VERSION as u32 => ProtocolConfig {
+            aes_128: true,
+            ..Default::default()
+        },
+        v => return Err(SignalProtocolError::UnsupportedSessionVersion(v)),
+    };
+
     let alice_identity = IdentityKeyPair::generate(&mut csprng);
     let bob_identity = IdentityKeyPair::generate(&mut csprng);
 
@@ -125,7 +148,8 @@ pub fn initialize_sessions_v3() -> Result<(SessionRecord, SessionRecord), Signal
         bob_ephemeral_key.public_key,
     );
 
-    let alice_session = initialize_alice_session_record(&alice_params, &mut csprng)?;
+    let alice_session =
+        initialize_alice_session_record_with_config(&alice_params, &config, &mut csprng)?;
 
     let bob_params = BobSignalProtocolParameters::new(
         bob_identity,
@@ -136,7 +160,7 @@ pub fn initialize_sessions_v3() -> Result<(SessionRecord, SessionRecord), Signal
         alice_base_key.public_key,
     );
 
-    let bob_session = initialize_bob_session_record(&bob_params)?;
+    let bob_session = initialize_bob_session_record_with_config(&bob_params, &config)?;
 
     Ok((alice_session, bob_session))
 }